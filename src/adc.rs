@@ -0,0 +1,151 @@
+//! Raw access to the RP2040 ADC for channels selected at runtime by a HostRequest.
+//! The HAL brings the ADC out of reset in init, conversions are started here.
+//! Channels 0..3 are GPIO26..GPIO29, channel 4 is the internal temperature sensor.
+
+use core::fmt;
+
+const ADC_CS: u32 = 0x4004_c000;
+const ADC_RESULT: u32 = 0x4004_c004;
+
+// Atomic bitmask set/clear aliases of every peripheral register (RP2040 datasheet 2.1.2)
+const REG_ALIAS_SET: u32 = 0x2000;
+const REG_ALIAS_CLR: u32 = 0x3000;
+
+const CS_TS_EN: u32 = 1 << 1;
+const CS_START_ONCE: u32 = 1 << 2;
+const CS_READY: u32 = 1 << 8;
+const CS_AINSEL_SHIFT: u32 = 12;
+const CS_AINSEL_MASK: u32 = 0b111 << CS_AINSEL_SHIFT;
+
+// Pad of the first ADC capable GPIO (GPIO26), so the digital input can be turned off
+const PADS_GPIO26: u32 = 0x4001_c000 + 0x04 + 4 * 26;
+const PAD_IE: u32 = 1 << 6;
+const PAD_OD: u32 = 1 << 7;
+
+const TEMP_SENSOR_CHANNEL: u8 = 4;
+
+fn set_bits(reg: u32, mask: u32) {
+    unsafe { core::ptr::write_volatile((reg + REG_ALIAS_SET) as *mut u32, mask) }
+}
+
+fn clear_bits(reg: u32, mask: u32) {
+    unsafe { core::ptr::write_volatile((reg + REG_ALIAS_CLR) as *mut u32, mask) }
+}
+
+// Full scale of a conversion, the ADC reference is the 3.3 V supply on the Pico
+pub const ADC_VREF_MV: u32 = 3300;
+const ADC_FULL_SCALE: u32 = 4096;
+// gpio threshold compares against half the supply unless a level is given
+pub const THRESHOLD_DEFAULT_MV: u32 = 1650;
+
+// ADC channel wired to a GPIO, None for the pins without an analog input
+pub fn gpio_channel(pin: u8) -> Option<u8> {
+    match pin {
+        26..=29 => Some(pin - 26),
+        _ => None,
+    }
+}
+
+pub fn to_mv(raw: u16) -> u32 {
+    raw as u32 * ADC_VREF_MV / ADC_FULL_SCALE
+}
+
+// Samples per line of an adc stream answer, 12 bit values fit 80 columns
+pub const STREAM_LINE: usize = 16;
+
+// Fill samples with read, one every period_us against a schedule fixed at
+// the first sample, so a slow read does not push the later ones back.
+// now_us reads the free running microsecond timer, the schedule start is returned
+pub fn capture(samples: &mut [u16], period_us: u32, mut now_us: impl FnMut() -> u32, mut read: impl FnMut() -> u16) -> u32 {
+    let start = now_us();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let due = i as u32 * period_us;
+        while now_us().wrapping_sub(start) < due {}
+        *sample = read();
+    }
+    start
+}
+
+// One line of an adc stream answer. With cfg timestamp on it starts with the
+// timer reading (in us, wraps after 71 minutes) of its first sample
+pub fn write_stream_line(out: &mut impl fmt::Write, samples: &[u16], stamp: Option<u32>) -> fmt::Result {
+    write!(out, "\n\r")?;
+    if let Some(stamp) = stamp {
+        write!(out, "[{}] ", stamp)?;
+    }
+    for sample in samples {
+        write!(out, "{} ", sample)?;
+    }
+    Ok(())
+}
+
+// Single 12 bit conversion on a channel, blocks until the result is ready (96 ADC clocks)
+pub fn read_channel(channel: u8) -> u16 {
+    if channel == TEMP_SENSOR_CHANNEL {
+        set_bits(ADC_CS, CS_TS_EN);
+    } else {
+        // Disconnect the digital input and output of the pad, as the datasheet recommends
+        let pad = PADS_GPIO26 + 4 * channel as u32;
+        clear_bits(pad, PAD_IE);
+        set_bits(pad, PAD_OD);
+    }
+    clear_bits(ADC_CS, CS_AINSEL_MASK);
+    set_bits(ADC_CS, (channel as u32) << CS_AINSEL_SHIFT);
+    set_bits(ADC_CS, CS_START_ONCE);
+    unsafe {
+        while core::ptr::read_volatile(ADC_CS as *const u32) & CS_READY == 0 {}
+        (core::ptr::read_volatile(ADC_RESULT as *const u32) & 0xFFF) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn capture_on_a_fixed_schedule() {
+        // Moves 3 us per look and wraps during the capture
+        let clock = Cell::new(0xFFFF_FFF0_u32);
+        let mut taken = [0_u32; 4];
+        let mut count = 0;
+        let mut samples = [0_u16; 4];
+        let now_us = || {
+            let now = clock.get();
+            clock.set(now.wrapping_add(3));
+            now
+        };
+        let start = capture(&mut samples, 100, now_us, || {
+            taken[count] = clock.get().wrapping_sub(0xFFFF_FFF0);
+            count += 1;
+            count as u16
+        });
+        assert_eq!((start, samples), (0xFFFF_FFF0, [1, 2, 3, 4]));
+        for (i, at) in taken.iter().enumerate() {
+            assert!(*at >= 100 * i as u32 && *at < 100 * i as u32 + 10);
+        }
+    }
+
+    #[test]
+    fn analog_pins_and_millivolts() {
+        assert_eq!((gpio_channel(26), gpio_channel(29), gpio_channel(3)), (Some(0), Some(3), None));
+        assert_eq!((to_mv(0), to_mv(2048), to_mv(4095)), (0, 1650, 3299));
+    }
+
+    #[test]
+    fn stream_line_with_and_without_stamp() {
+        let mut buf = [0_u8; 112];
+        let mut out = Wrapper::new(&mut buf);
+        write_stream_line(&mut out, &[1, 4095], None).unwrap();
+        assert_eq!(out.as_str(), "\n\r1 4095 ");
+        let mut buf = [0_u8; 112];
+        let mut out = Wrapper::new(&mut buf);
+        write_stream_line(&mut out, &[1, 4095], Some(123456)).unwrap();
+        assert_eq!(out.as_str(), "\n\r[123456] 1 4095 ");
+        // A full line of full scale samples and the widest stamp fit the buffer adc stream uses
+        let mut buf = [0_u8; 112];
+        let mut out = Wrapper::new(&mut buf);
+        assert!(write_stream_line(&mut out, &[4095; STREAM_LINE], Some(u32::MAX)).is_ok());
+    }
+}
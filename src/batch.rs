@@ -0,0 +1,314 @@
+//! Batch mode for provisioning scripts: lines between `begin` and `end` are
+//! parsed and held back, then handed to the dispatcher one after another on
+//! `end`, followed by a single summary of how many lines were good.
+//! `pause` holds lines back the same way until `resume`, which runs them
+//! without a summary.
+//! Held as an RTIC shared resource: the serial reader fills it, send_out drains it.
+
+use core::fmt::{self, Write};
+use heapless::Deque;
+use crate::fmt::Wrapper;
+use crate::serial::EMPTY_COMMAND;
+use crate::protocol::host::{Clean, HostRequest, ValidInterfaces, ValidOps};
+
+pub const BATCH_MAX: usize = 16;
+
+// Microseconds until the next batch step may run under cfg ratelimit hz,
+// 0 when it is due or there is no limit. last and now read the free running
+// microsecond timer, so the difference is taken wrapping
+pub fn step_wait_us(last: u32, now: u32, hz: u32) -> u32 {
+    if hz == 0 {
+        return 0
+    }
+    (1_000_000 / hz).saturating_sub(now.wrapping_sub(last))
+}
+
+pub struct Batch {
+    // Each request with its line index (from 1) in the block
+    requests: Deque<(usize, HostRequest<Clean>), BATCH_MAX>,
+    // Between begin and end, lines are queued here instead of run
+    open: bool,
+    // Between pause and resume, lines are queued here instead of run
+    paused: bool,
+    // After end or resume, until the last queued request has been dispatched
+    running: bool,
+    // The run ends with a summary, only for a begin/end block
+    summary: bool,
+    // Command lines seen since begin, empty lines and comments do not count
+    lines: usize,
+    // Lines that failed to parse or whose request failed when it was run
+    failed: usize,
+    // Line index (from 1) and error of the first line that failed
+    first_failure: Option<(usize, &'static str)>,
+    // Line index of the request next() handed out last
+    step: usize,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch {
+            requests: Deque::new(),
+            open: false,
+            paused: false,
+            running: false,
+            summary: false,
+            lines: 0,
+            failed: 0,
+            first_failure: None,
+            step: 0,
+        }
+    }
+
+    // Route one parsed line. Lines inside an open block are queued and their
+    // outcome recorded, anything else is handed back to be handled right away
+    pub fn route(&mut self, line: Result<HostRequest<Clean>, &'static str>) -> Result<HostRequest<Clean>, &'static str> {
+        let op = match &line {
+            Ok(hr) if hr.interface == ValidInterfaces::Config => hr.operation,
+            _ => ValidOps::None,
+        };
+        match op {
+            ValidOps::BatchBegin => {
+                if self.open { return Err("Batch already open\n\r") }
+                if self.paused { return Err("Paused, resume first\n\r") }
+                if self.running { return Err("Batch still running\n\r") }
+                *self = Batch::new();
+                self.open = true;
+                Err("\n\rBatch open, end runs it\n\r->")
+            }
+            ValidOps::BatchEnd => {
+                if !self.open { return Err("No batch open\n\r") }
+                self.open = false;
+                self.running = true;
+                self.summary = true;
+                Err("Ok")
+            }
+            ValidOps::Pause => {
+                if self.paused { return Err("Already paused\n\r") }
+                if self.open { return Err("Batch open, end it first\n\r") }
+                if self.running { return Err("Batch still running\n\r") }
+                *self = Batch::new();
+                self.paused = true;
+                Err("\n\rPaused, resume runs the queued lines\n\r->")
+            }
+            ValidOps::Resume => {
+                if !self.paused { return Err("Not paused\n\r") }
+                self.paused = false;
+                self.running = true;
+                Err("\n\rResumed\n\r->")
+            }
+            // Not queued even inside a block, the rest of the block is dropped
+            ValidOps::Estop => {
+                *self = Batch::new();
+                line
+            }
+            // Errors are answered right away, only requests wait for resume
+            _ if self.paused => {
+                let hr = line?;
+                if self.requests.push_back((0, hr)).is_err() {
+                    return Err("Pause queue full\n\r")
+                }
+                Err(EMPTY_COMMAND)
+            }
+            _ if !self.open => line,
+            _ => {
+                match line {
+                    // Nothing to queue
+                    Err("Ok") | Err(EMPTY_COMMAND) => {}
+                    Ok(hr) => {
+                        self.lines += 1;
+                        if self.requests.push_back((self.lines, hr)).is_err() {
+                            self.fail(self.lines, "Batch full\n\r");
+                        }
+                    }
+                    Err(err) => {
+                        self.lines += 1;
+                        self.fail(self.lines, err);
+                    }
+                }
+                Err(EMPTY_COMMAND)
+            }
+        }
+    }
+
+    // Requests run after every line has been parsed, so the first failure
+    // is the lowest line index rather than the first one recorded
+    fn fail(&mut self, line: usize, err: &'static str) {
+        self.failed += 1;
+        match self.first_failure {
+            Some((first, _)) if first <= line => {}
+            _ => self.first_failure = Some((line, err)),
+        }
+    }
+
+    // The request next() handed out failed when it was run
+    pub fn step_failed(&mut self, err: &'static str) {
+        if self.summary {
+            self.fail(self.step, err);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // The next queued request once the block has been closed by end
+    pub fn next(&mut self) -> Option<HostRequest<Clean>> {
+        if !self.running {
+            return None
+        }
+        let (line, hr) = self.requests.pop_front()?;
+        self.step = line;
+        Some(hr)
+    }
+
+    // Every queued request has been dispatched, the summary is due
+    pub fn is_finished(&self) -> bool {
+        self.running && self.requests.is_empty()
+    }
+
+    // Summary of the block, ends the run. Nothing for resumed lines
+    pub fn report(&mut self, out: &mut Wrapper) -> fmt::Result {
+        self.running = false;
+        if !self.summary {
+            return Ok(())
+        }
+        write!(out, "\n\rBatch: {} ok, {} failed", self.lines - self.failed, self.failed)?;
+        if let Some((index, err)) = self.first_failure {
+            write!(out, "\n\rline {}: {}", index, err.trim_end())?;
+        }
+        write!(out, "\n\r->")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::message_parse_build;
+
+    fn route(batch: &mut Batch, line: &str) -> Result<HostRequest<Clean>, &'static str> {
+        batch.route(message_parse_build(line).and_then(|hr| hr.init_clean()))
+    }
+
+    #[test]
+    fn block_counts_refused_and_failed_lines() {
+        let mut batch = Batch::new();
+        assert_eq!(route(&mut batch, "begin").err(), Some("\n\rBatch open, end runs it\n\r->"));
+        assert_eq!(route(&mut batch, "cfg retry 1").err(), Some(EMPTY_COMMAND));
+        assert_eq!(route(&mut batch, "cfg retry 9").err(), Some(EMPTY_COMMAND));
+        assert_eq!(route(&mut batch, "cfg retry 2").err(), Some(EMPTY_COMMAND));
+        assert_eq!(route(&mut batch, "cfg retry 3").err(), Some(EMPTY_COMMAND));
+        assert!(batch.next().is_none());
+        assert_eq!(route(&mut batch, "end").err(), Some("Ok"));
+        assert!(batch.is_running());
+        assert_eq!(batch.next().unwrap().payload[0], 1);
+        assert_eq!(batch.next().unwrap().payload[0], 2);
+        assert_eq!(batch.next().unwrap().payload[0], 3);
+        // The last line failed when run, line 2 was refused when queued and is reported first
+        batch.step_failed("Bus NAK\n\r");
+        assert!(batch.is_finished());
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        batch.report(&mut out).unwrap();
+        assert_eq!(out.as_str(), "\n\rBatch: 2 ok, 2 failed\n\rline 2: Retry count must be 0 to 5\n\r->");
+        assert!(!batch.is_running());
+    }
+
+    #[test]
+    fn end_without_begin() {
+        let mut batch = Batch::new();
+        assert_eq!(route(&mut batch, "end").err(), Some("No batch open\n\r"));
+        assert!(route(&mut batch, "cfg retry 1").is_ok());
+    }
+
+    #[test]
+    fn step_wait() {
+        assert_eq!(step_wait_us(0, 5, 0), 0);
+        assert_eq!(step_wait_us(1_000, 1_000, 100), 10_000);
+        assert_eq!(step_wait_us(1_000, 4_000, 100), 7_000);
+        assert_eq!(step_wait_us(1_000, 20_000, 100), 0);
+        // The timer wrapped between the steps
+        assert_eq!(step_wait_us(u32::MAX - 99, 400, 1000), 500);
+    }
+
+    #[test]
+    fn estop_drops_the_batch() {
+        let mut batch = Batch::new();
+        // Handed back right away from inside an open block, which is dropped
+        let _ = route(&mut batch, "begin");
+        let _ = route(&mut batch, "cfg retry 1");
+        assert_eq!(route(&mut batch, "estop").unwrap().operation, ValidOps::Estop);
+        assert_eq!(route(&mut batch, "end").err(), Some("No batch open\n\r"));
+        // And a running one stops stepping
+        let _ = route(&mut batch, "begin");
+        let _ = route(&mut batch, "cfg retry 1");
+        let _ = route(&mut batch, "end");
+        assert!(batch.is_running());
+        assert!(route(&mut batch, "ESTOP").is_ok());
+        assert!(!batch.is_running() && batch.next().is_none());
+    }
+
+    #[test]
+    fn pause_holds_lines_until_resume() {
+        let mut batch = Batch::new();
+        assert_eq!(route(&mut batch, "resume").err(), Some("Not paused\n\r"));
+        assert!(route(&mut batch, "pause").err().unwrap().starts_with("\n\rPaused"));
+        assert_eq!(route(&mut batch, "PAUSE").err(), Some("Already paused\n\r"));
+        assert_eq!(route(&mut batch, "begin").err(), Some("Paused, resume first\n\r"));
+        assert_eq!(route(&mut batch, "cfg retry 1").err(), Some(EMPTY_COMMAND));
+        // A line that does not parse is answered right away
+        assert_eq!(route(&mut batch, "cfg retry 9").err(), Some("Retry count must be 0 to 5\n\r"));
+        assert_eq!(route(&mut batch, "cfg retry 2").err(), Some(EMPTY_COMMAND));
+        assert!(batch.is_paused() && !batch.is_running() && batch.next().is_none());
+        assert_eq!(route(&mut batch, "RESUME").err(), Some("\n\rResumed\n\r->"));
+        assert_eq!(batch.next().unwrap().payload[0], 1);
+        assert_eq!(batch.next().unwrap().payload[0], 2);
+        assert!(batch.is_finished() && !batch.is_paused());
+    }
+
+    #[test]
+    fn resumed_lines_have_no_summary() {
+        let mut batch = Batch::new();
+        assert!(route(&mut batch, "pause").is_err());
+        assert_eq!(route(&mut batch, "cfg retry 1").err(), Some(EMPTY_COMMAND));
+        assert_eq!(route(&mut batch, "resume").err(), Some("\n\rResumed\n\r->"));
+        assert!(batch.next().is_some());
+        batch.step_failed("Bus NAK\n\r");
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        batch.report(&mut out).unwrap();
+        assert_eq!(out.as_str(), "");
+    }
+
+    #[test]
+    fn pause_queue_bounded() {
+        let mut batch = Batch::new();
+        let _ = route(&mut batch, "pause");
+        for _ in 0..BATCH_MAX {
+            assert_eq!(route(&mut batch, "cfg retry 1").err(), Some(EMPTY_COMMAND));
+        }
+        assert_eq!(route(&mut batch, "cfg retry 1").err(), Some("Pause queue full\n\r"));
+        // estop drops what was held back
+        assert_eq!(route(&mut batch, "estop").unwrap().operation, ValidOps::Estop);
+        assert!(!batch.is_paused() && batch.next().is_none());
+        let _ = route(&mut batch, "begin");
+        assert_eq!(route(&mut batch, "pause").err(), Some("Batch open, end it first\n\r"));
+    }
+
+    #[test]
+    fn dropped_line_not_counted() {
+        // An unknown line under cfg strict off is handled the way a simple command is
+        let mut batch = Batch::new();
+        let _ = route(&mut batch, "begin");
+        let dropped = crate::serial::unknown_line(message_parse_build("xyzzy 1 2"), false);
+        assert_eq!(batch.route(dropped.and_then(|hr| hr.init_clean())).err(), Some(EMPTY_COMMAND));
+        let _ = route(&mut batch, "end");
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        batch.report(&mut out).unwrap();
+        assert_eq!(out.as_str(), "\n\rBatch: 0 ok, 0 failed\n\r->");
+    }
+}
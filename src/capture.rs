@@ -0,0 +1,99 @@
+//! Bytes of the last transfer on an interface, for spi last and smi last.
+//! The dispatcher records what went out and came back around each transfer,
+//! the capture holds only the latest one.
+
+use crate::fmt::format_hexdump;
+use core::fmt;
+
+// Bytes kept per direction, the longest SPI transfer (spi wstr) fits
+pub const CAPTURE_MAX: usize = 64;
+
+pub struct Capture {
+    sent: [u8; CAPTURE_MAX],
+    sent_len: usize,
+    received: [u8; CAPTURE_MAX],
+    received_len: usize,
+    recorded: bool,
+}
+
+impl Capture {
+    pub const fn new() -> Capture {
+        Capture {
+            sent: [0; CAPTURE_MAX],
+            sent_len: 0,
+            received: [0; CAPTURE_MAX],
+            received_len: 0,
+            recorded: false,
+        }
+    }
+
+    // Replace the capture. Bytes past CAPTURE_MAX are dropped
+    pub fn record(&mut self, sent: &[u8], received: &[u8]) {
+        self.sent_len = sent.len().min(CAPTURE_MAX);
+        self.sent[..self.sent_len].copy_from_slice(&sent[..self.sent_len]);
+        self.recorded = true;
+        self.set_received(received);
+    }
+
+    // Bytes of an answer that arrives after the transfer was recorded
+    pub fn set_received(&mut self, received: &[u8]) {
+        self.received_len = received.len().min(CAPTURE_MAX);
+        self.received[..self.received_len].copy_from_slice(&received[..self.received_len]);
+    }
+
+    pub fn sent(&self) -> &[u8] {
+        &self.sent[..self.sent_len]
+    }
+
+    pub fn received(&self) -> &[u8] {
+        &self.received[..self.received_len]
+    }
+
+    // Answer to spi last and smi last, both directions as hex dumps
+    pub fn write_last(&self, out: &mut impl fmt::Write, name: &str) -> fmt::Result {
+        if !self.recorded {
+            return write!(out, "\n\r{} no transfer yet\n\r->", name)
+        }
+        write!(out, "\n\r{} last out {} bytes", name, self.sent_len)?;
+        format_hexdump(out, self.sent())?;
+        write!(out, "\n\r{} last in {} bytes", name, self.received_len)?;
+        format_hexdump(out, self.received())?;
+        write!(out, "\n\r->")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn last_transfer_shown() {
+        let mut capture = Capture::new();
+        let mut buf = [0_u8; 768];
+        let mut out = Wrapper::new(&mut buf);
+        capture.write_last(&mut out, "SPI").unwrap();
+        assert_eq!(out.as_str(), "\n\rSPI no transfer yet\n\r->");
+        capture.record(&[0x9F, 0, 0, 0], &[0xFF, 0xEF, 0x40, 0x18]);
+        let mut buf = [0_u8; 768];
+        let mut out = Wrapper::new(&mut buf);
+        capture.write_last(&mut out, "SPI").unwrap();
+        assert_eq!(out.as_str(), "\n\rSPI last out 4 bytes\n\r0000  9F 00 00 00                                      |....|\n\rSPI last in 4 bytes\n\r0000  FF EF 40 18                                      |..@.|\n\r->");
+    }
+
+    #[test]
+    fn record_replaces_both_directions() {
+        let mut capture = Capture::new();
+        capture.record(&[0x9F, 0, 0, 0], &[0xFF, 0xEF, 0x40, 0x18]);
+        // A later answer replaces the bytes in only
+        capture.set_received(&[1, 2]);
+        assert_eq!((capture.sent(), capture.received()), (&[0x9F, 0, 0, 0][..], &[1, 2][..]));
+        capture.record(&[0xAA; 80], &[]);
+        assert_eq!((capture.sent().len(), capture.received().len()), (64, 0));
+        // Both directions full fit the buffer spi last formats into
+        capture.record(&[0x41; 64], &[0x41; 64]);
+        let mut buf = [0_u8; 768];
+        let mut out = Wrapper::new(&mut buf);
+        assert!(capture.write_last(&mut out, "SPI").is_ok());
+    }
+}
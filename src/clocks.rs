@@ -0,0 +1,42 @@
+//! Clock measurement with the RP2040 frequency counter, for `cfg clocks`.
+//! The counter runs from clk_ref, so the result is what the clock tree
+//! actually delivers, not what init asked for.
+
+use rp_pico::XOSC_CRYSTAL_FREQ;
+
+// CLOCKS block frequency counter registers
+const FC0_REF_KHZ: *mut u32 = 0x4000_8080 as *mut u32;
+const FC0_MIN_KHZ: *mut u32 = 0x4000_8084 as *mut u32;
+const FC0_MAX_KHZ: *mut u32 = 0x4000_8088 as *mut u32;
+const FC0_INTERVAL: *mut u32 = 0x4000_8090 as *mut u32;
+const FC0_SRC: *mut u32 = 0x4000_8094 as *mut u32;
+const FC0_STATUS: *const u32 = 0x4000_8098 as *const u32;
+const FC0_RESULT: *const u32 = 0x4000_809c as *const u32;
+
+const FC0_STATUS_DONE: u32 = 1 << 4;
+const FC0_STATUS_RUNNING: u32 = 1 << 8;
+// Integer kHz starts above 5 fraction bits
+const FC0_RESULT_KHZ_SHIFT: u32 = 5;
+// 2^10 reference cycles, about 85 us per measurement
+const FC0_INTERVAL_DEFAULT: u32 = 10;
+
+// FC0_SRC selections
+pub const FC0_SRC_CLK_SYS: u32 = 0x09;
+pub const FC0_SRC_CLK_PERI: u32 = 0x0a;
+pub const FC0_SRC_CLK_USB: u32 = 0x0b;
+
+// Measure one clock in kHz, blocks for the counting interval.
+// clk_ref runs from the crystal, as set up in init
+pub fn measure_khz(src: u32) -> u32 {
+    unsafe {
+        while core::ptr::read_volatile(FC0_STATUS) & FC0_STATUS_RUNNING != 0 {}
+        core::ptr::write_volatile(FC0_REF_KHZ, XOSC_CRYSTAL_FREQ / 1000);
+        core::ptr::write_volatile(FC0_INTERVAL, FC0_INTERVAL_DEFAULT);
+        core::ptr::write_volatile(FC0_MIN_KHZ, 0);
+        core::ptr::write_volatile(FC0_MAX_KHZ, u32::MAX);
+        // Writing the source starts the count
+        core::ptr::write_volatile(FC0_SRC, src);
+        while core::ptr::read_volatile(FC0_STATUS) & FC0_STATUS_DONE == 0 {}
+        core::ptr::read_volatile(FC0_RESULT) >> FC0_RESULT_KHZ_SHIFT
+    }
+}
@@ -0,0 +1,296 @@
+//! Runtime settings changed by cfg commands.
+//! Held as an RTIC shared resource: the dispatcher applies cfg requests, the
+//! serial reader and the other tasks only read it.
+
+use crate::smi::{SMI_PREAMBLE_DEFAULT, SMI_MDC_PIN, SMI_MDIO_PIN};
+use crate::protocol::host::ValidInterfaces;
+use crate::serial::interface_word;
+use crate::log::Level;
+use crate::safestate::SafeState;
+use core::fmt;
+
+// USB CDC port an answer is written to. The control port gets the menu, prompts
+// and errors, the data port only carries device interface results
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Channel {
+    Control,
+    Data,
+}
+
+// How answers are delimited, set by cfg framing. Length puts a 2 byte little
+// endian byte count in front of every write, for hosts reading a raw device
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Framing {
+    Line,
+    Length,
+}
+
+// Frames plain smi r and smi w send, set by smi clause. Clause 45 reads and
+// writes take a device address (MMD) and a 16 bit register
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SmiClause {
+    Clause22,
+    Clause45,
+}
+
+// Byte order of the payload words spi r and spi w carry, set by cfg endian
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    // Bytes of word in the order they go out on the wire
+    pub fn word_bytes(&self, word: u32) -> [u8; 4] {
+        match self {
+            Endian::Little => word.to_le_bytes(),
+            Endian::Big => word.to_be_bytes(),
+        }
+    }
+
+    // Word made of four bytes in the order they came in from the wire
+    pub fn word_from_bytes(&self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+pub struct Config {
+    // Write "\n\r" before handling each line, on for interactive terminals.
+    // Scripts turn it off with cfg autonl off for cleaner output
+    pub auto_newline: bool,
+    // MDIO preamble bits sent before each SMI frame, 0..=32
+    pub smi_preamble: u8,
+    // MDC and MDIO pins of the SMI state machine, set by smi pins
+    pub smi_pins: (u8, u8),
+    // Clause of smi r and smi w
+    pub smi_clause: SmiClause,
+    // Interfaces locked out by cfg disable, one bit per ValidInterfaces value
+    pub disabled: u16,
+    // Where device interface results go, set by cfg channel
+    pub channel: Channel,
+    // Answers wait for room in the USB buffer instead of dropping what does not fit.
+    // Never loses output but stalls the dispatcher while the host is not reading
+    pub block_write: bool,
+    // USB polls idle makes before sleeping until the next interrupt. 0 leaves
+    // USB to its interrupt alone (lowest power), more polls cut latency
+    pub usb_poll: u8,
+    // Least severe log message written, set by cfg loglevel
+    pub log_level: Level,
+    // Pin all serial output is copied to, set by cfg mirror on
+    pub mirror: Option<u8>,
+    // Widest formatted answer line before it is split, 0 for no limit. Set by cfg maxline
+    pub max_line: u8,
+    // Delimiting of everything written to the host
+    pub framing: Framing,
+    // Interface of a command that starts with an operation, set by cfg iface
+    pub default_interface: Option<ValidInterfaces>,
+    // Most batch steps run per second, 0 for no limit. Set by cfg ratelimit
+    pub rate_limit: u16,
+    // Byte order of payload words on the wire
+    pub endian: Endian,
+    // Onboard LED shows the outcome of each request, set by cfg led
+    pub led: bool,
+    // Extra attempts of a failed blocking read, set by cfg retry
+    pub retries: u8,
+    // Gap between bytes of an SPI master transfer in microseconds, set by cfg slowdown
+    pub slowdown_us: u16,
+    // Lines that do not parse are answered with an error, off drops them. Set by cfg strict
+    pub strict: bool,
+    // Streamed lines (adc stream) start with the microsecond timer, set by cfg timestamp
+    pub timestamp: bool,
+    // Pin levels driven at boot, read from flash in init and saved by cfg safestate
+    pub safe_state: SafeState,
+    // Multi-drop address a line has to start with (>N), None takes every line. Set by cfg addr
+    pub address: Option<u8>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            auto_newline: true,
+            smi_preamble: SMI_PREAMBLE_DEFAULT,
+            smi_pins: (SMI_MDC_PIN, SMI_MDIO_PIN),
+            smi_clause: SmiClause::Clause22,
+            disabled: 0,
+            channel: Channel::Control,
+            block_write: false,
+            usb_poll: 0,
+            log_level: Level::Info,
+            mirror: None,
+            max_line: 0,
+            framing: Framing::Line,
+            default_interface: None,
+            rate_limit: 0,
+            endian: Endian::Little,
+            led: false,
+            retries: 0,
+            slowdown_us: 0,
+            strict: true,
+            timestamp: false,
+            safe_state: SafeState::new(),
+            address: None,
+        }
+    }
+
+    // Back to plain text answers after the resync escape. The caller turns
+    // length framing off in write_serial as well
+    pub fn reset_protocol(&mut self) {
+        self.framing = Framing::Line;
+    }
+
+    pub fn set_enabled(&mut self, interface: ValidInterfaces, enabled: bool) {
+        if enabled {
+            self.disabled &= !(1 << interface as u16);
+        } else {
+            self.disabled |= 1 << interface as u16;
+        }
+    }
+
+    // Port that answers a request. Bridge commands (cfg, stat) always answer on
+    // the control port so the data port stays free of anything but results
+    pub fn output_channel(&self, interface: ValidInterfaces) -> Channel {
+        match interface {
+            ValidInterfaces::Config | ValidInterfaces::Stat | ValidInterfaces::None => Channel::Control,
+            _ => self.channel,
+        }
+    }
+
+    // Answer to `cfg show`, one key=value line per setting, keyed by the cfg
+    // command that changes it. Add a line here for every new field
+    pub fn write_settings(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        write!(out, "\n\rautonl={}", on_off(self.auto_newline))?;
+        write!(out, "\n\rpreamble={}", self.smi_preamble)?;
+        write!(out, "\n\rpins={},{}", self.smi_pins.0, self.smi_pins.1)?;
+        write!(out, "\n\rclause={}", match self.smi_clause {
+            SmiClause::Clause22 => "22",
+            SmiClause::Clause45 => "45",
+        })?;
+        write!(out, "\n\rdisabled=0x{:04X}", self.disabled)?;
+        write!(out, "\n\rchannel={}", match self.channel {
+            Channel::Control => "control",
+            Channel::Data => "data",
+        })?;
+        write!(out, "\n\rblockwrite={}", on_off(self.block_write))?;
+        write!(out, "\n\rusbpoll={}", self.usb_poll)?;
+        write!(out, "\n\rloglevel={}", match self.log_level {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        })?;
+        match self.mirror {
+            Some(pin) => write!(out, "\n\rmirror={}", pin)?,
+            None => write!(out, "\n\rmirror=off")?,
+        }
+        write!(out, "\n\rmaxline={}", self.max_line)?;
+        write!(out, "\n\rframing={}", match self.framing {
+            Framing::Line => "line",
+            Framing::Length => "length",
+        })?;
+        write!(out, "\n\riface={}", self.default_interface.map_or("off", interface_word))?;
+        write!(out, "\n\rratelimit={}", self.rate_limit)?;
+        write!(out, "\n\rendian={}", match self.endian {
+            Endian::Little => "little",
+            Endian::Big => "big",
+        })?;
+        write!(out, "\n\rled={}", on_off(self.led))?;
+        write!(out, "\n\rretry={}", self.retries)?;
+        write!(out, "\n\rslowdown={}", self.slowdown_us)?;
+        write!(out, "\n\rstrict={}", on_off(self.strict))?;
+        write!(out, "\n\rtimestamp={}", on_off(self.timestamp))?;
+        write!(out, "\n\rsafestate=")?;
+        self.safe_state.write_table(out)?;
+        match self.address {
+            Some(address) => write!(out, "\n\raddr={}", address)?,
+            None => write!(out, "\n\raddr=off")?,
+        }
+        write!(out, "\n\r->")
+    }
+
+    // Checked by the dispatcher before a request is executed
+    pub fn check(&self, interface: ValidInterfaces) -> Result<(), &'static str> {
+        if self.disabled & (1 << interface as u16) != 0 {
+            return Err("Interface disabled\n\r")
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn every_setting_shown() {
+        let mut config = Config::new();
+        config.mirror = Some(22);
+        // Fits the buffer cfg show formats into
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        let shown = out.as_str();
+        for key in ["autonl", "preamble", "pins", "disabled", "channel", "blockwrite", "usbpoll", "loglevel", "mirror", "maxline", "led"] {
+            assert!(shown.split("\n\r").any(|line| line.split_once('=').map(|(shown_key, _)| shown_key) == Some(key)));
+        }
+        assert!(shown.contains("\n\rautonl=on\n\r") && shown.contains("\n\rpins=9,8\n\r") && shown.contains("\n\rmirror=22\n\r") && shown.contains("\n\rloglevel=info\n\r") && shown.contains("\n\rled=off\n\r"));
+        assert!(shown.ends_with("\n\r->"));
+    }
+
+    #[test]
+    fn resync_back_to_line_framing() {
+        let mut config = Config::new();
+        config.framing = Framing::Length;
+        config.reset_protocol();
+        assert_eq!(config.framing, Framing::Line);
+    }
+
+    #[test]
+    fn endian_word_order() {
+        assert_eq!(Config::new().endian, Endian::Little);
+        assert_eq!(Endian::Little.word_bytes(0x1234_5678), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(Endian::Big.word_bytes(0x1234_5678), [0x12, 0x34, 0x56, 0x78]);
+        for endian in [Endian::Little, Endian::Big] {
+            assert_eq!(endian.word_from_bytes(endian.word_bytes(0xDEAD_BEEF)), 0xDEAD_BEEF);
+        }
+    }
+
+    #[test]
+    fn safe_state_shown() {
+        let mut config = Config::new();
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().contains("\n\rsafestate=off\n\r"));
+        // Every pin in the table still fits the buffer cfg show formats into
+        for pin in 0..30 {
+            config.safe_state.set(pin, Some(true));
+        }
+        config.mirror = Some(29);
+        config.default_interface = Some(ValidInterfaces::SMI);
+        config.address = Some(254);
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        assert!(config.write_settings(&mut out).is_ok());
+        assert!(out.as_str().contains(",29:1\n\r"));
+    }
+
+    #[test]
+    fn address_shown() {
+        let mut config = Config::new();
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().contains("\n\raddr=off\n\r"));
+        config.address = Some(3);
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().contains("\n\raddr=3\n\r"));
+    }
+}
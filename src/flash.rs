@@ -0,0 +1,283 @@
+//! Flash layout for `cfg flash`: the size of the external QSPI flash, read
+//! from its JEDEC ID, and the sector at its end kept for persisted config.
+//! Reading the ID and writing the config take the flash out of XIP, so
+//! everything from the exit to the re-entry runs from RAM with interrupts off,
+//! like the pico-sdk flash_do_cmd. The config region holds the cfg safestate
+//! table at its start.
+
+use rp_pico::hal::rom_data;
+
+// Start of the XIP window, boot2 is its first 256 bytes
+const XIP_BASE: *const u32 = 0x1000_0000 as *const u32;
+const BOOT2_WORDS: usize = 64;
+
+// IO_QSPI GPIO_QSPI_SS_CTRL, OUTOVER drives the chip select by hand
+const QSPI_SS_CTRL: *mut u32 = 0x4001_800c as *mut u32;
+const SS_OUTOVER_SHIFT: u32 = 8;
+const SS_OUTOVER_MASK: u32 = 0b11 << SS_OUTOVER_SHIFT;
+const SS_OUTOVER_NORMAL: u32 = 0;
+const SS_OUTOVER_LOW: u32 = 2;
+const SS_OUTOVER_HIGH: u32 = 3;
+
+// XIP SSI status and data registers
+const SSI_SR: *const u32 = 0x1800_0028 as *const u32;
+const SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+
+// Read JEDEC ID: manufacturer, memory type, capacity as log2 of the size in bytes.
+// The same command identifies a flash on the SPI master
+pub const JEDEC_READ_ID: u8 = 0x9F;
+
+// Erase unit of the flash, the config region is the last one
+pub const FLASH_SECTOR: u32 = 4096;
+// Program unit, the config region is written as one page
+const FLASH_PAGE: usize = 256;
+// Block erase the ROM uses when the range covers a whole block, never for one sector
+const FLASH_BLOCK: u32 = 1 << 16;
+const FLASH_BLOCK_ERASE: u8 = 0xD8;
+// Capacity codes of the chips the XIP window can map, 128 KiB to 16 MiB
+const CAPACITY_MIN: u32 = 17;
+const CAPACITY_MAX: u32 = 24;
+
+// Executed from RAM, XIP is off while the flash is busy with the command
+static mut BOOT2_COPY: [u32; BOOT2_WORDS] = [0; BOOT2_WORDS];
+
+// Flash size in bytes from the 24 bit JEDEC ID, None for a capacity code
+// outside of what the RP2040 can use (no chip, or the bus read all ones)
+pub fn size_from_jedec(id: u32) -> Option<u32> {
+    let capacity = id & 0xFF;
+    if (CAPACITY_MIN..=CAPACITY_MAX).contains(&capacity) {
+        Some(1 << capacity)
+    } else {
+        None
+    }
+}
+
+// Manufacturer IDs, the first byte of the JEDEC ID, of the usual SPI NOR flash vendors
+const FLASH_VENDORS: [(u8, &str); 8] = [
+    (0x01, "Infineon"),
+    (0x1F, "Adesto"),
+    (0x20, "Micron"),
+    (0x9D, "ISSI"),
+    (0xBF, "Microchip"),
+    (0xC2, "Macronix"),
+    (0xC8, "GigaDevice"),
+    (0xEF, "Winbond"),
+];
+
+// Full 24 bit JEDEC IDs of common parts, for spi flashid
+const FLASH_CHIPS: [(u32, &str); 16] = [
+    (0x20BA18, "N25Q128A"),
+    (0x20BA19, "N25Q256A"),
+    (0x9D6017, "IS25LP064"),
+    (0x9D6018, "IS25LP128"),
+    (0xBF2641, "SST26VF016B"),
+    (0xBF2642, "SST26VF032B"),
+    (0xC22016, "MX25L3233F"),
+    (0xC22017, "MX25L6433F"),
+    (0xC22018, "MX25L12835F"),
+    (0xC84016, "GD25Q32"),
+    (0xC84017, "GD25Q64"),
+    (0xC84018, "GD25Q128"),
+    (0xEF4015, "W25Q16JV"),
+    (0xEF4016, "W25Q32JV"),
+    (0xEF4017, "W25Q64JV"),
+    (0xEF4018, "W25Q128JV"),
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FlashIdent {
+    pub vendor: Option<&'static str>,
+    pub part: Option<&'static str>,
+    pub size: Option<u32>,
+}
+
+// What the tables know of a JEDEC ID. None when nothing answered: the bus
+// read all ones (MISO pulled up) or all zeros
+pub fn decode_jedec(id: u32) -> Option<FlashIdent> {
+    if id == 0 || id == 0xFF_FFFF {
+        return None
+    }
+    let manufacturer = (id >> 16) as u8;
+    Some(FlashIdent {
+        vendor: FLASH_VENDORS.iter().find(|(code, _)| *code == manufacturer).map(|(_, name)| *name),
+        part: FLASH_CHIPS.iter().find(|(chip, _)| *chip == id).map(|(_, name)| *name),
+        size: size_from_jedec(id),
+    })
+}
+
+// Answer to spi flashid
+pub fn write_flash_ident(out: &mut impl core::fmt::Write, id: u32) -> core::fmt::Result {
+    let ident = match decode_jedec(id) {
+        Some(ident) => ident,
+        None => return write!(out, "\n\rNo SPI flash answered jedec=0x{:06X}\n\r->", id),
+    };
+    write!(out, "\n\rSPI flash jedec=0x{:06X} {} {}", id, ident.vendor.unwrap_or("unknown vendor"), ident.part.unwrap_or("unknown part"))?;
+    if let Some(size) = ident.size {
+        write!(out, " {} KiB", size / 1024)?;
+    }
+    write!(out, "\n\r->")
+}
+
+// Offset from the start of flash of the sector persisted config goes in.
+// Kept clear of the program, which grows up from offset 0
+pub fn config_region(size: u32) -> u32 {
+    size - FLASH_SECTOR
+}
+
+// boot2 puts back the fast XIP mode after a command, it has to be copied out
+// while the flash can still be read
+fn copy_boot2() {
+    unsafe {
+        let copy = core::ptr::addr_of_mut!(BOOT2_COPY) as *mut u32;
+        for i in 0..BOOT2_WORDS {
+            copy.add(i).write(core::ptr::read_volatile(XIP_BASE.add(i)));
+        }
+    }
+}
+
+// The first words of the config region at offset, read through XIP
+pub fn read_config<const N: usize>(offset: u32) -> [u32; N] {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = unsafe { core::ptr::read_volatile(XIP_BASE.add(offset as usize / 4 + i)) };
+    }
+    words
+}
+
+// Erase the config region at offset and write words to its start. The sector
+// erase keeps interrupts off for tens of milliseconds
+pub fn write_config(offset: u32, words: &[u32]) {
+    let mut page = [0xFF_u8; FLASH_PAGE];
+    for (word, bytes) in words.iter().zip(page.chunks_mut(4)) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    copy_boot2();
+    cortex_m::interrupt::free(|_| unsafe {
+        erase_and_program(offset, &page,
+            rom_data::connect_internal_flash::ptr(),
+            rom_data::flash_exit_xip::ptr(),
+            rom_data::flash_range_erase::ptr(),
+            rom_data::flash_range_program::ptr(),
+            rom_data::flash_flush_cache::ptr())
+    });
+}
+
+// The 24 bit JEDEC ID of the flash the program runs from
+pub fn read_jedec_id() -> u32 {
+    copy_boot2();
+    let mut command = [JEDEC_READ_ID, 0, 0, 0];
+    cortex_m::interrupt::free(|_| unsafe {
+        do_command(&mut command,
+            rom_data::connect_internal_flash::ptr(),
+            rom_data::flash_exit_xip::ptr(),
+            rom_data::flash_flush_cache::ptr())
+    });
+    u32::from_be_bytes([0, command[1], command[2], command[3]])
+}
+
+// Send command and replace it with what the flash answered, byte for byte.
+// The ROM functions are looked up by the caller, the lookup runs from flash
+#[inline(never)]
+#[link_section = ".data.bar"] // Execute from IRAM
+unsafe fn do_command(command: &mut [u8; 4], connect: *const u32, exit_xip: *const u32, flush_cache: *const u32) {
+    let connect: extern "C" fn() = core::mem::transmute(connect);
+    let exit_xip: extern "C" fn() = core::mem::transmute(exit_xip);
+    let flush_cache: extern "C" fn() = core::mem::transmute(flush_cache);
+    connect();
+    exit_xip();
+
+    set_chip_select(SS_OUTOVER_LOW);
+    let mut sent = 0;
+    let mut received = 0;
+    while received < command.len() {
+        let status = core::ptr::read_volatile(SSI_SR);
+        if sent < command.len() && status & SSI_SR_TFNF != 0 {
+            core::ptr::write_volatile(SSI_DR0, command[sent] as u32);
+            sent += 1;
+        }
+        if status & SSI_SR_RFNE != 0 {
+            command[received] = core::ptr::read_volatile(SSI_DR0 as *const u32) as u8;
+            received += 1;
+        }
+    }
+    set_chip_select(SS_OUTOVER_HIGH);
+    // Back to the SSI, which holds it high while idle
+    set_chip_select(SS_OUTOVER_NORMAL);
+
+    flush_cache();
+    // Thumb code, the low bit of the address has to be set
+    let boot2: extern "C" fn() = core::mem::transmute(core::ptr::addr_of!(BOOT2_COPY) as usize + 1);
+    boot2();
+}
+
+// Erase the sector at offset and program page to its start, from RAM like do_command
+#[inline(never)]
+#[link_section = ".data.bar"] // Execute from IRAM
+unsafe fn erase_and_program(offset: u32, page: &[u8; FLASH_PAGE], connect: *const u32, exit_xip: *const u32,
+    erase: *const u32, program: *const u32, flush_cache: *const u32) {
+    let connect: extern "C" fn() = core::mem::transmute(connect);
+    let exit_xip: extern "C" fn() = core::mem::transmute(exit_xip);
+    let erase: extern "C" fn(u32, usize, u32, u8) = core::mem::transmute(erase);
+    let program: extern "C" fn(u32, *const u8, usize) = core::mem::transmute(program);
+    let flush_cache: extern "C" fn() = core::mem::transmute(flush_cache);
+    connect();
+    exit_xip();
+    erase(offset, FLASH_SECTOR as usize, FLASH_BLOCK, FLASH_BLOCK_ERASE);
+    program(offset, page.as_ptr(), FLASH_PAGE);
+    flush_cache();
+    let boot2: extern "C" fn() = core::mem::transmute(core::ptr::addr_of!(BOOT2_COPY) as usize + 1);
+    boot2();
+}
+
+#[inline(always)]
+unsafe fn set_chip_select(outover: u32) {
+    let ctrl = core::ptr::read_volatile(QSPI_SS_CTRL) & !SS_OUTOVER_MASK;
+    core::ptr::write_volatile(QSPI_SS_CTRL, ctrl | outover << SS_OUTOVER_SHIFT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn size_from_the_capacity_byte() {
+        assert_eq!(size_from_jedec(0xEF4015), Some(2 * 1024 * 1024));
+        // No chip answering, the bus floats high or reads low
+        assert_eq!(size_from_jedec(0xFFFFFF), None);
+        assert_eq!(size_from_jedec(0), None);
+    }
+
+    #[test]
+    fn config_in_the_last_sector() {
+        assert_eq!(config_region(2 * 1024 * 1024), 0x1F_F000);
+    }
+
+    #[test]
+    fn jedec_decoded() {
+        assert_eq!(decode_jedec(0xEF4018), Some(FlashIdent { vendor: Some("Winbond"), part: Some("W25Q128JV"), size: Some(16 << 20) }));
+        // Known vendor, capacity byte out of range
+        assert_eq!(decode_jedec(0xC84099), Some(FlashIdent { vendor: Some("GigaDevice"), part: None, size: None }));
+        // Unknown vendor, the size still comes from the capacity byte
+        assert_eq!(decode_jedec(0x424015), Some(FlashIdent { vendor: None, part: None, size: Some(2 << 20) }));
+        assert_eq!((decode_jedec(0xFF_FFFF), decode_jedec(0)), (None, None));
+    }
+
+    #[test]
+    fn flash_ident_answer() {
+        let mut buf = [0_u8; 96];
+        let mut out = Wrapper::new(&mut buf);
+        write_flash_ident(&mut out, 0xEF4018).unwrap();
+        assert_eq!(out.as_str(), "\n\rSPI flash jedec=0xEF4018 Winbond W25Q128JV 16384 KiB\n\r->");
+        let mut buf = [0_u8; 96];
+        let mut out = Wrapper::new(&mut buf);
+        write_flash_ident(&mut out, 0x123456).unwrap();
+        assert_eq!(out.as_str(), "\n\rSPI flash jedec=0x123456 unknown vendor unknown part\n\r->");
+        let mut buf = [0_u8; 96];
+        let mut out = Wrapper::new(&mut buf);
+        write_flash_ident(&mut out, 0xFF_FFFF).unwrap();
+        assert_eq!(out.as_str(), "\n\rNo SPI flash answered jedec=0xFFFFFF\n\r->");
+    }
+}
@@ -6,16 +6,28 @@
 
 use core::fmt;
 
+// Ends a line cut by cfg maxline, the text goes on in the next line
+pub const CONTINUATION: &str = "\\\n\r";
+
 pub(crate) struct Wrapper<'a> {
     buf: &'a mut [u8],
     offset: usize,
+    // Widest line in characters, marker included, 0 for no limit
+    max_line: usize,
+    column: usize,
 }
 
+impl<'a> Wrapper<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Wrapper { buf, offset: 0, max_line: 0, column: 0 }
+    }
 
-impl<'a> fmt::Write for Wrapper<'a> {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        let bytes = s.as_bytes();
+    // Lines longer than max_line are split with CONTINUATION
+    pub fn with_max_line(buf: &'a mut [u8], max_line: usize) -> Self {
+        Wrapper { buf, offset: 0, max_line, column: 0 }
+    }
 
+    fn push(&mut self, bytes: &[u8]) -> fmt::Result {
         // Skip over already copied data.
         let remainder = &mut self.buf[self.offset..];
         // Check if there is space remaining (return error instead of panicing)
@@ -33,4 +45,128 @@ impl<'a> fmt::Write for Wrapper<'a> {
 
         Ok(())
     }
+
+    // The formatted text written so far
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.offset]).unwrap_or("")
+    }
+}
+
+impl<'a> fmt::Write for Wrapper<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.max_line == 0 {
+            return self.push(s.as_bytes())
+        }
+        // Whatever does not fit the buffer is cut at a character boundary
+        let mut utf8 = [0_u8; 4];
+        for c in s.chars() {
+            if c == '\n' || c == '\r' {
+                self.column = 0;
+            } else if self.column + 1 == self.max_line {
+                self.push(CONTINUATION.as_bytes())?;
+                self.column = 0;
+            }
+            self.push(c.encode_utf8(&mut utf8).as_bytes())?;
+            if c != '\n' && c != '\r' {
+                self.column += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sent ahead of each write under cfg framing length: the number of bytes
+// that follow, little endian. No answer buffer comes near 64 KiB
+pub fn length_prefix(body: &[u8]) -> [u8; 2] {
+    (body.len() as u16).to_le_bytes()
+}
+
+// Bytes per hex dump line
+pub const HEXDUMP_WIDTH: usize = 16;
+
+// Classic hex dump for byte results, one line per 16 bytes, each led by "\n\r":
+// offset, the bytes in hex, then the printable ASCII with '.' for anything else
+pub fn format_hexdump(out: &mut impl fmt::Write, bytes: &[u8]) -> fmt::Result {
+    for (line, chunk) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+        write!(out, "\n\r{:04X} ", line * HEXDUMP_WIDTH)?;
+        for i in 0..HEXDUMP_WIDTH {
+            match chunk.get(i) {
+                Some(byte) => write!(out, " {:02X}", byte)?,
+                // Keep the ASCII gutter aligned on a short last line
+                None => out.write_str("   ")?,
+            }
+        }
+        out.write_str("  |")?;
+        for byte in chunk {
+            let shown = if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' };
+            out.write_char(shown)?;
+        }
+        out.write_str("|")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn hexdump_line() {
+        let mut buf = [0_u8; 128];
+        let mut out = Wrapper::new(&mut buf);
+        format_hexdump(&mut out, b"Hello, World!\n\x00\xFF").unwrap();
+        assert_eq!(out.as_str(), "\n\r0000  48 65 6C 6C 6F 2C 20 57 6F 72 6C 64 21 0A 00 FF  |Hello, World!...|");
+    }
+
+    #[test]
+    fn hexdump_short_last_line() {
+        let mut buf = [0_u8; 192];
+        let mut out = Wrapper::new(&mut buf);
+        format_hexdump(&mut out, &[0x41; 18]).unwrap();
+        let (first, last) = out.as_str()[2..].split_once("\n\r").unwrap();
+        assert!(first.starts_with("0000  41 41") && first.ends_with("  |AAAAAAAAAAAAAAAA|"));
+        // The gutter lines up with the full line above
+        assert!(last.starts_with("0010  41 41 ") && last.ends_with("  |AA|"));
+        assert_eq!(last.len() - "AA|".len(), first.len() - "AAAAAAAAAAAAAAAA|".len());
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::with_max_line(&mut buf, 16);
+        write!(out, "\n\r{}\n\rshort\n\r", "0123456789ABCDEFGHIJ").unwrap();
+        assert_eq!(out.as_str(), "\n\r0123456789ABCDE\\\n\rFGHIJ\n\rshort\n\r");
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        write!(out, "{}", "0123456789ABCDEFGHIJ").unwrap();
+        assert_eq!(out.as_str(), "0123456789ABCDEFGHIJ");
+    }
+
+    #[test]
+    fn hexdump_within_the_width() {
+        let mut buf = [0_u8; 256];
+        let mut out = Wrapper::with_max_line(&mut buf, 32);
+        format_hexdump(&mut out, &[0x41; 16]).unwrap();
+        assert!(out.as_str().split("\n\r").all(|line| line.len() <= 32));
+        assert!(out.as_str().contains(CONTINUATION));
+    }
+
+    #[test]
+    fn split_line_cut_at_the_buffer_end() {
+        let mut buf = [0_u8; 20];
+        let mut out = Wrapper::with_max_line(&mut buf, 16);
+        assert!(write!(out, "{}", "0123456789ABCDEFGHIJKLMNOP").is_err());
+        assert_eq!(out.as_str(), "0123456789ABCDE\\\n\rFG");
+    }
+
+    #[test]
+    fn frame_length_little_endian() {
+        assert_eq!(length_prefix(&[0; 0x123]), [0x23, 0x01]);
+        assert_eq!(length_prefix(b""), [0, 0]);
+        let mut buf = [0_u8; 320];
+        let mut out = Wrapper::new(&mut buf);
+        format_hexdump(&mut out, &[0x41; 20]).unwrap();
+        assert_eq!(u16::from_le_bytes(length_prefix(out.as_str().as_bytes())) as usize, out.as_str().len());
+    }
 }
@@ -0,0 +1,225 @@
+//! Raw register access for GPIO pins selected at runtime by a HostRequest.
+//! The HAL only hands out typed pins at init, so settings addressed by pin
+//! number from the host (pad drive, slew, ...) are written here directly.
+
+use crate::protocol::GPIO_MAX_PIN;
+
+// PADS_BANK0 register block, GPIOx pad control at offset 0x04 + 4 * x
+const PADS_BANK0_BASE: u32 = 0x4001_c000;
+// IO_BANK0 register block, GPIOx control at offset 0x04 + 8 * x
+const IO_BANK0_BASE: u32 = 0x4001_4000;
+
+// Raw interrupt status, 4 bits per GPIO starting at INTR0, 8 GPIOs per register
+const IO_BANK0_INTR0: u32 = IO_BANK0_BASE + 0x0f0;
+
+// Atomic bitmask set/clear aliases of every peripheral register (RP2040 datasheet 2.1.2)
+const REG_ALIAS_SET: u32 = 0x2000;
+const REG_ALIAS_CLR: u32 = 0x3000;
+
+// Single-cycle IO block, has its own set/clear registers instead of aliases
+const SIO_GPIO_IN: *const u32 = 0xd000_0004 as *const u32;
+const SIO_GPIO_OUT_SET: *mut u32 = 0xd000_0014 as *mut u32;
+const SIO_GPIO_OUT_CLR: *mut u32 = 0xd000_0018 as *mut u32;
+const SIO_GPIO_OE_SET: *mut u32 = 0xd000_0024 as *mut u32;
+const SIO_GPIO_OE_CLR: *mut u32 = 0xd000_0028 as *mut u32;
+
+// Pad control fields
+const PAD_SLEWFAST: u32 = 1 << 0;
+const PAD_SCHMITT: u32 = 1 << 1;
+const PAD_PDE: u32 = 1 << 2;
+const PAD_PUE: u32 = 1 << 3;
+const PAD_DRIVE_SHIFT: u32 = 4;
+const PAD_DRIVE_MASK: u32 = 0b11 << PAD_DRIVE_SHIFT;
+const PAD_IE: u32 = 1 << 6;
+const PAD_OD: u32 = 1 << 7;
+
+// Interrupt status bits of one GPIO. The edge bits latch until cleared
+pub const INTR_EDGE_LOW: u32 = 1 << 2;
+pub const INTR_EDGE_HIGH: u32 = 1 << 3;
+
+// IO control function select
+const FUNCSEL_MASK: u32 = 0x1f;
+pub const FUNCSEL_PWM: u32 = 4;
+pub const FUNCSEL_SIO: u32 = 5;
+
+fn pad_ctrl(pin: u8) -> u32 {
+    PADS_BANK0_BASE + 0x04 + 4 * pin as u32
+}
+
+fn io_ctrl(pin: u8) -> u32 {
+    IO_BANK0_BASE + 0x04 + 8 * pin as u32
+}
+
+fn set_bits(reg: u32, mask: u32) {
+    unsafe { core::ptr::write_volatile((reg + REG_ALIAS_SET) as *mut u32, mask) }
+}
+
+fn clear_bits(reg: u32, mask: u32) {
+    unsafe { core::ptr::write_volatile((reg + REG_ALIAS_CLR) as *mut u32, mask) }
+}
+
+// Set the pad drive strength. milliamps must already be validated as 2, 4, 8 or 12
+pub fn set_drive_strength(pin: u8, milliamps: u32) {
+    let code = match milliamps {
+        2 => 0,
+        4 => 1,
+        8 => 2,
+        _ => 3,
+    };
+    clear_bits(pad_ctrl(pin), PAD_DRIVE_MASK);
+    set_bits(pad_ctrl(pin), code << PAD_DRIVE_SHIFT);
+}
+
+// Set the pad output slew rate
+pub fn set_slew_fast(pin: u8, fast: bool) {
+    if fast {
+        set_bits(pad_ctrl(pin), PAD_SLEWFAST);
+    } else {
+        clear_bits(pad_ctrl(pin), PAD_SLEWFAST);
+    }
+}
+
+// Input hysteresis of the pad, on by default after reset
+pub fn set_schmitt(pin: u8, enable: bool) {
+    if enable {
+        set_bits(pad_ctrl(pin), PAD_SCHMITT);
+    } else {
+        clear_bits(pad_ctrl(pin), PAD_SCHMITT);
+    }
+}
+
+// Route a pin to a peripheral, clearing any overrides
+pub fn set_function(pin: u8, funcsel: u32) {
+    unsafe { core::ptr::write_volatile(io_ctrl(pin) as *mut u32, funcsel) }
+}
+
+// Hand a pin to the SIO so it can be driven and read by number, input enabled
+pub fn set_function_sio(pin: u8) {
+    set_function(pin, FUNCSEL_SIO);
+    set_bits(pad_ctrl(pin), PAD_IE);
+    clear_bits(pad_ctrl(pin), PAD_OD);
+}
+
+pub fn set_output_enable(pin: u8, enable: bool) {
+    let reg = if enable { SIO_GPIO_OE_SET } else { SIO_GPIO_OE_CLR };
+    unsafe { core::ptr::write_volatile(reg, 1 << pin) }
+}
+
+// estop: every pin the SIO or a PWM slice drives back to an input.
+// Pins of the peripherals (SPI, UART, PIO) keep their function
+pub fn release_outputs() {
+    for pin in 0..=GPIO_MAX_PIN as u8 {
+        let funcsel = unsafe { core::ptr::read_volatile(io_ctrl(pin) as *const u32) } & FUNCSEL_MASK;
+        if funcsel == FUNCSEL_SIO || funcsel == FUNCSEL_PWM {
+            set_function_sio(pin);
+            set_output_enable(pin, false);
+        }
+    }
+}
+
+pub fn set_level(pin: u8, high: bool) {
+    let reg = if high { SIO_GPIO_OUT_SET } else { SIO_GPIO_OUT_CLR };
+    unsafe { core::ptr::write_volatile(reg, 1 << pin) }
+}
+
+// Open drain emulation: low enables the output at level 0, high tri-states the
+// pin and leaves it to the bus pull-up
+pub fn set_open_drain(pin: u8, high: bool) {
+    set_function_sio(pin);
+    set_level(pin, false);
+    set_output_enable(pin, !high);
+}
+
+// Pulse a pin high then low, cycles times, and leave it driven low.
+// wait_us blocks for the given microseconds, the caller owns the timer
+pub fn strobe(pin: u8, high_us: u32, low_us: u32, cycles: u32, mut wait_us: impl FnMut(u32)) {
+    set_function_sio(pin);
+    set_level(pin, false);
+    set_output_enable(pin, true);
+    for _ in 0..cycles {
+        set_level(pin, true);
+        wait_us(high_us);
+        set_level(pin, false);
+        wait_us(low_us);
+    }
+}
+
+// Pull-up and pull-down of a pad, the reset state is pull-down only
+pub fn set_pulls(pin: u8, up: bool, down: bool) {
+    if up { set_bits(pad_ctrl(pin), PAD_PUE) } else { clear_bits(pad_ctrl(pin), PAD_PUE) }
+    if down { set_bits(pad_ctrl(pin), PAD_PDE) } else { clear_bits(pad_ctrl(pin), PAD_PDE) }
+}
+
+// Settle time after the drive pin changes, covers a cable's capacitance against the pull
+const CONN_SETTLE_US: u32 = 10;
+
+// Whether drive_pin is wired to sense_pin: the sense pin has to follow the drive
+// pin high against its pull-down and low against its pull-up, a floating or
+// stuck pin fails one of the two. Both pins are left inputs with the reset pulls
+pub fn connected(drive_pin: u8, sense_pin: u8, mut wait_us: impl FnMut(u32)) -> bool {
+    set_function_sio(drive_pin);
+    set_function_sio(sense_pin);
+    set_output_enable(sense_pin, false);
+    set_output_enable(drive_pin, true);
+
+    set_pulls(sense_pin, false, true);
+    set_level(drive_pin, true);
+    wait_us(CONN_SETTLE_US);
+    let follows_high = read_level(sense_pin);
+
+    set_pulls(sense_pin, true, false);
+    set_level(drive_pin, false);
+    wait_us(CONN_SETTLE_US);
+    let follows_low = !read_level(sense_pin);
+
+    set_output_enable(drive_pin, false);
+    set_pulls(sense_pin, false, true);
+    follows_high && follows_low
+}
+
+fn intr_reg(pin: u8) -> u32 {
+    IO_BANK0_INTR0 + 4 * (pin as u32 / 8)
+}
+
+fn intr_shift(pin: u8) -> u32 {
+    4 * (pin as u32 % 8)
+}
+
+// The 4 interrupt status bits of a pin: level low, level high, edge low, edge high
+pub fn interrupt_status(pin: u8) -> u32 {
+    unsafe { (core::ptr::read_volatile(intr_reg(pin) as *const u32) >> intr_shift(pin)) & 0xF }
+}
+
+// Clear the latched edge flags of a pin, they are write 1 to clear.
+// Level flags follow the pin and can not be cleared
+pub fn clear_edge_status(pin: u8) {
+    let mask = (INTR_EDGE_LOW | INTR_EDGE_HIGH) << intr_shift(pin);
+    unsafe { core::ptr::write_volatile(intr_reg(pin) as *mut u32, mask) }
+}
+
+pub fn read_level(pin: u8) -> bool {
+    unsafe { core::ptr::read_volatile(SIO_GPIO_IN) & (1 << pin) != 0 }
+}
+
+// Time one period of the signal on an input pin: wait for a rising edge, then
+// time the high and the low phase up to the next rising edge. now_us reads a
+// free running microsecond counter. None if the period is not over in timeout_us
+pub fn measure(pin: u8, timeout_us: u32, now_us: impl Fn() -> u32) -> Option<(u32, u32)> {
+    set_function_sio(pin);
+    set_output_enable(pin, false);
+    let start = now_us();
+    let wait_for = |level: bool| -> Option<u32> {
+        while read_level(pin) != level {
+            if now_us().wrapping_sub(start) > timeout_us {
+                return None
+            }
+        }
+        Some(now_us())
+    };
+    // A pin already high is in the middle of a pulse
+    wait_for(false)?;
+    let rise = wait_for(true)?;
+    let fall = wait_for(false)?;
+    let next_rise = wait_for(true)?;
+    Some((fall.wrapping_sub(rise), next_rise.wrapping_sub(fall)))
+}
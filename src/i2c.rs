@@ -0,0 +1,118 @@
+//! Raw access to the RP2040 I2C0 block for settings changed at runtime by a HostRequest.
+//! The HAL sets the master up in init and carries out the transfers, the bus
+//! speed set by i2c cfg is written here directly, the way the pico-sdk does.
+
+use core::fmt;
+
+// i2c scan probes every address outside the two reserved blocks
+pub const SCAN_FIRST: u8 = 0x08;
+pub const SCAN_LAST: u8 = 0x77;
+
+const I2C0_BASE: u32 = 0x4004_4000;
+
+// Register offsets
+const IC_CON: u32 = 0x00;
+const IC_FS_SCL_HCNT: u32 = 0x1c;
+const IC_FS_SCL_LCNT: u32 = 0x20;
+const IC_ENABLE: u32 = 0x6c;
+const IC_SDA_HOLD: u32 = 0x7c;
+const IC_FS_SPKLEN: u32 = 0xa0;
+
+// Fast mode counts are used for every speed, standard mode ones are left alone
+const IC_CON_SPEED_SHIFT: u32 = 1;
+const IC_CON_SPEED_MASK: u32 = 0b11 << IC_CON_SPEED_SHIFT;
+const IC_CON_SPEED_FAST: u32 = 2;
+const IC_SDA_TX_HOLD_MASK: u32 = 0xFFFF;
+
+// SCL high and low counts, spike filter length and SDA hold time of one
+// bus speed, in system clock cycles
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Timing {
+    pub hcnt: u32,
+    pub lcnt: u32,
+    pub spklen: u32,
+    pub sda_hold: u32,
+}
+
+// SCL is low for 60 % of the period. SDA is held 300 ns past the falling
+// edge of SCL, 120 ns at fast mode plus
+pub fn timing(hz: u32, sys_clk_hz: u32) -> Timing {
+    let period = (sys_clk_hz + hz / 2) / hz;
+    let lcnt = period * 3 / 5;
+    let sda_hold = if hz < 1_000_000 {
+        sys_clk_hz * 3 / 10_000_000 + 1
+    } else {
+        sys_clk_hz * 3 / 25_000_000 + 1
+    };
+    Timing {
+        hcnt: period - lcnt,
+        lcnt,
+        spklen: if lcnt < 16 { 1 } else { lcnt / 16 },
+        sda_hold,
+    }
+}
+
+fn read(offset: u32) -> u32 {
+    unsafe { core::ptr::read_volatile((I2C0_BASE + offset) as *const u32) }
+}
+
+fn write(offset: u32, value: u32) {
+    unsafe { core::ptr::write_volatile((I2C0_BASE + offset) as *mut u32, value) }
+}
+
+// Change the bus speed, hz must already be validated. The block is disabled
+// while the counts change, only call it between transfers
+pub fn set_speed(hz: u32, sys_clk_hz: u32) {
+    let timing = timing(hz, sys_clk_hz);
+    write(IC_ENABLE, 0);
+    write(IC_CON, (read(IC_CON) & !IC_CON_SPEED_MASK) | IC_CON_SPEED_FAST << IC_CON_SPEED_SHIFT);
+    write(IC_FS_SCL_HCNT, timing.hcnt);
+    write(IC_FS_SCL_LCNT, timing.lcnt);
+    write(IC_FS_SPKLEN, timing.spklen);
+    write(IC_SDA_HOLD, (read(IC_SDA_HOLD) & !IC_SDA_TX_HOLD_MASK) | timing.sda_hold);
+    write(IC_ENABLE, 1);
+}
+
+// Answer of i2c scan, the addresses that acknowledged in ascending order
+pub fn write_scan(out: &mut impl fmt::Write, found: impl Iterator<Item = u8>) -> fmt::Result {
+    write!(out, "\n\rI2C scan")?;
+    let mut none = true;
+    for addr in found {
+        write!(out, " 0x{:02X}", addr)?;
+        none = false;
+    }
+    if none {
+        write!(out, " none")?;
+    }
+    write!(out, "\n\r->")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn counts_at_125_mhz() {
+        let counts = |hz| {
+            let t = timing(hz, 125_000_000);
+            (t.hcnt, t.lcnt, t.spklen, t.sda_hold)
+        };
+        assert_eq!(counts(100_000), (500, 750, 46, 38));
+        assert_eq!(counts(400_000), (126, 187, 11, 38));
+        // Fast mode plus holds SDA 120 ns
+        assert_eq!(counts(1_000_000), (50, 75, 4, 16));
+    }
+
+    #[test]
+    fn scan_answer() {
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        write_scan(&mut out, [0x1D_u8, 0x50, 0x68].iter().copied()).unwrap();
+        assert_eq!(out.as_str(), "\n\rI2C scan 0x1D 0x50 0x68\n\r->");
+        let mut buf = [0_u8; 64];
+        let mut out = Wrapper::new(&mut buf);
+        write_scan(&mut out, core::iter::empty()).unwrap();
+        assert_eq!(out.as_str(), "\n\rI2C scan none\n\r->");
+    }
+}
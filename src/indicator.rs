@@ -0,0 +1,86 @@
+//! Onboard LED feedback, turned on by cfg led: a short blink for every request
+//! the dispatcher carries out, steady on after a line that was refused until
+//! the next request goes through. The LED is on GPIO25, the pin gpio set drives.
+
+use embedded_hal::digital::v2::OutputPin;
+use crate::log::Level;
+
+// Long enough to see, every request takes this much longer while cfg led is on
+pub const BLINK_US: u32 = 20_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Pattern {
+    Blink,
+    Lit,
+}
+
+impl Pattern {
+    // Ok for a request that was carried out, Err with what the host was told
+    // otherwise. None leaves the LED as it is: simple commands and
+    // informational answers (batch open, empty line) are neither
+    pub fn of(outcome: Result<(), &str>) -> Option<Pattern> {
+        match outcome {
+            Ok(()) => Some(Pattern::Blink),
+            Err("Ok") => None,
+            Err(message) if Level::of_message(message) == Level::Error => Some(Pattern::Lit),
+            Err(_) => None,
+        }
+    }
+}
+
+// wait_us blocks for the given microseconds, the caller owns the timer
+pub fn show(pattern: Pattern, led: &mut impl OutputPin, mut wait_us: impl FnMut(u32)) {
+    let _ = led.set_high();
+    if pattern == Pattern::Blink {
+        wait_us(BLINK_US);
+        let _ = led.set_low();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the levels it was set to
+    struct Led {
+        levels: [bool; 4],
+        count: usize,
+    }
+
+    impl OutputPin for Led {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.levels[self.count] = false;
+            self.count += 1;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            self.levels[self.count] = true;
+            self.count += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pattern_of_the_outcome() {
+        assert_eq!(Pattern::of(Ok(())), Some(Pattern::Blink));
+        assert_eq!(Pattern::of(Err("Invalid Interface\n\r")), Some(Pattern::Lit));
+        assert_eq!(Pattern::of(Err("Interface disabled\n\r")), Some(Pattern::Lit));
+        assert_eq!(Pattern::of(Err("Ok")), None);
+        assert_eq!(Pattern::of(Err("\n\r->")), None);
+        assert_eq!(Pattern::of(Err("\n\rBatch open, end runs it\n\r->")), None);
+    }
+
+    #[test]
+    fn blink_turns_off_again() {
+        let mut led = Led { levels: [false; 4], count: 0 };
+        let mut waited = 0;
+        show(Pattern::Blink, &mut led, |us| waited += us);
+        assert_eq!((&led.levels[..led.count], waited), (&[true, false][..], BLINK_US));
+        let mut led = Led { levels: [false; 4], count: 0 };
+        show(Pattern::Lit, &mut led, |_| panic!("lit does not wait"));
+        assert_eq!(&led.levels[..led.count], &[true][..]);
+    }
+}
@@ -0,0 +1,315 @@
+//! Bit-banged JTAG for `jtag mem`: one word of target memory read through the
+//! ARM debug access port (ADIv5 JTAG-DP and MEM-AP 0). The SIO drives TCK, TMS
+//! and TDI on GPIO2, GPIO3 and GPIO6 and samples TDO on GPIO7, for a chain
+//! with the DAP as its only TAP.
+
+use crate::gpio;
+
+pub const JTAG_TCK_PIN: u8 = 2;
+pub const JTAG_TMS_PIN: u8 = 3;
+pub const JTAG_TDI_PIN: u8 = 6;
+pub const JTAG_TDO_PIN: u8 = 7;
+
+// JTAG-DP instructions, 4 bit IR
+const IR_LEN: u32 = 4;
+const IR_DPACC: u32 = 0xA;
+const IR_APACC: u32 = 0xB;
+// RnW, A[3:2] and 32 data bits
+const ACC_LEN: u32 = 35;
+
+// DP registers
+const DP_CTRL_STAT: u8 = 0x4;
+const DP_SELECT: u8 = 0x8;
+const DP_RDBUFF: u8 = 0xC;
+// MEM-AP registers, bank 0
+const AP_CSW: u8 = 0x0;
+const AP_TAR: u8 = 0x4;
+const AP_DRW: u8 = 0xC;
+
+// CTRL/STAT debug and system power up requests and their acknowledges.
+// STICKYERR is set by a faulted bus access and cleared by writing it back
+const CTRL_PWRUP_REQ: u32 = 1 << 30 | 1 << 28;
+const CTRL_PWRUP_ACK: u32 = 1 << 31 | 1 << 29;
+const CTRL_STICKYERR: u32 = 1 << 5;
+// 32 bit accesses, no address increment, privileged data access
+const CSW_WORD: u32 = 0x2300_0002;
+
+// ACK of an access scan
+const ACK_OK: u64 = 0b010;
+const ACK_WAIT: u64 = 0b001;
+// Scans answered WAIT before the access is given up, and power up polls
+const JTAG_RETRIES: u32 = 100;
+// Spins per TCK half period, a few MHz at the default system clock
+const HALF_PERIOD_SPINS: u32 = 16;
+
+// One TCK cycle: TMS and TDI are set up for the rising edge, TDO is sampled
+// before it. Returns TDO
+pub trait Tap {
+    fn clock(&mut self, tms: bool, tdi: bool) -> bool;
+}
+
+pub struct Pins;
+
+impl Pins {
+    // Hand the four pins to the SIO, TCK low, TMS high and TDO pulled up
+    pub fn take() -> Pins {
+        for pin in [JTAG_TCK_PIN, JTAG_TMS_PIN, JTAG_TDI_PIN] {
+            gpio::set_function_sio(pin);
+            gpio::set_level(pin, pin == JTAG_TMS_PIN);
+            gpio::set_output_enable(pin, true);
+        }
+        gpio::set_function_sio(JTAG_TDO_PIN);
+        gpio::set_output_enable(JTAG_TDO_PIN, false);
+        gpio::set_pulls(JTAG_TDO_PIN, true, false);
+        Pins
+    }
+}
+
+fn half_period() {
+    for _ in 0..HALF_PERIOD_SPINS {
+        core::hint::spin_loop();
+    }
+}
+
+impl Tap for Pins {
+    fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+        gpio::set_level(JTAG_TMS_PIN, tms);
+        gpio::set_level(JTAG_TDI_PIN, tdi);
+        half_period();
+        let tdo = gpio::read_level(JTAG_TDO_PIN);
+        gpio::set_level(JTAG_TCK_PIN, true);
+        half_period();
+        gpio::set_level(JTAG_TCK_PIN, false);
+        tdo
+    }
+}
+
+// Test-Logic-Reset from any state, then Run-Test/Idle
+fn reset(tap: &mut impl Tap) {
+    for _ in 0..5 {
+        tap.clock(true, false);
+    }
+    tap.clock(false, false);
+}
+
+// Shift len bits of the IR or of the DR, LSB first, from Run-Test/Idle and back.
+// Returns the bits shifted out
+fn scan(tap: &mut impl Tap, ir: bool, bits: u64, len: u32) -> u64 {
+    tap.clock(true, false);
+    if ir {
+        tap.clock(true, false);
+    }
+    // Capture, then Shift
+    tap.clock(false, false);
+    tap.clock(false, false);
+    let mut out = 0;
+    for i in 0..len {
+        // The last bit leaves for Exit1
+        if tap.clock(i == len - 1, bits >> i & 1 != 0) {
+            out |= 1 << i;
+        }
+    }
+    // Update, then Run-Test/Idle
+    tap.clock(true, false);
+    tap.clock(false, false);
+    out
+}
+
+struct Dap<'a, T: Tap> {
+    tap: &'a mut T,
+    // Instruction in the IR, None after reset
+    ir: Option<u32>,
+}
+
+impl<'a, T: Tap> Dap<'a, T> {
+    // One DPACC or APACC access. Returns the result of the previous read,
+    // the DP only answers a read on the scan after it
+    fn access(&mut self, ir: u32, addr: u8, read: bool, data: u32) -> Result<u32, &'static str> {
+        if self.ir != Some(ir) {
+            scan(self.tap, true, ir as u64, IR_LEN);
+            self.ir = Some(ir);
+        }
+        let request = (data as u64) << 3 | (addr as u64 >> 2 & 0b11) << 1 | read as u64;
+        // A WAIT answer drops the request, it is sent again
+        for _ in 0..JTAG_RETRIES {
+            let answer = scan(self.tap, false, request, ACC_LEN);
+            match answer & 0b111 {
+                ACK_OK => return Ok((answer >> 3) as u32),
+                ACK_WAIT => continue,
+                _ => return Err("JTAG DAP failed, no target answered\n\r"),
+            }
+        }
+        Err("JTAG DAP timed out\n\r")
+    }
+
+    fn write(&mut self, ir: u32, addr: u8, data: u32) -> Result<(), &'static str> {
+        self.access(ir, addr, false, data).map(|_| ())
+    }
+
+    // Read a register, RDBUFF collects the result
+    fn read(&mut self, ir: u32, addr: u8) -> Result<u32, &'static str> {
+        self.access(ir, addr, true, 0)?;
+        self.access(IR_DPACC, DP_RDBUFF, true, 0)
+    }
+}
+
+// Read the word at a word aligned address through MEM-AP 0, powering up the
+// debug domain first
+pub fn read_mem(tap: &mut impl Tap, address: u32) -> Result<u32, &'static str> {
+    reset(tap);
+    let mut dap = Dap { tap, ir: None };
+    // Clears a STICKYERR left from an earlier access
+    dap.write(IR_DPACC, DP_CTRL_STAT, CTRL_PWRUP_REQ | CTRL_STICKYERR)?;
+    let mut powered = false;
+    for _ in 0..JTAG_RETRIES {
+        if dap.read(IR_DPACC, DP_CTRL_STAT)? & CTRL_PWRUP_ACK == CTRL_PWRUP_ACK {
+            powered = true;
+            break
+        }
+    }
+    if !powered {
+        return Err("JTAG debug power up timed out\n\r")
+    }
+    dap.write(IR_DPACC, DP_SELECT, 0)?;
+    dap.write(IR_APACC, AP_CSW, CSW_WORD)?;
+    dap.write(IR_APACC, AP_TAR, address)?;
+    let word = dap.read(IR_APACC, AP_DRW)?;
+    if dap.read(IR_DPACC, DP_CTRL_STAT)? & CTRL_STICKYERR != 0 {
+        dap.write(IR_DPACC, DP_CTRL_STAT, CTRL_PWRUP_REQ | CTRL_STICKYERR)?;
+        return Err("JTAG memory access failed\n\r")
+    }
+    Ok(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum State { Reset, Idle, SelectDr, CaptureDr, ShiftDr, Exit1Dr, UpdateDr, SelectIr, CaptureIr, ShiftIr, Exit1Ir, UpdateIr }
+
+    // A JTAG-DP with a MEM-AP in front of memory, None for a faulting address.
+    // Answers WAIT to the first waits access scans
+    struct Target {
+        state: State,
+        ir: u32,
+        shift: u64,
+        ctrl: u32,
+        csw: u32,
+        tar: u32,
+        read_result: u32,
+        waits: u32,
+        waited: bool,
+        memory: fn(u32) -> Option<u32>,
+    }
+
+    impl Target {
+        fn new(memory: fn(u32) -> Option<u32>) -> Target {
+            Target { state: State::Reset, ir: 0xE, shift: 0, ctrl: 0, csw: 0, tar: 0, read_result: 0, waits: 0, waited: false, memory }
+        }
+
+        fn update_dr(&mut self) {
+            if self.waited {
+                return
+            }
+            let (read, addr, data) = (self.shift & 1 != 0, (self.shift >> 1 & 0b11) as u8 * 4, (self.shift >> 3) as u32);
+            match (self.ir, read, addr) {
+                (IR_DPACC, false, DP_CTRL_STAT) => {
+                    // Power comes up at once, a written STICKYERR is cleared
+                    self.ctrl = (self.ctrl & !data & CTRL_STICKYERR) | (data & CTRL_PWRUP_REQ) << 1;
+                }
+                (IR_DPACC, true, DP_CTRL_STAT) => self.read_result = self.ctrl,
+                (IR_DPACC, _, _) => {}
+                (IR_APACC, false, AP_CSW) => self.csw = data,
+                (IR_APACC, false, AP_TAR) => self.tar = data,
+                (IR_APACC, true, AP_DRW) => match (self.memory)(self.tar) {
+                    Some(word) => self.read_result = word,
+                    None => self.ctrl |= CTRL_STICKYERR,
+                },
+                _ => panic!("unexpected access"),
+            }
+        }
+    }
+
+    impl Tap for Target {
+        fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+            let tdo = self.shift & 1 != 0;
+            let len = if self.state == State::ShiftIr { IR_LEN } else { ACC_LEN };
+            match self.state {
+                State::ShiftDr | State::ShiftIr => self.shift = self.shift >> 1 | (tdi as u64) << (len - 1),
+                State::CaptureDr => {
+                    self.waited = self.waits > 0;
+                    let ack = if self.waited { self.waits -= 1; ACK_WAIT } else { ACK_OK };
+                    self.shift = (self.read_result as u64) << 3 | ack;
+                }
+                State::CaptureIr => self.shift = 0b0001,
+                State::UpdateIr => self.ir = self.shift as u32,
+                State::UpdateDr => self.update_dr(),
+                _ => {}
+            }
+            self.state = match (self.state, tms) {
+                (State::Reset, true) => State::Reset,
+                (State::Reset, false) | (State::Idle, false) | (State::UpdateDr, false) | (State::UpdateIr, false) => State::Idle,
+                (State::Idle, true) | (State::UpdateDr, true) | (State::UpdateIr, true) => State::SelectDr,
+                (State::SelectDr, false) => State::CaptureDr,
+                (State::SelectDr, true) => State::SelectIr,
+                (State::SelectIr, false) => State::CaptureIr,
+                (State::SelectIr, true) => State::Reset,
+                (State::CaptureDr, false) | (State::ShiftDr, false) => State::ShiftDr,
+                (State::CaptureIr, false) | (State::ShiftIr, false) => State::ShiftIr,
+                (State::ShiftDr, true) => State::Exit1Dr,
+                (State::ShiftIr, true) => State::Exit1Ir,
+                (State::Exit1Dr, true) => State::UpdateDr,
+                (State::Exit1Ir, true) => State::UpdateIr,
+                (state, _) => panic!("no pause states: {:?}", state),
+            };
+            if self.state == State::Reset {
+                self.ir = 0xE;
+            }
+            tdo
+        }
+    }
+
+    fn sram(address: u32) -> Option<u32> {
+        (address >> 28 == 0x2).then_some(address ^ 0xA5A5_0000)
+    }
+
+    #[test]
+    fn word_through_the_mem_ap() {
+        let mut target = Target::new(sram);
+        assert_eq!(read_mem(&mut target, 0x2000_0000), Ok(0x85A5_0000));
+        assert_eq!((target.csw, target.tar), (CSW_WORD, 0x2000_0000));
+        assert_eq!(read_mem(&mut target, 0x2000_0104), Ok(0x85A5_0104));
+    }
+
+    #[test]
+    fn wait_is_retried() {
+        let mut target = Target::new(sram);
+        target.waits = 3;
+        assert_eq!(read_mem(&mut target, 0x2000_0010), Ok(0x85A5_0010));
+        target.waits = JTAG_RETRIES;
+        assert_eq!(read_mem(&mut target, 0x2000_0010), Err("JTAG DAP timed out\n\r"));
+    }
+
+    #[test]
+    fn faulted_access_is_reported_and_cleared() {
+        let mut target = Target::new(sram);
+        assert_eq!(read_mem(&mut target, 0x1000_0000), Err("JTAG memory access failed\n\r"));
+        assert_eq!(target.ctrl & CTRL_STICKYERR, 0);
+        assert!(read_mem(&mut target, 0x2000_0000).is_ok());
+    }
+
+    struct NoTarget;
+
+    impl Tap for NoTarget {
+        // TDO floats up to the pull-up
+        fn clock(&mut self, _tms: bool, _tdi: bool) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn nothing_on_the_chain() {
+        assert_eq!(read_mem(&mut NoTarget, 0x2000_0000), Err("JTAG DAP failed, no target answered\n\r"));
+    }
+}
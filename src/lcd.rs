@@ -0,0 +1,146 @@
+//! HD44780 character LCD behind a PCF8574 I2C backpack, the module these
+//! displays are usually sold with. It shares I2C0 with the i2c commands, the
+//! controller runs in 4 bit mode: P0 RS, P1 RW (held low), P2 E, P3 backlight
+//! and P4..P7 D4..D7.
+
+// Address of the backpack with its three address jumpers open
+pub const LCD_ADDR: u8 = 0x27;
+
+// Geometry lcd init accepts, the controller holds 80 characters of DDRAM
+pub const LCD_MAX_COLS: u32 = 40;
+pub const LCD_MAX_ROWS: u32 = 4;
+pub const LCD_MAX_CHARS: u32 = 80;
+
+const RS: u8 = 1 << 0;
+const EN: u8 = 1 << 2;
+const BACKLIGHT: u8 = 1 << 3;
+
+// Instructions
+const CLEAR: u8 = 0x01;
+const ENTRY_INCREMENT: u8 = 0x06;
+const DISPLAY_ON: u8 = 0x0C;
+const FUNCTION_4BIT: u8 = 0x20;
+const FUNCTION_2LINE: u8 = 0x08;
+const SET_DDRAM: u8 = 0x80;
+
+// Execution times from the datasheet, clear takes 1.52 ms, the rest 37 us.
+// A byte on the bus at 100 kHz takes longer than 37 us, only clear waits
+const CLEAR_US: u32 = 2_000;
+const POWER_UP_US: u32 = 40_000;
+const WAKE_US: u32 = 4_100;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Geometry {
+    pub cols: u8,
+    pub rows: u8,
+}
+
+// Expander bytes that clock one byte into the controller: each nibble is put
+// on D4..D7 with E high, then latched as E falls. rs selects data over instruction
+pub fn byte_frames(byte: u8, rs: bool) -> [u8; 4] {
+    let flags = BACKLIGHT | if rs { RS } else { 0 };
+    let high = (byte & 0xF0) | flags;
+    let low = (byte << 4) | flags;
+    [high | EN, high, low | EN, low]
+}
+
+// DDRAM address of a position. Lines 2 and 3 of a four line display continue
+// lines 0 and 1 past the visible columns
+pub fn ddram_address(geometry: Geometry, row: u8, col: u8) -> u8 {
+    let start = match row {
+        0 => 0x00,
+        1 => 0x40,
+        2 => geometry.cols,
+        _ => 0x40 + geometry.cols,
+    };
+    start + col
+}
+
+fn instruction<E>(send: &mut impl FnMut(&[u8]) -> Result<(), E>, byte: u8) -> Result<(), E> {
+    send(&byte_frames(byte, false))
+}
+
+// The reset by instruction sequence of the datasheet (figure 24), it brings
+// the controller into 4 bit mode from any state, then clears the display.
+// send writes expander bytes to the backpack, wait_us blocks
+pub fn init<E>(geometry: Geometry, mut send: impl FnMut(&[u8]) -> Result<(), E>, mut wait_us: impl FnMut(u32)) -> Result<(), E> {
+    wait_us(POWER_UP_US);
+    // Three times 8 bit mode, then 4 bit mode, as single nibbles
+    for (nibble, wait) in [(0x30, WAKE_US), (0x30, WAKE_US), (0x30, 100), (0x20, 100)] {
+        send(&[nibble | BACKLIGHT | EN, nibble | BACKLIGHT])?;
+        wait_us(wait);
+    }
+    let lines = if geometry.rows > 1 { FUNCTION_2LINE } else { 0 };
+    instruction(&mut send, FUNCTION_4BIT | lines)?;
+    instruction(&mut send, DISPLAY_ON)?;
+    instruction(&mut send, ENTRY_INCREMENT)?;
+    clear(send, wait_us)
+}
+
+pub fn clear<E>(mut send: impl FnMut(&[u8]) -> Result<(), E>, mut wait_us: impl FnMut(u32)) -> Result<(), E> {
+    instruction(&mut send, CLEAR)?;
+    wait_us(CLEAR_US);
+    Ok(())
+}
+
+// text must fit the row from col, the dispatcher checks it against the geometry
+pub fn write_at<E>(geometry: Geometry, row: u8, col: u8, text: &[u8], mut send: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+    instruction(&mut send, SET_DDRAM | ddram_address(geometry, row, col))?;
+    for byte in text {
+        send(&byte_frames(*byte, true))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps the first bytes handed to send
+    struct Sent {
+        bytes: [u8; 64],
+        len: usize,
+    }
+
+    impl Sent {
+        fn new() -> Sent {
+            Sent { bytes: [0; 64], len: 0 }
+        }
+
+        fn send(&mut self, frames: &[u8]) -> Result<(), ()> {
+            let end = (self.len + frames.len()).min(self.bytes.len());
+            self.bytes[self.len..end].copy_from_slice(&frames[..end - self.len]);
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn nibble_frames() {
+        // 'A' as data: high nibble 4, low nibble 1, backlight and RS set
+        assert_eq!(byte_frames(b'A', true), [0x4D, 0x49, 0x1D, 0x19]);
+    }
+
+    #[test]
+    fn row_addresses() {
+        let geometry = Geometry { cols: 20, rows: 4 };
+        assert_eq!([0, 1, 2, 3].map(|row| ddram_address(geometry, row, 1)), [0x01, 0x41, 0x15, 0x55]);
+        let mut sent = Sent::new();
+        write_at(geometry, 1, 0, b"A", |frames| sent.send(frames)).unwrap();
+        assert_eq!(&sent.bytes[..sent.len], &[0xCC, 0xC8, 0x0C, 0x08, 0x4D, 0x49, 0x1D, 0x19][..]);
+    }
+
+    #[test]
+    fn init_sequence() {
+        let mut sent = Sent::new();
+        let mut waited = 0;
+        init(Geometry { cols: 16, rows: 2 }, |frames| sent.send(frames), |us| waited += us).unwrap();
+        // Three 8 bit function sets, then 4 bit mode
+        assert_eq!(&sent.bytes[..8], &[0x3C, 0x38, 0x3C, 0x38, 0x3C, 0x38, 0x2C, 0x28]);
+        // Function set 4 bit, 2 lines
+        assert_eq!(sent.bytes[8..12], byte_frames(0x28, false));
+        assert!(waited >= 40_000 + 2 * 4_100 + 2_000);
+        // The first failed send stops it
+        assert_eq!(init(Geometry { cols: 16, rows: 2 }, |_| Err(7), |_| {}), Err(7));
+    }
+}
@@ -0,0 +1,104 @@
+//! Levelled messages over serial, so a host can tell errors from notices.
+//! Each message gets a [E] / [W] / [I] / [D] prefix and messages above the
+//! level set by `cfg loglevel` are dropped. Answers to requests are not log
+//! messages and are written as they are.
+
+use rp_pico::hal as hal;
+use usbd_serial::SerialPort;
+use crate::config::Config;
+use crate::serial::write_serial;
+use crate::fmt::Wrapper;
+use core::fmt::Write;
+
+// Longest message with its prefix, well past the longest error text
+const MESSAGE_LEN: usize = 256;
+
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    // Payload value of cfg loglevel, anything past the last level is Debug
+    pub fn from_number(number: u32) -> Level {
+        match number {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    // Parse and validation errors are bare lines, notices start on a new line
+    pub fn of_message(message: &str) -> Level {
+        if message.starts_with("\n\r") { Level::Info } else { Level::Error }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Level::Error => "[E] ",
+            Level::Warn => "[W] ",
+            Level::Info => "[I] ",
+            Level::Debug => "[D] ",
+        }
+    }
+}
+
+// A message is shown if it is at least as severe as the active level
+pub fn enabled(active: Level, level: Level) -> bool {
+    level <= active
+}
+
+// Write a message with its level prefix, after any leading line break.
+// A bare prompt is not a message and is always written as it is.
+// Sent in one write, so cfg framing length puts the message in one frame
+pub fn write(serial: &mut SerialPort<'static, hal::usb::UsbBus>, config: &Config, level: Level, message: &str) {
+    let body = message.trim_start_matches(['\n', '\r']);
+    if body.starts_with("->") {
+        write_serial(serial, message, config.block_write);
+        return
+    }
+    if !enabled(config.log_level, level) {
+        return
+    }
+    let mut buf = [0_u8; MESSAGE_LEN];
+    let mut out = Wrapper::new(&mut buf);
+    let prefixed = out.write_str(&message[..message.len() - body.len()])
+        .and_then(|_| out.write_str(level.prefix()))
+        .and_then(|_| out.write_str(body));
+    match prefixed {
+        Ok(()) => write_serial(serial, out.as_str(), config.block_write),
+        // Too long to prefix, still better whole than cut
+        Err(_) => write_serial(serial, message, config.block_write),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_from_payload() {
+        assert_eq!(Level::from_number(0), Level::Error);
+        assert_eq!(Level::from_number(2), Level::Info);
+        assert_eq!(Level::from_number(9), Level::Debug);
+    }
+
+    #[test]
+    fn message_levels() {
+        assert_eq!(Level::of_message("Invalid Interface\n\r"), Level::Error);
+        assert_eq!(Level::of_message("\n\rBatch open, end runs it\n\r->"), Level::Info);
+        assert_eq!(Level::of_message(crate::serial::EMPTY_COMMAND), Level::Info);
+    }
+
+    #[test]
+    fn shown_at_or_above_the_active_level() {
+        assert!(enabled(Level::Info, Level::Error));
+        assert!(enabled(Level::Info, Level::Info));
+        assert!(!enabled(Level::Info, Level::Debug));
+        assert!(!enabled(Level::Error, Level::Warn));
+    }
+}
@@ -19,14 +19,40 @@
 use defmt_rtt as _;
 use panic_halt as _;
 mod fmt;
+mod config;
 mod serial;
 mod protocol;
+mod adc;
+mod smi;
+mod selftest;
+mod batch;
+mod log;
+#[cfg(feature = "gpio")]
+mod gpio;
+#[cfg(feature = "gpio")]
+mod pwm;
+#[cfg(feature = "i2c")]
+mod i2c;
+#[cfg(feature = "jtag")]
+mod jtag;
+mod lcd;
+mod capture;
+mod safestate;
+mod ws2812;
+mod clocks;
+mod serial_number;
+mod mirror;
+mod reset;
+mod tasks;
+mod flash;
+mod indicator;
 
 #[rtic::app(device = rp_pico::pac, peripherals = true, dispatchers= [PWM_IRQ_WRAP, SIO_IRQ_PROC0, SIO_IRQ_PROC1, UART1_IRQ])]
 mod app {
     use embedded_hal::digital::v2::OutputPin;
     use embedded_hal::blocking::spi::{Transfer, Write};
     use embedded_hal::spi::FullDuplex;
+    use embedded_hal::blocking::i2c::{Read, Write as I2cWrite, WriteRead};
 
     use fugit::HertzU32;
     use rp_pico::XOSC_CRYSTAL_FREQ;
@@ -38,7 +64,7 @@ mod app {
     const UART0_ICR: *mut u32 = 0x4003_4044 as *mut u32;
     const SPI0_ICR: *mut u32 = 0x4003_c020 as *mut u32;
     //use embedded_hal::
-    use hal::{clocks::Clock,
+    use hal::{clocks::Clock, timer::Alarm,
         uart::{UartConfig, DataBits, StopBits},
         gpio::{pin::bank0::*, Pin, FunctionUart},
         pio::{PIOExt, ShiftDirection,PIOBuilder, SM0, PinDir,},
@@ -51,14 +77,40 @@ mod app {
 
     // USB Communications Class Device support
     use usbd_serial::SerialPort;
-    use fugit::RateExtU32;
-
-    use crate::serial::{match_usb_serial_buf, write_serial};
-    use crate::protocol::{Send,
+    use fugit::{ExtU32, RateExtU32};
+
+    use crate::serial::{addressed_line, match_usb_serial_buf, write_serial, write_serial_polled, set_length_framing, hold_prompt, tx_dropped, tx_overflow, EMPTY_COMMAND, sync_serial, write_caps, write_grammar, write_limits, Edit, LineEditor, Resync, SmiRegCache, LastCommand, LINE_LEN, HISTORY_ANSWER_LEN};
+    #[cfg(feature = "gpio")]
+    use crate::gpio;
+    #[cfg(feature = "gpio")]
+    use crate::pwm;
+    #[cfg(feature = "i2c")]
+    use crate::i2c;
+    #[cfg(feature = "jtag")]
+    use crate::jtag;
+    use crate::ws2812;
+    use crate::lcd::{self, Geometry};
+    use crate::capture::Capture;
+    use crate::safestate::{self, SafeState};
+    use crate::mirror;
+    use crate::indicator::{self, Pattern};
+    use crate::reset::{self, ResetReason};
+    use crate::clocks;
+    use crate::log::{self, Level};
+    use crate::serial_number::{self, SERIAL_NUMBER_MAX};
+    use crate::adc;
+    use crate::smi;
+    use crate::selftest;
+    use crate::fmt::{Wrapper, format_hexdump};
+    use crate::config::{Channel, Config, Endian, Framing, SmiClause};
+    use crate::batch::{self, Batch};
+    use crate::protocol::{Send, ValidHostInterfaces, ErrorCode, ADDR_OFF, retry, paced, spi_wr_byte, encode_smi, smi_wire_frame, write_build, SPI_WR_MAX_WRITE, SPI_WR_MAX_READ, SPI_R_MAX_WORDS, SMI_MAX_ADDR, I2C_DUMP_MAX, ADC_STREAM_MAX,
         host::{HostRequest, Clean, ValidOps, ValidInterfaces,}, 
-        slave::{NotReady, SlaveResponse}};
+        slave::{NotReady, SlaveResponse, SPI_RESPONSE_LEN}};
 
     use core::str;
+    use core::convert::TryFrom;
+    use core::fmt::Write as FmtWrite;
 
     /// Clock divider for the PIO SM
     const SMI_DEFAULT_CLKDIV: u16 =  1;//4; // (133000000 / 2500000)
@@ -66,6 +118,10 @@ mod app {
 
     type UartTx = Pin<Gpio0, FunctionUart>;
     type UartRx = Pin<Gpio1, FunctionUart>;
+    type PassthroughTx = Pin<Gpio4, FunctionUart>;
+    type PassthroughRx = Pin<Gpio5, FunctionUart>;
+    type I2cSda = Pin<Gpio20, hal::gpio::FunctionI2C>;
+    type I2cScl = Pin<Gpio21, hal::gpio::FunctionI2C>;
 
     /// External high-speed crystal on the Raspberry Pi Pico board is 12 MHz. Adjust
     /// if your board has a different frequency
@@ -74,6 +130,8 @@ mod app {
     struct Shared {
         
         serial: SerialPort<'static, hal::usb::UsbBus>,
+        // Second CDC port, device interface results go here after cfg channel data
+        serial_data: SerialPort<'static, hal::usb::UsbBus>,
         usb_dev: usb_device::device::UsbDevice<'static, hal::usb::UsbBus>,
 
         pio0: hal::pio::PIO<pac::PIO0>,
@@ -84,14 +142,35 @@ mod app {
         // SMI PIO RX FIFO
         smi_rx: hal::pio::Rx<(pac::PIO0, SM0)>,
 
+        // Held so the ADC stays out of reset, channels are converted through crate::adc
+        adc: hal::adc::Adc,
+
         // String command that will be received over serial and must be matched
-        serial_buf: [u8; 64],
+        line_editor: LineEditor,
+
+        // Runtime settings, written by cfg requests in send_out
+        config: Config,
+
+        // Lines held between begin and end, drained by send_out
+        batch: Batch,
 
-        host_producer: Producer<'static, HostRequest<Clean>, 3>,
+        // Register last read from each PHY, completes smi r phyAddr
+        smi_regs: SmiRegCache,
+        // Frames of the last smi r or smi w, the PIO interrupt adds the answer
+        smi_last: Capture,
+
+        // USB bus resets seen since power up, reported by stat usb
+        usb_resets: u32,
+
+        host_producer: Producer<'static, HostRequest<Clean>, 9>,
 
         #[lock_free]
         _spi_tx_buf: [u16; 9],
 
+        // Wakes send_out when the next cfg ratelimit batch step is due
+        #[lock_free]
+        step_alarm: hal::timer::Alarm1,
+
         // pin for interrupt testing, additional functions, etc..
         freepin: Pin<Gpio25, hal::gpio::Output<hal::gpio::PushPull>>,
         spi_dev: hal::Spi<hal::spi::Enabled, pac::SPI0, 8>,
@@ -101,25 +180,63 @@ mod app {
     struct Local {
         uart_dev: hal::uart::UartPeripheral<hal::uart::Enabled, pac::UART0, (UartTx, UartRx)>,
 
-        spi_tx_producer: Producer<'static, [u8; 18], 3>,
-        spi_tx_consumer: Consumer<'static, [u8; 18], 3>,
+        spi_tx_producer: Producer<'static, [u8; SPI_RESPONSE_LEN], 3>,
+        spi_tx_consumer: Consumer<'static, [u8; SPI_RESPONSE_LEN], 3>,
+
+        host_consumer: Consumer<'static, HostRequest<Clean>, 9>,
+
+        // Where the SMI program was installed in PIO0 instruction memory
+        smi_program_offset: u8,
+
+        // System clock in Hz, PWM dividers are computed from it
+        sys_clk_hz: u32,
+
+        // Free running microsecond counter, times requests given the time prefix
+        timer: hal::Timer,
+
+        // Device facing UART1, written by uart wstr
+        uart_passthrough: hal::uart::UartPeripheral<hal::uart::Enabled, pac::UART1, (PassthroughTx, PassthroughRx)>,
+
+        // Device facing SPI master and its chip select
+        spi_master: hal::Spi<hal::spi::Enabled, pac::SPI1, 8>,
+        spi_master_cs: Pin<Gpio13, hal::gpio::Output<hal::gpio::PushPull>>,
+
+        // Device facing I2C master
+        i2c_master: hal::I2C<pac::I2C0, (I2cSda, I2cScl)>,
 
-        host_consumer: Consumer<'static, HostRequest<Clean>, 3>,
+        // PIO1 state machine 0 TX FIFO, one GRB pixel per word
+        led_tx: hal::pio::Tx<(pac::PIO1, SM0)>,
+
+        // Serial number the USB descriptor was built with
+        usb_serial: &'static str,
+
+        // Cause of the reset before this boot, reported by stat resetreason
+        reset_reason: ResetReason,
+
+        // USB device state at the last USB interrupt, to spot bus resets
+        usb_last_state: UsbDeviceState,
+        // Spots the escape back to text mode in the bytes usb_rx reads
+        resync: Resync,
+        last_command: LastCommand,
 
         producer: Producer<'static, SlaveResponse<NotReady>, 3>,    // Statically allocated non-blocking, non critical section access to writng to queue
         consumer: Consumer<'static, SlaveResponse<NotReady>, 3>,    // Statically allocated non-blocking, non critical section access to read to queue 
     }
 
     #[init(local = [usb_bus: Option<usb_device::bus::UsbBusAllocator<hal::usb::UsbBus>> = None,
-        spi_q: Queue<[u8; 18], 3> = Queue::new(),
+        spi_q: Queue<[u8; SPI_RESPONSE_LEN], 3> = Queue::new(),
         q: Queue<SlaveResponse<NotReady>, 3> = Queue::new(),
-        host_q: Queue<HostRequest<Clean>, 3> = Queue::new()])]
+        // Holds 8, as many short lines as one 64 byte USB packet can carry
+        host_q: Queue<HostRequest<Clean>, 9> = Queue::new(),
+        usb_serial_buf: [u8; SERIAL_NUMBER_MAX] = [0; SERIAL_NUMBER_MAX]])]
     fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
         unsafe {
             hal::sio::spinlock_reset();
         }
         let mut core = c.core;
         let mut p = c.device;
+        // Before anything here can cause another reset
+        let reset_reason = reset::read();
         //*******
         // Initialization of the system clock.
         let mut watchdog = hal::watchdog::Watchdog::new(p.WATCHDOG);
@@ -189,8 +306,29 @@ mod app {
             sio.gpio_bank0,
             &mut resets,
         );
+        // The first thing the banks do out of reset, ahead of the peripherals taking their pins
+        let safe_state = flash::size_from_jedec(flash::read_jedec_id())
+            .map_or(SafeState::new(), |size| SafeState::from_record(flash::read_config(flash::config_region(size))));
+        #[cfg(feature = "gpio")]
+        safe_state.apply(|pin, high| {
+            gpio::set_function_sio(pin);
+            gpio::set_level(pin, high);
+            gpio::set_output_enable(pin, true);
+        });
+        let mut config = Config::new();
+        config.safe_state = safe_state;
 
         let mut freepin = pins.gpio25.into_push_pull_output();
+
+        // Take the ADC out of reset and enable it, channels are selected per request
+        let adc = hal::adc::Adc::new(p.ADC, &mut resets);
+        // Take the PWM block out of reset, slices are set up per request through crate::pwm
+        let _pwm_slices = hal::pwm::Slices::new(p.PWM, &mut resets);
+        let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+        // Counts microseconds from the watchdog tick set up above
+        let mut timer = hal::Timer::new(p.TIMER, &mut resets);
+        let mut step_alarm = timer.alarm_1().unwrap();
+        step_alarm.enable_interrupt();
         // SPI Pre-Init Reset State
         // DEBUG Breakpoint Here: 
         // Test points:
@@ -229,6 +367,30 @@ mod app {
         // TX FIFO Prime state
         //      SPI0_SPSR_0x4003c00c Expect: 0x0, SPI TX FIFO is not empty
 
+        // SPI1 is the device facing master used by spi wr, GPIO10..13
+        let _spi1_sclk = pins.gpio10.into_mode::<hal::gpio::FunctionSpi>();
+        let _spi1_mosi = pins.gpio11.into_mode::<hal::gpio::FunctionSpi>();
+        let _spi1_miso = pins.gpio12.into_mode::<hal::gpio::FunctionSpi>();
+        // Chip select is driven by hand so it stays asserted for the whole write then read
+        let mut spi_master_cs = pins.gpio13.into_push_pull_output();
+        spi_master_cs.set_high().unwrap();
+        let spi_master = hal::Spi::<_, _, 8>::new(p.SPI1).init(
+            &mut resets,
+            clocks.peripheral_clock.freq(),
+            1_000_000u32.Hz(),
+            &embedded_hal::spi::MODE_0,
+        );
+
+        // I2C0 is the device facing master used by i2c commands, GPIO20 (SDA) and GPIO21 (SCL)
+        let i2c_master = hal::I2C::i2c0(
+            p.I2C0,
+            pins.gpio20.into_mode::<hal::gpio::FunctionI2C>(),
+            pins.gpio21.into_mode::<hal::gpio::FunctionI2C>(),
+            100_000u32.Hz(),
+            &mut resets,
+            clocks.system_clock.freq(),
+        );
+
         let uart_pins = (
             // UART TX (characters sent from RP2040) on pin 1 (GPIO0)
             pins.gpio0.into_mode::<hal::gpio::FunctionUart>(),
@@ -246,6 +408,19 @@ mod app {
         uart_dev.enable_rx_interrupt();
         uart_dev.write_full_blocking(b"UART Alive\r\n");
 
+        // UART1 is the device facing passthrough, GPIO4 (TX) and GPIO5 (RX).
+        // Polled only, its interrupt is an RTIC dispatcher
+        let passthrough_pins = (
+            pins.gpio4.into_mode::<hal::gpio::FunctionUart>(),
+            pins.gpio5.into_mode::<hal::gpio::FunctionUart>(),
+        );
+        let uart_passthrough = hal::uart::UartPeripheral::new(p.UART1, passthrough_pins, &mut resets)
+        .enable(
+            UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
+            clocks.peripheral_clock.freq(),
+        )
+        .unwrap();
+
         //*****
         // Initialization of the USB and Serial and USB Device ID
 
@@ -267,16 +442,22 @@ mod app {
 
         // Set up the USB Communication Class Device Driver
         let serial = SerialPort::new(usb_bus);
+        let serial_data = SerialPort::new(usb_bus);
+
+        // Set by cfg usbserial before the last soft reset, else the default
+        let usb_serial: &'static str = serial_number::load(c.local.usb_serial_buf);
 
         // Create a USB device with a VID and PID
+        // Two CDC functions, so the device is a composite using interface association descriptors
         let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
                 .manufacturer("Validation")
                 .product("Serial port")
-                .serial_number("TEST")
-                .device_class(2) // from https://www.usb.org/defined-class-codes
+                .serial_number(usb_serial)
+                .composite_with_iads()
                 .build();
          //*****
         // Initialization of the PIO0 and SMI state machine
+        // smi::SMI_MDIO_PIN and smi::SMI_MDC_PIN, smi pins moves them later
         let _mdio_pin = pins.gpio8.into_mode::<hal::gpio::FunctionPio0>();
         let _mdc_pin = pins.gpio9.into_mode::<hal::gpio::FunctionPio0>();
         let program = pio_proc::pio_asm!( 
@@ -295,29 +476,30 @@ mod app {
         "set pins, 0  side 1     [4]",
         "nop side 0 [2]",
         "set y, 11 side 0 [2]",
-        "set pins, 1 side 1 [4]",
+        "out pins, 1 side 1 [4]",  // Second start bit, 1 for Clause 22 and 0 for Clause 45
         "nop side 0 [1]",
     "addr:",
-        "set x, 15 side 0 [3]",
+        "set x, 16 side 0 [3]",  // Reads sample the second turnaround bit and 16 data bits
         "out pins, 1   side 1 [4]",
         "jmp y-- addr side 0 [1]",
         "set pins, 0 side 0  [3]",  
-        "out y 1 side 1 [4]",
+        "out y 1 side 1 [4]",    // First turnaround bit
         "nop side 0 [4]",
-        "nop side 1 [4]",
         "jmp y-- write_data    side 0 [2]", // If Autopull pulled in another word from our TX FIFO, we have data to write
-        "set pindirs, 0 side 0 [2]",
+        "set pindirs, 0 side 0 [2]", // Release MDIO, the PHY drives the second turnaround bit low
     "read_data:",
         "in pins 1 side 1 [4]",
         "jmp x-- read_data side 0 [4]",
         "push side 0",
         "irq 1 side 0",        // Set IRQ flag with index 1 (State machine 1)
-        "out null 19 side 0"   // // Discard remaining 19 bits of 32 bit word (we wrote first 12 which are OP/PHY/REG fields)
+        "out null 18 side 0"   // // Discard remaining 18 bits of 32 bit word (we wrote first 13 which are ST/OP/PHY/REG fields)
         "jmp start side 0",
     "write_data:",
-        "nop side 0 [1]",
+        "nop side 1 [4]",            // Second turnaround bit
+        "jmp x-- write_bits side 0 [1]", // x = 16 -> 15, 16 data bits follow
+    "write_bits:",
         "out pins, 1 side 1 [4]",
-        "jmp x-- write_data side 0 [3]",
+        "jmp x-- write_bits side 0 [3]",
         "set pins 0 side 0",        // Set IRQ flag with index 1 (State machine 1)
         "out null 32 side 0",
         ".wrap",
@@ -325,9 +507,11 @@ mod app {
             
         let (mut pio0, sm0, _, _, _,) = p.PIO0.split(&mut resets);
         let installed = pio0.install(&program.program).unwrap();
+        // Kept so the preamble counter of the program can be patched later
+        let smi_program_offset = installed.offset();
         let (mut sm, smi_rx, smi_tx) = PIOBuilder::from_program(installed)
-            .out_pins(5, 1)
-            .side_set_pin_base(6)
+            .out_pins(smi::SMI_MDIO_PIN, 1)
+            .side_set_pin_base(smi::SMI_MDC_PIN)
             .out_sticky(false)
             .clock_divisor_fixed_point(SMI_DEFAULT_CLKDIV, PIO_CLK_DIV_FRAQ) // freq = 1 / (int + (frac/256))
             .out_shift_direction(ShiftDirection::Right)
@@ -335,18 +519,65 @@ mod app {
             .autopush(true)
             .autopull(false)
             // .pull_threshold()  // TEST Designed to autofill when OSRE completely empty, maybe 32 is valid. 
-            .set_pins(5, 1)
-            .in_pin_base(5)
+            .set_pins(smi::SMI_MDIO_PIN, 1)
+            .in_pin_base(smi::SMI_MDIO_PIN)
             .build(sm0);
-        sm.set_pindirs([(5, PinDir::Output)]);
-        sm.set_pindirs([(6, PinDir::Output)]);
+        sm.set_pindirs([(smi::SMI_MDIO_PIN, PinDir::Output)]);
+        sm.set_pindirs([(smi::SMI_MDC_PIN, PinDir::Output)]);
         let smi_master = sm.start();
-        let serial_buf = [0_u8; 64];
+
+        // Initialization of PIO1 and the WS2812 state machine, T1 = 2, T2 = 5, T3 = 3 cycles
+        let ws2812_program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+        "bitloop:",
+            "out x, 1 side 0 [2]",
+            "jmp !x do_zero side 1 [1]",
+        "do_one:",
+            "jmp bitloop side 1 [4]",
+        "do_zero:",
+            "nop side 0 [4]",
+            ".wrap",
+        );
+        let (mut pio1, pio1_sm0, pio1_sm1, _, _,) = p.PIO1.split(&mut resets);
+        let ws2812_installed = pio1.install(&ws2812_program.program).unwrap();
+        let (ws2812_div_int, ws2812_div_frac) = ws2812::clock_divisor(sys_clk_hz);
+        let (led_sm, _, led_tx) = PIOBuilder::from_program(ws2812_installed)
+            .side_set_pin_base(ws2812::WS2812_DEFAULT_PIN)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(24)
+            .clock_divisor_fixed_point(ws2812_div_int, ws2812_div_frac)
+            .build(pio1_sm0);
+        // The pin is only handed to PIO1 by the first led set
+        let _led_sm = led_sm.start();
+
+        // PIO1 state machine 1 is the transmit only UART of cfg mirror, 8 cycles per bit
+        let mirror_program = pio_proc::pio_asm!(
+            ".side_set 1 opt",
+            "pull side 1 [7]",
+            "set x, 7 side 0 [7]",
+        "bitloop:",
+            "out pins, 1",
+            "jmp x-- bitloop [6]",
+        );
+        let mirror_installed = pio1.install(&mirror_program.program).unwrap();
+        let (mirror_div_int, mirror_div_frac) = mirror::clock_divisor(sys_clk_hz);
+        let (mirror_sm, _, _mirror_tx) = PIOBuilder::from_program(mirror_installed)
+            .side_set_pin_base(mirror::MIRROR_DEFAULT_PIN)
+            .out_pins(mirror::MIRROR_DEFAULT_PIN, 1)
+            .set_pins(mirror::MIRROR_DEFAULT_PIN, 1)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(mirror_div_int, mirror_div_frac)
+            .build(pio1_sm1);
+        // Fed through raw FIFO writes from write_serial, the pin is routed by cfg mirror on
+        let _mirror_sm = mirror_sm.start();
+        let line_editor = LineEditor::new();
         let _spi_tx_buf = [0_u16; 9];
 
         let (mut spi_tx_producer, spi_tx_consumer) = c.local.spi_q.split();
         // initialize our first buffer
-        spi_tx_producer.enqueue([0_u8; 18]).unwrap();
+        spi_tx_producer.enqueue([0_u8; SPI_RESPONSE_LEN]).unwrap();
 
         freepin.set_low().unwrap();
         // q has 'static lifetime so after the split and return of 'init'
@@ -372,6 +603,7 @@ mod app {
         (
             Shared {
                 serial,
+                serial_data,
                 usb_dev,
 
                 pio0,
@@ -380,8 +612,15 @@ mod app {
                 smi_tx,          // SMI TX FIFO
                 smi_rx,          // SMI RX FIFO
 
-                serial_buf,
+                adc,
+                line_editor,
+                usb_resets: 0,
+                config,
+                batch: Batch::new(),
+                smi_regs: SmiRegCache::new(),
+                smi_last: Capture::new(),
                 _spi_tx_buf,
+                step_alarm,
 
                 host_producer,
                 freepin,
@@ -394,6 +633,19 @@ mod app {
                 spi_tx_consumer,
 
                 host_consumer,
+                sys_clk_hz,
+                smi_program_offset,
+                timer,
+                uart_passthrough,
+                led_tx,
+                spi_master,
+                spi_master_cs,
+                i2c_master,
+                usb_serial,
+                reset_reason,
+                usb_last_state: UsbDeviceState::Default,
+                resync: Resync::new(),
+                last_command: LastCommand::new(),
 
                 producer,
                 consumer,
@@ -402,20 +654,29 @@ mod app {
         )
     }
 
-    #[task(binds=UART0_IRQ, priority=2, local=[uart_dev], shared=[serial, host_producer])]
+    #[task(binds=UART0_IRQ, priority=2, local=[uart_dev], shared=[serial, host_producer, config, smi_regs])]
     fn uart0(cx: uart0::Context) {
         let uart = cx.local.uart_dev;
         let host_producer = cx.shared.host_producer;
         // RX FIFO is 32 bytes deep
-        let mut buffer = [0_u8; 64];
+        let mut buffer = [0_u8; LINE_LEN];
         let serial = cx.shared.serial;
-        (serial, host_producer).lock(|serial, host_producer| {
+        let config = cx.shared.config;
+        let smi_regs = cx.shared.smi_regs;
+        (serial, host_producer, config, smi_regs).lock(|serial, host_producer, config, smi_regs| {
         match uart.read_raw(&mut buffer) {
             Err(_err) => {   
-                    write_serial(serial, "Uart RX Error", false);  
+                    log::write(serial, config, Level::Error, "Uart RX Error\n\r");
             }
-            _ => {
-                match match_usb_serial_buf(&buffer, serial) {
+            Ok(count) => {
+                // Parse only the bytes read this time
+                let line = str::from_utf8(&buffer[..count]).unwrap_or("");
+                // Lines for another bridge on the bus are dropped silently
+                let request = match addressed_line(line, config.address) {
+                    Some(line) => match_usb_serial_buf(line, ValidHostInterfaces::UART, serial, config, smi_regs),
+                    None => Err("Ok"),
+                };
+                match request {
                     Ok(hr) => { // Got a Host Request from the Serial Port
                         let clean = hr.init_clean(); // Validate it
                         match clean {
@@ -425,19 +686,19 @@ mod app {
 
                                     }
                                     Err(..) => {
-                                        write_serial(serial, "Error Pushing Host Request to queue\n\r", false);
+                                        log::write(serial, config, Level::Error, "Error Pushing Host Request to queue\n\r");
                                     }
                                 };
-                                send_out::spawn().unwrap(); // Send our clean host request to its destination
+                                let _ = send_out::spawn(); // Send our clean host request to its destination
                             }
                             Err(err) =>  {
-                                write_serial(serial, err, false);
+                                log::write(serial, config, Level::Error, err);
                             }
                         } 
                     }
                     Err("Ok") => { }// We processed a simple command without constructing a Host Request
                     Err(err) => {
-                        write_serial(serial, err, false); // Print the error back to the Serial port
+                        log::write(serial, config, Level::of_message(err), err); // Print the error back to the Serial port
                     }
             }
         }
@@ -552,18 +813,37 @@ mod app {
     // USB interrupt handler hardware task. Runs every time host requests new data
     #[inline(never)]
     #[link_section = ".data.bar"] // Execute from IRAM
-    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [serial, usb_dev, serial_buf, freepin, host_producer])]
+    // Priority is tasks::USB_PRIORITY, update the table in tasks.rs with it
+    #[task(binds = USBCTRL_IRQ, priority = 3, local = [usb_last_state, resync, last_command], shared = [serial, serial_data, usb_dev, line_editor, freepin, host_producer, usb_resets, config, batch, smi_regs])]
     fn usb_rx(cx: usb_rx::Context) {
         let usb_dev = cx.shared.usb_dev;
         let serial = cx.shared.serial;
-        let serial_buf = cx.shared.serial_buf;
+        let serial_data = cx.shared.serial_data;
+        let line_editor = cx.shared.line_editor;
         let freepin = cx.shared.freepin;
         let host_producer = cx.shared.host_producer;
-
-        (usb_dev, serial, serial_buf, freepin, host_producer).lock(
-            |usb_dev_a, serial_a, serial_buf, freepin, host_producer| {
+        let usb_resets = cx.shared.usb_resets;
+        let config = cx.shared.config;
+        let batch = cx.shared.batch;
+        let smi_regs = cx.shared.smi_regs;
+        let usb_last_state = cx.local.usb_last_state;
+        let resync = cx.local.resync;
+        let last_command = cx.local.last_command;
+
+        (usb_dev, serial, serial_data, line_editor, freepin, host_producer, usb_resets, config, batch, smi_regs).lock(
+            |usb_dev_a, serial_a, serial_data, line_editor, freepin, host_producer, usb_resets, config, batch, smi_regs| {
+                let new_data = usb_dev_a.poll(&mut [serial_a, serial_data]);
+                // The data port is output only, drop anything the host sends to it
+                let mut discard = [0u8; 64];
+                let _ = serial_data.read(&mut discard);
+                // A bus reset always drops the device back to the Default state
+                let state = usb_dev_a.state();
+                if state == UsbDeviceState::Default && *usb_last_state != UsbDeviceState::Default {
+                    *usb_resets += 1;
+                }
+                *usb_last_state = state;
                 // Check for new data
-                if  usb_dev_a.poll(&mut [serial_a]) {
+                if new_data {
                     let mut buf = [0u8; 64];
                     match serial_a.read(&mut buf) {
                         Err(_e) => {
@@ -571,69 +851,74 @@ mod app {
                         }
                         Ok(0) => {
                             // Do nothing
-                            let _ = serial_a.write(b"Didn't received data.\n\r");
-                            let _ = serial_a.flush();
+                            log::write(serial_a, config, Level::Warn, "Didn't received data.\n\r");
                         }
                         // TODO Add backspace function
-                        Ok(_count) => {
-                            match buf[0] {
-                                // Check if return key was given \n, if so a command was given.
-                                b'\r' => { 
-                                    //freepin.set_high().unwrap();
-                                    let first_zero = serial_buf.iter().position(|&x| x == 0);
-                                    match first_zero {
-                                        Some(Index) => { serial_buf[Index] = b' '; }
-                                        None => { // This means buffer is completely full (should not happen)
-                                            for elem in serial_buf.iter_mut() {
-                                                *elem = 0;
+                        Ok(count) => {
+                            // Escape sequences (arrow keys) arrive as several bytes in one packet
+                            for byte in &buf[..count] {
+                                // Recognized whatever mode the host left the bridge in
+                                if resync.feed(*byte) {
+                                    config.reset_protocol();
+                                    set_length_framing(false);
+                                    line_editor.clear();
+                                    write_serial(serial_a, "\n\rText mode\n\r->", false);
+                                    continue
+                                }
+                                match line_editor.feed(*byte) {
+                                    Edit::Line => {
+                                        //freepin.set_high().unwrap();
+                                        // Got a Host Request from the Serial Port, validate it
+                                        // An empty line runs the last command again. Lines for
+                                        // another bridge are dropped first, they repeat nothing
+                                        let request = match addressed_line(line_editor.as_str(), config.address) {
+                                            Some(line) => last_command
+                                                .line(line, |line| match_usb_serial_buf(line, ValidHostInterfaces::Serial, serial_a, config, smi_regs))
+                                                .and_then(|hr| hr.init_clean()),
+                                            None => Err("Ok"),
+                                        };
+                                        // Inside a begin/end block the line is queued in the batch instead
+                                        match batch.route(request) {
+                                            Ok(hr) => {
+                                                match host_producer.enqueue(hr) {
+                                                    Ok(..) => {
+
+                                                    }
+                                                    Err(..) => {
+                                                        log::write(serial_a, config, Level::Error, "Error Pushing Host Request to queue\n\r");
+                                                    }
+                                                };
+                                                // Already pending if this packet held more than one line
+                                                let _ = send_out::spawn(); // Send our clean host request to its destination
                                             }
-                                        }
-                                    }
-                                    match match_usb_serial_buf(serial_buf, serial_a) {
-                                        Ok(hr) => { // Got a Host Request from the Serial Port
-                                            let clean = hr.init_clean(); // Validate it
-                                            match clean {
-                                                Ok(hr) => {
-                                                    match host_producer.enqueue(hr) {
-                                                        Ok(..) => {
-
-                                                        }
-                                                        Err(..) => {
-                                                            write_serial(serial_a, "Error Pushing Host Request to queue\n\r", false);
-                                                        }
-                                                    };
-                                                    send_out::spawn().unwrap(); // Send our clean host request to its destination
+                                            Err("Ok") => { }// We processed a simple command without constructing a Host Request
+                                            Err(err) => {
+                                                log::write(serial_a, config, Level::of_message(err), err); // Print the error back to the Serial port
+                                                // Only Lit comes from here, the blink needs the dispatcher's timer
+                                                if let (true, Some(pattern)) = (config.led, Pattern::of(Err(err))) {
+                                                    indicator::show(pattern, freepin, |_| {});
                                                 }
-                                                Err(err) =>  {
-                                                    write_serial(serial_a, err, false);
-                                                }
-                                            } 
+                                            }
                                         }
-                                        Err("Ok") => { }// We processed a simple command without constructing a Host Request
-                                        Err(err) => {
-                                            write_serial(serial_a, err, false); // Print the error back to the Serial port
+                                        // end was just accepted, send_out drains the batch
+                                        if batch.is_running() {
+                                            let _ = send_out::spawn();
                                         }
+                                        // Reset serial buffer
+                                        line_editor.clear();
                                     }
-                                    // Reset serial buffer
-                                    for elem in serial_buf.iter_mut() {
-                                        *elem = 0;
-                                    }
-                                }
-
-                                _ => {
-                                    // Add the byte to the front of the serial_buf buffer, building the command
-                                    let first_zero = serial_buf.iter().position(|&x| x == 0);
-                                    match first_zero {
-                                        Some(Index) => { serial_buf[Index] = buf[0]; }
-                                        None => { // This means buffer is completely full (should not happen)
-                                            for elem in serial_buf.iter_mut() {
-                                                *elem = 0;
-                                            }
+                                    Edit::Echo => {
+                                        // Print the single byte that was written so user can see type
+                                        if let Ok(command) = str::from_utf8(core::slice::from_ref(byte)) {
+                                            write_serial(serial_a, command, false);
                                         }
                                     }
-                                    // Print the single byte that was written so user can see type
-                                    let command = str::from_utf8(&mut buf[0..1]).unwrap();
-                                    write_serial(serial_a, command, false);
+                                    Edit::Redraw => {
+                                        // Erase the terminal line and show the recalled command
+                                        write_serial(serial_a, "\r\x1b[K", false);
+                                        write_serial(serial_a, line_editor.as_str(), false);
+                                    }
+                                    Edit::None => {}
                                 }
                             }
                     } } }
@@ -644,7 +929,7 @@ mod app {
     // Software task that sends clean HostRequest to its destination (SysConfig or state machine)
     // Must validate that Associated State Machine is available and ready before sending, if not, return an Err
     // Pushes a SlaveResponse<NotReady> to process queue, that PIO_IRQ will build when response is gotten from state machine  
-    #[task(priority = 3, local = [producer, host_consumer], shared = [serial, smi_master, smi_tx, smi_rx, freepin])]
+    #[task(priority = 3, local = [last_step: u32 = 0, lcd_geometry: Option<Geometry> = None, spi_last: Capture = Capture::new(), producer, host_consumer, sys_clk_hz, smi_program_offset, timer, uart_passthrough, spi_master, spi_master_cs, i2c_master, led_tx, usb_serial, reset_reason], shared = [serial, serial_data, smi_master, smi_tx, smi_rx, smi_last, freepin, adc, usb_dev, usb_resets, config, batch, line_editor, step_alarm])]
     fn send_out(cx: send_out::Context) {
 
         let mut slave_response = false;
@@ -653,25 +938,74 @@ mod app {
         let smi_tx = cx.shared.smi_tx;
         let smi_rx = cx.shared.smi_rx;
         let smi_master = cx.shared.smi_master;
+        let smi_last = cx.shared.smi_last;
         let serial = cx.shared.serial; 
+        let serial_data = cx.shared.serial_data;
+        let adc = cx.shared.adc;
+        let usb_dev = cx.shared.usb_dev;
+        let usb_resets = cx.shared.usb_resets;
+        let mut config = cx.shared.config;
+        let mut batch = cx.shared.batch;
+        let mut line_editor = cx.shared.line_editor;
 
         let producer = cx.local.producer;
+        let sys_clk_hz = *cx.local.sys_clk_hz;
+        let smi_program_offset = *cx.local.smi_program_offset;
+        let timer = cx.local.timer;
+        let uart_passthrough = cx.local.uart_passthrough;
+        let spi_master = cx.local.spi_master;
+        let spi_master_cs = cx.local.spi_master_cs;
+        let i2c_master = cx.local.i2c_master;
+        let lcd_geometry = cx.local.lcd_geometry;
+        let spi_last = cx.local.spi_last;
+        let led_tx = cx.local.led_tx;
+        let usb_serial = *cx.local.usb_serial;
+        let reset_reason = *cx.local.reset_reason;
+        let last_step = cx.local.last_step;
+        // For stat parser: where usb_rx is in the line after this request
+        let (reader_state, reader_len, reader_cap) = line_editor.lock(|editor| (editor.state(), editor.len(), editor.capacity()));
 
         let mut return_string = "\n\r->";
-        let mut hr = cx.local.host_consumer.dequeue();
+        // Queued requests go first, a closed batch is drained once they are all handled
+        let (hr, batched) = match cx.local.host_consumer.dequeue() {
+            Some(hr) => (Some(hr), false),
+            None => {
+                // cfg ratelimit spaces the batch steps out. Until the next one is
+                // due the step alarm is set to spawn this task again, usb_rx runs meanwhile
+                let rate_limit = config.lock(|config| config.rate_limit as u32);
+                let wait_us = batch::step_wait_us(*last_step, timer.get_counter_low(), rate_limit);
+                if wait_us > 0 && batch.lock(|batch| batch.is_running()) {
+                    let _ = cx.shared.step_alarm.schedule(wait_us.micros());
+                    return
+                }
+                *last_step = timer.get_counter_low();
+                (batch.lock(|batch| batch.next()), true)
+            }
+        };
+        let dispatched = hr.is_some();
+        // Err once a handler reports a failure, shown by cfg led and counted in the batch summary
+        let mut outcome: Result<(), &'static str> = Ok(());
         match hr  {
             Some(mut hr) => {
-                (freepin, smi_tx, smi_rx, smi_master, serial).lock(|freepin, smi_tx, smi_rx, smi_master, serial| {
+                (freepin, smi_tx, smi_rx, smi_master, smi_last, serial, serial_data, adc, usb_dev, usb_resets, config).lock(|freepin, smi_tx, smi_rx, smi_master, smi_last, serial, serial_data, _adc, usb_dev, usb_resets, config| {
+                let started = timer.get_counter_low();
+                hold_prompt(hr.timed);
+                // Everything this request writes goes to the port cfg channel selected for it
+                let serial = match config.output_channel(hr.interface) {
+                    Channel::Control => serial,
+                    Channel::Data => serial_data,
+                };
                 match hr.interface {
+                    // Locked out at runtime by cfg disable
+                    interface if config.check(interface).is_err() => {
+                        outcome = Err("Interface disabled\n\r");
+                        log::write(serial, config, Level::Error, "Interface disabled\n\r");
+                        return_string = "";
+                    }
                     // For each additional supported interface, add another match arm that sends to the interface
                     // Take handle of its TX FIFO and send payload word by word according to the size
+                    #[cfg(feature = "smi")]
                     ValidInterfaces::SMI => {
-                        // Send 32 bit word of for either read or write to SMI TX FIFO
-                        smi_tx.write(hr.payload[0]);
-                        smi_rx.read(); // for now we will empty the RX FIFO
-                        slave_response = true;
-                    }
-                    ValidInterfaces::Config => {
                         if hr.operation == ValidOps::SmiSet {
                             if hr.payload[0] == 25 {
                                     smi_master.set_clock_divisor(4.56640625);
@@ -684,16 +1018,924 @@ mod app {
                             }
                             else {smi_master.clock_divisor_fixed_point(hr.payload[0] as u16, 0);}
                         }
+                        else if hr.operation == ValidOps::Set || hr.operation == ValidOps::Clr {
+                            let mask = hr.payload[2] as u16;
+                            let (set, clear) = if hr.operation == ValidOps::Set { (mask, 0) } else { (0, mask) };
+                            match smi::modify_blocking(smi_tx, smi_rx, hr.payload[0] as u8, hr.payload[1] as u8, set, clear) {
+                                Ok(value) => {
+                                    let mut buf = [0_u8; 48];
+                                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                    let _ = write!(out, "\n\rSMI register now 0x{:04X}\n\r->", value);
+                                    write_serial(serial, out.as_str(), config.block_write);
+                                    return_string = "";
+                                }
+                                Err(err) => {
+                                    outcome = Err(err);
+                                    log::write(serial, config, Level::Error, err);
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::Reset {
+                            // init_clean rejects this without the gpio feature
+                            #[cfg(feature = "gpio")]
+                            {
+                                let wait_ms = |ms: u32| {
+                                    let start = timer.get_counter_low();
+                                    while timer.get_counter_low().wrapping_sub(start) < ms * 1000 {}
+                                };
+                                let pin = hr.payload[0] as u8;
+                                gpio::set_function_sio(pin);
+                                gpio::set_level(pin, false);
+                                gpio::set_output_enable(pin, true);
+                                wait_ms(hr.payload[1]);
+                                gpio::set_level(pin, true);
+                                wait_ms(smi::SMI_RESET_SETTLE_MS);
+                            }
+                            let mut buf = [0_u8; 48];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = match smi::find_phy(smi_tx, smi_rx) {
+                                Some((phy, id)) => write!(out, "\n\rPHY found at {} id 0x{:08X}\n\r->", phy, id),
+                                None => write!(out, "\n\rNo PHY found\n\r->"),
+                            };
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::ReadPage || hr.operation == ValidOps::WritePage {
+                            let (phy, page, reg) = (hr.payload[0] as u8, hr.payload[1] as u16, hr.payload[2] as u8);
+                            let result = if hr.operation == ValidOps::ReadPage {
+                                retry(config.retries, || smi::read_page_blocking(smi_tx, smi_rx, phy, page, reg)).map(Some)
+                            } else {
+                                smi::write_page_blocking(smi_tx, smi_rx, phy, page, reg, hr.payload[3] as u16).map(|_| None)
+                            };
+                            match result {
+                                Ok(value) => {
+                                    let mut buf = [0_u8; 48];
+                                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                    let _ = match value {
+                                        Some(value) => write!(out, "\n\rSMI page {} reg {} = 0x{:04X}\n\r->", page, reg, value),
+                                        None => write!(out, "\n\rSMI page {} reg {} written\n\r->", page, reg),
+                                    };
+                                    write_serial(serial, out.as_str(), config.block_write);
+                                    return_string = "";
+                                }
+                                Err(err) => {
+                                    outcome = Err(err);
+                                    log::write(serial, config, Level::Error, err);
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::Read && hr.fields {
+                            let (phy, reg) = (hr.payload[0] as u8, hr.payload[1] as u8);
+                            let frame = smi_wire_frame(encode_smi(true, phy, reg, 0));
+                            smi_last.record(&frame, &[]);
+                            match retry(config.retries, || smi::read_blocking(smi_tx, smi_rx, phy, reg)) {
+                                Ok(value) => {
+                                    smi_last.set_received(&value.to_be_bytes());
+                                    let mut buf = [0_u8; 256];
+                                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                    let _ = write!(out, "\n\rPHY {} reg {} = 0x{:04X}\n\r", phy, reg, value);
+                                    let _ = smi::write_fields(&mut out, reg, value);
+                                    let _ = write!(out, "\n\r->");
+                                    write_serial(serial, out.as_str(), config.block_write);
+                                    return_string = "";
+                                }
+                                Err(err) => {
+                                    outcome = Err(err);
+                                    log::write(serial, config, Level::Error, err);
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::Link {
+                            let phy = hr.payload[0];
+                            match retry(config.retries, || smi::read_link(smi_tx, smi_rx, phy as u8)) {
+                                Ok(link) => {
+                                    let mut buf = [0_u8; 64];
+                                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                    let _ = match link {
+                                        smi::Link::Down => write!(out, "\n\rPHY {} link down\n\r->", phy),
+                                        smi::Link::Up { mbps, full_duplex } => write!(out, "\n\rPHY {} link up {} Mbps {} duplex\n\r->",
+                                            phy, mbps, if full_duplex { "full" } else { "half" }),
+                                    };
+                                    write_serial(serial, out.as_str(), config.block_write);
+                                    return_string = "";
+                                }
+                                Err(err) => {
+                                    outcome = Err(err);
+                                    log::write(serial, config, Level::Error, err);
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::Whois {
+                            let phy = hr.payload[0];
+                            match retry(config.retries, || smi::read_phy_id(smi_tx, smi_rx, phy as u8).ok_or("No PHY at that address\n\r")) {
+                                Ok(id) => {
+                                    let ident = smi::decode_phy_id(id);
+                                    let mut buf = [0_u8; 96];
+                                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                    let _ = write!(out, "\n\rPHY {} {} id=0x{:08X} oui=0x{:06X} model={} rev={}\n\r->",
+                                        phy, ident.vendor.unwrap_or("unknown vendor"), id, ident.oui, ident.model, ident.revision);
+                                    write_serial(serial, out.as_str(), config.block_write);
+                                    return_string = "";
+                                }
+                                Err(err) => {
+                                    outcome = Err(err);
+                                    log::write(serial, config, Level::Error, err);
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::ScanMap {
+                            let map = smi::scan_bitmap((0..=SMI_MAX_ADDR as u8).filter(|phy| smi::read_phy_id(smi_tx, smi_rx, *phy).is_some()));
+                            let mut buf = [0_u8; 32];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write!(out, "\n\rSMI scan 0x{:08X}\n\r->", map);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Pins {
+                            let (mdc, mdio) = (hr.payload[0] as u8, hr.payload[1] as u8);
+                            smi::set_pins(config.smi_pins, (mdc, mdio));
+                            config.smi_pins = (mdc, mdio);
+                            return_string = "\n\rSMI pins set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::Last {
+                            let mut buf = [0_u8; 768];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = smi_last.write_last(&mut out, "SMI");
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Clause {
+                            config.smi_clause = if hr.payload[0] == 45 { SmiClause::Clause45 } else { SmiClause::Clause22 };
+                            return_string = if hr.payload[0] == 45 { "\n\rSMI Clause 45\n\r->" } else { "\n\rSMI Clause 22\n\r->" };
+                        }
+                        else if hr.operation == ValidOps::Preamble {
+                            config.smi_preamble = hr.payload[0] as u8;
+                            smi::set_preamble(smi_program_offset, config.smi_preamble);
+                            let mut buf = [0_u8; 48];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write!(out, "\n\rSMI preamble set to {} bits\n\r->", config.smi_preamble);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else {
+                            // Send the 32 bit frame words (one, two for Clause 45) for either read or write to SMI TX FIFO
+                            let mut frames = [0_u8; 8];
+                            for (word, frame) in hr.payload[..hr.size as usize].iter().zip(frames.chunks_mut(4)) {
+                                smi_tx.write(*word);
+                                frame.copy_from_slice(&smi_wire_frame(*word));
+                            }
+                            smi_last.record(&frames[..4 * hr.size as usize], &[]);
+                            smi_rx.read(); // for now we will empty the RX FIFO
+                            slave_response = true;
+                        }
+                    }
+                    ValidInterfaces::Config => {
+                        if hr.operation == ValidOps::SelfTest {
+                            let checks = selftest::run(|phy, reg| smi::read_blocking(smi_tx, smi_rx, phy, reg).ok());
+                            let mut buf = [0_u8; 128];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = selftest::report(&mut out, &checks);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::History {
+                            let mut buf = [0_u8; HISTORY_ANSWER_LEN];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = line_editor.lock(|editor| editor.write_history(&mut out));
+                            // Longer than the USB buffer, written whatever cfg blockwrite says
+                            write_serial_polled(usb_dev, serial, out.as_str());
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Caps {
+                            let mut buf = [0_u8; 768];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write_caps(&mut out);
+                            // Longer than the USB buffer, written whatever cfg blockwrite says
+                            write_serial_polled(usb_dev, serial, out.as_str());
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Show {
+                            let mut buf = [0_u8; 512];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let shown = config.write_settings(&mut out);
+                            // Longer than the USB buffer, written whatever cfg blockwrite says
+                            write_serial_polled(usb_dev, serial, out.as_str());
+                            return_string = "";
+                            // What fit was written, the error says the rest is missing
+                            if shown.is_err() {
+                                outcome = Err("Settings cut short, answer buffer full\n\r");
+                                log::write(serial, config, Level::Error, "Settings cut short, answer buffer full\n\r");
+                                return_string = EMPTY_COMMAND;
+                            }
+                        }
+                        else if hr.operation == ValidOps::Flash {
+                            let id = flash::read_jedec_id();
+                            let mut buf = [0_u8; 80];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = match flash::size_from_jedec(id) {
+                                Some(size) => write!(out, "\n\rFlash {} KiB jedec=0x{:06X} config=0x{:08X}\n\r->",
+                                    size / 1024, id, flash::config_region(size)),
+                                None => write!(out, "\n\rFlash size unknown jedec=0x{:06X}\n\r->", id),
+                            };
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Limits {
+                            let mut buf = [0_u8; 96];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write_limits(&mut out);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Grammar {
+                            let mut buf = [0_u8; 1536];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write_grammar(&mut out);
+                            // Far longer than the USB buffer, written whatever cfg blockwrite says
+                            write_serial_polled(usb_dev, serial, out.as_str());
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Enable || hr.operation == ValidOps::Disable {
+                            // Checked by init_clean
+                            if let Ok(interface) = ValidInterfaces::try_from(hr.payload[0] as u16) {
+                                config.set_enabled(interface, hr.operation == ValidOps::Enable);
+                            }
+                            return_string = if hr.operation == ValidOps::Enable {
+                                "\n\rInterface enabled\n\r->"
+                            } else {
+                                "\n\rInterface locked out until cfg enable\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::UsbSerial {
+                            if hr.size != 0 {
+                                serial_number::store(&hr.text[..hr.size as usize]);
+                                return_string = "\n\rUSB serial set, applied on the next reset\n\r->";
+                            } else {
+                                let mut next = [0_u8; SERIAL_NUMBER_MAX];
+                                let mut buf = [0_u8; 80];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = write!(out, "\n\rUSB serial {}, next reset {}\n\r->", usb_serial, serial_number::load(&mut next));
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                        }
+                        else if hr.operation == ValidOps::Mirror {
+                            if let Some(pin) = config.mirror.take() {
+                                mirror::disable(pin);
+                            }
+                            if hr.payload[0] != 0 {
+                                mirror::enable(hr.payload[1] as u8);
+                                config.mirror = Some(hr.payload[1] as u8);
+                                return_string = "\n\rMirror on\n\r->";
+                            } else {
+                                return_string = "\n\rMirror off\n\r->";
+                            }
+                        }
+                        else if hr.operation == ValidOps::MaxLine {
+                            config.max_line = hr.payload[0] as u8;
+                            return_string = "\n\rMaximum line width set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::LogLevel {
+                            config.log_level = Level::from_number(hr.payload[0]);
+                            return_string = "\n\rLog level set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::RateLimit {
+                            config.rate_limit = hr.payload[0] as u16;
+                            return_string = "\n\rRate limit set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::UsbPoll {
+                            config.usb_poll = hr.payload[0] as u8;
+                            return_string = "\n\rUSB poll count set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::BlockWrite {
+                            config.block_write = hr.payload[0] != 0;
+                            return_string = if config.block_write {
+                                "\n\rBlocking writes on\n\r->"
+                            } else {
+                                "\n\rBlocking writes off\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::Channel {
+                            config.channel = if hr.payload[0] == 1 { Channel::Data } else { Channel::Control };
+                            return_string = if config.channel == Channel::Data {
+                                "\n\rInterface results on the data port\n\r->"
+                            } else {
+                                "\n\rInterface results on the control port\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::DefaultInterface {
+                            // Checked by init_clean
+                            config.default_interface = match ValidInterfaces::try_from(hr.payload[0] as u16) {
+                                Ok(ValidInterfaces::None) | Err(_) => None,
+                                Ok(interface) => Some(interface),
+                            };
+                            return_string = if config.default_interface.is_some() {
+                                "\n\rDefault interface set\n\r->"
+                            } else {
+                                "\n\rDefault interface off\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::Retry {
+                            config.retries = hr.payload[0] as u8;
+                            return_string = "\n\rRetry count set\n\r->";
+                        }
+                        // Every byte of an SPI master transfer then takes this much longer
+                        // and the dispatcher is held for all of it: a 27 byte xferx at
+                        // 1000 us keeps every other request waiting for 26 ms
+                        else if hr.operation == ValidOps::Slowdown {
+                            config.slowdown_us = hr.payload[0] as u16;
+                            return_string = if config.slowdown_us == 0 { "\n\rSlowdown off\n\r->" } else { "\n\rSlowdown set\n\r->" };
+                        }
+                        // Saved right away, the sector erase holds off every interrupt for
+                        // tens of milliseconds. The answer and prompt go out first, so the
+                        // host is not left waiting on a USB port that stopped answering.
+                        // The pin itself is left as it is until the next boot
+                        else if hr.operation == ValidOps::SafeState {
+                            let level = match hr.payload[1] {
+                                safestate::LEVEL_OFF => None,
+                                level => Some(level != 0),
+                            };
+                            config.safe_state.set(hr.payload[0] as u8, level);
+                            match flash::size_from_jedec(flash::read_jedec_id()) {
+                                Some(size) => {
+                                    write_serial(serial, "\n\rSafe state saved\n\r->", config.block_write);
+                                    let _ = sync_serial(usb_dev, serial);
+                                    flash::write_config(flash::config_region(size), &config.safe_state.to_record());
+                                    return_string = "";
+                                }
+                                None => {
+                                    outcome = Err("Flash size unknown, safe state not saved\n\r");
+                                    log::write(serial, config, Level::Error, "Flash size unknown, safe state not saved\n\r");
+                                    return_string = EMPTY_COMMAND;
+                                }
+                            }
+                        }
+                        else if hr.operation == ValidOps::Address {
+                            config.address = match hr.payload[0] {
+                                ADDR_OFF => None,
+                                address => Some(address as u8),
+                            };
+                            return_string = if config.address.is_some() { "\n\rAddress set\n\r->" } else { "\n\rAddress off\n\r->" };
+                        }
+                        else if hr.operation == ValidOps::Timestamp {
+                            config.timestamp = hr.payload[0] != 0;
+                            return_string = if config.timestamp { "\n\rTimestamps on\n\r->" } else { "\n\rTimestamps off\n\r->" };
+                        }
+                        else if hr.operation == ValidOps::Strict {
+                            config.strict = hr.payload[0] != 0;
+                            return_string = if config.strict { "\n\rStrict parsing on\n\r->" } else { "\n\rStrict parsing off\n\r->" };
+                        }
+                        else if hr.operation == ValidOps::Indicator {
+                            config.led = hr.payload[0] != 0;
+                            return_string = if config.led { "\n\rLED feedback on\n\r->" } else { "\n\rLED feedback off\n\r->" };
+                        }
+                        else if hr.operation == ValidOps::Endian {
+                            config.endian = if hr.payload[0] == 1 { Endian::Big } else { Endian::Little };
+                            return_string = "\n\rEndian set\n\r->";
+                        }
+                        else if hr.operation == ValidOps::Framing {
+                            config.framing = if hr.payload[0] == 1 { Framing::Length } else { Framing::Line };
+                            set_length_framing(config.framing == Framing::Length);
+                            // Already framed the new way
+                            return_string = if config.framing == Framing::Length {
+                                "\n\rLength framing on\n\r->"
+                            } else {
+                                "\n\rLength framing off\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::Build {
+                            let mut buf = [0_u8; 64];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write_build(&mut out);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::Clocks {
+                            let mut buf = [0_u8; 80];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = write!(out, "\n\rclk_sys={} kHz clk_peri={} kHz clk_usb={} kHz\n\r->",
+                                clocks::measure_khz(clocks::FC0_SRC_CLK_SYS),
+                                clocks::measure_khz(clocks::FC0_SRC_CLK_PERI),
+                                clocks::measure_khz(clocks::FC0_SRC_CLK_USB));
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        else if hr.operation == ValidOps::AutoNewline {
+                            config.auto_newline = hr.payload[0] != 0;
+                            return_string = if config.auto_newline {
+                                "\n\rAuto newline on\n\r->"
+                            } else {
+                                "\n\rAuto newline off\n\r->"
+                            };
+                        }
+                        else if hr.operation == ValidOps::Estop {
+                            // batch.route already dropped the batch
+                            #[cfg(feature = "gpio")]
+                            {
+                                pwm::stop_all();
+                                gpio::release_outputs();
+                            }
+                            if let Some(pin) = config.mirror.take() {
+                                mirror::disable(pin);
+                            }
+                            return_string = "\n\rStopped\n\r->";
+                        }
+                        else if hr.operation == ValidOps::Sync {
+                            // Replies to everything queued earlier were written before this one
+                            return_string = if sync_serial(usb_dev, serial) {
+                                "\n\rSYNCED\n\r->"
+                            } else {
+                                outcome = Err("Sync timed out\n\r");
+                                "\n\rSync timed out\n\r->"
+                            };
+                        }
                     }
+                    #[cfg(feature = "gpio")]
                     ValidInterfaces::GPIO => {
-
-                            if hr.payload[0] != 0 {freepin.set_high().unwrap();}
-                            else {freepin.set_low().unwrap();}
-                            // We do not do slave response on set/config commands
+                        match hr.operation {
+                            ValidOps::Drive => {
+                                gpio::set_drive_strength(hr.payload[0] as u8, hr.payload[1]);
+                                return_string = "\n\rGPIO drive strength set\n\r->";
+                            }
+                            ValidOps::Slew => {
+                                gpio::set_slew_fast(hr.payload[0] as u8, hr.payload[1] != 0);
+                                return_string = "\n\rGPIO slew rate set\n\r->";
+                            }
+                            ValidOps::Schmitt => {
+                                gpio::set_schmitt(hr.payload[0] as u8, hr.payload[1] != 0);
+                                return_string = "\n\rGPIO schmitt trigger set\n\r->";
+                            }
+                            ValidOps::Threshold => {
+                                let (pin, level_mv) = (hr.payload[0] as u8, hr.payload[1]);
+                                // init_clean only lets the ADC pins through
+                                let mv = adc::to_mv(adc::read_channel(adc::gpio_channel(pin).unwrap_or(0)));
+                                let mut buf = [0_u8; 64];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = write!(out, "\n\rGPIO{} {} {} mV ({} mV)\n\r->", pin, if mv > level_mv { "above" } else { "below" }, level_mv, mv);
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            ValidOps::Measure => {
+                                let pin = hr.payload[0] as u8;
+                                let measured = gpio::measure(pin, hr.payload[1] * 1000, || timer.get_counter_low());
+                                let mut buf = [0_u8; 64];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = match measured {
+                                    Some((high, low)) => write!(out, "\n\rGPIO{} high={} us low={} us\n\r->", pin, high, low),
+                                    None => {
+                                        outcome = Err("Measure timed out\n\r");
+                                        write!(out, "\n\rGPIO{} measure timed out\n\r->", pin)
+                                    }
+                                };
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            ValidOps::Freq => {
+                                let (pin, gate_ms) = (hr.payload[0] as u8, hr.payload[1]);
+                                let mut buf = [0_u8; 48];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                // The slice is shared with the even neighbour, do not take it from a running PWM
+                                let _ = if pwm::is_running(pin) {
+                                    write!(out, "\n\rPWM running on the slice of GPIO{}\n\r->", pin)
+                                } else {
+                                    let edges = pwm::count_edges(pin, gate_ms * 1000, || timer.get_counter_low());
+                                    write!(out, "\n\rGPIO{} {} Hz\n\r->", pin, pwm::frequency_hz(edges, gate_ms))
+                                };
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            ValidOps::Conn => {
+                                let (drive, sense) = (hr.payload[0] as u8, hr.payload[1] as u8);
+                                let connected = gpio::connected(drive, sense, |us| {
+                                    let start = timer.get_counter_low();
+                                    while timer.get_counter_low().wrapping_sub(start) < us {}
+                                });
+                                let mut buf = [0_u8; 48];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = write!(out, "\n\rGPIO{} to GPIO{} {}\n\r->", drive, sense,
+                                    if connected { "connected" } else { "not connected" });
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            ValidOps::Strobe => {
+                                gpio::strobe(hr.payload[0] as u8, hr.payload[1], hr.payload[2], hr.payload[3], |us| {
+                                    let start = timer.get_counter_low();
+                                    while timer.get_counter_low().wrapping_sub(start) < us {}
+                                });
+                                return_string = "\n\rGPIO strobe done\n\r->";
+                            }
+                            ValidOps::OpenDrain => {
+                                gpio::set_open_drain(hr.payload[0] as u8, hr.payload[1] != 0);
+                                return_string = "\n\rGPIO open drain level set\n\r->";
+                            }
+                            ValidOps::IntStatus => {
+                                let status = gpio::interrupt_status(hr.payload[0] as u8);
+                                let mut buf = [0_u8; 64];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = write!(out, "\n\rGPIO{} edge rise={} fall={}\n\r->",
+                                    hr.payload[0],
+                                    (status & gpio::INTR_EDGE_HIGH != 0) as u8,
+                                    (status & gpio::INTR_EDGE_LOW != 0) as u8);
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            ValidOps::IntClear => {
+                                gpio::clear_edge_status(hr.payload[0] as u8);
+                                return_string = "\n\rGPIO edge flags cleared\n\r->";
+                            }
+                            ValidOps::Pwm => {
+                                pwm::start(hr.payload[0] as u8, hr.payload[1], hr.payload[2], sys_clk_hz);
+                                return_string = "\n\rPWM started\n\r->";
+                            }
+                            ValidOps::Clock => {
+                                pwm::start(hr.payload[0] as u8, hr.payload[1], 50, sys_clk_hz);
+                                return_string = "\n\rClock started\n\r->";
+                            }
+                            ValidOps::PwmDuty => {
+                                return_string = if pwm::set_duty(hr.payload[0] as u8, hr.payload[1]) {
+                                    "\n\rPWM duty set\n\r->"
+                                } else {
+                                    "\n\rPWM not running on pin\n\r->"
+                                };
+                            }
+                            ValidOps::PwmStop => {
+                                pwm::stop(hr.payload[0] as u8);
+                                return_string = "\n\rPWM stopped\n\r->";
+                            }
+                            _ => {
+                                if hr.payload[0] != 0 {freepin.set_high().unwrap();}
+                                else {freepin.set_low().unwrap();}
+                            }
+                        }
+                        // We do not do slave response on set/config commands
+                    }
+                    #[cfg(feature = "spi")]
+                    ValidInterfaces::SPI => {
+                        // Gap of cfg slowdown between the bytes, chip select stays low throughout
+                        let gap_us = config.slowdown_us;
+                        let mut wait_us = |us: u32| {
+                            let start = timer.get_counter_low();
+                            while timer.get_counter_low().wrapping_sub(start) < us {}
+                        };
+                        if hr.operation == ValidOps::Last {
+                            let mut buf = [0_u8; 768];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = spi_last.write_last(&mut out, "SPI");
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        // Read JEDEC ID, then the manufacturer, memory type and capacity bytes
+                        if hr.operation == ValidOps::FlashId {
+                            let mut bytes = [flash::JEDEC_READ_ID, 0, 0, 0];
+                            let sent = bytes;
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(bytes.len(), gap_us, |range| spi_master.transfer(&mut bytes[range]).map(|_| ()), &mut wait_us);
+                            spi_master_cs.set_high().unwrap();
+                            spi_last.record(&sent, if result.is_ok() { &bytes[..] } else { &[] });
+
+                            let mut buf = [0_u8; 96];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = match result {
+                                Ok(()) => flash::write_flash_ident(&mut out, u32::from_be_bytes([0, bytes[1], bytes[2], bytes[3]])),
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    write!(out, "\n\rSPI transfer failed\n\r->")
+                                }
+                            };
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        if hr.operation == ValidOps::Write {
+                            let mut bytes = [0_u8; 12];
+                            for (word, chunk) in hr.payload[..hr.size as usize].iter().zip(bytes.chunks_mut(4)) {
+                                chunk.copy_from_slice(&config.endian.word_bytes(*word));
+                            }
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(4 * hr.size as usize, gap_us, |range| spi_master.write(&bytes[range]), &mut wait_us);
+                            spi_master_cs.set_high().unwrap();
+                            // Write only, what comes back on MISO is not read
+                            spi_last.record(&bytes[..4 * hr.size as usize], &[]);
+                            return_string = match result {
+                                Ok(()) => "\n\rSPI words sent\n\r->",
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    "\n\rSPI transfer failed\n\r->"
+                                }
+                            };
+                        }
+                        if hr.operation == ValidOps::Read {
+                            let count = hr.payload[0] as usize;
+                            let mut bytes = [0_u8; 4 * SPI_R_MAX_WORDS as usize];
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(4 * count, gap_us, |range| spi_master.transfer(&mut bytes[range]).map(|_| ()), &mut wait_us)
+                                .map(|()| &bytes[..4 * count]);
+                            spi_master_cs.set_high().unwrap();
+                            // Zeros are clocked out for the read
+                            spi_last.record(&[0_u8; 4 * SPI_R_MAX_WORDS as usize][..4 * count], result.as_ref().map_or(&[][..], |rx| *rx));
+
+                            let mut buf = [0_u8; 64];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            match result {
+                                Ok(rx) => {
+                                    let _ = write!(out, "\n\rSPI read");
+                                    for chunk in rx.chunks(4) {
+                                        let word = config.endian.word_from_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                                        let _ = write!(out, " 0x{:08X}", word);
+                                    }
+                                    let _ = write!(out, "\n\r->");
+                                }
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    let _ = write!(out, "\n\rSPI transfer failed\n\r->");
+                                }
+                            }
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        if hr.operation == ValidOps::WriteStr {
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(hr.size as usize, gap_us, |range| spi_master.write(&hr.text[range]), &mut wait_us);
+                            spi_master_cs.set_high().unwrap();
+                            spi_last.record(&hr.text[..hr.size as usize], &[]);
+                            return_string = match result {
+                                Ok(()) => "\n\rSPI string sent\n\r->",
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    "\n\rSPI transfer failed\n\r->"
+                                }
+                            };
+                        }
+                        if hr.operation == ValidOps::XferHex {
+                            let mut bytes = hr.text;
+                            let len = hr.size as usize;
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(len, gap_us, |range| spi_master.transfer(&mut bytes[range]).map(|_| ()), &mut wait_us)
+                                .map(|()| &bytes[..len]);
+                            spi_master_cs.set_high().unwrap();
+                            spi_last.record(&hr.text[..len], result.as_ref().map_or(&[][..], |rx| *rx));
+
+                            let mut buf = [0_u8; 320];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            match result {
+                                Ok(rx) => {
+                                    let _ = write!(out, "\n\rSPI xfer {} bytes", len);
+                                    let _ = format_hexdump(&mut out, rx);
+                                    let _ = write!(out, "\n\r->");
+                                }
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    let _ = write!(out, "\n\rSPI transfer failed\n\r->");
+                                }
+                            }
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        if hr.operation == ValidOps::WriteRead {
+                            // Full duplex: the read bytes are clocked in while zeros follow the write bytes
+                            let write_len = hr.size as usize;
+                            let read_len = hr.payload[0] as usize;
+                            let mut bytes = [0_u8; SPI_WR_MAX_WRITE + SPI_WR_MAX_READ as usize];
+                            for (i, byte) in bytes[..write_len].iter_mut().enumerate() {
+                                *byte = spi_wr_byte(&hr.payload, i);
+                            }
+                            let sent = bytes;
+                            spi_master_cs.set_low().unwrap();
+                            let result = paced(write_len + read_len, gap_us, |range| spi_master.transfer(&mut bytes[range]).map(|_| ()), &mut wait_us)
+                                .map(|()| &bytes[..write_len + read_len]);
+                            spi_master_cs.set_high().unwrap();
+                            spi_last.record(&sent[..write_len + read_len], result.as_ref().map_or(&[][..], |rx| *rx));
+
+                            let mut buf = [0_u8; 112];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            match result {
+                                Ok(rx) => {
+                                    let _ = write!(out, "\n\rSPI read {} bytes", read_len);
+                                    let _ = format_hexdump(&mut out, &rx[write_len..]);
+                                    let _ = write!(out, "\n\r->");
+                                }
+                                Err(_) => {
+                                    outcome = Err("SPI transfer failed\n\r");
+                                    let _ = write!(out, "\n\rSPI transfer failed\n\r->");
+                                }
+                            }
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                    }
+                    #[cfg(feature = "i2c")]
+                    ValidInterfaces::I2C => {
+                        if hr.operation == ValidOps::Dump {
+                            let (addr, start, count) = (hr.payload[0] as u8, hr.payload[1] as u8, hr.payload[2] as usize);
+                            // Register address write, then a repeated start into the read
+                            let mut bytes = [0_u8; I2C_DUMP_MAX as usize];
+                            let result = retry(config.retries, || i2c_master.write_read(addr, &[start], &mut bytes[..count]));
+
+                            let mut buf = [0_u8; 256];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            match result {
+                                Ok(()) => {
+                                    let _ = write!(out, "\n\rI2C 0x{:02X} from 0x{:02X}", addr, start);
+                                    let _ = format_hexdump(&mut out, &bytes[..count]);
+                                    let _ = write!(out, "\n\r->");
+                                }
+                                Err(_) => {
+                                    outcome = Err("I2C transfer failed\n\r");
+                                    let _ = write!(out, "\n\rI2C transfer failed\n\r->");
+                                }
+                            }
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        if hr.operation == ValidOps::Scan {
+                            // The I2C block cannot send an address without data, a one byte
+                            // read stands in for the zero length write (like the pico-sdk bus_scan)
+                            let found = (i2c::SCAN_FIRST..=i2c::SCAN_LAST).filter(|addr| i2c_master.read(*addr, &mut [0_u8; 1]).is_ok());
+                            let mut buf = [0_u8; 640];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = i2c::write_scan(&mut out, found);
+                            write_serial(serial, out.as_str(), config.block_write);
+                            return_string = "";
+                        }
+                        if hr.operation == ValidOps::Speed {
+                            i2c::set_speed(hr.payload[0], sys_clk_hz);
+                            return_string = "\n\rI2C speed set\n\r->";
+                        }
+                    }
+                    #[cfg(feature = "uart")]
+                    ValidInterfaces::UART => {
+                        uart_passthrough.write_full_blocking(&hr.text[..hr.size as usize]);
+                        return_string = "\n\rUART string sent\n\r->";
+                    }
+                    #[cfg(feature = "led")]
+                    ValidInterfaces::LED => {
+                        ws2812::select_pin(hr.payload[0] as u8);
+                        // The FIFO drains within 30 us, a request never finds it full
+                        led_tx.write(ws2812::grb(hr.payload[1] as u8, hr.payload[2] as u8, hr.payload[3] as u8));
+                        return_string = "\n\rLED set\n\r->";
+                    }
+                    // The geometry from lcd init bounds the writes, nothing is sent before it
+                    #[cfg(feature = "lcd")]
+                    ValidInterfaces::LCD => {
+                        let mut send = |bytes: &[u8]| i2c_master.write(lcd::LCD_ADDR, bytes);
+                        let wait_us = |us: u32| {
+                            let start = timer.get_counter_low();
+                            while timer.get_counter_low().wrapping_sub(start) < us {}
+                        };
+                        let result = match (hr.operation, *lcd_geometry) {
+                            (ValidOps::Init, _) => {
+                                let geometry = Geometry { cols: hr.payload[0] as u8, rows: hr.payload[1] as u8 };
+                                let result = lcd::init(geometry, &mut send, wait_us).map_err(|_| "LCD not answering\n\r");
+                                // Kept only for a display that took the reset sequence
+                                *lcd_geometry = result.ok().map(|()| geometry);
+                                result.map(|()| "\n\rLCD ready\n\r->")
+                            }
+                            (_, None) => Err("LCD not initialized, run lcd init\n\r"),
+                            (ValidOps::Clear, Some(_)) => lcd::clear(&mut send, wait_us)
+                                .map(|()| "\n\rLCD cleared\n\r->")
+                                .map_err(|_| "LCD not answering\n\r"),
+                            (_, Some(geometry)) => {
+                                let (row, col, len) = (hr.payload[0] as u8, hr.payload[1] as u8, hr.size as usize);
+                                if row >= geometry.rows || col >= geometry.cols {
+                                    Err("Position outside the LCD\n\r")
+                                }
+                                else if col as usize + len > geometry.cols as usize {
+                                    Err("Text runs past the end of the LCD row\n\r")
+                                }
+                                else {
+                                    lcd::write_at(geometry, row, col, &hr.text[..len], &mut send)
+                                        .map(|()| "\n\rLCD written\n\r->")
+                                        .map_err(|_| "LCD not answering\n\r")
+                                }
+                            }
+                        };
+                        match result {
+                            Ok(done) => return_string = done,
+                            Err(err) => {
+                                outcome = Err(err);
+                                log::write(serial, config, Level::Error, err);
+                                return_string = EMPTY_COMMAND;
+                            }
+                        }
+                    }
+                    #[cfg(feature = "jtag")]
+                    ValidInterfaces::JTAG if hr.operation == ValidOps::Mem => {
+                        let address = hr.payload[0];
+                        match jtag::read_mem(&mut jtag::Pins::take(), address) {
+                            Ok(word) => {
+                                let mut buf = [0_u8; 48];
+                                let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                                let _ = write!(out, "\n\r0x{:08X}: 0x{:08X}\n\r->", address, word);
+                                write_serial(serial, out.as_str(), config.block_write);
+                                return_string = "";
+                            }
+                            Err(err) => {
+                                outcome = Err(err);
+                                log::write(serial, config, Level::Error, err);
+                                return_string = EMPTY_COMMAND;
+                            }
+                        }
+                    }
+                    // There is no TAP engine on the PIO yet, jtag r and jtag w are
+                    // parsed, then refused here
+                    #[cfg(feature = "jtag")]
+                    ValidInterfaces::JTAG => {
+                        return_string = "\n\rJTAG is not implemented yet\n\r->";
+                    }
+                    ValidInterfaces::ADC if hr.operation == ValidOps::Stream => {
+                        // Captured first and written after, so the serial port does not disturb the pacing
+                        let (channel, rate, count) = (hr.payload[0] as u8, hr.payload[1], hr.payload[2] as usize);
+                        let mut samples = [0_u16; ADC_STREAM_MAX as usize];
+                        let period_us = 1_000_000 / rate;
+                        let start = adc::capture(&mut samples[..count], period_us, || timer.get_counter_low(), || adc::read_channel(channel));
+                        let mut buf = [0_u8; 48];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rADC ch{} {} Hz {} samples", channel, rate, count);
+                        // Every sample line is due, each waits for the one before it to drain
+                        write_serial_polled(usb_dev, serial, out.as_str());
+                        for (i, line) in samples[..count].chunks(adc::STREAM_LINE).enumerate() {
+                            let stamp = start.wrapping_add((i * adc::STREAM_LINE) as u32 * period_us);
+                            let mut buf = [0_u8; 112];
+                            let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                            let _ = adc::write_stream_line(&mut out, line, if config.timestamp { Some(stamp) } else { None });
+                            write_serial_polled(usb_dev, serial, out.as_str());
+                        }
+                        return_string = "\n\r->";
+                    }
+                    ValidInterfaces::ADC => {
+                        // Convert every listed channel back to back and answer in one line
+                        let mut buf = [0_u8; 96];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rADC");
+                        for channel in &hr.payload[..hr.size as usize] {
+                            let _ = write!(out, " ch{}={}", channel, adc::read_channel(*channel as u8));
+                        }
+                        let _ = write!(out, "\n\r->");
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
+                    }
+                    ValidInterfaces::Stat if hr.operation == ValidOps::TxQueue => {
+                        let mut buf = [0_u8; 64];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rTX dropped {} bytes overflow={}\n\r->", tx_dropped(), if tx_overflow() { "yes" } else { "no" });
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
+                    }
+                    ValidInterfaces::Stat if hr.operation == ValidOps::Parser => {
+                        let mut buf = [0_u8; 64];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rParser state={} pending={}/{}\n\r->", reader_state.name(), reader_len, reader_cap);
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
+                    }
+                    ValidInterfaces::Stat if hr.operation == ValidOps::Tasks => {
+                        let mut buf = [0_u8; 192];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = tasks::write_tasks(&mut out);
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
+                    }
+                    ValidInterfaces::Stat if hr.operation == ValidOps::ResetReason => {
+                        let mut buf = [0_u8; 48];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rReset reason: {}\n\r->", reset_reason.name());
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
+                    }
+                    ValidInterfaces::Stat => {
+                        let state = usb_dev.state();
+                        let yes_no = |b: bool| if b { "yes" } else { "no" };
+                        let mut buf = [0_u8; 64];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = write!(out, "\n\rUSB configured={} suspended={} resets={}\n\r->",
+                            yes_no(state == UsbDeviceState::Configured),
+                            yes_no(state == UsbDeviceState::Suspend),
+                            usb_resets);
+                        write_serial(serial, out.as_str(), config.block_write);
+                        return_string = "";
                     }
                     _ => {}
                 }
-                write_serial(serial, return_string, false);
+                write_serial(serial, return_string, config.block_write);
+                if hr.timed {
+                    let took = timer.get_counter_low().wrapping_sub(started);
+                    // The answer went out without its prompt, report the time then prompt again
+                    hold_prompt(false);
+                    let mut buf = [0_u8; 32];
+                    let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                    let _ = write!(out, "took {} us\n\r->", took);
+                    write_serial(serial, out.as_str(), config.block_write);
+                }
+                if let (true, Some(pattern)) = (config.led, Pattern::of(outcome)) {
+                    indicator::show(pattern, freepin, |us| {
+                        let start = timer.get_counter_low();
+                        while timer.get_counter_low().wrapping_sub(start) < us {}
+                    });
+                }
                 
                 if slave_response {
                     // Exchange our Host Request for slave response that needs to be ready
@@ -706,7 +1948,8 @@ mod app {
 
                                 }
                                 Err(err) => {
-                                    write_serial(serial, "Consumer queue is full", false);
+                                    outcome = Err("Consumer queue is full\n\r");
+                                    log::write(serial, config, Level::Error, "Consumer queue is full\n\r");
                                 }
                             }
                         }
@@ -716,30 +1959,71 @@ mod app {
                     }
                 }
                 });
+                if let (true, Err(err)) = (batched, outcome) {
+                    batch.lock(|batch| batch.step_failed(err));
+                }
+                // A binary host reads why the request failed from the status byte
+                if let Err(err) = outcome {
+                    if let Ok(mut sr) = hr.exchange_for_slave_response() {
+                        if sr.host_config == ValidHostInterfaces::SPI {
+                            sr.set_status(ErrorCode::from_message(err));
+                            if let Ok(sr) = sr.init_ready() {
+                                let _ = respond_to_host::spawn(sr);
+                            }
+                        }
+                    }
+                }
             }
-            None => {}
+            None => {
+                // Every batched request has been handled, close the run with its summary
+                (serial, batch, config).lock(|serial, batch, config| {
+                    if batch.is_finished() {
+                        let mut buf = [0_u8; 128];
+                        let mut out = Wrapper::with_max_line(&mut buf, config.max_line as usize);
+                        let _ = batch.report(&mut out);
+                        if !out.as_str().is_empty() {
+                            write_serial(serial, out.as_str(), config.block_write);
+                        }
+                    }
+                });
             }
+            }
+        // More than one request can be queued by a single USB packet, a batch
+        // is run one request per spawn until the summary has been written
+        if cx.local.host_consumer.ready() || (batched && dispatched) {
+            let _ = send_out::spawn();
+        }
+    }
+
+    // Hardware task associated with TIMER_IRQ_1
+    // The step alarm went off, the batch step send_out was waiting for is due
+    #[task(binds = TIMER_IRQ_1, priority = 3, shared = [step_alarm])]
+    fn step_due(cx: step_due::Context) {
+        cx.shared.step_alarm.clear_interrupt();
+        let _ = send_out::spawn();
     }
 
     // Hardware task associated with PIO0_IRQ_0
     // Takes control of shared state machine and rx fifo of PIO_0 SM_0 
     // Reads rx fifo into buffer and pushed to queue, spawn software task to return value
-    #[task(binds = PIO0_IRQ_0, priority = 3, shared = [serial, pio0, smi_rx], local = [consumer])]
+    #[task(binds = PIO0_IRQ_0, priority = 3, shared = [serial, pio0, smi_rx, smi_last, config], local = [consumer])]
     fn pio_sm_rx(cx: pio_sm_rx::Context) {
         // All statemachines implement IRQ flags, of which the first 0-3 LSB 
         let mut serial = cx.shared.serial;
-        (serial).lock(|serial| {
-            write_serial(serial, "fired", false);
+        let mut config = cx.shared.config;
+        (&mut serial, &mut config).lock(|serial, config| {
+            log::write(serial, config, Level::Debug, "PIO0 IRQ fired\n\r");
         });
         if let Some(mut slave_response) = cx.local.consumer.dequeue() {
 
             let pio0 = cx.shared.pio0;
             let rx = cx.shared.smi_rx;
+            let smi_last = cx.shared.smi_last;
             // let serial = cx.shared.serial;
 
             // Eventually lock all implemented state machines and rx fifos
-            (pio0, rx, serial).lock(
-                |pio0, rx_a, serial,| {
+            (pio0, rx, smi_last, serial, config).lock(
+                |pio0, rx_a, smi_last, serial, config| {
                     // First, read the index of the state machine IRQ flag 
                     // This determines which state machine flagged an IRQ
                     let index = pio0.get_irq_raw();
@@ -749,7 +2033,17 @@ mod app {
                             match rx_a.read() {
                                 Some(word) => {
                                     // We got a word from the SMI RX FIFO
-                                    slave_response.set_payload(word);
+                                    match smi::check_turnaround(word) {
+                                        Ok(data) => {
+                                            smi_last.set_received(&data.to_be_bytes());
+                                            slave_response.set_payload(data as u32)
+                                        }
+                                        // No PHY drove the turnaround, a binary host gets the code
+                                        Err(err) => {
+                                            slave_response.set_status(ErrorCode::HardwareTimeout);
+                                            log::write(serial, config, Level::Error, err);
+                                        }
+                                    }
                                 }
                                 _ => {
                                     // No word received
@@ -767,11 +2061,11 @@ mod app {
                     // TODO Add match case for this
                     match slave_response.init_ready() {
                         Ok(sr) => {
-                            write_serial(serial, "Hi", false);
-                            // respond_to_host::spawn(sr);
+                            log::write(serial, config, Level::Debug, "Slave response ready\n\r");
+                            let _ = respond_to_host::spawn(sr);
                         }
                         Err(err) => {
-                                    write_serial(serial, err, false);
+                                    log::write(serial, config, Level::Error, err);
                         }
                     }
                 }
@@ -790,6 +2084,11 @@ mod app {
     fn respond_to_host(cx: respond_to_host::Context, sr: SlaveResponse<crate::protocol::slave::Ready>) {
         // If Host Response was SPI, we need to update the slave TX Buffer
         // This slave response will go out when the Master requests it again.
+        // Serial and UART hosts were answered in text by send_out already
+        if sr.host_config == ValidHostInterfaces::SPI {
+            // A full queue keeps the older responses, the master reads those first
+            let _ = cx.local.spi_tx_producer.enqueue(sr.to_spi_frame());
+        }
         /* let serial = cx.shared.serial;
         (serial).lock(|serial| {});
             if sr.host_config == ValidHostInterfaces::SPI {
@@ -818,10 +2117,15 @@ mod app {
     }
 
     // Task with least priority that only runs when nothing else is running.
-    #[idle(local = [], shared = [spi_dev])]
-    fn idle(_cx: idle::Context) -> ! {
+    #[idle(local = [], shared = [spi_dev, config])]
+    fn idle(mut cx: idle::Context) -> ! {
         // Locals in idle have lifetime 'static
         loop {
+            // Run the USB task a few more times before sleeping, as set by cfg usbpoll
+            let polls = cx.shared.config.lock(|config| config.usb_poll);
+            for _ in 0..polls {
+                rtic::pend(Interrupt::USBCTRL_IRQ);
+            }
             //rtic::pend(Interrupt::UART0_IRQ);
             //rtic::pend(Interrupt::SPI0_IRQ);
             // Now Wait For Interrupt is used instead of a busy-wait loop
@@ -0,0 +1,97 @@
+//! Copy of all serial output on a spare pin, for `cfg mirror`.
+//! Both hardware UARTs are taken, so PIO1 state machine 1 runs a transmit
+//! only UART at MIRROR_BAUD 8N1. write_serial tees every byte it sends into
+//! the state machine FIFO while the mirror is on.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const MIRROR_BAUD: u32 = 115_200;
+// Pin the program is built for, nothing is driven until cfg mirror on
+pub const MIRROR_DEFAULT_PIN: u8 = 22;
+// The program spends 8 PIO cycles on each bit
+const MIRROR_CYCLES_PER_BIT: u32 = 8;
+
+// PIO1 FSTAT, state machine 1 TX FIFO, INSTR (forced execution) and PINCTRL registers
+const PIO1_FSTAT: *const u32 = 0x5030_0004 as *const u32;
+const PIO1_TXF1: *mut u32 = 0x5030_0014 as *mut u32;
+const PIO1_SM1_INSTR: *mut u32 = 0x5030_00f0 as *mut u32;
+const PIO1_SM1_PINCTRL: *mut u32 = 0x5030_00f4 as *mut u32;
+const FSTAT_TXFULL_SM1: u32 = 1 << 17;
+const PINCTRL_SIDESET_COUNT_SHIFT: u32 = 29;
+const PINCTRL_SET_COUNT_SHIFT: u32 = 26;
+const PINCTRL_OUT_COUNT_SHIFT: u32 = 20;
+const PINCTRL_SIDESET_BASE_SHIFT: u32 = 10;
+const PINCTRL_SET_BASE_SHIFT: u32 = 5;
+// One side-set bit plus the enable bit of an optional side-set
+const MIRROR_SIDESET_COUNT: u32 = 2;
+// "set pins, 1" and "set pindirs, 1", without side-set
+const SET_PINS_1: u32 = 0xE001;
+const SET_PINDIRS_1: u32 = 0xE081;
+
+// IO_BANK0 GPIO0_CTRL, each pin has a status and a control register
+const IO_BANK0_GPIO0_CTRL: u32 = 0x4001_4004;
+const FUNCSEL_PIO1: u32 = 7;
+const FUNCSEL_NULL: u32 = 0x1f;
+
+// Read by write_serial, which has no access to the shared config
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Integer and 1/256 fraction of the state machine clock divisor
+pub fn clock_divisor(sys_clk_hz: u32) -> (u16, u8) {
+    let bit_hz = MIRROR_BAUD * MIRROR_CYCLES_PER_BIT;
+    let int = sys_clk_hz / bit_hz;
+    let frac = (sys_clk_hz % bit_hz) as u64 * 256 / bit_hz as u64;
+    (int as u16, frac as u8)
+}
+
+fn set_function(pin: u8, funcsel: u32) {
+    unsafe { core::ptr::write_volatile((IO_BANK0_GPIO0_CTRL + 8 * pin as u32) as *mut u32, funcsel) }
+}
+
+// Point the state machine at pin, idle it high and start teeing output
+pub fn enable(pin: u8) {
+    let pinctrl = (MIRROR_SIDESET_COUNT << PINCTRL_SIDESET_COUNT_SHIFT)
+        | (1 << PINCTRL_SET_COUNT_SHIFT)
+        | (1 << PINCTRL_OUT_COUNT_SHIFT)
+        | ((pin as u32) << PINCTRL_SIDESET_BASE_SHIFT)
+        | ((pin as u32) << PINCTRL_SET_BASE_SHIFT)
+        | pin as u32;
+    unsafe {
+        core::ptr::write_volatile(PIO1_SM1_PINCTRL, pinctrl);
+        core::ptr::write_volatile(PIO1_SM1_INSTR, SET_PINS_1);
+        core::ptr::write_volatile(PIO1_SM1_INSTR, SET_PINDIRS_1);
+    }
+    set_function(pin, FUNCSEL_PIO1);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Stop teeing and let the pin float
+pub fn disable(pin: u8) {
+    ENABLED.store(false, Ordering::Relaxed);
+    set_function(pin, FUNCSEL_NULL);
+}
+
+// Send bytes out of the mirror pin, waits for room in the FIFO.
+// Every byte takes 87 us at 115200 baud, the mirror slows all output down
+pub fn tee(bytes: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return
+    }
+    for byte in bytes {
+        unsafe {
+            while core::ptr::read_volatile(PIO1_FSTAT) & FSTAT_TXFULL_SM1 != 0 {}
+            core::ptr::write_volatile(PIO1_TXF1, *byte as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisor_at_the_default_clock() {
+        // 125 MHz over 8 cycles of a 115200 baud bit
+        assert_eq!(clock_divisor(125_000_000), (135, 162));
+    }
+}
@@ -1,5 +1,7 @@
 // Check if this must implement send and sync
     use core::result::Result;
+    use core::convert::TryFrom;
+    use core::ops::Range;
 
     use self::slave::{SlaveResponse, NotReady, HostErr};
 
@@ -27,9 +29,189 @@
         None = 0b11,
     }
 
+    // Status byte of a binary mode response, shared by request and response handling.
+    // The values are part of the wire format: append new codes, never renumber
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum ErrorCode {
+        Ok = 0x00,
+        BadCrc = 0x01,
+        UnknownInterface = 0x02,
+        InvalidOp = 0x03,
+        HardwareTimeout = 0x04,
+        InvalidArguments = 0x05,
+    }
+
+    impl ErrorCode {
+        // Classify a text mode parse or validation error, so a failure reports
+        // the same code whichever mode the host is using
+        pub fn from_message(message: &str) -> ErrorCode {
+            // Handled without a request, or nothing to do (prompt only)
+            if message == "Ok" || message == "\n\r->" {
+                ErrorCode::Ok
+            }
+            else if message.starts_with("Invalid Interface")
+                || message.starts_with("Interface not enabled")
+                || message.starts_with("Interface disabled")
+                || message.starts_with("No Interface Selected") {
+                ErrorCode::UnknownInterface
+            }
+            else if message.starts_with("Invalid Operation") {
+                ErrorCode::InvalidOp
+            }
+            // A request that was carried out but the bus or device did not answer
+            else if message.contains("timed out") || message.contains("failed") || message.contains("NAK") {
+                ErrorCode::HardwareTimeout
+            }
+            else {
+                ErrorCode::InvalidArguments
+            }
+        }
+    }
+
+    impl From<HostErr> for ErrorCode {
+        fn from(err: HostErr) -> ErrorCode {
+            match err {
+                HostErr::Timeout => ErrorCode::HardwareTimeout,
+                HostErr::None => ErrorCode::Ok,
+            }
+        }
+    }
+
+    impl TryFrom<u8> for ErrorCode {
+        type Error = ();
+
+        fn try_from(num: u8) -> Result<Self, Self::Error> {
+            match num {
+                0x00 => Ok(ErrorCode::Ok),
+                0x01 => Ok(ErrorCode::BadCrc),
+                0x02 => Ok(ErrorCode::UnknownInterface),
+                0x03 => Ok(ErrorCode::InvalidOp),
+                0x04 => Ok(ErrorCode::HardwareTimeout),
+                0x05 => Ok(ErrorCode::InvalidArguments),
+                _ => Err(()),
+            }
+        }
+    }
+
+    // Data words a request carries, the payload array length
+    pub const PAYLOAD_CAP: usize = 4;
+    // Highest user GPIO on the RP2040 (GPIO0..GPIO29)
+    pub const GPIO_MAX_PIN: u32 = 29;
+    // ADC inputs 0..3 are GPIO26..GPIO29, 4 is the internal temperature sensor
+    pub const ADC_MAX_CHANNEL: u32 = 4;
+    // adc stream: samples held until the capture ends, the fastest rate the
+    // microsecond timer paces and the longest the dispatcher may be held
+    pub const ADC_STREAM_MAX: u32 = 256;
+    pub const ADC_STREAM_MAX_HZ: u32 = 100_000;
+    pub const ADC_STREAM_MAX_MS: u32 = 10_000;
+    // SMI PHY and register addresses are 5 bit fields of the frame
+    pub const SMI_MAX_ADDR: u32 = 31;
+    // Extended register pages selectable through the page register, 8 bits on common PHYs
+    pub const SMI_MAX_PAGE: u32 = 0xFF;
+    // 7 bit I2C addresses and 8 bit register addresses
+    pub const I2C_MAX_ADDR: u32 = 0x7F;
+    pub const I2C_MAX_REG: u32 = 0xFF;
+    // Most registers one i2c dump reads
+    pub const I2C_DUMP_MAX: u32 = 32;
+    // Bus speeds i2c cfg accepts: standard, fast and fast mode plus
+    pub const I2C_SPEEDS: [u32; 3] = [100_000, 400_000, 1_000_000];
+    // PWM frequency range in Hz, the low end is where the slice divider runs out
+    pub const PWM_MIN_FREQ: u32 = 10;
+    pub const PWM_MAX_FREQ: u32 = 1_000_000;
+    // gpio strobe limits, the dispatcher is busy for the whole pattern
+    pub const STROBE_MAX_US: u32 = 1_000_000;
+    pub const STROBE_MAX_CYCLES: u32 = 10_000;
+    pub const STROBE_MAX_MS: u32 = 10_000;
+    // Longest gpio measure waits for a full period
+    pub const MEASURE_MAX_MS: u32 = 10_000;
+    // Longest gpio freq gate window
+    pub const FREQ_MAX_GATE_MS: u32 = 10_000;
+    // Literal bytes a request can carry, the rest of a command line after `uart wstr `
+    pub const TEXT_MAX: usize = 56;
+    // spi wr limits, the write bytes are packed into the payload words after the read length
+    pub const SPI_WR_MAX_WRITE: usize = 12;
+    pub const SPI_WR_MAX_READ: u32 = 16;
+    // Most words one spi r reads, each 4 bytes in the cfg endian order
+    pub const SPI_R_MAX_WORDS: u32 = 4;
+    // Most USB polls idle makes before it sleeps, see cfg usbpoll
+    pub const USB_POLL_MAX: u32 = 16;
+    // Fastest cfg ratelimit, a batch step every millisecond
+    pub const RATE_LIMIT_MAX_HZ: u32 = 1000;
+    // Narrowest line cfg maxline splits answers at, the widest fits its u8
+    pub const MAX_LINE_MIN: u32 = 16;
+    pub const MAX_LINE_MAX: u32 = 255;
+    // Highest cfg addr, the payload of cfg addr off is one past it
+    pub const ADDR_MAX: u32 = 254;
+    pub const ADDR_OFF: u32 = ADDR_MAX + 1;
+
+    // Most extra attempts cfg retry allows for a failed read
+    pub const RETRY_MAX: u32 = 5;
+
+    // Run read until it succeeds, at most 1 + retries times. The error of the
+    // last attempt is the one returned
+    pub fn retry<T, E>(retries: u8, mut read: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let mut result = read();
+        for _ in 0..retries {
+            if result.is_ok() {
+                break
+            }
+            result = read();
+        }
+        result
+    }
+
+    // Longest gap cfg slowdown puts between bytes, in microseconds
+    pub const SLOWDOWN_MAX_US: u32 = 1000;
+
+    // Move len bytes with step, all at once when gap_us is 0. Otherwise one
+    // byte per step with wait_us(gap_us) in between, stopping at the first error
+    pub fn paced<E>(len: usize, gap_us: u16, mut step: impl FnMut(Range<usize>) -> Result<(), E>, mut wait_us: impl FnMut(u32)) -> Result<(), E> {
+        if gap_us == 0 {
+            return step(0..len)
+        }
+        for i in 0..len {
+            if i > 0 {
+                wait_us(gap_us as u32);
+            }
+            step(i..i + 1)?;
+        }
+        Ok(())
+    }
+
+    // Write byte `index` of an spi wr payload
+    pub fn spi_wr_byte(payload: &[u32; 4], index: usize) -> u8 {
+        (payload[1 + index / 4] >> (8 * (index % 4))) as u8
+    }
+
+    // Short git hash and unix build time, embedded by build.rs
+    pub const GIT_HASH: &str = match option_env!("PICO_BRIDGE_GIT_HASH") { Some(hash) => hash, None => "unknown" };
+    pub const BUILD_TIME: &str = match option_env!("PICO_BRIDGE_BUILD_TIME") { Some(time) => time, None => "unknown" };
+
+    // Answer to `cfg build`
+    pub fn write_build(out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        write!(out, "\n\rversion {} git {} built {}\n\r->", env!("CARGO_PKG_VERSION"), GIT_HASH, BUILD_TIME)
+    }
+
 pub mod host {
-    use super::{combine_u16_to_u32, combine_u8_to_u32, encode_smi};
+    use super::{combine_u16_to_u32, combine_u8_to_u32, GPIO_MAX_PIN, ADC_MAX_CHANNEL, ADC_STREAM_MAX, ADC_STREAM_MAX_HZ, ADC_STREAM_MAX_MS, TEXT_MAX, USB_POLL_MAX, RATE_LIMIT_MAX_HZ, RETRY_MAX, SLOWDOWN_MAX_US, MAX_LINE_MIN, MAX_LINE_MAX, ADDR_OFF, PAYLOAD_CAP};
+    #[cfg(feature = "smi")]
+    use super::{encode_smi, encode_smi45, Smi45Op, SMI_MAX_ADDR, SMI_MAX_PAGE};
+    #[cfg(feature = "gpio")]
+    use super::{PWM_MIN_FREQ, PWM_MAX_FREQ, STROBE_MAX_US, STROBE_MAX_CYCLES, STROBE_MAX_MS, MEASURE_MAX_MS, FREQ_MAX_GATE_MS};
+    #[cfg(feature = "spi")]
+    use super::{SPI_WR_MAX_READ, SPI_R_MAX_WORDS};
+    #[cfg(feature = "i2c")]
+    use super::{I2C_MAX_ADDR, I2C_MAX_REG, I2C_DUMP_MAX, I2C_SPEEDS};
     use core::{marker::PhantomData};
+    #[cfg(feature = "smi")]
+    use crate::smi::{SMI_PREAMBLE_MAX, SMI_MDIO_PIN, SMI_MDC_PIN, SMI_RESET_MAX_MS};
+    use crate::serial_number;
+    #[cfg(feature = "gpio")]
+    use crate::adc;
+    #[cfg(feature = "gpio")]
+    use crate::pwm;
+    #[cfg(feature = "lcd")]
+    use crate::lcd::{LCD_MAX_COLS, LCD_MAX_ROWS, LCD_MAX_CHARS};
     use core::convert::TryFrom;
     use super::Send;
     use super::{SlaveResponse, ValidHostInterfaces};
@@ -37,11 +219,13 @@ pub mod host {
     // State of the request
     pub trait State {}
     // request has not been validated
+    #[derive(Clone)]
     pub struct Unclean {
         __private: (),
     }
 
     // The request has been validated
+    #[derive(Clone)]
     pub struct Clean {
         __private: (),
     }
@@ -49,13 +233,93 @@ pub mod host {
     impl State for Unclean {}
     impl State for Clean {}
 
-    #[derive(PartialEq, Debug)]
+    #[derive(Copy, Clone, PartialEq, Debug)]
     pub enum ValidOps  {
         None,
         Read,
         Write, 
         SmiSet,
         SmiGet,
+        Drive,
+        Slew,
+        Scan,
+        SelfTest,
+        Pwm,
+        PwmStop,
+        PwmDuty,
+        Usb,
+        Sync,
+        OpenDrain,
+        WriteRead,
+        Caps,
+        WriteStr,
+        AutoNewline,
+        IntStatus,
+        IntClear,
+        Preamble,
+        Schmitt,
+        BatchBegin,
+        BatchEnd,
+        Set,
+        Clocks,
+        Build,
+        Enable,
+        Disable,
+        Channel,
+        Mem,
+        BlockWrite,
+        UsbPoll,
+        LogLevel,
+        Clr,
+        UsbSerial,
+        Mirror,
+        ResetReason,
+        TxQueue,
+        MaxLine,
+        XferHex,
+        Clock,
+        Grammar,
+        Strobe,
+        Measure,
+        Show,
+        Limits,
+        ReadPage,
+        WritePage,
+        Framing,
+        Conn,
+        Tasks,
+        Reset,
+        DefaultInterface,
+        RateLimit,
+        Flash,
+        Pins,
+        ScanMap,
+        Parser,
+        Endian,
+        Estop,
+        Dump,
+        Speed,
+        Indicator,
+        Link,
+        Retry,
+        Pause,
+        Resume,
+        Whois,
+        Slowdown,
+        History,
+        Stream,
+        Strict,
+        Threshold,
+        Timestamp,
+        Init,
+        Clear,
+        WriteAt,
+        Freq,
+        Clause,
+        Last,
+        SafeState,
+        FlashId,
+        Address,
     }
 
     impl TryFrom<u16> for ValidOps {
@@ -68,13 +332,93 @@ pub mod host {
                 2 => Ok(ValidOps::Write),
                 3 => Ok(ValidOps::SmiSet),
                 4 =>  Ok(ValidOps::SmiGet),
+                5 => Ok(ValidOps::Drive),
+                6 => Ok(ValidOps::Slew),
+                7 => Ok(ValidOps::Scan),
+                8 => Ok(ValidOps::SelfTest),
+                9 => Ok(ValidOps::Pwm),
+                10 => Ok(ValidOps::PwmStop),
+                11 => Ok(ValidOps::PwmDuty),
+                12 => Ok(ValidOps::Usb),
+                13 => Ok(ValidOps::Sync),
+                14 => Ok(ValidOps::OpenDrain),
+                15 => Ok(ValidOps::WriteRead),
+                16 => Ok(ValidOps::Caps),
+                17 => Ok(ValidOps::WriteStr),
+                18 => Ok(ValidOps::AutoNewline),
+                19 => Ok(ValidOps::IntStatus),
+                20 => Ok(ValidOps::IntClear),
+                21 => Ok(ValidOps::Preamble),
+                22 => Ok(ValidOps::Schmitt),
+                23 => Ok(ValidOps::BatchBegin),
+                24 => Ok(ValidOps::BatchEnd),
+                25 => Ok(ValidOps::Set),
+                26 => Ok(ValidOps::Clocks),
+                27 => Ok(ValidOps::Build),
+                28 => Ok(ValidOps::Enable),
+                29 => Ok(ValidOps::Disable),
+                30 => Ok(ValidOps::Channel),
+                31 => Ok(ValidOps::Mem),
+                32 => Ok(ValidOps::BlockWrite),
+                33 => Ok(ValidOps::UsbPoll),
+                34 => Ok(ValidOps::LogLevel),
+                35 => Ok(ValidOps::Clr),
+                36 => Ok(ValidOps::UsbSerial),
+                37 => Ok(ValidOps::Mirror),
+                38 => Ok(ValidOps::ResetReason),
+                39 => Ok(ValidOps::TxQueue),
+                40 => Ok(ValidOps::MaxLine),
+                41 => Ok(ValidOps::XferHex),
+                42 => Ok(ValidOps::Clock),
+                43 => Ok(ValidOps::Grammar),
+                44 => Ok(ValidOps::Strobe),
+                45 => Ok(ValidOps::Measure),
+                46 => Ok(ValidOps::Show),
+                47 => Ok(ValidOps::Limits),
+                48 => Ok(ValidOps::ReadPage),
+                49 => Ok(ValidOps::WritePage),
+                50 => Ok(ValidOps::Framing),
+                51 => Ok(ValidOps::Conn),
+                52 => Ok(ValidOps::Tasks),
+                53 => Ok(ValidOps::Reset),
+                54 => Ok(ValidOps::DefaultInterface),
+                55 => Ok(ValidOps::RateLimit),
+                56 => Ok(ValidOps::Flash),
+                57 => Ok(ValidOps::Pins),
+                58 => Ok(ValidOps::ScanMap),
+                59 => Ok(ValidOps::Parser),
+                60 => Ok(ValidOps::Endian),
+                61 => Ok(ValidOps::Estop),
+                62 => Ok(ValidOps::Dump),
+                63 => Ok(ValidOps::Speed),
+                64 => Ok(ValidOps::Indicator),
+                65 => Ok(ValidOps::Link),
+                66 => Ok(ValidOps::Retry),
+                67 => Ok(ValidOps::Pause),
+                68 => Ok(ValidOps::Resume),
+                69 => Ok(ValidOps::Whois),
+                70 => Ok(ValidOps::Slowdown),
+                71 => Ok(ValidOps::History),
+                72 => Ok(ValidOps::Stream),
+                73 => Ok(ValidOps::Strict),
+                74 => Ok(ValidOps::Threshold),
+                75 => Ok(ValidOps::Timestamp),
+                76 => Ok(ValidOps::Init),
+                77 => Ok(ValidOps::Clear),
+                78 => Ok(ValidOps::WriteAt),
+                79 => Ok(ValidOps::Freq),
+                80 => Ok(ValidOps::Clause),
+                81 => Ok(ValidOps::Last),
+                82 => Ok(ValidOps::SafeState),
+                83 => Ok(ValidOps::FlashId),
+                84 => Ok(ValidOps::Address),
                 // ... add more variants here
                 _ => Err(()),
             }
         }
     }
     
-    #[derive(Debug)]
+    #[derive(Copy, Clone, PartialEq, Debug)]
     pub enum ValidInterfaces  {
         None,
         SMI,
@@ -83,6 +427,33 @@ pub mod host {
         SPI,
         Config,
         GPIO,
+        ADC,
+        // Bridge status reports, answered by the firmware itself
+        Stat,
+        UART,
+        // WS2812 pixel driven from PIO1
+        LED,
+        // HD44780 character LCD on the I2C0 bus
+        LCD,
+    }
+
+    impl ValidInterfaces {
+        // Device facing interfaces can be compiled out with cargo features.
+        // With all of them off every arm is a literal, which clippy would rather see as matches!
+        #[allow(clippy::match_like_matches_macro)]
+        pub fn is_enabled(&self) -> bool {
+            match self {
+                ValidInterfaces::SMI => cfg!(feature = "smi"),
+                ValidInterfaces::JTAG => cfg!(feature = "jtag"),
+                ValidInterfaces::I2C => cfg!(feature = "i2c"),
+                ValidInterfaces::SPI => cfg!(feature = "spi"),
+                ValidInterfaces::GPIO => cfg!(feature = "gpio"),
+                ValidInterfaces::UART => cfg!(feature = "uart"),
+                ValidInterfaces::LED => cfg!(feature = "led"),
+                ValidInterfaces::LCD => cfg!(feature = "lcd"),
+                _ => true,
+            }
+        }
     }
 
     impl TryFrom<u16> for ValidInterfaces {
@@ -97,12 +468,17 @@ pub mod host {
                 4 => Ok(ValidInterfaces::SPI),
                 5 => Ok(ValidInterfaces::Config),
                 6 => Ok(ValidInterfaces::GPIO),
+                7 => Ok(ValidInterfaces::ADC),
+                8 => Ok(ValidInterfaces::Stat),
+                9 => Ok(ValidInterfaces::UART),
+                10 => Ok(ValidInterfaces::LED),
+                11 => Ok(ValidInterfaces::LCD),
                 // ... add more variants here
                 _ => Err(()),
             }
         }
     }
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct HostRequest<S: State> {
         state: PhantomData<S>,
         proc_id: u8,
@@ -111,7 +487,11 @@ pub mod host {
         pub operation: ValidOps,
         checksum: u8,         // Wrapping checksum
         pub size: u8,             // A value between 0 and 4
-        pub payload: [u32; 4],     // Max payload size over SPI is 4 bytes 
+        pub payload: [u32; PAYLOAD_CAP],     // Max payload size over SPI is 4 bytes 
+        pub text: [u8; TEXT_MAX],  // Literal bytes of a wstr request, size holds the length
+        pub timed: bool,           // Report how long the request took to handle (time prefix)
+        pub fields: bool,          // Decode the named bit fields of an smi r value (fields suffix)
+        pub clause45: bool,        // smi r and smi w use Clause 45 frames, set from cfg by smi clause
 
     }
 
@@ -126,6 +506,10 @@ pub mod host {
                 checksum: self.checksum,
                 size: self.size,       
                 payload: self.payload,
+                text: self.text,
+                timed: self.timed,
+                fields: self.fields,
+                clause45: self.clause45,
             })
         }
     }
@@ -148,7 +532,11 @@ pub mod host {
                 operation: ValidOps::None,
                 checksum: 0_u8,
                 size: 0_u8,           
-                payload: [0_u32; 4],
+                payload: [0_u32; PAYLOAD_CAP],
+                text: [0_u8; TEXT_MAX],
+                timed: false,
+                fields: false,
+                clause45: false,
             }
         }
         pub fn set_proc_id(&mut self, proc_id: u8) {
@@ -159,22 +547,63 @@ pub mod host {
             self.operation =  op;
         }
 
-        pub fn set_size(&mut self, size: u8) {
-            if size > 4 {
-                // Assert or log that data size too large
-                self.size = 4;
+        // Data words in use. A count past the payload is refused rather than
+        // stored, so nothing downstream indexes the payload out of bounds
+        pub fn set_size(&mut self, size: u8) -> Result<(), &'static str> {
+            if size as usize > PAYLOAD_CAP {
+                return Err("Too many data words\n\r")
             }
-            self.size = size
+            self.size = size;
+            Ok(())
+        }
+
+        // Append a data word, size counts it in the same step so the two
+        // cannot disagree. Refused once the payload is full
+        pub fn push_word(&mut self, word: u32) -> Result<(), &'static str> {
+            if self.size as usize >= PAYLOAD_CAP {
+                return Err("Too many data words\n\r")
+            }
+            self.payload[self.size as usize] = word;
+            self.size += 1;
+            Ok(())
+        }
+
+        // Bytes packed into the payload after its first word (spi wr), 4 per word
+        pub fn set_byte_count(&mut self, count: u8) -> Result<(), &'static str> {
+            if count as usize > (PAYLOAD_CAP - 1) * 4 {
+                return Err("Too many data words\n\r")
+            }
+            self.size = count;
+            Ok(())
         }
 
         pub fn set_host_config(&mut self, cfg: ValidHostInterfaces) {
             self.host_config = cfg
         }
 
-        pub fn set_payload(&mut self, payload: [u32; 4]) {
+        pub fn set_payload(&mut self, payload: [u32; PAYLOAD_CAP]) {
             self.payload =  payload;
         }
 
+        // Store literal bytes, size becomes their count
+        pub fn set_text(&mut self, text: &[u8]) {
+            let len = text.len().min(TEXT_MAX);
+            self.text[..len].copy_from_slice(&text[..len]);
+            self.size = len as u8;
+        }
+
+        pub fn set_timed(&mut self, timed: bool) {
+            self.timed = timed;
+        }
+
+        pub fn set_fields(&mut self, fields: bool) {
+            self.fields = fields;
+        }
+
+        pub fn set_clause45(&mut self, clause45: bool) {
+            self.clause45 = clause45;
+        }
+
         pub fn set_checksum(&mut self, checksum: u8) {
             self.checksum =  checksum;
         }
@@ -210,12 +639,12 @@ pub mod host {
             let checksum = (buf[0] & 0xFF) as u8;
             let payload = combine_u16_to_u32(&buf[1..]);
 
-            self.set_size(size);
+            self.set_size(size)?;
             self.set_host_config(ValidHostInterfaces::SPI);
             self.set_payload(payload);
             self.set_checksum(checksum);
 
-            return self.init_clean()
+            self.init_clean()
         }
 
         pub fn build_from_8bit_spi(mut self, buf: &[u8]) -> Result<HostRequest<Clean>, &'static str> {
@@ -245,61 +674,457 @@ pub mod host {
             let checksum = buf[1];
             let payload = combine_u8_to_u32(&buf[2..]);
 
-            self.set_size(size);
+            self.set_size(size)?;
             self.set_host_config(ValidHostInterfaces::SPI);
             self.set_payload(payload);
             self.set_checksum(checksum);
 
-            return self.init_clean()
+            self.init_clean()
+        }
+
+        // SMI requests are encoded into frames here, the other interfaces are only checked
+        #[cfg(feature = "smi")]
+        fn init_clean_smi(mut self) -> Result<HostRequest<Clean>, &'static str> {
+            // Clause 45: an address frame with the register, then the read or write
+            // frame, both to the MMD (device address) of the port
+            if self.clause45 && (self.operation == ValidOps::Read || self.operation == ValidOps::Write) {
+                let words = if self.operation == ValidOps::Read { 3 } else { 4 };
+                if self.size != words {return Err("Invalid Arguments for SMI: Clause 45\n\r")}
+                if self.payload[0] > SMI_MAX_ADDR || self.payload[1] > SMI_MAX_ADDR {
+                    return Err("SMI port and device address must be 0 to 31\n\r")
+                }
+                if self.payload[2] > 0xFFFF || self.payload[3] > 0xFFFF {
+                    return Err("SMI register and data must be 16 bit\n\r")
+                }
+                let (port, devad) = (self.payload[0] as u8, self.payload[1] as u8);
+                let access = if self.operation == ValidOps::Read {
+                    encode_smi45(Smi45Op::Read, port, devad, 0)
+                } else {
+                    encode_smi45(Smi45Op::Write, port, devad, self.payload[3] as u16)
+                };
+                self.payload[0] = encode_smi45(Smi45Op::Address, port, devad, self.payload[2] as u16);
+                self.payload[1] = access;
+                self.size = 2;
+            }
+            // If it is SMI Read, we need PHY address and REG address
+            else if self.operation == ValidOps::Read {
+                if self.size != 2 {return Err("Invalid Arguments for SMI: Read\n\r")}
+                // Read blocking by the dispatcher to decode, the addresses stay as given
+                if self.fields {
+                    if self.payload[0] > SMI_MAX_ADDR || self.payload[1] > SMI_MAX_ADDR {
+                        return Err("SMI PHY and register address must be 0 to 31\n\r")
+                    }
+                }
+                else {
+                                                    // Opcode    PhyAddr               RegAddr
+                    self.payload[0] = encode_smi(true, self.payload[0] as u8, self.payload[1] as u8, 0_u16);
+                    self.size = 1;
+                }
+            }
+            // If it is SMI Write, we need PHY address and REG address + Data
+            else if self.operation == ValidOps::Write {
+                if self.size != 3 {return Err("Invalid Arguments for SMI: Write\n\r")}
+                self.payload[0] = encode_smi(false, self.payload[0] as u8, self.payload[1] as u8, self.payload[2] as u16);
+
+                self.size = 1;
+            }
+            // Clause of plain smi r and smi w, 22 or 45
+            else if self.operation == ValidOps::Clause {
+                if self.size != 1 {return Err("Invalid Arguments for SMI: Clause\n\r")}
+                if self.payload[0] != 22 && self.payload[0] != 45 {return Err("SMI clause must be 22 or 45\n\r")}
+            }
+            // Number of preamble bits before each frame
+            else if self.operation == ValidOps::Preamble {
+                if self.size != 1 {return Err("Invalid Arguments for SMI: Preamble\n\r")}
+                if self.payload[0] > SMI_PREAMBLE_MAX as u32 {return Err("SMI preamble must be 0 to 32 bits\n\r")}
+            }
+            // Read-modify-write of the bits in a 16 bit mask, done by the dispatcher
+            else if self.operation == ValidOps::Set || self.operation == ValidOps::Clr {
+                if self.size != 3 {return Err("Invalid Arguments for SMI: set/clr\n\r")}
+                if self.payload[0] > SMI_MAX_ADDR || self.payload[1] > SMI_MAX_ADDR {
+                    return Err("SMI PHY and register address must be 0 to 31\n\r")
+                }
+                if self.payload[2] > u16::MAX as u32 {return Err("SMI mask must be 16 bits\n\r")}
+            }
+            // MDC pin, then MDIO pin
+            else if self.operation == ValidOps::Pins {
+                if self.size != 2 {return Err("Invalid Arguments for SMI: pins\n\r")}
+                if self.payload[0] > GPIO_MAX_PIN || self.payload[1] > GPIO_MAX_PIN {return Err("Invalid GPIO pin\n\r")}
+                if self.payload[0] == self.payload[1] {return Err("MDC and MDIO pin must differ\n\r")}
+            }
+            // Active low reset pulse on a GPIO then a PHY scan: pin and hold time in ms
+            else if self.operation == ValidOps::Reset {
+                if !cfg!(feature = "gpio") {return Err("SMI reset needs the gpio feature\n\r")}
+                if self.size != 2 {return Err("Invalid Arguments for SMI: reset\n\r")}
+                if self.payload[0] > GPIO_MAX_PIN {return Err("Invalid GPIO pin\n\r")}
+                if self.payload[0] == SMI_MDIO_PIN as u32 || self.payload[0] == SMI_MDC_PIN as u32 {
+                    return Err("Reset pin must not be an SMI pin\n\r")
+                }
+                if self.payload[1] == 0 || self.payload[1] > SMI_RESET_MAX_MS {
+                    return Err("SMI reset hold must be 1 to 1000 ms\n\r")
+                }
+            }
+            else if self.operation == ValidOps::Link || self.operation == ValidOps::Whois {
+                if self.size != 1 {return Err("Invalid Arguments for SMI: link/whois\n\r")}
+                if self.payload[0] > SMI_MAX_ADDR {return Err("SMI PHY address must be 0 to 31\n\r")}
+            }
+            // phy page reg [data], the page select and restore are done by the dispatcher
+            else if self.operation == ValidOps::ReadPage || self.operation == ValidOps::WritePage {
+                let size = if self.operation == ValidOps::ReadPage { 3 } else { 4 };
+                if self.size != size {return Err("Invalid Arguments for SMI: rpage/wpage\n\r")}
+                if self.payload[0] > SMI_MAX_ADDR || self.payload[2] > SMI_MAX_ADDR {
+                    return Err("SMI PHY and register address must be 0 to 31\n\r")
+                }
+                if self.payload[1] > SMI_MAX_PAGE {return Err("SMI page must be 0 to 255\n\r")}
+                if size == 4 && self.payload[3] > u16::MAX as u32 {return Err("SMI data must be 16 bits\n\r")}
+            }
+            /* match self.payload[0] {
+                10 => {
+                    self.payload[0] = 0
+                }
+                1 => {
+                    self.payload[0] = 10
+                }
+                _ => {
+                    return Err("Clock frequency not support yet!\n\r")
+                }
+            }*/
+            else if self.operation == ValidOps::SmiSet && self.size != 1 {
+                return Err("Invalid Arguments SMI Set\n\r")
+            }
+            self.transition(Clean {__private: () })
         }
 
         // This will validate all the interface rules for our HostRequest
-        pub fn init_clean(mut self) -> Result<HostRequest<Clean>, &'static str> {
+        pub fn init_clean(self) -> Result<HostRequest<Clean>, &'static str> {
             // if let valid_packet = checksum(self.checksum) ...
             // Any other kind of packet sanitizing
+            if !self.interface.is_enabled() {
+                return Err("Interface not enabled\n\r")
+            }
 
             match self.interface {
-                ValidInterfaces::SMI => {
-                    // If it is SMI Read, we need PHY address and REG address
-                    if self.operation == ValidOps::Read {
-                        if self.size != 2 {return Err("Invalid Arguments for SMI: Read\n\r")}
-                                                        // Opcode    PhyAddr               RegAddr
-                        self.payload[0] = encode_smi(true, self.payload[0] as u8, self.payload[1] as u8, 0_u16);
-                        self.size = 1;
+                #[cfg(feature = "smi")]
+                ValidInterfaces::SMI => return self.init_clean_smi(),
+
+                #[cfg(feature = "gpio")]
+                ValidInterfaces::GPIO => {
+                    match self.operation {
+                        // Pad drive strength in mA, only the steps the pad supports
+                        ValidOps::Drive => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Drive\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            match self.payload[1] {
+                                2 | 4 | 8 | 12 => {}
+                                _ => { return Err("Drive strength must be 2, 4, 8 or 12 mA\n\r") }
+                            }
+                        }
+                        // Pad slew rate, 1 = fast, 0 = slow
+                        ValidOps::Slew => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Slew\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                        }
+                        // Start PWM on a pin: frequency in Hz, duty in percent
+                        ValidOps::Pwm => {
+                            if self.size != 3 { return Err("Invalid Arguments for GPIO: PWM\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[1] < PWM_MIN_FREQ || self.payload[1] > PWM_MAX_FREQ {
+                                return Err("PWM frequency out of range\n\r")
+                            }
+                            if self.payload[2] > 100 { return Err("PWM duty must be 0 to 100 %\n\r") }
+                        }
+                        // Square wave on a pin: frequency in Hz, same range as PWM
+                        ValidOps::Clock => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Clock\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[1] < PWM_MIN_FREQ || self.payload[1] > PWM_MAX_FREQ {
+                                return Err("Clock frequency out of range\n\r")
+                            }
+                        }
+                        // Connectivity check: drive pin, then sense pin
+                        ValidOps::Conn => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Conn\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN || self.payload[1] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[0] == self.payload[1] { return Err("Drive and sense pin must differ\n\r") }
+                        }
+                        // Time one period of an input: timeout in ms
+                        ValidOps::Measure => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Measure\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[1] == 0 || self.payload[1] > MEASURE_MAX_MS {
+                                return Err("Measure timeout must be 1 to 10000 ms\n\r")
+                            }
+                        }
+                        // Count edges in a gate window: gate in ms. Only the odd pins reach a slice counter
+                        ValidOps::Freq => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Freq\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if !pwm::can_count(self.payload[0] as u8) { return Err("Frequency is counted on odd GPIO pins only\n\r") }
+                            if self.payload[1] == 0 || self.payload[1] > FREQ_MAX_GATE_MS {
+                                return Err("Gate must be 1 to 10000 ms\n\r")
+                            }
+                        }
+                        // Pulse train: high time and low time in us, then the number of pulses
+                        ValidOps::Strobe => {
+                            if self.size != 4 { return Err("Invalid Arguments for GPIO: Strobe\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[1] == 0 || self.payload[1] > STROBE_MAX_US
+                                || self.payload[2] == 0 || self.payload[2] > STROBE_MAX_US {
+                                return Err("Strobe times must be 1 to 1000000 us\n\r")
+                            }
+                            if self.payload[3] == 0 || self.payload[3] > STROBE_MAX_CYCLES {
+                                return Err("Strobe cycles must be 1 to 10000\n\r")
+                            }
+                            let total_us = (self.payload[1] as u64 + self.payload[2] as u64) * self.payload[3] as u64;
+                            if total_us > STROBE_MAX_MS as u64 * 1000 {
+                                return Err("Strobe pattern must be at most 10 s\n\r")
+                            }
+                        }
+                        // Input Schmitt trigger, 1 = on, 0 = off
+                        ValidOps::Schmitt => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Schmitt\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                        }
+                        // Open drain level, 1 = released high, 0 = driven low
+                        ValidOps::OpenDrain => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: Open Drain\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                        }
+                        // Pin and an optional level in mV. The RP2040 has no comparator,
+                        // only the pins with an ADC input can be compared
+                        ValidOps::Threshold => {
+                            if self.size == 0 || self.size > 2 { return Err("Invalid Arguments for GPIO: Threshold\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if adc::gpio_channel(self.payload[0] as u8).is_none() {
+                                return Err("GPIO has no analog input, use 26 to 29\n\r")
+                            }
+                            // Checked again with the default level filled in
+                            if self.size == 1 {
+                                let mut hr = self;
+                                hr.push_word(adc::THRESHOLD_DEFAULT_MV)?;
+                                return hr.init_clean()
+                            }
+                            if self.payload[1] > adc::ADC_VREF_MV { return Err("Threshold must be 0 to 3300 mV\n\r") }
+                        }
+                        // Read or clear the pin's edge interrupt flags
+                        ValidOps::IntStatus | ValidOps::IntClear => {
+                            if self.size != 1 { return Err("Invalid Arguments for GPIO: Interrupt Status\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                        }
+                        ValidOps::PwmStop => {
+                            if self.size != 1 { return Err("Invalid Arguments for GPIO: PWM stop\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                        }
+                        ValidOps::PwmDuty => {
+                            if self.size != 2 { return Err("Invalid Arguments for GPIO: PWM duty\n\r") }
+                            if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                            if self.payload[1] > 100 { return Err("PWM duty must be 0 to 100 %\n\r") }
+                        }
+                        _ => {
+                            // So far, only support output High and Low
+                            if self.size != 1 { return Err("Invalid Arguments") }
+                        }
                     }
-                    // If it is SMI Write, we need PHY address and REG address + Data
-                    else if self.operation == ValidOps::Write {
-                        if self.size != 3 {return Err("Invalid Arguments for SMI: Write\n\r")}
-                        self.payload[0] = encode_smi(false, self.payload[0] as u8, self.payload[1] as u8, self.payload[2] as u16);
+                }
 
-                        self.size = 1;
+                ValidInterfaces::ADC => {
+                    // channel rate_hz count, sampled on a fixed schedule
+                    if self.operation == ValidOps::Stream {
+                        if self.size != 3 { return Err("Invalid Arguments for ADC: Stream\n\r") }
+                        let (channel, rate, count) = (self.payload[0], self.payload[1], self.payload[2]);
+                        if channel > ADC_MAX_CHANNEL { return Err("Invalid ADC channel\n\r") }
+                        if rate == 0 || rate > ADC_STREAM_MAX_HZ { return Err("ADC stream rate must be 1 to 100000 Hz\n\r") }
+                        if count == 0 || count > ADC_STREAM_MAX { return Err("ADC stream count must be 1 to 256\n\r") }
+                        if count as u64 * 1000 > ADC_STREAM_MAX_MS as u64 * rate as u64 {
+                            return Err("ADC stream must not last over 10 s\n\r")
+                        }
+                    }
+                    // Scan reads each listed channel once, in the order given
+                    else {
+                        if self.operation != ValidOps::Scan { return Err("Invalid Operation for ADC\n\r") }
+                        if self.size == 0 { return Err("Invalid Arguments for ADC: Scan\n\r") }
+                        let channels = &self.payload[..self.size as usize];
+                        for (i, channel) in channels.iter().enumerate() {
+                            if *channel > ADC_MAX_CHANNEL { return Err("Invalid ADC channel\n\r") }
+                            if channels[..i].contains(channel) { return Err("Duplicate ADC channel\n\r") }
+                        }
                     }
                 }
+
+                #[cfg(feature = "spi")]
+                ValidInterfaces::SPI => {
+                    // Whole words, 4 bytes each in the cfg endian order
+                    if self.operation == ValidOps::Read
+                        && (self.size != 1 || self.payload[0] == 0 || self.payload[0] > SPI_R_MAX_WORDS) {
+                        return Err("SPI read must be 1 to 4 words\n\r")
+                    }
+                    // Write the command bytes then read in the same chip select assertion
+                    if self.operation == ValidOps::WriteStr && self.size == 0 {
+                        return Err("Invalid Arguments for SPI: Write String\n\r")
+                    }
+                    // Full duplex transfer of the decoded hex bytes, carried as text
+                    if self.operation == ValidOps::XferHex && self.size == 0 {
+                        return Err("Invalid Arguments for SPI: xferx\n\r")
+                    }
+                    if self.operation == ValidOps::WriteRead {
+                        if self.size == 0 { return Err("Invalid Arguments for SPI: Write Read\n\r") }
+                        if self.payload[0] == 0 || self.payload[0] > SPI_WR_MAX_READ {
+                            return Err("SPI read length must be 1 to 16\n\r")
+                        }
+                    }
+                }
+
+                #[cfg(feature = "jtag")]
+                // Target memory through the MEM-AP, 32 bit accesses only
+                ValidInterfaces::JTAG if self.operation == ValidOps::Mem => {
+                    if self.size != 1 { return Err("Invalid Arguments for JTAG: Mem\n\r") }
+                    if !self.payload[0].is_multiple_of(4) { return Err("JTAG memory address must be word aligned\n\r") }
+                }
+
+                #[cfg(feature = "i2c")]
+                ValidInterfaces::I2C => {
+                    // Consecutive registers from startReg, the device increments the address
+                    if self.operation == ValidOps::Dump {
+                        if self.size != 3 { return Err("Invalid Arguments for I2C: Dump\n\r") }
+                        if self.payload[0] > I2C_MAX_ADDR { return Err("I2C address must be 0 to 0x7F\n\r") }
+                        if self.payload[1] > I2C_MAX_REG { return Err("I2C register must be 0 to 0xFF\n\r") }
+                        if self.payload[2] == 0 || self.payload[2] > I2C_DUMP_MAX {
+                            return Err("I2C dump count must be 1 to 32\n\r")
+                        }
+                    }
+                    if self.operation == ValidOps::Scan && self.size != 0 {
+                        return Err("Invalid Arguments for I2C: Scan\n\r")
+                    }
+                    if self.operation == ValidOps::Speed {
+                        if self.size != 1 { return Err("Invalid Arguments for I2C: cfg\n\r") }
+                        if !I2C_SPEEDS.contains(&self.payload[0]) {
+                            return Err("I2C speed must be 100000, 400000 or 1000000\n\r")
+                        }
+                    }
+                }
+
+                #[cfg(feature = "uart")]
+                ValidInterfaces::UART if self.size == 0 => {
+                    return Err("Invalid Arguments for UART: Write String\n\r")
+                }
+
                 ValidInterfaces::Config => {
-                    if self.operation == ValidOps::SmiSet {
-                        if self.size != 1 {return Err("Invalid Arguments SMI Set\n\r")}
-                        /* match self.payload[0] {
-                            10 => {
-                                self.payload[0] = 0
+                    if self.operation == ValidOps::AutoNewline && self.size != 1 {
+                        return Err("Invalid Arguments for CFG: autonl\n\r")
+                    }
+                    // on takes the pin to copy output to, off nothing
+                    if self.operation == ValidOps::Mirror {
+                        match (self.payload[0], self.size) {
+                            (1, 2) => {
+                                if self.payload[1] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
                             }
-                            1 => {
-                                self.payload[0] = 10
+                            (0, 1) => {}
+                            _ => return Err("Invalid Arguments for CFG: mirror on pin|off\n\r"),
+                        }
+                    }
+                    // Pin and level (0 low, 1 high, 2 off) of the table applied at boot
+                    if self.operation == ValidOps::SafeState {
+                        if !cfg!(feature = "gpio") { return Err("cfg safestate needs the gpio feature\n\r") }
+                        if self.size != 2 { return Err("Invalid Arguments for CFG: safestate\n\r") }
+                        if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                    }
+                    // A serial to set is carried as text, size 0 is a get
+                    if self.operation == ValidOps::UsbSerial && self.size != 0 {
+                        serial_number::validate(&self.text[..self.size as usize])?;
+                    }
+                    if self.operation == ValidOps::MaxLine {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: maxline\n\r") }
+                        let width = self.payload[0];
+                        if width != 0 && !(MAX_LINE_MIN..=MAX_LINE_MAX).contains(&width) {
+                            return Err("Line width must be 0 (off) or 16 to 255\n\r")
+                        }
+                    }
+                    // Bus address lines must be prefixed with, ADDR_OFF takes the filter off
+                    if self.operation == ValidOps::Address {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: addr\n\r") }
+                        if self.payload[0] > ADDR_OFF { return Err("Address must be 0 to 254 or off\n\r") }
+                    }
+                    if self.operation == ValidOps::RateLimit {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: ratelimit\n\r") }
+                        if self.payload[0] > RATE_LIMIT_MAX_HZ { return Err("Rate limit must be 0 (off) to 1000 Hz\n\r") }
+                    }
+                    if self.operation == ValidOps::Retry {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: retry\n\r") }
+                        if self.payload[0] > RETRY_MAX { return Err("Retry count must be 0 to 5\n\r") }
+                    }
+                    if self.operation == ValidOps::Slowdown {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: slowdown\n\r") }
+                        if self.payload[0] > SLOWDOWN_MAX_US { return Err("Slowdown must be 0 (off) to 1000 us\n\r") }
+                    }
+                    if self.operation == ValidOps::UsbPoll {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: usbpoll\n\r") }
+                        if self.payload[0] > USB_POLL_MAX { return Err("USB poll count must be 0 to 16\n\r") }
+                    }
+                    // payload[0] is the ValidInterfaces value, None clears the default
+                    if self.operation == ValidOps::DefaultInterface {
+                        let interface = ValidInterfaces::try_from(self.payload[0] as u16).map_err(|_| "Invalid Interface\n\r")?;
+                        if !interface.is_enabled() { return Err("Interface not enabled\n\r") }
+                    }
+                    // payload[0] is the ValidInterfaces value to gate
+                    if self.operation == ValidOps::Enable || self.operation == ValidOps::Disable {
+                        if self.size != 1 { return Err("Invalid Arguments for CFG: enable/disable\n\r") }
+                        let interface = ValidInterfaces::try_from(self.payload[0] as u16).map_err(|_| "Invalid Interface\n\r")?;
+                        if self.operation == ValidOps::Disable && interface == ValidInterfaces::Config {
+                            return Err("The cfg interface can not be disabled\n\r")
+                        }
+                    }
+                }
+
+                // Pin then red, green and blue of the pixel
+                #[cfg(feature = "led")]
+                ValidInterfaces::LED => {
+                    if self.operation != ValidOps::Set { return Err("Invalid Operation for LED\n\r") }
+                    if self.size != 4 { return Err("Invalid Arguments for LED: Set\n\r") }
+                    if self.payload[0] > GPIO_MAX_PIN { return Err("Invalid GPIO pin\n\r") }
+                    if self.payload[1..4].iter().any(|color| *color > 255) {
+                        return Err("LED color must be 0 to 255\n\r")
+                    }
+                }
+
+                // Bounds of the largest display here, the dispatcher checks the
+                // geometry lcd init set
+                #[cfg(feature = "lcd")]
+                ValidInterfaces::LCD => {
+                    match self.operation {
+                        ValidOps::Init => {
+                            if self.size != 2 { return Err("Invalid Arguments for LCD: init\n\r") }
+                            let (cols, rows) = (self.payload[0], self.payload[1]);
+                            if cols == 0 || cols > LCD_MAX_COLS || rows == 0 || rows > LCD_MAX_ROWS || cols * rows > LCD_MAX_CHARS {
+                                return Err("LCD must be 1 to 40 columns by 1 to 4 rows, 80 characters at most\n\r")
                             }
-                            _ => {
-                                return Err("Clock frequency not support yet!\n\r")
+                        }
+                        ValidOps::Clear => {
+                            if self.size != 0 { return Err("Invalid Arguments for LCD: clear\n\r") }
+                        }
+                        // row col text, size holds the text length
+                        ValidOps::WriteAt => {
+                            if self.size == 0 { return Err("Invalid Arguments for LCD: write\n\r") }
+                            if self.payload[0] >= LCD_MAX_ROWS || self.payload[1] >= LCD_MAX_COLS {
+                                return Err("LCD position must be row 0 to 3, column 0 to 39\n\r")
                             }
-                        }*/
+                        }
+                        _ => return Err("Invalid Operation for LCD\n\r"),
                     }
                 }
 
-                ValidInterfaces::GPIO => {
-                    // So far, only support output High and Low
-                    if self.size != 1 { return Err("Invalid Arguments") }
+                ValidInterfaces::Stat => {
+                    match self.operation {
+                        ValidOps::Usb | ValidOps::ResetReason | ValidOps::TxQueue | ValidOps::Tasks | ValidOps::Parser => {}
+                        _ => return Err("Invalid Operation for Stat\n\r"),
+                    }
+                    if self.size != 0 { return Err("Invalid Arguments for Stat\n\r") }
                 }
 
                 ValidInterfaces::None => {
                     return Err("No Interface Selected\n\r")
                 }
+                // Reached by the interfaces a build leaves out with its features
+                #[allow(unreachable_patterns)]
                 _ => {
 
                 }
@@ -311,7 +1136,10 @@ pub mod host {
 
 pub mod slave {
     use core::{marker::PhantomData};
-    use super::{Respond, ValidHostInterfaces};
+    use super::{ErrorCode, Respond, ValidHostInterfaces};
+
+    // Bytes the SPI master clocks out for one response, the slave TX buffer size
+    pub const SPI_RESPONSE_LEN: usize = 18;
 
         // State of the request
     pub trait State {}
@@ -333,6 +1161,8 @@ pub mod slave {
         state: PhantomData<S>,
         pub proc_id: u8,
         pub host_config: ValidHostInterfaces,
+        // Ok, or why the request was refused or failed
+        pub status: ErrorCode,
         pub size: u8,             // A value between 0 and 4
         pub payload: u32,     // Max payload size over SPI is 4 bytes 
     }
@@ -343,12 +1173,26 @@ pub mod slave {
                 state: PhantomData,
                 proc_id: self.proc_id,
                 host_config: self.host_config,
+                status: self.status,
                 size: self.size,       
                 payload: self.payload,
             })
         }
     }
 
+    impl SlaveResponse<Ready> {
+        // Binary response: proc_id, status byte, size, then the payload most
+        // significant byte first. The rest of the buffer is zero
+        pub fn to_spi_frame(&self) -> [u8; SPI_RESPONSE_LEN] {
+            let mut frame = [0_u8; SPI_RESPONSE_LEN];
+            frame[0] = self.proc_id;
+            frame[1] = self.status as u8;
+            frame[2] = self.size;
+            frame[3..7].copy_from_slice(&self.payload.to_be_bytes());
+            frame
+        }
+    }
+
     impl Respond for SlaveResponse<Ready> {
         fn respond_to_host(&self) -> HostErr {
             match self.host_config {
@@ -378,6 +1222,7 @@ pub mod slave {
                 state: PhantomData,
                 proc_id: 0_u8,
                 host_config: ValidHostInterfaces::None,
+                status: ErrorCode::Ok,
                 size: 0_u8,       
                 payload: 0,
             }
@@ -395,6 +1240,10 @@ pub mod slave {
             self.size = size;
         }
 
+        pub fn set_status(&mut self, status: ErrorCode) {
+            self.status = status;
+        }
+
         pub fn set_payload(&mut self, payload: u32) {
             self.payload = payload;
         }
@@ -437,32 +1286,69 @@ fn combine_u8_to_u32(values: &[u8]) -> [u32; 4] {
 }
 
 
-fn encode_smi(read: bool, phy_addr: u8, reg_addr: u8, write_data: u16) -> u32 {
-    let mut packet: u32 = 0;
+// Word for the SMI state machine, shifted out LSB first after the preamble and
+// the first start bit (0): bit 0 second start bit, bits 1-2 opcode, 3-7 PHY or
+// port address, 8-12 register or device address, bit 13 set for a frame that
+// drives 16 data bits from bits 14-29. Without it the PHY answers a read
+fn smi_frame(start: u8, opcode: u8, phy_addr: u8, reg_addr: u8, write_data: Option<u16>) -> u32 {
+    let mut packet = (start & 1) as u32;
+    // Opcode, PHY and register go out MSB first, so they are stored reversed
+    packet |= ((reverse_u8_bits(opcode) >> 6) as u32 & 0b11) << 1;
+    packet |= ((reverse_u8_bits(phy_addr) >> 3) as u32 & 0b11111) << 3;
+    packet |= ((reverse_u8_bits(reg_addr) >> 3) as u32 & 0b11111) << 8;
+    if let Some(data) = write_data {
+        packet |= 1 << 13;
+        packet |= (reverse_u16_bits(data) as u32) << 14;
+    }
+    packet
+}
 
-    
+// Clause 22 frame: start 01, opcode 10 read or 01 write
+pub fn encode_smi(read: bool, phy_addr: u8, reg_addr: u8, write_data: u16) -> u32 {
     if read {
-        packet |= (1 as u32) & 0b11; // The opcodes are reversed on purpose, LSB
+        smi_frame(1, 0b10, phy_addr, reg_addr, None)
     }
     else {
-        // This is reversed, on purpose 
-        packet |= (2 as u32) & 0b11;
+        smi_frame(1, 0b01, phy_addr, reg_addr, Some(write_data))
     }
-    // Set the PHY address (bits 2-6)
-    packet |= (((reverse_u8_bits(phy_addr)>> 3) as u32) & 0b11111) << 2;
+}
 
-    // Set the register address (bits 7-11)
-    packet |= (((reverse_u8_bits(reg_addr) >> 3) as u32) & 0b11111) << 7;
+// Clause 45 opcodes. An access is an address frame with the register address,
+// then the read or write frame it applies to
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Smi45Op {
+    Address = 0b00,
+    Write = 0b01,
+    Read = 0b11,
+}
 
-    if !read {
-        packet |= 1 << 12; // set the 13th bit to 1
-        packet &= !0b111111111111100000000000000000;
+// Clause 45 frame: start 00, then the port address and the MMD (device address)
+// in place of the PHY and register. Reads carry no data, the others 16 bits
+pub fn encode_smi45(op: Smi45Op, port_addr: u8, dev_addr: u8, data: u16) -> u32 {
+    let data = if op == Smi45Op::Read { None } else { Some(data) };
+    smi_frame(0, op as u8, port_addr, dev_addr, data)
+}
 
-        // Shift u16_value left by 14 bits and combine with u32_value
-        packet |= (reverse_u16_bits(write_data) as u32) << 13;
+// The 32 bits a frame word puts on MDIO after the preamble, the first in the
+// MSB. Writes drive both turnaround bits low, then the data. A read drives the
+// first turnaround bit and leaves the rest to the PHY, recorded as the
+// released bus (ones)
+pub fn smi_wire_frame(word: u32) -> [u8; 4] {
+    // The first start bit is always 0, then start, opcode and addresses
+    let mut frame: u32 = 0;
+    for bit in 0..13 {
+        frame = (frame << 1) | ((word >> bit) & 1);
     }
-
-    packet
+    if word & (1 << 13) != 0 {
+        frame <<= 2;
+        for bit in 14..30 {
+            frame = (frame << 1) | ((word >> bit) & 1);
+        }
+    }
+    else {
+        frame = (frame << 18) | 0x1FFFF;
+    }
+    frame.to_be_bytes()
 }
 
 fn reverse_u8_bits(value: u8) -> u8 {
@@ -479,4 +1365,174 @@ fn reverse_u16_bits(value: u16) -> u16 {
         result |= ((value >> i) & 1) << (15 - i);
     }
     result
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The code a text mode line would get, Ok for a line that parses
+    fn parse_code(line: &str) -> ErrorCode {
+        match crate::serial::message_parse_build(line).and_then(|hr| hr.init_clean()) {
+            Ok(_) => ErrorCode::Ok,
+            Err(err) => ErrorCode::from_message(err),
+        }
+    }
+
+    #[test]
+    fn parse_errors_map_to_codes() {
+        assert_eq!(parse_code("cfg retry 1"), ErrorCode::Ok);
+        assert_eq!(parse_code(""), ErrorCode::Ok);
+        assert_eq!(parse_code("foo r 1"), ErrorCode::UnknownInterface);
+        assert_eq!(parse_code("cfg zz 1"), ErrorCode::InvalidOp);
+        assert_eq!(parse_code("cfg retry 9"), ErrorCode::InvalidArguments);
+        assert_eq!(parse_code("cfg retry 1 2"), ErrorCode::InvalidArguments);
+        assert_eq!(ErrorCode::from_message("Interface disabled\n\r"), ErrorCode::UnknownInterface);
+        assert_eq!(ErrorCode::from_message("Sync timed out\n\r"), ErrorCode::HardwareTimeout);
+        assert_eq!(ErrorCode::from_message("I2C transfer failed\n\r"), ErrorCode::HardwareTimeout);
+        assert_eq!(ErrorCode::from(HostErr::Timeout), ErrorCode::HardwareTimeout);
+    }
+
+    #[cfg(not(feature = "smi"))]
+    #[test]
+    fn compiled_out_interface_is_unknown() {
+        assert_eq!(parse_code("smi r 1 16"), ErrorCode::UnknownInterface);
+    }
+
+    #[test]
+    fn codes_are_stable() {
+        for code in 0..=5_u8 {
+            assert_eq!(ErrorCode::try_from(code).unwrap() as u8, code);
+        }
+        assert!(ErrorCode::try_from(6).is_err());
+        assert_eq!(ErrorCode::BadCrc as u8, 0x01);
+        assert_eq!(ErrorCode::HardwareTimeout as u8, 0x04);
+    }
+
+    #[test]
+    fn status_byte_in_the_spi_frame() {
+        let mut sr = SlaveResponse::new();
+        sr.set_proc_id(7);
+        sr.set_host_config(ValidHostInterfaces::SPI);
+        sr.set_status(ErrorCode::HardwareTimeout);
+        sr.set_size(2);
+        sr.set_payload(0x1234_5678);
+        let frame = sr.init_ready().unwrap().to_spi_frame();
+        assert_eq!(frame[..7], [7, 0x04, 2, 0x12, 0x34, 0x56, 0x78]);
+        assert!(frame[7..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn build_line() {
+        let mut buf = [0_u8; 96];
+        let mut out = crate::fmt::Wrapper::new(&mut buf);
+        write_build(&mut out).unwrap();
+        let line = out.as_str();
+        assert!(line.starts_with("\n\rversion ") && line.ends_with("\n\r->"));
+        assert!(line.contains(env!("CARGO_PKG_VERSION")) && line.contains(GIT_HASH) && line.contains(BUILD_TIME));
+    }
+
+    #[test]
+    fn size_bounded_by_the_payload() {
+        let mut hr = host::HostRequest::new();
+        assert_eq!(hr.set_size(PAYLOAD_CAP as u8), Ok(()));
+        assert_eq!(hr.set_size(PAYLOAD_CAP as u8 + 1), Err("Too many data words\n\r"));
+        assert_eq!(hr.set_size(255), Err("Too many data words\n\r"));
+        // A refused size leaves the last good one
+        assert_eq!(hr.size as usize, PAYLOAD_CAP);
+        // spi wr counts the bytes after the read length word
+        assert_eq!(hr.set_byte_count(12), Ok(()));
+        assert!(hr.set_byte_count(13).is_err());
+    }
+
+    #[test]
+    fn paced_steps() {
+        // No gap moves everything in one step
+        let mut steps = [(0, 0); 4];
+        let mut taken = 0;
+        let mut waited = 0;
+        paced::<()>(3, 0, |range| { steps[taken] = (range.start, range.end); taken += 1; Ok(()) }, |us| waited += us).unwrap();
+        assert_eq!((taken, &steps[0], waited), (1, &(0, 3), 0));
+        // A gap moves one byte per step with the wait between bytes only
+        taken = 0;
+        paced::<()>(3, 5, |range| { steps[taken] = (range.start, range.end); taken += 1; Ok(()) }, |us| waited += us).unwrap();
+        assert_eq!((taken, &steps[..3], waited), (3, &[(0, 1), (1, 2), (2, 3)][..], 10));
+        // The first error stops the transfer
+        taken = 0;
+        let failed = paced(3, 5, |range| { taken += 1; if range.start == 1 { Err("bus") } else { Ok(()) } }, |_| ());
+        assert_eq!((failed, taken), (Err("bus"), 2));
+    }
+
+    #[test]
+    fn retry_until_ok() {
+        let mut attempts = 0;
+        let read = retry(3, || { attempts += 1; if attempts < 2 { Err("SMI read timeout\n\r") } else { Ok(0x796D) } });
+        assert_eq!((read, attempts), (Ok(0x796D), 2));
+        // 0 is one attempt only
+        let mut attempts = 0;
+        let read = retry(0, || { attempts += 1; if attempts < 2 { Err("SMI read timeout\n\r") } else { Ok(1) } });
+        assert_eq!((read, attempts), (Err("SMI read timeout\n\r"), 1));
+        // The last error is the one handed back
+        let mut attempts = 0;
+        let read: Result<u16, u32> = retry(2, || { attempts += 1; Err(attempts) });
+        assert_eq!(read, Err(3));
+    }
+
+    #[test]
+    fn push_word_counts_the_size() {
+        let mut hr = host::HostRequest::new();
+        for i in 0..PAYLOAD_CAP {
+            assert_eq!(hr.push_word(0x10 + i as u32), Ok(()));
+            assert_eq!((hr.size as usize, hr.payload[i]), (i + 1, 0x10 + i as u32));
+        }
+        assert_eq!(hr.push_word(1), Err("Too many data words\n\r"));
+        assert_eq!(hr.size as usize, PAYLOAD_CAP);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn answer_goes_to_the_host_that_asked() {
+        let host_of = |line, host| {
+            let mut hr = crate::serial::message_parse_build_default(line, None, host, crate::config::SmiClause::Clause22)
+                .and_then(|hr| hr.init_clean()).unwrap();
+            hr.exchange_for_slave_response().unwrap().host_config
+        };
+        assert_eq!(host_of("smi r 1 2", ValidHostInterfaces::UART), ValidHostInterfaces::UART);
+        assert_eq!(host_of("time smi r 1 2", ValidHostInterfaces::UART), ValidHostInterfaces::UART);
+        assert_eq!(host_of("smi r 1 2", ValidHostInterfaces::Serial), ValidHostInterfaces::Serial);
+    }
+
+    // The state machine word for fields given in the order they go out, each MSB first
+    fn sent_in_order(fields: &[(u32, u32)]) -> u32 {
+        let mut word = 0;
+        let mut bit = 0;
+        for &(value, width) in fields {
+            for i in (0..width).rev() {
+                word |= (value >> i & 1) << bit;
+                bit += 1;
+            }
+        }
+        word
+    }
+
+    #[test]
+    fn smi_frames_on_the_wire() {
+        // Second start bit, opcode, PHY, register, drive flag then data
+        assert_eq!(encode_smi(true, 1, 2, 0), sent_in_order(&[(1, 1), (0b10, 2), (1, 5), (2, 5), (0, 1)]));
+        assert_eq!(encode_smi(false, 3, 4, 0x8001), sent_in_order(&[(1, 1), (0b01, 2), (3, 5), (4, 5), (1, 1), (0x8001, 16)]));
+        // Clause 45: start 00, port 5, MMD 1
+        assert_eq!(encode_smi45(Smi45Op::Address, 5, 1, 7), sent_in_order(&[(0, 1), (0b00, 2), (5, 5), (1, 5), (1, 1), (7, 16)]));
+        assert_eq!(encode_smi45(Smi45Op::Read, 5, 1, 0xFFFF), sent_in_order(&[(0, 1), (0b11, 2), (5, 5), (1, 5), (0, 1)]));
+        assert_eq!(encode_smi45(Smi45Op::Write, 5, 1, 0x1234), sent_in_order(&[(0, 1), (0b01, 2), (5, 5), (1, 5), (1, 1), (0x1234, 16)]));
+    }
+
+    #[test]
+    fn smi_frame_as_seen_on_mdio() {
+        // Read of PHY 1 reg 2: 01 10 00001 00010, one turnaround bit driven low, then released
+        let read = 0b01 << 30 | 0b10 << 28 | 1 << 23 | 2 << 18 | 0x1FFFF;
+        assert_eq!(u32::from_be_bytes(smi_wire_frame(encode_smi(true, 1, 2, 0))), read);
+        // Write of 0x8001 to PHY 3 reg 4: both turnaround bits low, then the data
+        let write = 0b01 << 30 | 0b01 << 28 | 3 << 23 | 4 << 18 | 0x8001;
+        assert_eq!(u32::from_be_bytes(smi_wire_frame(encode_smi(false, 3, 4, 0x8001))), write);
+    }
+}
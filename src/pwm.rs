@@ -0,0 +1,153 @@
+//! Raw access to the RP2040 PWM slices for pins selected at runtime by a HostRequest.
+//! GPIO n is driven by slice (n >> 1) & 7, channel A for even pins and B for odd pins.
+
+use crate::gpio;
+
+const PWM_BASE: u32 = 0x4005_0000;
+const SLICE_STRIDE: u32 = 0x14;
+
+// Slice register offsets
+const CSR: u32 = 0x00;
+const DIV: u32 = 0x04;
+const CTR: u32 = 0x08;
+const CC: u32 = 0x0c;
+const TOP: u32 = 0x10;
+
+const CSR_EN: u32 = 1 << 0;
+// Clock the counter from rising edges on the B input instead of the divider
+const CSR_DIVMODE_RISE: u32 = 2 << 4;
+
+const SLICES: u8 = 8;
+
+// Integer.fraction divider in 1/16 steps, 1.0 up to 255 + 15/16
+const DIV_MIN: u32 = 16;
+const DIV_MAX: u32 = 4095;
+const TOP_MAX: u32 = 0xFFFF;
+
+fn reg(pin: u8, offset: u32) -> *mut u32 {
+    (PWM_BASE + SLICE_STRIDE * ((pin as u32 >> 1) & 7) + offset) as *mut u32
+}
+
+fn is_channel_b(pin: u8) -> bool {
+    pin & 1 == 1
+}
+
+fn read(pin: u8, offset: u32) -> u32 {
+    unsafe { core::ptr::read_volatile(reg(pin, offset)) }
+}
+
+fn write(pin: u8, offset: u32, value: u32) {
+    unsafe { core::ptr::write_volatile(reg(pin, offset), value) }
+}
+
+// Set the compare level of the pin's channel, leaving the other channel alone
+fn set_level(pin: u8, level: u32) {
+    let cc = read(pin, CC);
+    let cc = if is_channel_b(pin) {
+        (cc & 0x0000_FFFF) | (level << 16)
+    } else {
+        (cc & 0xFFFF_0000) | (level & 0xFFFF)
+    };
+    write(pin, CC, cc);
+}
+
+fn duty_level(pin: u8, percent: u32) -> u32 {
+    (read(pin, TOP) + 1) * percent / 100
+}
+
+pub fn is_running(pin: u8) -> bool {
+    read(pin, CSR) & CSR_EN != 0
+}
+
+// Start the slice at the closest frequency the divider and wrap value allow.
+// The slice is shared with the neighbouring pin, which gets the same frequency
+pub fn start(pin: u8, freq: u32, percent: u32, sys_clk_hz: u32) {
+    // Smallest divider that still fits one period in the 16 bit counter
+    let div = ((sys_clk_hz as u64 * 16) / (freq as u64 * (TOP_MAX as u64 + 1)) + 1) as u32;
+    let div = div.clamp(DIV_MIN, DIV_MAX);
+    let top = ((sys_clk_hz as u64 * 16) / (div as u64 * freq as u64)) as u32;
+    let top = top.saturating_sub(1).min(TOP_MAX);
+
+    write(pin, CSR, 0);
+    write(pin, DIV, div);
+    write(pin, TOP, top);
+    set_level(pin, duty_level(pin, percent));
+    gpio::set_function(pin, gpio::FUNCSEL_PWM);
+    write(pin, CSR, CSR_EN);
+}
+
+// Change the duty of a running slice without restarting its counter.
+// Returns false if no PWM was started on the pin's slice
+pub fn set_duty(pin: u8, percent: u32) -> bool {
+    if !is_running(pin) {
+        return false
+    }
+    set_level(pin, duty_level(pin, percent));
+    true
+}
+
+// Edges on an odd pin are counted by its slice, the even pins have no counter input
+pub fn can_count(pin: u8) -> bool {
+    is_channel_b(pin)
+}
+
+// Count rising edges on the B input of the pin's slice for gate_us. The counter
+// is 16 bits, it is polled and the wraps are added up, which holds up to the
+// highest input the slice takes (half the system clock). The slice is stopped
+// and the pin handed back to the SIO as an input afterwards
+pub fn count_edges(pin: u8, gate_us: u32, now_us: impl Fn() -> u32) -> u32 {
+    write(pin, CSR, 0);
+    write(pin, DIV, DIV_MIN);
+    write(pin, TOP, TOP_MAX);
+    write(pin, CTR, 0);
+    gpio::set_function(pin, gpio::FUNCSEL_PWM);
+    let start = now_us();
+    write(pin, CSR, CSR_DIVMODE_RISE | CSR_EN);
+    let (mut edges, mut last) = (0_u32, 0_u32);
+    while now_us().wrapping_sub(start) < gate_us {
+        let ctr = read(pin, CTR);
+        edges += ctr.wrapping_sub(last) & TOP_MAX;
+        last = ctr;
+    }
+    write(pin, CSR, 0);
+    edges += read(pin, CTR).wrapping_sub(last) & TOP_MAX;
+    gpio::set_function_sio(pin);
+    gpio::set_output_enable(pin, false);
+    edges
+}
+
+pub fn frequency_hz(edges: u32, gate_ms: u32) -> u32 {
+    (edges as u64 * 1000 / gate_ms as u64) as u32
+}
+
+// Stop every slice with both channels low, for estop. The pins are
+// released by gpio::release_outputs
+pub fn stop_all() {
+    for slice in 0..SLICES {
+        let pin = 2 * slice;
+        write(pin, CSR, 0);
+        write(pin, CC, 0);
+    }
+}
+
+// Drive the channel low and hand the pin back to the SIO as an input.
+// The slice itself is stopped once neither channel is in use
+pub fn stop(pin: u8) {
+    set_level(pin, 0);
+    gpio::set_function_sio(pin);
+    gpio::set_output_enable(pin, false);
+    if read(pin, CC) == 0 {
+        write(pin, CSR, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counted_on_the_b_channel() {
+        assert_eq!((can_count(7), can_count(6)), (true, false));
+        assert_eq!((frequency_hz(1234, 100), frequency_hz(625_000_000, 10_000)), (12340, 62_500_000));
+    }
+}
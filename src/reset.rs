@@ -0,0 +1,80 @@
+//! Cause of the last reset, for `stat resetreason`.
+//! Read once in init, the registers only ever describe the latest reset.
+
+// VREG_AND_CHIP_RESET CHIP_RESET and WATCHDOG REASON
+const CHIP_RESET: *const u32 = 0x4006_4008 as *const u32;
+const WATCHDOG_REASON: *const u32 = 0x4005_8008 as *const u32;
+
+const CHIP_RESET_HAD_POR: u32 = 1 << 8;
+const CHIP_RESET_HAD_RUN: u32 = 1 << 16;
+const CHIP_RESET_HAD_PSM_RESTART: u32 = 1 << 20;
+const WATCHDOG_REASON_TIMER: u32 = 1 << 0;
+const WATCHDOG_REASON_FORCE: u32 = 1 << 1;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResetReason {
+    PowerOn,
+    RunPin,
+    // Rescue reset through the debug port
+    Debugger,
+    WatchdogTimeout,
+    // Software reset, the watchdog trigger was written
+    WatchdogForce,
+    Unknown,
+}
+
+impl ResetReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power-on",
+            ResetReason::RunPin => "run pin",
+            ResetReason::Debugger => "debugger",
+            ResetReason::WatchdogTimeout => "watchdog timeout",
+            ResetReason::WatchdogForce => "software (watchdog force)",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+}
+
+// A watchdog reset leaves the chip reset bits of the reset before it,
+// so the watchdog reason is checked first
+pub fn decode(chip_reset: u32, watchdog_reason: u32) -> ResetReason {
+    if watchdog_reason & WATCHDOG_REASON_FORCE != 0 {
+        ResetReason::WatchdogForce
+    } else if watchdog_reason & WATCHDOG_REASON_TIMER != 0 {
+        ResetReason::WatchdogTimeout
+    } else if chip_reset & CHIP_RESET_HAD_PSM_RESTART != 0 {
+        ResetReason::Debugger
+    } else if chip_reset & CHIP_RESET_HAD_RUN != 0 {
+        ResetReason::RunPin
+    } else if chip_reset & CHIP_RESET_HAD_POR != 0 {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+pub fn read() -> ResetReason {
+    unsafe { decode(core::ptr::read_volatile(CHIP_RESET), core::ptr::read_volatile(WATCHDOG_REASON)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chip_reset_bits() {
+        assert_eq!(decode(1 << 8, 0), ResetReason::PowerOn);
+        assert_eq!(decode(1 << 16, 0), ResetReason::RunPin);
+        assert_eq!(decode(1 << 20, 0), ResetReason::Debugger);
+        assert_eq!(decode(0, 0), ResetReason::Unknown);
+        assert_eq!(ResetReason::PowerOn.name(), "power-on");
+    }
+
+    #[test]
+    fn watchdog_reason_first() {
+        assert_eq!(decode(1 << 8, 1), ResetReason::WatchdogTimeout);
+        assert_eq!(decode(1 << 8, 2), ResetReason::WatchdogForce);
+        assert_eq!(decode(0, 3), ResetReason::WatchdogForce);
+    }
+}
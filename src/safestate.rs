@@ -0,0 +1,163 @@
+//! Pin levels driven at boot, set by cfg safestate. The table is kept in the
+//! config region of the flash and applied in init as soon as the GPIO banks
+//! are out of reset, so the pins do not float while the rest comes up.
+
+use crate::protocol::GPIO_MAX_PIN;
+use core::fmt;
+
+// Payload level of cfg safestate pin off, takes the pin out of the table
+pub const LEVEL_OFF: u32 = 2;
+
+// Words of the table in the config region
+pub const RECORD_WORDS: usize = 4;
+// First word of a stored table, erased flash reads all ones
+const MAGIC: u32 = 0x5341_4645;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SafeState {
+    // One bit per GPIO that has a safe level, and the level of each
+    pins: u32,
+    levels: u32,
+}
+
+impl SafeState {
+    pub const fn new() -> SafeState {
+        SafeState { pins: 0, levels: 0 }
+    }
+
+    // None takes the pin out of the table
+    pub fn set(&mut self, pin: u8, level: Option<bool>) {
+        let bit = 1 << pin;
+        match level {
+            Some(high) => {
+                self.pins |= bit;
+                if high { self.levels |= bit } else { self.levels &= !bit }
+            }
+            None => {
+                self.pins &= !bit;
+                self.levels &= !bit;
+            }
+        }
+    }
+
+    pub fn level(&self, pin: u8) -> Option<bool> {
+        if self.pins & (1 << pin) == 0 {
+            return None
+        }
+        Some(self.levels & (1 << pin) != 0)
+    }
+
+    // Call drive with the level of every pin in the table, lowest pin first
+    pub fn apply(&self, mut drive: impl FnMut(u8, bool)) {
+        for pin in 0..=GPIO_MAX_PIN as u8 {
+            if let Some(high) = self.level(pin) {
+                drive(pin, high);
+            }
+        }
+    }
+
+    // Checked with the magic and a check word, so a torn write reads as empty
+    pub fn to_record(self) -> [u32; RECORD_WORDS] {
+        [MAGIC, self.pins, self.levels, MAGIC ^ self.pins ^ self.levels]
+    }
+
+    // The table of a record, empty for erased flash or anything else to_record did not write
+    pub fn from_record(record: [u32; RECORD_WORDS]) -> SafeState {
+        let [magic, pins, levels, check] = record;
+        if magic != MAGIC || check != MAGIC ^ pins ^ levels || pins >> (GPIO_MAX_PIN + 1) != 0 {
+            return SafeState::new()
+        }
+        SafeState { pins, levels: levels & pins }
+    }
+
+    // pin:level pairs for cfg show, off for an empty table
+    pub fn write_table(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        if self.pins == 0 {
+            return out.write_str("off")
+        }
+        let mut first = true;
+        let mut result = Ok(());
+        self.apply(|pin, high| {
+            if result.is_ok() {
+                result = write!(out, "{}{}:{}", if first { "" } else { "," }, pin, high as u8);
+                first = false;
+            }
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn set_and_level() {
+        let mut table = SafeState::new();
+        assert_eq!(table.level(7), None);
+        table.set(7, Some(false));
+        assert_eq!(table.level(7), Some(false));
+        table.set(7, Some(true));
+        assert_eq!(table.level(7), Some(true));
+        table.set(7, None);
+        assert_eq!((table.level(7), table), (None, SafeState::new()));
+    }
+
+    #[test]
+    fn applied_lowest_pin_first() {
+        let mut table = SafeState::new();
+        table.set(29, Some(true));
+        table.set(5, Some(true));
+        table.set(2, Some(false));
+        let mut driven = [(0, false); 4];
+        let mut count = 0;
+        table.apply(|pin, high| {
+            driven[count] = (pin, high);
+            count += 1;
+        });
+        assert_eq!(&driven[..count], &[(2, false), (5, true), (29, true)][..]);
+    }
+
+    #[test]
+    fn table_shown_as_pairs() {
+        let mut buf = [0_u8; 16];
+        let mut out = Wrapper::new(&mut buf);
+        SafeState::new().write_table(&mut out).unwrap();
+        assert_eq!(out.as_str(), "off");
+        let mut table = SafeState::new();
+        table.set(7, Some(false));
+        let mut buf = [0_u8; 16];
+        let mut out = Wrapper::new(&mut buf);
+        table.write_table(&mut out).unwrap();
+        assert_eq!(out.as_str(), "7:0");
+        table.set(29, Some(true));
+        let mut buf = [0_u8; 16];
+        let mut out = Wrapper::new(&mut buf);
+        table.write_table(&mut out).unwrap();
+        assert_eq!(out.as_str(), "7:0,29:1");
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut table = SafeState::new();
+        table.set(2, Some(false));
+        table.set(5, Some(true));
+        assert_eq!(SafeState::from_record(table.to_record()), table);
+    }
+
+    #[test]
+    fn bad_records_read_as_empty() {
+        let mut table = SafeState::new();
+        table.set(5, Some(true));
+        // Erased flash
+        assert_eq!(SafeState::from_record([0xFFFF_FFFF; RECORD_WORDS]), SafeState::new());
+        // A torn write
+        let mut torn = table.to_record();
+        torn[2] ^= 1 << 5;
+        assert_eq!(SafeState::from_record(torn), SafeState::new());
+        // A pin past GPIO29, even with a good check word
+        let pins = 1 << 30;
+        assert_eq!(SafeState::from_record([MAGIC, pins, 0, MAGIC ^ pins]), SafeState::new());
+    }
+}
@@ -0,0 +1,134 @@
+//! Built in self test for manufacturing, run by the `selftest` command.
+//! Expects the test fixture: a jumper between SELFTEST_DRIVE_PIN and
+//! SELFTEST_SENSE_PIN and a PHY strapped to SELFTEST_PHY_ADDR on the SMI bus.
+
+use core::fmt::{self, Write};
+use crate::fmt::Wrapper;
+use crate::adc;
+#[cfg(feature = "gpio")]
+use crate::gpio;
+
+pub const SELFTEST_DRIVE_PIN: u8 = 14;
+pub const SELFTEST_SENSE_PIN: u8 = 15;
+pub const SELFTEST_PHY_ADDR: u8 = 1;
+
+// PHY Identifier 1 register (Clause 22)
+const PHY_ID1_REG: u8 = 2;
+const TEMP_SENSOR_CHANNEL: u8 = 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    // The subsystem is compiled out, does not count against the result
+    Skip,
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+pub const SELFTEST_CHECKS: usize = 3;
+
+// Run every check in order. The SMI read is passed in so this module does not
+// need the PIO handles owned by the dispatcher
+pub fn run(smi_read: impl FnOnce(u8, u8) -> Option<u16>) -> [Check; SELFTEST_CHECKS] {
+    [
+        Check { name: "GPIO loopback", outcome: gpio_loopback() },
+        Check { name: "SMI PHY ID", outcome: smi_phy_id(smi_read) },
+        Check { name: "ADC", outcome: adc_sanity() },
+    ]
+}
+
+// The self test passes only if no check failed
+pub fn passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.outcome != Outcome::Fail)
+}
+
+// One line per subsystem followed by the overall result
+pub fn report(out: &mut Wrapper, checks: &[Check]) -> fmt::Result {
+    for check in checks {
+        let outcome = match check.outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        };
+        write!(out, "\n\r{}: {}", check.name, outcome)?;
+    }
+    write!(out, "\n\rSELFTEST {}\n\r->", if passed(checks) { "PASS" } else { "FAIL" })
+}
+
+// Drive the jumpered pin high then low and sense it back
+#[cfg(feature = "gpio")]
+fn gpio_loopback() -> Outcome {
+    gpio::set_function_sio(SELFTEST_DRIVE_PIN);
+    gpio::set_function_sio(SELFTEST_SENSE_PIN);
+    gpio::set_output_enable(SELFTEST_SENSE_PIN, false);
+    gpio::set_output_enable(SELFTEST_DRIVE_PIN, true);
+
+    let mut ok = true;
+    for level in &[true, false] {
+        gpio::set_level(SELFTEST_DRIVE_PIN, *level);
+        // Let the pin settle before sampling
+        cortex_m::asm::delay(1_000);
+        ok &= gpio::read_level(SELFTEST_SENSE_PIN) == *level;
+    }
+    // Leave both pins as inputs
+    gpio::set_output_enable(SELFTEST_DRIVE_PIN, false);
+    if ok { Outcome::Pass } else { Outcome::Fail }
+}
+
+#[cfg(not(feature = "gpio"))]
+fn gpio_loopback() -> Outcome {
+    Outcome::Skip
+}
+
+// A PHY that answers returns something other than an idle (pulled up) or shorted bus
+#[cfg(feature = "smi")]
+fn smi_phy_id(smi_read: impl FnOnce(u8, u8) -> Option<u16>) -> Outcome {
+    match smi_read(SELFTEST_PHY_ADDR, PHY_ID1_REG) {
+        Some(0xFFFF) | Some(0x0000) | None => Outcome::Fail,
+        Some(_) => Outcome::Pass,
+    }
+}
+
+#[cfg(not(feature = "smi"))]
+fn smi_phy_id(_smi_read: impl FnOnce(u8, u8) -> Option<u16>) -> Outcome {
+    Outcome::Skip
+}
+
+// The temperature sensor should never sit on either rail
+fn adc_sanity() -> Outcome {
+    match adc::read_channel(TEMP_SENSOR_CHANNEL) {
+        0 | 0xFFF => Outcome::Fail,
+        _ => Outcome::Pass,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_checks_do_not_fail() {
+        let checks = [
+            Check { name: "GPIO loopback", outcome: Outcome::Pass },
+            Check { name: "SMI PHY ID", outcome: Outcome::Skip },
+        ];
+        assert!(passed(&checks));
+    }
+
+    #[test]
+    fn report_lists_each_check() {
+        let checks = [
+            Check { name: "GPIO loopback", outcome: Outcome::Pass },
+            Check { name: "ADC", outcome: Outcome::Fail },
+        ];
+        assert!(!passed(&checks));
+        let mut buf = [0_u8; 96];
+        let mut out = Wrapper::new(&mut buf);
+        report(&mut out, &checks).unwrap();
+        assert_eq!(out.as_str(), "\n\rGPIO loopback: PASS\n\rADC: FAIL\n\rSELFTEST FAIL\n\r->");
+    }
+}
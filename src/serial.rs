@@ -1,19 +1,94 @@
-use crate::protocol::{ValidHostInterfaces,  host::{self, HostRequest, ValidInterfaces, ValidOps}};
+use crate::protocol::{ValidHostInterfaces, ADDR_OFF, MAX_LINE_MIN, PAYLOAD_CAP, SPI_WR_MAX_WRITE, TEXT_MAX, host::{self, HostRequest, ValidInterfaces, ValidOps}};
+
+use crate::config::{Config, SmiClause};
+use crate::log::Level;
+use crate::mirror;
+use crate::safestate;
+use crate::fmt::{length_prefix, Wrapper, CONTINUATION};
 
 use rp_pico::hal as hal;
 // USB Device support 
-use usb_device::{class_prelude::*};
+use usb_device::{class_prelude::*, device::UsbDevice};
 // USB Communications Class Device support
 use usbd_serial::SerialPort;
 
 use core::{str, u32};
-use core::str::SplitWhitespace;
+use core::fmt::Write as FmtWrite;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// Output write_serial dropped because the USB buffer stayed full, for stat txqueue.
+// Only updated under a serial lock, which no other writer can preempt, so a
+// plain load and store is enough (thumbv6 has no atomic add)
+static TX_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+pub fn tx_dropped() -> u32 {
+    TX_DROPPED.load(Ordering::Relaxed)
+}
+
+fn count_dropped(bytes: usize) {
+    TX_DROPPED.store(tx_dropped().wrapping_add(bytes as u32), Ordering::Relaxed);
+    TX_OVERFLOW.store(true, Ordering::Relaxed);
+}
+
+// Set when output is dropped, cleared once TRUNCATED_MARKER has gone out
+// ahead of the next write. Shown by stat txqueue, updated under the serial
+// lock like TX_DROPPED
+static TX_OVERFLOW: AtomicBool = AtomicBool::new(false);
+
+pub const TRUNCATED_MARKER: &str = "[output truncated]\n\r";
+
+pub fn tx_overflow() -> bool {
+    TX_OVERFLOW.load(Ordering::Relaxed)
+}
+
+// Set from config.framing by the dispatcher, write_serial has no access to the config
+static LENGTH_FRAMING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_length_framing(on: bool) {
+    LENGTH_FRAMING.store(on, Ordering::Relaxed);
+}
+
+// Set by the dispatcher while it handles a `time` request. Every write drops
+// its trailing prompt so the one prompt comes after the timing line, whichever
+// way the handler sent its answer
+static PROMPT_HELD: AtomicBool = AtomicBool::new(false);
+
+pub fn hold_prompt(held: bool) {
+    PROMPT_HELD.store(held, Ordering::Relaxed);
+}
 
+fn without_held_prompt(bytes: &[u8]) -> &[u8] {
+    match PROMPT_HELD.load(Ordering::Relaxed) {
+        true => bytes.strip_suffix(b"->").unwrap_or(bytes),
+        false => bytes,
+    }
+}
 
 // Helper function to ensure all data is written across the serial interface
 #[inline(never)]
 #[link_section = ".data.bar"] // Execute from IRAM
 pub fn write_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &str, block: bool) {
+    write_serial_waiting(serial, buf, block, &mut |_| true);
+}
+
+// Blocking write of an answer longer than the USB buffer (caps, grammar, cfg show,
+// streams). The buffer only drains while the device is polled, which the USB
+// interrupt cannot do while the caller holds the port, so poll while waiting.
+// Gives up on a host that stopped reading like sync_serial, the rest is dropped
+#[inline(never)]
+#[link_section = ".data.bar"] // Execute from IRAM
+pub fn write_serial_polled(usb_dev: &mut UsbDevice<'static, hal::usb::UsbBus>, serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &str) {
+    let mut attempts = 0;
+    write_serial_waiting(serial, buf, true, &mut |serial| {
+        attempts += 1;
+        usb_dev.poll(&mut [&mut *serial]);
+        attempts < SYNC_FLUSH_ATTEMPTS
+    });
+}
+
+// wait is called each time a blocking write finds the buffer full, false drops the rest
+fn write_serial_waiting(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &str, block: bool,
+    wait: &mut dyn FnMut(&mut SerialPort<'static, hal::usb::UsbBus>) -> bool) {
     let write_ptr = buf.as_bytes();
 
     // Because the buffer is of constant size and initialized to zero (0) we 
@@ -23,40 +98,478 @@ pub fn write_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &st
     while index < write_ptr.len() && write_ptr[index] != 0 {
         index += 1;
     }
-    let mut write_ptr = &write_ptr[0..index];
+    let write_ptr = without_held_prompt(&write_ptr[0..index]);
+    // A copy goes to the mirror pin whatever the USB host takes
+    mirror::tee(write_ptr);
+
+    // A frame cut short would throw the host off every frame after it,
+    // so framed output always waits for room
+    let framed = LENGTH_FRAMING.load(Ordering::Relaxed);
+    let block = block || framed;
+    // Tell the host earlier output was lost. The flag is cleared first, if the
+    // buffer is still full the marker is dropped and sets it again
+    if tx_overflow() {
+        TX_OVERFLOW.store(false, Ordering::Relaxed);
+        write_frame(serial, TRUNCATED_MARKER.as_bytes(), framed, block, wait);
+    }
+    write_frame(serial, write_ptr, framed, block, wait);
+    let _ = serial.flush();
+}
+
+fn write_frame(serial: &mut SerialPort<'static, hal::usb::UsbBus>, write_ptr: &[u8], framed: bool, block: bool,
+    wait: &mut dyn FnMut(&mut SerialPort<'static, hal::usb::UsbBus>) -> bool) {
+    if framed {
+        write_all(serial, &length_prefix(write_ptr), block, wait);
+    }
+    write_all(serial, write_ptr, block, wait);
+}
+
+fn write_all(serial: &mut SerialPort<'static, hal::usb::UsbBus>, mut write_ptr: &[u8], block: bool,
+    wait: &mut dyn FnMut(&mut SerialPort<'static, hal::usb::UsbBus>) -> bool) {
 
     while !write_ptr.is_empty() {
         match serial.write(write_ptr) {
             Ok(len) => write_ptr = &write_ptr[len..],
             // Meaning the USB write buffer is full
             Err(UsbError::WouldBlock) => {
-                if !block {
+                if !block || !wait(serial) {
+                    count_dropped(write_ptr.len());
                     break;
                 }
             }
             // On error, just drop unwritten data
-            Err(_) => break,
+            Err(_) => {
+                count_dropped(write_ptr.len());
+                break
+            }
+        }
+    }
+}
+
+// Flush attempts before sync gives up on a host that stopped reading
+const SYNC_FLUSH_ATTEMPTS: u32 = 10_000;
+
+// Drain everything buffered in the serial port out to the host.
+// The IN endpoint is only released by polling the device, and the USB interrupt
+// cannot run while the caller holds the USB resources, so poll while waiting.
+// Returns false if the buffer did not empty in time
+pub fn sync_serial(usb_dev: &mut UsbDevice<'static, hal::usb::UsbBus>, serial: &mut SerialPort<'static, hal::usb::UsbBus>) -> bool {
+    for _ in 0..SYNC_FLUSH_ATTEMPTS {
+        match serial.flush() {
+            Ok(()) => return true,
+            Err(UsbError::WouldBlock) => {
+                usb_dev.poll(&mut [&mut *serial]);
+            }
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+// Length of a command line, the default size of LineEditor and of the
+// uart0 read buffer. Set here, everything else takes it from LineEditor<N>
+pub const LINE_LEN: usize = 64;
+// Number of previous command lines kept for up/down arrow recall
+pub const HISTORY_LEN: usize = 8;
+// Longest history answer: every entry a full line, split as often as the
+// narrowest cfg maxline does, and the prompt
+pub const HISTORY_ANSWER_LEN: usize =
+    HISTORY_LEN * (2 + LINE_LEN + LINE_LEN / (MAX_LINE_MIN as usize - 1) * CONTINUATION.len()) + 4;
+
+// ESC bytes in a row that put the protocol back to interactive text mode.
+// Arrow keys send a single ESC, three only come from a host resyncing
+pub const RESYNC_ESCAPES: u8 = 3;
+
+// Counts consecutive ESC bytes ahead of the line editor and framing
+pub struct Resync {
+    escapes: u8,
+}
+
+impl Resync {
+    pub fn new() -> Resync {
+        Resync { escapes: 0 }
+    }
+
+    // True on the byte that completes the escape sequence
+    pub fn feed(&mut self, byte: u8) -> bool {
+        if byte != 0x1B {
+            self.escapes = 0;
+            return false
+        }
+        self.escapes += 1;
+        if self.escapes == RESYNC_ESCAPES {
+            self.escapes = 0;
+            return true
+        }
+        false
+    }
+}
+
+// What the caller of LineEditor::feed should do with the byte
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Edit {
+    // Byte was added to the line, echo it back
+    Echo,
+    // Return was given, as_str() holds the command to parse
+    Line,
+    // A history entry replaced the line, erase the terminal line and print as_str()
+    Redraw,
+    // Byte was consumed without changing the line (part of an escape sequence)
+    None,
+}
+
+// What the line editor is in the middle of, for stat parser
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ReaderState {
+    // Nothing received since the last line
+    Idle,
+    // Part of a line, waiting for its terminator
+    Accumulating,
+    // Part of a line with a double quote still open
+    InQuote,
+    // Part of an escape sequence (arrow key)
+    Escape,
+}
+
+impl ReaderState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReaderState::Idle => "idle",
+            ReaderState::Accumulating => "accumulating",
+            ReaderState::InQuote => "in-quote",
+            ReaderState::Escape => "escape",
+        }
+    }
+}
+
+// ANSI escape sequence progress, arrow keys arrive as ESC [ A / ESC [ B
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Escape {
+    Idle,
+    Esc,
+    Csi,
+}
+
+// Builds a command line one byte at a time from the serial port and keeps a
+// ring buffer of the last HISTORY_LEN lines for recall with the arrow keys.
+// N is the longest line in bytes, batched commands want more than LINE_LEN
+pub struct LineEditor<const N: usize = LINE_LEN> {
+    buf: [u8; N],
+    len: usize,
+    escape: Escape,
+    history: [[u8; N]; HISTORY_LEN],
+    history_len: [usize; HISTORY_LEN],
+    // Next history slot to be written
+    history_head: usize,
+    history_count: usize,
+    // How far back in history the line currently is, 0 is the line being typed
+    recall: usize,
+    // The last byte ended a line with '\r', so a '\n' right after it is the
+    // second half of a CRLF and not an empty line
+    after_cr: bool,
+}
+
+impl<const N: usize> LineEditor<N> {
+    pub fn new() -> LineEditor<N> {
+        LineEditor {
+            buf: [0_u8; N],
+            len: 0,
+            escape: Escape::Idle,
+            history: [[0_u8; N]; HISTORY_LEN],
+            history_len: [0; HISTORY_LEN],
+            history_head: 0,
+            history_count: 0,
+            recall: 0,
+            after_cr: false,
+        }
+    }
+
+    // Fed every byte of a packet in order, a packet holding several lines
+    // returns Edit::Line once for each of them
+    pub fn feed(&mut self, byte: u8) -> Edit {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match (self.escape, byte) {
+            (Escape::Idle, 0x1B) => {
+                self.escape = Escape::Esc;
+                Edit::None
+            }
+            (Escape::Esc, b'[' | b'O') => {
+                self.escape = Escape::Csi;
+                Edit::None
+            }
+            (Escape::Csi, b'A') => {
+                self.escape = Escape::Idle;
+                self.recall_older()
+            }
+            (Escape::Csi, b'B') => {
+                self.escape = Escape::Idle;
+                self.recall_newer()
+            }
+            (Escape::Esc | Escape::Csi, _) => {
+                // Unsupported escape sequence, drop it
+                self.escape = Escape::Idle;
+                Edit::None
+            }
+            (Escape::Idle, b'\n') if after_cr => Edit::None,
+            // Check if return key was given, if so a command was given.
+            // Scripts end lines with '\n' or "\r\n" instead
+            (Escape::Idle, b'\r' | b'\n') => {
+                self.after_cr = byte == b'\r';
+                self.push_history();
+                Edit::Line
+            }
+            (Escape::Idle, _) => {
+                // Add the byte to the end of the line, building the command
+                if self.len < N {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    // This means buffer is completely full (should not happen)
+                    self.clear();
+                }
+                Edit::Echo
+            }
+        }
+    }
+
+    // The line typed or recalled so far: the command to parse once Edit::Line
+    // is returned, and what to re-echo to the terminal on Edit::Redraw.
+    // Only the bytes received for this line, whatever is left in the buffer past them
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn state(&self) -> ReaderState {
+        if self.escape != Escape::Idle {
+            ReaderState::Escape
+        } else if self.len == 0 {
+            ReaderState::Idle
+        } else if self.buf[..self.len].iter().filter(|byte| **byte == b'"').count() % 2 == 1 {
+            ReaderState::InQuote
+        } else {
+            ReaderState::Accumulating
+        }
+    }
+
+    // Bytes of the line received so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Longest line the editor holds
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // Reset the line after it has been parsed
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.recall = 0;
+    }
+
+    fn push_history(&mut self) {
+        if self.len == 0 {
+            return
+        }
+        self.history[self.history_head] = self.buf;
+        self.history_len[self.history_head] = self.len;
+        self.history_head = (self.history_head + 1) % HISTORY_LEN;
+        if self.history_count < HISTORY_LEN {
+            self.history_count += 1;
+        }
+    }
+
+    // Answer of history: the recall lines oldest first, one per line, so a
+    // session can be pasted into a script
+    pub fn write_history(&self, out: &mut impl FmtWrite) -> core::fmt::Result {
+        if self.history_count == 0 {
+            return write!(out, "\n\rHistory empty\n\r->")
+        }
+        for back in (1..=self.history_count).rev() {
+            let index = (self.history_head + HISTORY_LEN - back) % HISTORY_LEN;
+            write!(out, "\n\r{}", str::from_utf8(&self.history[index][..self.history_len[index]]).unwrap_or(""))?;
+        }
+        write!(out, "\n\r->")
+    }
+
+    // Copy the entry `back` lines behind the newest one (1 = newest) into the line
+    fn load_history(&mut self, back: usize) {
+        let index = (self.history_head + HISTORY_LEN - back) % HISTORY_LEN;
+        self.buf = self.history[index];
+        self.len = self.history_len[index];
+    }
+
+    fn recall_older(&mut self) -> Edit {
+        if self.recall >= self.history_count {
+            return Edit::None
+        }
+        self.recall += 1;
+        self.load_history(self.recall);
+        Edit::Redraw
+    }
+
+    fn recall_newer(&mut self) -> Edit {
+        match self.recall {
+            0 => Edit::None,
+            1 => {
+                // Back to an empty line past the newest entry
+                self.clear();
+                Edit::Redraw
+            }
+            _ => {
+                self.recall -= 1;
+                self.load_history(self.recall);
+                Edit::Redraw
+            }
         }
     }
-    let _ = serial.flush();
 }
 
 // Match the Serial Input commands to a hardware/software request
 #[inline(never)]
 #[link_section = ".data.bar"] // Execute from IRAM
-pub fn match_usb_serial_buf( buf: &[u8; 64],
-    serial: &mut SerialPort<'static, hal::usb::UsbBus> ) 
+// buf holds exactly the received line, without terminator or padding.
+// host_interface is the transport the line came in on, requests built from it carry it
+pub fn match_usb_serial_buf( buf: &str, host_interface: ValidHostInterfaces,
+    serial: &mut SerialPort<'static, hal::usb::UsbBus>, config: &Config, smi_regs: &mut SmiRegCache )
     -> Result<HostRequest<host::Unclean>, &'static str> {
-    let buf = str::from_utf8(buf).unwrap();
-    write_serial(serial, "\n\r", false);
+    // Answered before anything else touches the line, for round trip timing
+    if let Some(payload) = ping_payload(buf) {
+        // A LINE_LEN payload and "pong" around it, a longer editor line is refused
+        // rather than answered with a pong cut short
+        let mut reply = [0_u8; LINE_LEN + 16];
+        let mut out = Wrapper::new(&mut reply);
+        write_pong(&mut out, payload).map_err(|_| "Ping payload too long\n\r")?;
+        write_serial(serial, out.as_str(), false);
+        return Err("Ok")
+    }
+    if config.auto_newline {
+        write_serial(serial, "\n\r", false);
+    }
 
     if slice_contains(buf, "menu") {
         print_menu(serial);
         Err("Ok")
     }
     else {
-        write_serial(serial, "\n\r", false);
-        message_parse_build(buf)
+        if config.auto_newline {
+            write_serial(serial, "\n\r", false);
+        }
+        let mut hr = unknown_line(message_parse_build_default(buf, config.default_interface, host_interface, config.smi_clause), config.strict)?;
+        smi_regs.fill(&mut hr)?;
+        Ok(hr)
+    }
+}
+
+// Under cfg strict off a line that does not parse is dropped without an
+// answer, as a handled simple command. Lines that parse but fail validation
+// in init_clean are still errors
+pub fn unknown_line(parsed: Result<HostRequest<host::Unclean>, &'static str>, strict: bool)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    match parsed {
+        Err(_) if !strict => Err("Ok"),
+        parsed => parsed,
+    }
+}
+
+// Multi-drop addressing, set by cfg addr: a line is for this bridge when it
+// starts with >N for its address or >* for every bridge. Returns the rest of
+// the line to parse, None for a line to drop without an answer. With addressing
+// off every line is taken, a prefix is stripped if it has one
+pub fn addressed_line(line: &str, address: Option<u8>) -> Option<&str> {
+    let target = line.trim_start().strip_prefix('>').map(|rest| {
+        let (target, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        let matched = target == "*" || match (bytes_to_number(target), address) {
+            (Ok(n), Some(address)) => n == address as u32,
+            (Ok(_), None) => true,
+            (Err(_), _) => false,
+        };
+        (matched, rest.trim_start())
+    });
+    match (target, address) {
+        (Some((true, rest)), _) => Some(rest),
+        (_, Some(_)) => None,
+        // Not a valid prefix, left for the parser to refuse
+        (_, None) => Some(line),
+    }
+}
+
+// The rest of a ping line, None for any other line
+pub fn ping_payload(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let rest = line.strip_prefix("ping").or_else(|| line.strip_prefix("PING"))?;
+    if rest.is_empty() || rest.starts_with(' ') {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+// "pong", then the payload of the ping if it had one
+pub fn write_pong(out: &mut impl FmtWrite, payload: &str) -> core::fmt::Result {
+    write!(out, "\n\rpong")?;
+    if !payload.is_empty() {
+        write!(out, " {}", payload)?;
+    }
+    write!(out, "\n\r->")
+}
+
+// Last register address read from each PHY, so smi r phyAddr can leave it out
+pub struct SmiRegCache {
+    regs: [Option<u32>; 32],
+}
+
+impl SmiRegCache {
+    pub fn new() -> SmiRegCache {
+        SmiRegCache { regs: [None; 32] }
+    }
+
+    // Complete a smi r that only names the PHY, or remember the register of a full one.
+    // Out of range PHY addresses are left for init_clean to reject
+    pub fn fill(&mut self, hr: &mut HostRequest<host::Unclean>) -> Result<(), &'static str> {
+        // The cache holds Clause 22 registers
+        if hr.interface != ValidInterfaces::SMI || hr.operation != ValidOps::Read || hr.clause45 {
+            return Ok(())
+        }
+        let reg = match self.regs.get_mut(hr.payload[0] as usize) {
+            Some(reg) => reg,
+            None => return Ok(()),
+        };
+        match hr.size {
+            1 => {
+                hr.push_word(reg.ok_or("No register address cached for this PHY\n\r")?)?;
+            }
+            2 => *reg = Some(hr.payload[1]),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// The last command typed on the USB console, an empty line runs it again
+pub struct LastCommand {
+    hr: Option<HostRequest<host::Unclean>>,
+}
+
+impl LastCommand {
+    pub fn new() -> LastCommand {
+        LastCommand { hr: None }
+    }
+
+    // Repeat the last command for an empty line, otherwise parse and remember it.
+    // Streams and the batch controls (begin, end, pause, resume, estop) are not
+    // kept, repeating them would hold the dispatcher or change a batch unasked
+    pub fn line(&mut self, line: &str, parse: impl FnOnce(&str) -> Result<HostRequest<host::Unclean>, &'static str>)
+        -> Result<HostRequest<host::Unclean>, &'static str> {
+        if line.trim().is_empty() {
+            return self.hr.clone().ok_or(EMPTY_COMMAND)
+        }
+        let hr = parse(line)?;
+        if !matches!(hr.operation, ValidOps::Stream | ValidOps::BatchBegin | ValidOps::BatchEnd
+            | ValidOps::Pause | ValidOps::Resume | ValidOps::Estop) {
+            self.hr = Some(hr.clone());
+        }
+        Ok(hr)
     }
 }
 
@@ -68,10 +581,96 @@ pub fn print_menu(serial: &mut SerialPort<'static, hal::usb::UsbBus>){
 *  Send system or device interface commands\n\r
 *  Menu:\n\r
 *  M / m - Print menu\n\r
-*    - smi r phyAddr RegAddr\n\r
+*    - smi r phyAddr [RegAddr]\n\r
 *    - smi w phyAddr RegAddr Data\n\r
-*    - smi setclk frequency\n\r
+*    - smi smiset frequency\n\r
+*    - smi set|clr phyAddr RegAddr mask\n\r
+*    - smi rpage phyAddr page RegAddr\n\r
+*    - smi wpage phyAddr page RegAddr data\n\r
+*    - smi reset resetPin holdMs\n\r
+*    - smi pins mdc mdio\n\r
+*    - smi scanmap\n\r
+*    - smi link phyAddr\n\r
+*    - smi whois phyAddr\n\r
+*    - smi r phyAddr regAddr fields\n\r
+*    - smi preamble bits (0-32)\n\r
+*    - smi clause 22|45\n\r
+*    - smi r|w port devAddr reg [data] (clause 45)\n\r
+*    - smi last\n\r
 *    - gpio set level\n\r 
+*    - gpio drive pin mA (2/4/8/12)\n\r
+*    - gpio slew pin fast|slow\n\r
+*    - gpio schmitt pin on|off\n\r
+*    - gpio od pin high|low\n\r
+*    - gpio istat pin\n\r
+*    - gpio iclear pin\n\r
+*    - gpio pwm pin freq duty%\n\r
+*    - gpio pwm pin duty duty%\n\r
+*    - gpio pwm pin stop\n\r
+*    - gpio clock pin freq|off\n\r
+*    - gpio strobe pin high_us low_us cycles\n\r
+*    - gpio measure pin timeout_ms\n\r
+*    - gpio conn drivePin sensePin\n\r
+*    - gpio threshold pin [mV]\n\r
+*    - gpio freq pin gate_ms\n\r
+*    - spi w word (up to 3)\n\r
+*    - spi last\n\r
+*    - spi flashid\n\r
+*    - spi r words (1-4)\n\r
+*    - spi wr byte ... : read_len\n\r
+*    - spi wstr text\n\r
+*    - spi xferx hexbytes\n\r
+*    - uart wstr text\n\r
+*    - i2c scan\n\r
+*    - i2c dump addr startReg count\n\r
+*    - i2c speed|cfg hz (100000/400000/1000000)\n\r
+*    - led set pin r g b\n\r
+*    - lcd init cols rows\n\r
+*    - lcd clear\n\r
+*    - lcd write row col text\n\r
+*    - jtag mem address\n\r
+*    - adc scan ch0 ch1 ...\n\r
+*    - adc stream ch rateHz count\n\r
+*    - time command ...\n\r
+*    - selftest\n\r
+*    - stat usb\n\r
+*    - stat resetreason\n\r
+*    - stat txqueue\n\r
+*    - stat tasks\n\r
+*    - stat parser\n\r
+*    - sync\n\r
+*    - begin ... end\n\r
+*    - pause ... resume\n\r
+*    - history\n\r
+*    - ping [payload]\n\r
+*    - estop\n\r
+*    - cfg caps\n\r
+*    - cfg grammar\n\r
+*    - cfg show\n\r
+*    - cfg limits\n\r
+*    - cfg flash\n\r
+*    - cfg framing line|length\n\r
+*    - cfg iface interface|off\n\r
+*    - cfg ratelimit hz\n\r
+*    - cfg endian little|big\n\r
+*    - cfg led on|off\n\r
+*    - cfg strict on|off\n\r
+*    - cfg timestamp on|off\n\r
+*    - cfg safestate pin high|low|off (saved to flash, USB stalls for tens of ms after the prompt)\n\r
+*    - cfg addr n (0-254)|off\n\r
+*    - cfg retry count (0-5)\n\r
+*    - cfg slowdown us (0-1000)\n\r
+*    - cfg autonl on|off\n\r
+*    - cfg clocks\n\r
+*    - cfg build\n\r
+*    - cfg enable|disable interface\n\r
+*    - cfg channel control|data\n\r
+*    - cfg blockwrite on|off\n\r
+*    - cfg usbpoll count (0-16)\n\r
+*    - cfg loglevel error|warn|info|debug\n\r
+*    - cfg usbserial get|set serial\n\r
+*    - cfg mirror on pin|off\n\r
+*    - cfg maxline width (0, 16-255)\n\r
 *****************\n\r
 Enter option: ";
 
@@ -91,120 +690,2317 @@ pub fn slice_contains(haystack: &str, needle: &str) -> bool {
     false
 }
 
-// Helper function that takes list of bytes and deconstructs
-// into HostRequest fields. 
-// NOTE: Preliminary behavior is to drop message and log to serial an invalid message
-// if fields are missing or invalid
-#[inline(never)]
-#[link_section = ".data.bar"] // Execute from IRAM
-pub fn message_parse_build<'input>(input: &'input str) 
-    -> Result<HostRequest<host::Unclean>, &'static str>{
-    let mut payload = [0u32; 4];
+// Keyword tables of the command grammar, shared by the parser and `cfg caps`.
+// Every keyword is accepted in lower or upper case
+pub struct Keyword<T: 'static> {
+    pub lower: &'static str,
+    pub upper: &'static str,
+    pub value: T,
+}
 
-    // Split up the given string
-    let mut hr = HostRequest::new();
-    hr.set_host_config(ValidHostInterfaces::Serial);
+impl<T: Copy> Keyword<T> {
+    fn lookup(table: &[Keyword<T>], word: &str) -> Option<T> {
+        table.iter()
+            .find(|keyword| word == keyword.lower || word == keyword.upper)
+            .map(|keyword| keyword.value)
+    }
+}
 
-    let words = |input: &'input str| -> SplitWhitespace<'input>  {input.split_whitespace()};
-    let mut command = words(input);
-    let command_count = command.clone().count();
-    if command_count > 6 {
-        return Err("Too many arguments\n\r")
+const fn same_word(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false
     }
-    // Match on the first word
-    match command.next() {
-        Some("smi" | "SMI") => {
-            hr.set_interface(ValidInterfaces::SMI);
-        }
-        Some("cfg" | "CFG") => {
-            hr.set_interface(ValidInterfaces::Config);
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false
         }
-        Some("gpio" | "GPIO") => {
-            hr.set_interface(ValidInterfaces::GPIO);
+        i += 1;
+    }
+    true
+}
+
+const fn in_table<T>(word: &str, table: &[Keyword<T>]) -> bool {
+    let mut i = 0;
+    while i < table.len() {
+        if same_word(word, table[i].lower) || same_word(word, table[i].upper) {
+            return true
         }
-        Some("jtag" | "JTAG") => {
-            hr.set_interface(ValidInterfaces::JTAG);
+        i += 1;
+    }
+    false
+}
+
+// A word names an interface, an operation or a system command, never two of them.
+// Otherwise the first word of a line under cfg iface could be read either way
+pub const fn tables_collide<A, B>(a: &[Keyword<A>], b: &[Keyword<B>]) -> bool {
+    let mut i = 0;
+    while i < a.len() {
+        if in_table(a[i].lower, b) || in_table(a[i].upper, b) {
+            return true
         }
-        Some("spi" | "SPI") => {
-            hr.set_interface(ValidInterfaces::SPI);
+        i += 1;
+    }
+    false
+}
+
+pub const fn interfaces_collide<T>(interfaces: &[InterfaceKeyword], table: &[Keyword<T>]) -> bool {
+    let mut i = 0;
+    while i < interfaces.len() {
+        if in_table(interfaces[i].keyword.lower, table) || in_table(interfaces[i].keyword.upper, table) {
+            return true
         }
-        _ => {
-            return Err("Invalid Interface\n\r")
+        i += 1;
+    }
+    false
+}
+
+// Operation words that are also interface keywords, only recognized after
+// their own interface so they stay out of OP_KEYWORDS
+pub struct OpAlias {
+    pub interface: ValidInterfaces,
+    pub keyword: Keyword<ValidOps>,
+}
+
+pub const OP_ALIASES: [OpAlias; 2] = [
+    // Another name for i2c speed
+    OpAlias { interface: ValidInterfaces::I2C, keyword: Keyword { lower: "cfg", upper: "CFG", value: ValidOps::Speed } },
+    OpAlias { interface: ValidInterfaces::Config, keyword: Keyword { lower: "led", upper: "LED", value: ValidOps::Indicator } },
+];
+
+// Checked when the tables are built, a new keyword that collides fails the build
+const _: () = assert!(!interfaces_collide(&INTERFACE_KEYWORDS, &OP_KEYWORDS), "interface and operation keywords overlap");
+const _: () = assert!(!interfaces_collide(&INTERFACE_KEYWORDS, &SYSTEM_KEYWORDS), "interface and system keywords overlap");
+const _: () = assert!(!tables_collide(&SYSTEM_KEYWORDS, &OP_KEYWORDS), "system and operation keywords overlap");
+
+pub struct InterfaceKeyword {
+    pub keyword: Keyword<ValidInterfaces>,
+    // Operations the interface answers to
+    pub ops: &'static [ValidOps],
+}
+
+// First word commands that need no interface, handled by the firmware itself
+pub const SYSTEM_KEYWORDS: [Keyword<ValidOps>; 8] = [
+    // Runs the built in self test sequence
+    Keyword { lower: "selftest", upper: "SELFTEST", value: ValidOps::SelfTest },
+    // Queued behind every pending request, so its reply marks the end of their output
+    Keyword { lower: "sync", upper: "SYNC", value: ValidOps::Sync },
+    // Queue the following lines until end, then run them in order (crate::batch)
+    Keyword { lower: "begin", upper: "BEGIN", value: ValidOps::BatchBegin },
+    Keyword { lower: "end", upper: "END", value: ValidOps::BatchEnd },
+    // Hold the following lines back until resume (crate::batch as well)
+    Keyword { lower: "pause", upper: "PAUSE", value: ValidOps::Pause },
+    Keyword { lower: "resume", upper: "RESUME", value: ValidOps::Resume },
+    // Releases every driven pin and stops PWM, also drops any batch
+    Keyword { lower: "estop", upper: "ESTOP", value: ValidOps::Estop },
+    // Prints the arrow key recall lines, oldest first
+    Keyword { lower: "history", upper: "HISTORY", value: ValidOps::History },
+];
+
+pub const INTERFACE_KEYWORDS: [InterfaceKeyword; 11] = [
+    InterfaceKeyword {
+        keyword: Keyword { lower: "smi", upper: "SMI", value: ValidInterfaces::SMI },
+        ops: &[ValidOps::Read, ValidOps::Write, ValidOps::SmiSet, ValidOps::Preamble, ValidOps::Set, ValidOps::Clr,
+            ValidOps::ReadPage, ValidOps::WritePage, ValidOps::Reset, ValidOps::Pins,
+            ValidOps::ScanMap, ValidOps::Link, ValidOps::Whois, ValidOps::Clause,
+            ValidOps::Last],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "cfg", upper: "CFG", value: ValidInterfaces::Config },
+        ops: &[ValidOps::Caps, ValidOps::Grammar, ValidOps::Show, ValidOps::Limits, ValidOps::Flash, ValidOps::AutoNewline, ValidOps::Clocks, ValidOps::Build,
+            ValidOps::Enable, ValidOps::Disable, ValidOps::Channel, ValidOps::BlockWrite,
+            ValidOps::UsbPoll, ValidOps::LogLevel, ValidOps::UsbSerial, ValidOps::Mirror,
+            ValidOps::MaxLine, ValidOps::Framing, ValidOps::DefaultInterface,
+            ValidOps::RateLimit, ValidOps::Endian, ValidOps::Indicator, ValidOps::Retry, ValidOps::Slowdown, ValidOps::Strict, ValidOps::Timestamp,
+            ValidOps::SafeState, ValidOps::Address],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "gpio", upper: "GPIO", value: ValidInterfaces::GPIO },
+        ops: &[ValidOps::Write, ValidOps::Drive, ValidOps::Slew, ValidOps::OpenDrain, ValidOps::Pwm,
+            ValidOps::IntStatus, ValidOps::IntClear, ValidOps::Schmitt, ValidOps::Clock,
+            ValidOps::Strobe, ValidOps::Measure, ValidOps::Conn, ValidOps::Threshold,
+            ValidOps::Freq],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "jtag", upper: "JTAG", value: ValidInterfaces::JTAG },
+        ops: &[ValidOps::Read, ValidOps::Write, ValidOps::Mem],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "spi", upper: "SPI", value: ValidInterfaces::SPI },
+        ops: &[ValidOps::Read, ValidOps::Write, ValidOps::WriteRead, ValidOps::WriteStr, ValidOps::XferHex, ValidOps::Last,
+            ValidOps::FlashId],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "i2c", upper: "I2C", value: ValidInterfaces::I2C },
+        ops: &[ValidOps::Read, ValidOps::Write, ValidOps::Scan, ValidOps::Dump, ValidOps::Speed],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "adc", upper: "ADC", value: ValidInterfaces::ADC },
+        ops: &[ValidOps::Scan, ValidOps::Stream],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "stat", upper: "STAT", value: ValidInterfaces::Stat },
+        ops: &[ValidOps::Usb, ValidOps::ResetReason, ValidOps::TxQueue, ValidOps::Tasks, ValidOps::Parser],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "uart", upper: "UART", value: ValidInterfaces::UART },
+        ops: &[ValidOps::WriteStr],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "led", upper: "LED", value: ValidInterfaces::LED },
+        ops: &[ValidOps::Set],
+    },
+    InterfaceKeyword {
+        keyword: Keyword { lower: "lcd", upper: "LCD", value: ValidInterfaces::LCD },
+        ops: &[ValidOps::Init, ValidOps::Clear, ValidOps::WriteAt],
+    },
+];
+
+pub const OP_KEYWORDS: [Keyword<ValidOps>; 72] = [
+    Keyword { lower: "r", upper: "R", value: ValidOps::Read },
+    Keyword { lower: "w", upper: "W", value: ValidOps::Write },
+    Keyword { lower: "smiset", upper: "SMISET", value: ValidOps::SmiSet },
+    Keyword { lower: "drive", upper: "DRIVE", value: ValidOps::Drive },
+    Keyword { lower: "slew", upper: "SLEW", value: ValidOps::Slew },
+    Keyword { lower: "scan", upper: "SCAN", value: ValidOps::Scan },
+    Keyword { lower: "stream", upper: "STREAM", value: ValidOps::Stream },
+    Keyword { lower: "pwm", upper: "PWM", value: ValidOps::Pwm },
+    Keyword { lower: "od", upper: "OD", value: ValidOps::OpenDrain },
+    Keyword { lower: "wr", upper: "WR", value: ValidOps::WriteRead },
+    Keyword { lower: "usb", upper: "USB", value: ValidOps::Usb },
+    Keyword { lower: "caps", upper: "CAPS", value: ValidOps::Caps },
+    Keyword { lower: "wstr", upper: "WSTR", value: ValidOps::WriteStr },
+    Keyword { lower: "autonl", upper: "AUTONL", value: ValidOps::AutoNewline },
+    Keyword { lower: "istat", upper: "ISTAT", value: ValidOps::IntStatus },
+    Keyword { lower: "iclear", upper: "ICLEAR", value: ValidOps::IntClear },
+    Keyword { lower: "preamble", upper: "PREAMBLE", value: ValidOps::Preamble },
+    Keyword { lower: "schmitt", upper: "SCHMITT", value: ValidOps::Schmitt },
+    Keyword { lower: "set", upper: "SET", value: ValidOps::Set },
+    Keyword { lower: "clocks", upper: "CLOCKS", value: ValidOps::Clocks },
+    Keyword { lower: "build", upper: "BUILD", value: ValidOps::Build },
+    Keyword { lower: "enable", upper: "ENABLE", value: ValidOps::Enable },
+    Keyword { lower: "disable", upper: "DISABLE", value: ValidOps::Disable },
+    Keyword { lower: "channel", upper: "CHANNEL", value: ValidOps::Channel },
+    Keyword { lower: "mem", upper: "MEM", value: ValidOps::Mem },
+    Keyword { lower: "blockwrite", upper: "BLOCKWRITE", value: ValidOps::BlockWrite },
+    Keyword { lower: "usbpoll", upper: "USBPOLL", value: ValidOps::UsbPoll },
+    Keyword { lower: "loglevel", upper: "LOGLEVEL", value: ValidOps::LogLevel },
+    Keyword { lower: "clr", upper: "CLR", value: ValidOps::Clr },
+    Keyword { lower: "usbserial", upper: "USBSERIAL", value: ValidOps::UsbSerial },
+    Keyword { lower: "mirror", upper: "MIRROR", value: ValidOps::Mirror },
+    Keyword { lower: "resetreason", upper: "RESETREASON", value: ValidOps::ResetReason },
+    Keyword { lower: "txqueue", upper: "TXQUEUE", value: ValidOps::TxQueue },
+    Keyword { lower: "maxline", upper: "MAXLINE", value: ValidOps::MaxLine },
+    Keyword { lower: "xferx", upper: "XFERX", value: ValidOps::XferHex },
+    Keyword { lower: "clock", upper: "CLOCK", value: ValidOps::Clock },
+    Keyword { lower: "grammar", upper: "GRAMMAR", value: ValidOps::Grammar },
+    Keyword { lower: "strobe", upper: "STROBE", value: ValidOps::Strobe },
+    Keyword { lower: "measure", upper: "MEASURE", value: ValidOps::Measure },
+    Keyword { lower: "show", upper: "SHOW", value: ValidOps::Show },
+    Keyword { lower: "limits", upper: "LIMITS", value: ValidOps::Limits },
+    Keyword { lower: "rpage", upper: "RPAGE", value: ValidOps::ReadPage },
+    Keyword { lower: "wpage", upper: "WPAGE", value: ValidOps::WritePage },
+    Keyword { lower: "framing", upper: "FRAMING", value: ValidOps::Framing },
+    Keyword { lower: "conn", upper: "CONN", value: ValidOps::Conn },
+    Keyword { lower: "tasks", upper: "TASKS", value: ValidOps::Tasks },
+    Keyword { lower: "reset", upper: "RESET", value: ValidOps::Reset },
+    Keyword { lower: "iface", upper: "IFACE", value: ValidOps::DefaultInterface },
+    Keyword { lower: "ratelimit", upper: "RATELIMIT", value: ValidOps::RateLimit },
+    Keyword { lower: "flash", upper: "FLASH", value: ValidOps::Flash },
+    Keyword { lower: "pins", upper: "PINS", value: ValidOps::Pins },
+    Keyword { lower: "scanmap", upper: "SCANMAP", value: ValidOps::ScanMap },
+    Keyword { lower: "parser", upper: "PARSER", value: ValidOps::Parser },
+    Keyword { lower: "endian", upper: "ENDIAN", value: ValidOps::Endian },
+    Keyword { lower: "dump", upper: "DUMP", value: ValidOps::Dump },
+    Keyword { lower: "speed", upper: "SPEED", value: ValidOps::Speed },
+    Keyword { lower: "link", upper: "LINK", value: ValidOps::Link },
+    Keyword { lower: "whois", upper: "WHOIS", value: ValidOps::Whois },
+    Keyword { lower: "retry", upper: "RETRY", value: ValidOps::Retry },
+    Keyword { lower: "slowdown", upper: "SLOWDOWN", value: ValidOps::Slowdown },
+    Keyword { lower: "strict", upper: "STRICT", value: ValidOps::Strict },
+    Keyword { lower: "threshold", upper: "THRESHOLD", value: ValidOps::Threshold },
+    Keyword { lower: "freq", upper: "FREQ", value: ValidOps::Freq },
+    Keyword { lower: "clause", upper: "CLAUSE", value: ValidOps::Clause },
+    Keyword { lower: "last", upper: "LAST", value: ValidOps::Last },
+    Keyword { lower: "safestate", upper: "SAFESTATE", value: ValidOps::SafeState },
+    Keyword { lower: "flashid", upper: "FLASHID", value: ValidOps::FlashId },
+    Keyword { lower: "addr", upper: "ADDR", value: ValidOps::Address },
+    Keyword { lower: "timestamp", upper: "TIMESTAMP", value: ValidOps::Timestamp },
+    Keyword { lower: "init", upper: "INIT", value: ValidOps::Init },
+    Keyword { lower: "clear", upper: "CLEAR", value: ValidOps::Clear },
+    Keyword { lower: "write", upper: "WRITE", value: ValidOps::WriteAt },
+];
+
+// Data words taken by each operation, min..=max. Read, write, set and scan vary by interface,
+// the range covers the widest and init_clean checks the exact count.
+// wstr, wr, xferx and usbserial have their own parsers and are not listed
+pub struct Arity {
+    pub op: ValidOps,
+    pub min: u8,
+    pub max: u8,
+}
+
+pub const OP_ARITY: [Arity; 70] = [
+    Arity { op: ValidOps::Read, min: 1, max: 2 },
+    Arity { op: ValidOps::Write, min: 1, max: 3 },
+    Arity { op: ValidOps::SmiSet, min: 1, max: 1 },
+    Arity { op: ValidOps::Preamble, min: 1, max: 1 },
+    Arity { op: ValidOps::Drive, min: 2, max: 2 },
+    Arity { op: ValidOps::Slew, min: 2, max: 2 },
+    Arity { op: ValidOps::Schmitt, min: 2, max: 2 },
+    Arity { op: ValidOps::OpenDrain, min: 2, max: 2 },
+    Arity { op: ValidOps::IntStatus, min: 1, max: 1 },
+    Arity { op: ValidOps::IntClear, min: 1, max: 1 },
+    Arity { op: ValidOps::Pwm, min: 3, max: 3 },
+    Arity { op: ValidOps::PwmStop, min: 1, max: 1 },
+    Arity { op: ValidOps::PwmDuty, min: 2, max: 2 },
+    Arity { op: ValidOps::Clock, min: 2, max: 2 },
+    Arity { op: ValidOps::Strobe, min: 4, max: 4 },
+    Arity { op: ValidOps::Measure, min: 2, max: 2 },
+    Arity { op: ValidOps::Conn, min: 2, max: 2 },
+    Arity { op: ValidOps::Scan, min: 0, max: 4 },
+    Arity { op: ValidOps::Stream, min: 3, max: 3 },
+    Arity { op: ValidOps::Usb, min: 0, max: 0 },
+    Arity { op: ValidOps::ResetReason, min: 0, max: 0 },
+    Arity { op: ValidOps::TxQueue, min: 0, max: 0 },
+    Arity { op: ValidOps::Tasks, min: 0, max: 0 },
+    Arity { op: ValidOps::Parser, min: 0, max: 0 },
+    Arity { op: ValidOps::Endian, min: 1, max: 1 },
+    Arity { op: ValidOps::Dump, min: 3, max: 3 },
+    Arity { op: ValidOps::Speed, min: 1, max: 1 },
+    Arity { op: ValidOps::Indicator, min: 1, max: 1 },
+    Arity { op: ValidOps::Link, min: 1, max: 1 },
+    Arity { op: ValidOps::Whois, min: 1, max: 1 },
+    Arity { op: ValidOps::Retry, min: 1, max: 1 },
+    Arity { op: ValidOps::Slowdown, min: 1, max: 1 },
+    Arity { op: ValidOps::Strict, min: 1, max: 1 },
+    Arity { op: ValidOps::Threshold, min: 1, max: 2 },
+    Arity { op: ValidOps::Freq, min: 2, max: 2 },
+    Arity { op: ValidOps::Clause, min: 1, max: 1 },
+    Arity { op: ValidOps::Last, min: 0, max: 0 },
+    Arity { op: ValidOps::SafeState, min: 2, max: 2 },
+    Arity { op: ValidOps::FlashId, min: 0, max: 0 },
+    Arity { op: ValidOps::Address, min: 1, max: 1 },
+    Arity { op: ValidOps::Timestamp, min: 1, max: 1 },
+    Arity { op: ValidOps::Init, min: 2, max: 2 },
+    Arity { op: ValidOps::Clear, min: 0, max: 0 },
+    Arity { op: ValidOps::Caps, min: 0, max: 0 },
+    Arity { op: ValidOps::Grammar, min: 0, max: 0 },
+    Arity { op: ValidOps::Show, min: 0, max: 0 },
+    Arity { op: ValidOps::Limits, min: 0, max: 0 },
+    Arity { op: ValidOps::Flash, min: 0, max: 0 },
+    Arity { op: ValidOps::AutoNewline, min: 1, max: 1 },
+    Arity { op: ValidOps::Set, min: 3, max: 4 },
+    Arity { op: ValidOps::Clr, min: 3, max: 3 },
+    Arity { op: ValidOps::ReadPage, min: 3, max: 3 },
+    Arity { op: ValidOps::WritePage, min: 4, max: 4 },
+    Arity { op: ValidOps::Reset, min: 2, max: 2 },
+    Arity { op: ValidOps::Pins, min: 2, max: 2 },
+    Arity { op: ValidOps::ScanMap, min: 0, max: 0 },
+    Arity { op: ValidOps::Clocks, min: 0, max: 0 },
+    Arity { op: ValidOps::Build, min: 0, max: 0 },
+    Arity { op: ValidOps::Enable, min: 1, max: 1 },
+    Arity { op: ValidOps::Disable, min: 1, max: 1 },
+    Arity { op: ValidOps::Channel, min: 1, max: 1 },
+    Arity { op: ValidOps::Mem, min: 1, max: 1 },
+    Arity { op: ValidOps::BlockWrite, min: 1, max: 1 },
+    Arity { op: ValidOps::UsbPoll, min: 1, max: 1 },
+    Arity { op: ValidOps::LogLevel, min: 1, max: 1 },
+    Arity { op: ValidOps::Mirror, min: 1, max: 2 },
+    Arity { op: ValidOps::MaxLine, min: 1, max: 1 },
+    Arity { op: ValidOps::Framing, min: 1, max: 1 },
+    Arity { op: ValidOps::DefaultInterface, min: 1, max: 1 },
+    Arity { op: ValidOps::RateLimit, min: 1, max: 1 },
+];
+
+// An operation missing from OP_ARITY may fill the whole payload
+fn arity(op: ValidOps) -> (u8, u8) {
+    OP_ARITY.iter().find(|arity| arity.op == op).map_or((0, 4), |arity| (arity.min, arity.max))
+}
+
+// Clause 45 smi r and smi w name a device address (MMD) as well, and the
+// register can not be left out
+fn data_arity(hr: &HostRequest<host::Unclean>) -> (u8, u8) {
+    match (hr.interface, hr.operation) {
+        (ValidInterfaces::SMI, ValidOps::Read) if hr.clause45 => (3, 3),
+        (ValidInterfaces::SMI, ValidOps::Write) if hr.clause45 => (4, 4),
+        _ => arity(hr.operation),
+    }
+}
+
+fn interface_keyword(word: &str) -> Option<&'static InterfaceKeyword> {
+    INTERFACE_KEYWORDS.iter().find(|entry| word == entry.keyword.lower || word == entry.keyword.upper)
+}
+
+// Keyword of an interface, for answers that name one
+pub fn interface_word(interface: ValidInterfaces) -> &'static str {
+    INTERFACE_KEYWORDS.iter().find(|entry| entry.keyword.value == interface).map_or("?", |entry| entry.keyword.lower)
+}
+
+fn op_word(op: ValidOps) -> &'static str {
+    OP_KEYWORDS.iter().chain(OP_ALIASES.iter().map(|alias| &alias.keyword))
+        .find(|keyword| keyword.value == op).map_or("?", |keyword| keyword.lower)
+}
+
+fn op_alias(interface: ValidInterfaces, word: &str) -> Option<ValidOps> {
+    OP_ALIASES.iter()
+        .find(|alias| alias.interface == interface && (word == alias.keyword.lower || word == alias.keyword.upper))
+        .map(|alias| alias.keyword.value)
+}
+
+// Answer to `cfg caps`: the system commands, then every interface compiled into
+// this build with the operations it accepts, one per line
+pub fn write_caps(out: &mut impl FmtWrite) -> core::fmt::Result {
+    write!(out, "\n\rsystem:")?;
+    for keyword in SYSTEM_KEYWORDS.iter() {
+        write!(out, " {}", keyword.lower)?;
+    }
+    for entry in INTERFACE_KEYWORDS.iter().filter(|entry| entry.keyword.value.is_enabled()) {
+        write!(out, "\n\r{}:", entry.keyword.lower)?;
+        for op in entry.ops {
+            write!(out, " {}", op_word(*op))?;
         }
     }
-    // Match on the second word. This should be an operation. If not log incorrect
-    match command.next() {
-        Some("r" | "R") => {
-            hr.set_operation(ValidOps::Read);
+    write!(out, "\n\r->")
+}
+
+// Bumped whenever the cfg grammar output changes shape
+pub const GRAMMAR_VERSION: u32 = 1;
+
+// Argument form of an operation with its own parser, in place of a word count
+fn special_form(op: ValidOps) -> Option<&'static str> {
+    match op {
+        ValidOps::WriteStr => Some("text"),
+        ValidOps::WriteRead => Some("bytes : len"),
+        ValidOps::XferHex => Some("hex"),
+        ValidOps::WriteAt => Some("row col text"),
+        ValidOps::UsbSerial => Some("get|set serial"),
+        _ => None,
+    }
+}
+
+// Answer to `cfg grammar`, for host side tools. One line per system command
+// ("system <word>") and per interface operation ("<interface> <op> <min> <max>",
+// data word counts from OP_ARITY, or the argument form of a special parser)
+pub fn write_grammar(out: &mut impl FmtWrite) -> core::fmt::Result {
+    write!(out, "\n\rgrammar {}", GRAMMAR_VERSION)?;
+    write!(out, "\n\rprefix time")?;
+    for keyword in SYSTEM_KEYWORDS.iter() {
+        write!(out, "\n\rsystem {}", keyword.lower)?;
+    }
+    for entry in INTERFACE_KEYWORDS.iter().filter(|entry| entry.keyword.value.is_enabled()) {
+        for op in entry.ops {
+            write!(out, "\n\r{} {}", entry.keyword.lower, op_word(*op))?;
+            match special_form(*op) {
+                Some(form) => write!(out, " {}", form)?,
+                None => {
+                    let (min, max) = arity(*op);
+                    write!(out, " {} {}", min, max)?;
+                }
+            }
         }
-        Some("w" | "W") => {
-            hr.set_operation(ValidOps::Write);
+    }
+    write!(out, "\n\r->")
+}
+
+// Answer to `cfg limits`, the parser bounds a host has to stay within:
+// data words per request, characters per line and per word, words per
+// command and bytes of wstr text
+pub fn write_limits(out: &mut impl FmtWrite) -> core::fmt::Result {
+    write!(out, "\n\rpayload={}", PAYLOAD_CAP)?;
+    write!(out, "\n\rline={}", LINE_LEN)?;
+    write!(out, "\n\rtoken={}", MAX_TOKEN_LEN)?;
+    write!(out, "\n\rwords={}", MAX_WORDS)?;
+    write!(out, "\n\rtext={}", TEXT_MAX)?;
+    write!(out, "\n\r->")
+}
+
+// Returned in place of a request when nothing is left of a line once comment,
+// tag and checksum are stripped. Printed like any other answer, it reprints the prompt
+pub const EMPTY_COMMAND: &str = "\n\r->";
+
+// Strip what can surround the command itself:
+// a '#' comment (outside quotes) to the end of the line, a leading @tag (0..255) that is carried
+// back in the request's proc_id and a trailing *XX hex checksum.
+// Returns the command left over with the tag and checksum if they were given
+fn strip_framing(input: &str) -> Result<(&str, Option<u8>, Option<u8>), &'static str> {
+    let input = match comment_start(input) {
+        Some(index) => &input[..index],
+        None => input,
+    };
+    let mut input = input.trim();
+
+    let mut tag = None;
+    if let Some(rest) = input.strip_prefix('@') {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        match bytes_to_number(&rest[..end]) {
+            Ok(value) if value <= 0xFF => tag = Some(value as u8),
+            _ => return Err("Invalid tag\n\r"),
         }
-        Some("smiset" | "SMISET") => {
-            hr.set_operation(ValidOps::SmiSet);
+        input = rest[end..].trim_start();
+    }
+
+    let mut checksum = None;
+    if let Some(index) = input.rfind('*') {
+        let digits = &input[index + 1..];
+        let separated = index == 0 || input[..index].ends_with(char::is_whitespace);
+        if separated && digits.len() == 2 {
+            if let Ok(value) = u8::from_str_radix(digits, 16) {
+                checksum = Some(value);
+                input = input[..index].trim_end();
+            }
         }
-        _ => {
-            return Err("Invalid Operation\n\r");
+    }
+    Ok((input, tag, checksum))
+}
+
+// A '#' between double quotes is text, the first one outside them starts the comment
+fn comment_start(input: &str) -> Option<usize> {
+    let mut quoted = false;
+    input.char_indices().find(|&(_, c)| {
+        if c == '"' {
+            quoted = !quoted;
         }
+        c == '#' && !quoted
+    }).map(|(index, _)| index)
+}
+
+// Next word of a command and what follows it. A word that starts with '"'
+// runs to the next '"', spaces included, and is returned without the quotes
+fn next_token(input: &str) -> Result<Option<(&str, &str)>, &'static str> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return Ok(None)
     }
-    let mut size: u8 = 0;
-    while size < (command_count - 3) as u8 {
-        let val = command.nth(0).unwrap();
-            match bytes_to_number(val) {
-                Ok(value) => {
-                    payload[size as usize] = value;
-                }
-                Err(err) => {
-                    return Err(err) 
-                }
+    if let Some(quoted) = input.strip_prefix('"') {
+        let end = quoted.find('"').ok_or("Unterminated quote\n\r")?;
+        let rest = &quoted[end + 1..];
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return Err("Missing space after quote\n\r")
         }
-        size+=1;
+        return Ok(Some((&quoted[..end], rest)))
     }
-    hr.set_size(size);
-    hr.set_payload(payload);
-    Ok(hr)
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    Ok(Some((&input[..end], &input[end..])))
 }
 
-// Helper function to take &str in decimal or hex form
-// and return u32.
-// ie: s = "0xFF"  will return decimal value 255
+// Most words a command can hold: interface, operation and the data words
+// (at most spi wr: the write bytes, ':' and the read length)
+const MAX_WORDS: usize = 2 + SPI_WR_MAX_WRITE + 2;
+// Longest word outside of wstr text, "0xFFFFFFFF" and every keyword fit easily
+const MAX_TOKEN_LEN: usize = 16;
+
+// Parse a command that names its interface, received over USB serial
+pub fn message_parse_build(input: &str)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    message_parse_build_default(input, None, ValidHostInterfaces::Serial, SmiClause::Clause22)
+}
+
+// Helper function that takes list of bytes and deconstructs
+// into HostRequest fields. A command starting with an operation instead of
+// an interface goes to default, the interface set by cfg iface. host_interface is
+// the transport the command arrived on, it is kept in the request for the answer.
+// smi_clause picks the frames of smi r and smi w, set by smi clause.
+// NOTE: Preliminary behavior is to drop message and log to serial an invalid message
+// if fields are missing or invalid
 #[inline(never)]
 #[link_section = ".data.bar"] // Execute from IRAM
-pub fn bytes_to_number(s: &str) -> Result<u32, &'static str> {
-    let mut result: u32 = 0;
-    // Check if the input is hex or decimal
-    let mut chars = s.chars();
-    if let Some(c) = chars.next() {
-        if c != '0' || chars.next() != Some('x') {
-            if '0' <= c && c <= '9' {
-                result += c as u32 - '0' as u32;
-                for c in chars {
-                    let digit= match c {
-                        '0'..='9' => c as u32 - '0' as u32,
-                        _ => return Err("Invalid decimal character\n\r"),
-                    };
-                    if result >= 429_496_720 {
-                        return Err("Integer number too large!\n\r")
-                    }
-                    result = result * 10 + digit;
-                    
-                }
-                return Ok(result)
+pub fn message_parse_build_default<'input>(input: &'input str, default: Option<ValidInterfaces>,
+    host_interface: ValidHostInterfaces, smi_clause: SmiClause) -> Result<HostRequest<host::Unclean>, &'static str>{
+    // Split up the given string
+    let mut hr = HostRequest::new();
+    hr.set_host_config(host_interface);
+    hr.set_clause45(smi_clause == SmiClause::Clause45);
+
+    let (input, tag, checksum) = strip_framing(input)?;
+    // The one place an empty command is caught, whatever emptied it
+    if input.is_empty() {
+        return Err(EMPTY_COMMAND)
+    }
+    if let Some(tag) = tag {
+        hr.set_proc_id(tag);
+    }
+    if let Some(checksum) = checksum {
+        hr.set_checksum(checksum);
+    }
+
+    // Tokenize once, counting the words as they are stored
+    let mut words: [&'input str; MAX_WORDS] = [""; MAX_WORDS];
+    let mut command_count = 0;
+    // Only an error once the operation is known, wstr text may hold any number of words
+    let mut too_many = false;
+    let mut too_long = false;
+    // wstr takes its text as written, so a bad quote waits for the operation too
+    let mut bad_quote = None;
+    let mut rest = input;
+    loop {
+        let word = match next_token(rest) {
+            Ok(Some((word, after))) => {
+                rest = after;
+                word
             }
-            return Err("Not a hex or decimal string\n\r")
+            Ok(None) => break,
+            Err(err) => {
+                bad_quote = Some(err);
+                break
+            }
+        };
+        if command_count == MAX_WORDS {
+            too_many = true;
+            break
         }
+        too_long |= word.len() > MAX_TOKEN_LEN;
+        words[command_count] = word;
+        command_count += 1;
     }
-    if chars.clone().count() > 8 {
-        return Err("Integer number too large!\n\r")
+    let mut command = words[..command_count].iter().copied();
+    // Match on the first word, a system command or an interface
+    let first = command.next().unwrap_or("");
+    // time <command> handles the inner command as usual and reports how long it took.
+    // For smi r that is only the enqueue, the answer comes from the PIO interrupt
+    if first == "time" || first == "TIME" {
+        let start = first.as_ptr() as usize - input.as_ptr() as usize + first.len();
+        let mut hr = message_parse_build_default(&input[start..], default, host_interface, smi_clause)?;
+        hr.set_timed(true);
+        return Ok(hr)
     }
-    for c in chars {
+    if let Some(op) = Keyword::lookup(&SYSTEM_KEYWORDS, first) {
+        hr.set_interface(ValidInterfaces::Config);
+        hr.set_operation(op);
+        return Ok(hr)
+    }
+    // An interface name always wins over the default, the operation is then the second word
+    let (entry, op_word) = match interface_keyword(first) {
+        Some(entry) => (entry, command.next().unwrap_or("")),
+        None => {
+            let default = default.and_then(|default| INTERFACE_KEYWORDS.iter().find(|entry| entry.keyword.value == default));
+            match default {
+                Some(entry) if Keyword::lookup(&OP_KEYWORDS, first).is_some() => (entry, first),
+                _ => return Err("Invalid Interface\n\r"),
+            }
+        }
+    };
+    hr.set_interface(entry.keyword.value);
+    // The keyword is still recognized when its interface is compiled out
+    if !hr.interface.is_enabled() {
+        return Err("Interface not enabled\n\r")
+    }
+    // Match on the operation word. If not log incorrect
+    let op = op_alias(entry.keyword.value, op_word).or_else(|| Keyword::lookup(&OP_KEYWORDS, op_word));
+    // Reject a known verb on an interface that does not take it before parsing any data
+    match op {
+        Some(op) if !entry.ops.contains(&op) => {
+            if op == ValidOps::SmiSet {
+                return Err("Invalid Operation: smiset is only valid for smi\n\r")
+            }
+            return Err("Invalid Operation for interface\n\r")
+        }
+        _ => {}
+    }
+    if op == Some(ValidOps::WriteStr) {
+        hr.set_operation(ValidOps::WriteStr);
+        return parse_text(hr, input, op_word)
+    }
+    // Position words, then the rest of the line as text
+    if op == Some(ValidOps::WriteAt) {
+        hr.set_operation(ValidOps::WriteAt);
+        return parse_write_at(hr, input, command)
+    }
+    // One hex string, longer than any other token
+    if op == Some(ValidOps::XferHex) {
+        hr.set_operation(ValidOps::XferHex);
+        return parse_hex_blob(hr, command)
+    }
+    if let Some(err) = bad_quote {
+        return Err(err)
+    }
+    if too_long {
+        return Err("Token too long\n\r")
+    }
+    if too_many {
+        return Err("Too many arguments\n\r")
+    }
+    match op {
+        Some(ValidOps::WriteRead) => {
+            hr.set_operation(ValidOps::WriteRead);
+            return parse_write_read(hr, command)
+        }
+        Some(ValidOps::UsbSerial) => {
+            hr.set_operation(ValidOps::UsbSerial);
+            return parse_usb_serial(hr, command)
+        }
+        Some(op) => {
+            hr.set_operation(op);
+        }
+        None => {
+            return Err("Invalid Operation\n\r");
+        }
+    }
+    for val in command {
+        // gpio pwm pin stop|duty ... updates a running slice instead of starting one
+        match (&hr.operation, hr.size, val) {
+            (ValidOps::Pwm, 1, "stop" | "STOP") => {
+                hr.set_operation(ValidOps::PwmStop);
+                continue
+            }
+            (ValidOps::Pwm, 1, "duty" | "DUTY") => {
+                hr.set_operation(ValidOps::PwmDuty);
+                continue
+            }
+            // smi r phy [reg] fields, a flag rather than a data word
+            (ValidOps::Read, 1 | 2, "fields" | "FIELDS") if hr.interface == ValidInterfaces::SMI && !hr.fields => {
+                hr.set_fields(true);
+                continue
+            }
+            // A clock is a PWM slice at 50 %, stopped the same way
+            (ValidOps::Clock, 1, "off" | "OFF") => {
+                hr.set_operation(ValidOps::PwmStop);
+                continue
+            }
+            _ => {}
+        }
+        if hr.size == data_arity(&hr).1 {
+            return Err("Wrong number of arguments for operation\n\r")
+        }
+        // Slew rate is given as a keyword instead of a number
+        let parsed = match (&hr.operation, hr.size) {
+            (ValidOps::Slew, 1) => slew_to_number(val),
+            (ValidOps::OpenDrain, 1) => level_to_number(val),
+            (ValidOps::AutoNewline, 0) => on_off_to_number(val),
+            (ValidOps::Schmitt, 1) => on_off_to_number(val),
+            (ValidOps::Enable | ValidOps::Disable, 0) => interface_to_number(val),
+            (ValidOps::Channel, 0) => channel_to_number(val),
+            (ValidOps::BlockWrite, 0) => on_off_to_number(val),
+            (ValidOps::LogLevel, 0) => log_level_to_number(val),
+            (ValidOps::Mirror, 0) => on_off_to_number(val),
+            (ValidOps::Framing, 0) => framing_to_number(val),
+            (ValidOps::Endian, 0) => endian_to_number(val),
+            (ValidOps::Indicator, 0) => on_off_to_number(val),
+            (ValidOps::Strict, 0) => on_off_to_number(val),
+            (ValidOps::Timestamp, 0) => on_off_to_number(val),
+            (ValidOps::SafeState, 1) => safe_level_to_number(val),
+            (ValidOps::DefaultInterface, 0) => default_interface_to_number(val),
+            (ValidOps::Address, 0) => address_to_number(val),
+            _ => bytes_to_number(val),
+        };
+        // Whatever OP_ARITY says, push_word ends the payload at PAYLOAD_CAP
+        hr.push_word(parsed?)?;
+    }
+    if hr.size < data_arity(&hr).0 {
+        return Err("Wrong number of arguments for operation\n\r")
+    }
+    Ok(hr)
+}
+
+// spi wr b0 b1 ... : read_len
+// payload[0] holds the read length and the write bytes are packed little endian
+// from payload[1], size is the number of write bytes
+fn parse_write_read<'input>(mut hr: HostRequest<host::Unclean>, data: impl Iterator<Item = &'input str>)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    let mut payload = [0u32; PAYLOAD_CAP];
+    let mut size: u8 = 0;
+    let mut read_len = None;
+    let mut separated = false;
+    for val in data {
+        if val == ":" {
+            if separated { return Err("Invalid Arguments for SPI: Write Read\n\r") }
+            separated = true;
+            continue
+        }
+        let value = bytes_to_number(val)?;
+        if separated {
+            if read_len.is_some() { return Err("Invalid Arguments for SPI: Write Read\n\r") }
+            read_len = Some(value);
+        }
+        else {
+            if value > 0xFF { return Err("SPI write byte must be 0 to 0xFF\n\r") }
+            if size as usize == SPI_WR_MAX_WRITE { return Err("Too many SPI write bytes\n\r") }
+            payload[1 + size as usize / 4] |= value << (8 * (size % 4));
+            size += 1;
+        }
+    }
+    if !separated { return Err("Missing ':' between SPI write bytes and read length\n\r") }
+    match read_len {
+        Some(len) => payload[0] = len,
+        None => return Err("Missing SPI read length\n\r"),
+    }
+    hr.set_byte_count(size)?;
+    hr.set_payload(payload);
+    Ok(hr)
+}
+
+// wstr takes the rest of the line as literal bytes, internal spaces included.
+// The text starts after the single separator that follows the keyword, the
+// trailing whitespace and line terminator are not part of it. Text wrapped
+// in one pair of double quotes is sent without them, trailing spaces kept
+fn parse_text<'input>(mut hr: HostRequest<host::Unclean>, input: &'input str, keyword: &'input str)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    // keyword is a slice of input, so its position gives where the text begins
+    let start = keyword.as_ptr() as usize - input.as_ptr() as usize + keyword.len() + 1;
+    let text = input.get(start..).unwrap_or("").trim_end();
+    let text = match text.strip_prefix('"') {
+        Some(quoted) => match quoted.strip_suffix('"') {
+            Some(inner) => inner,
+            None => return Err("Unterminated quote\n\r"),
+        },
+        None => text,
+    };
+    if text.len() > TEXT_MAX {
+        return Err("Too many bytes\n\r")
+    }
+    hr.set_text(text.as_bytes());
+    Ok(hr)
+}
+
+// lcd write row col text: payload[0] and [1] hold the position, the text
+// after col is taken as wstr takes it and size holds its length
+fn parse_write_at<'input>(hr: HostRequest<host::Unclean>, input: &'input str, mut data: impl Iterator<Item = &'input str>)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    let (row, col) = match (data.next(), data.next()) {
+        (Some(row), Some(col)) => (bytes_to_number(row)?, col),
+        _ => return Err("Invalid Arguments for LCD: write\n\r"),
+    };
+    let position = [row, bytes_to_number(col)?];
+    let mut hr = parse_text(hr, input, col)?;
+    hr.payload[..2].copy_from_slice(&position);
+    Ok(hr)
+}
+
+// spi xferx DEADBEEF: two hex digits per byte, no prefix or spaces.
+// The bytes are carried as text, like wstr
+fn parse_hex_blob<'input>(mut hr: HostRequest<host::Unclean>, mut data: impl Iterator<Item = &'input str>)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    let hex = match (data.next(), data.next()) {
+        (Some(hex), None) => hex,
+        _ => return Err("Invalid Arguments for SPI: xferx hexbytes\n\r"),
+    };
+    let mut bytes = [0_u8; TEXT_MAX];
+    let len = hex_to_bytes(hex, &mut bytes)?;
+    hr.set_text(&bytes[..len]);
+    Ok(hr)
+}
+
+// Decode a contiguous hex string into out, returns the number of bytes
+pub fn hex_to_bytes(hex: &str, out: &mut [u8]) -> Result<usize, &'static str> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("Hex string must have an even number of digits\n\r")
+    }
+    if hex.len() / 2 > out.len() {
+        return Err("Too many bytes\n\r")
+    }
+    // from_str_radix alone would take a sign
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Invalid hex character\n\r")
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = str::from_utf8(pair).map_err(|_| "Invalid hex character\n\r")?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| "Invalid hex character\n\r")?;
+    }
+    Ok(hex.len() / 2)
+}
+
+// cfg usbserial get | set serial. The serial is carried as text, a get has none
+fn parse_usb_serial<'input>(mut hr: HostRequest<host::Unclean>, mut data: impl Iterator<Item = &'input str>)
+    -> Result<HostRequest<host::Unclean>, &'static str> {
+    let serial = match (data.next(), data.next()) {
+        (Some("get" | "GET"), None) => return Ok(hr),
+        (Some("set" | "SET"), Some(serial)) => serial,
+        _ => return Err("Invalid Arguments for CFG: usbserial get|set serial\n\r"),
+    };
+    if data.next().is_some() {
+        return Err("Wrong number of arguments for operation\n\r")
+    }
+    hr.set_text(serial.as_bytes());
+    Ok(hr)
+}
+
+// Helper function to map the gpio slew keyword to its payload value
+// ie: s = "fast" will return 1, s = "slow" will return 0
+pub fn slew_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "fast" | "FAST" => Ok(1),
+        "slow" | "SLOW" => Ok(0),
+        _ => Err("Slew rate must be fast or slow\n\r"),
+    }
+}
+
+// Helper function to map a gpio level keyword to its payload value
+// ie: s = "high" will return 1, s = "low" will return 0
+pub fn level_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "high" | "HIGH" => Ok(1),
+        "low" | "LOW" => Ok(0),
+        _ => Err("Level must be high or low\n\r"),
+    }
+}
+
+// Level of cfg safestate: high or 1, low or 0, off to remove the pin
+pub fn safe_level_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "1" => Ok(1),
+        "0" => Ok(0),
+        "off" | "OFF" => Ok(safestate::LEVEL_OFF),
+        _ => level_to_number(s).map_err(|_| "Level must be high, low or off\n\r"),
+    }
+}
+
+// Helper function to map an on/off setting keyword to its payload value
+// ie: s = "on" will return 1, s = "off" will return 0
+pub fn on_off_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "on" | "ON" => Ok(1),
+        "off" | "OFF" => Ok(0),
+        _ => Err("Setting must be on or off\n\r"),
+    }
+}
+
+// Helper function to map a USB port keyword to its payload value
+// ie: s = "data" will return 1, s = "control" will return 0
+pub fn channel_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "control" | "CONTROL" => Ok(0),
+        "data" | "DATA" => Ok(1),
+        _ => Err("Channel must be control or data\n\r"),
+    }
+}
+
+// Helper function to map the cfg endian keyword to its payload value
+// ie: s = "big" will return 1
+pub fn endian_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "little" | "LITTLE" => Ok(0),
+        "big" | "BIG" => Ok(1),
+        _ => Err("Endian must be little or big\n\r"),
+    }
+}
+
+// Helper function to map the cfg framing keyword to its payload value
+// ie: s = "length" will return 1
+pub fn framing_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "line" | "LINE" => Ok(0),
+        "length" | "LENGTH" => Ok(1),
+        _ => Err("Framing must be line or length\n\r"),
+    }
+}
+
+// Helper function to map a log level name to its Level value
+// ie: s = "info" will return 2
+pub fn log_level_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "error" | "ERROR" => Ok(Level::Error as u32),
+        "warn" | "WARN" => Ok(Level::Warn as u32),
+        "info" | "INFO" => Ok(Level::Info as u32),
+        "debug" | "DEBUG" => Ok(Level::Debug as u32),
+        _ => Err("Log level must be error, warn, info or debug\n\r"),
+    }
+}
+
+// cfg addr takes a device address or off, which maps to ADDR_OFF
+pub fn address_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "off" | "OFF" => Ok(ADDR_OFF),
+        _ => bytes_to_number(s),
+    }
+}
+
+// Helper function to map an interface keyword to its ValidInterfaces value
+// ie: s = "gpio" will return 6
+// cfg iface takes an interface or off, which maps to ValidInterfaces::None
+pub fn default_interface_to_number(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "off" | "OFF" => Ok(ValidInterfaces::None as u32),
+        _ => interface_to_number(s),
+    }
+}
+
+pub fn interface_to_number(s: &str) -> Result<u32, &'static str> {
+    match interface_keyword(s) {
+        Some(entry) => Ok(entry.keyword.value as u32),
+        None => Err("Invalid Interface\n\r"),
+    }
+}
+
+// Shift one more digit into a literal, shared by the decimal and hex branches
+// so a value past u32::MAX is an error in both rather than wrapping
+fn push_digit(result: u32, radix: u32, digit: u32) -> Result<u32, &'static str> {
+    result.checked_mul(radix)
+        .and_then(|result| result.checked_add(digit))
+        .ok_or("Integer number too large!\n\r")
+}
+
+// Helper function to take &str in decimal or hex form
+// and return u32.
+// ie: s = "0xFF"  will return decimal value 255
+// Only 0x / 0X selects hex. Leading zeros are plain decimal digits, not an
+// octal prefix: "0" and "00" are 0 and "010" is 10
+#[inline(never)]
+#[link_section = ".data.bar"] // Execute from IRAM
+pub fn bytes_to_number(s: &str) -> Result<u32, &'static str> {
+    let mut result: u32 = 0;
+    // Check if the input is hex or decimal
+    let hex = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => hex,
+        None => {
+            let mut chars = s.chars();
+            if let Some(c) = chars.next() {
+                if c.is_ascii_digit() {
+                    result += c as u32 - '0' as u32;
+                    for c in chars {
+                        let digit= match c {
+                            '0'..='9' => c as u32 - '0' as u32,
+                            _ => return Err("Invalid decimal character\n\r"),
+                        };
+                        // Everything up to u32::MAX fits
+                        result = push_digit(result, 10, digit)?;
+                    }
+                    return Ok(result)
+                }
+            }
+            return Err("Not a hex or decimal string\n\r")
+        }
+    };
+    // A prefix with no digits after it is not a number
+    if hex.is_empty() {
+        return Err("Empty numeric literal\n\r")
+    }
+    for c in hex.chars() {
         let digit =  match c {
             '0'..='9' => c as u32 - '0' as u32,
             'a'..='f' => c as u32 - 'a' as u32 + 10,
             'A'..='F' => c as u32 - 'A' as u32 + 10,
             _ => return Err("Invalid hex character\n\r"),
         };
-        result = result * 16 + digit;
+        // Counted by value, so leading zeros never overflow
+        result = push_digit(result, 16, digit)?;
     }
     Ok(result)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parsed and validated, as the serial reader hands requests on
+    fn parse(line: &str) -> Result<HostRequest<host::Clean>, &'static str> {
+        message_parse_build(line)?.init_clean()
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_drive_and_slew() {
+        let hr = parse("gpio drive 3 8").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::Drive, &[3, 8][..]));
+        let hr = parse("gpio slew 3 fast").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::Slew, &[3, 1][..]));
+        assert_eq!(parse("gpio slew 3 slow").unwrap().payload[1], 0);
+        assert_eq!(parse("gpio drive 3 6").err(), Some("Drive strength must be 2, 4, 8 or 12 mA\n\r"));
+        assert_eq!(parse("gpio drive 30 4").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("gpio slew 3 medium").err(), Some("Slew rate must be fast or slow\n\r"));
+    }
+
+    // Run with --no-default-features to see the rejection
+    #[cfg(not(feature = "smi"))]
+    #[test]
+    fn disabled_interface_is_refused() {
+        assert_eq!(parse("smi r 1 16").err(), Some("Interface not enabled\n\r"));
+        assert_eq!(parse("foo r 1").err(), Some("Invalid Interface\n\r"));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn enabled_interface_is_taken() {
+        assert!(parse("smi r 1 16").is_ok());
+        assert_eq!(parse("foo r 1").err(), Some("Invalid Interface\n\r"));
+    }
+
+    #[test]
+    fn adc_scan() {
+        let hr = parse("adc scan 0 1 2").unwrap();
+        assert_eq!((hr.operation, hr.size, &hr.payload[..3]), (ValidOps::Scan, 3, &[0, 1, 2][..]));
+        assert_eq!(parse("adc scan 9").err(), Some("Invalid ADC channel\n\r"));
+        assert_eq!(parse("adc scan 1 1").err(), Some("Duplicate ADC channel\n\r"));
+    }
+
+    #[test]
+    fn selftest_parses() {
+        let hr = parse("selftest").unwrap();
+        assert_eq!((hr.interface, hr.operation), (ValidInterfaces::Config, ValidOps::SelfTest));
+    }
+
+    fn feed_line<const N: usize>(editor: &mut LineEditor<N>, line: &str) -> Edit {
+        let mut last = Edit::None;
+        for byte in line.bytes() {
+            last = editor.feed(byte);
+        }
+        last
+    }
+
+    #[test]
+    fn history_recall() {
+        let mut editor: LineEditor = LineEditor::new();
+        assert_eq!(feed_line(&mut editor, "smi r 1 2\r"), Edit::Line);
+        editor.clear();
+        assert_eq!(feed_line(&mut editor, "smi r 1 3\r"), Edit::Line);
+        editor.clear();
+        // Up twice goes back to the older line, down once to the newer one
+        assert_eq!(feed_line(&mut editor, "\x1b[A"), Edit::Redraw);
+        assert_eq!(editor.as_str(), "smi r 1 3");
+        assert_eq!(feed_line(&mut editor, "\x1b[A"), Edit::Redraw);
+        assert_eq!(editor.as_str(), "smi r 1 2");
+        assert_eq!(feed_line(&mut editor, "\x1b[A"), Edit::None);
+        assert_eq!(feed_line(&mut editor, "\x1b[B"), Edit::Redraw);
+        assert_eq!(editor.as_str(), "smi r 1 3");
+        // Past the newest entry the line is empty again
+        assert_eq!(feed_line(&mut editor, "\x1b[B"), Edit::Redraw);
+        assert_eq!(editor.as_str(), "");
+        assert_eq!(feed_line(&mut editor, "\x1b[B"), Edit::None);
+    }
+
+    #[test]
+    fn history_wraps_at_the_ring_boundary() {
+        let mut editor: LineEditor = LineEditor::new();
+        let mut line = *b"cfg retry 0\r";
+        for n in 0..HISTORY_LEN as u8 + 2 {
+            line[10] = b'0' + n;
+            assert_eq!(feed_line(&mut editor, str::from_utf8(&line).unwrap()), Edit::Line);
+            editor.clear();
+        }
+        for _ in 0..HISTORY_LEN {
+            assert_eq!(feed_line(&mut editor, "\x1b[A"), Edit::Redraw);
+        }
+        // The two oldest lines were overwritten
+        assert_eq!(editor.as_str(), "cfg retry 2");
+        assert_eq!(feed_line(&mut editor, "\x1b[A"), Edit::None);
+        // A recalled line is sent like a typed one
+        assert_eq!(feed_line(&mut editor, "\r"), Edit::Line);
+        assert_eq!(message_parse_build(editor.as_str()).unwrap().payload[0], 2);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn argument_count_in_one_pass() {
+        let hr = message_parse_build("smi w 1 2 3").unwrap();
+        assert_eq!((hr.size, &hr.payload[..3]), (3, &[1, 2, 3][..]));
+        // Same request whatever the spacing
+        let spaced = message_parse_build("  smi   w 1  2   3 ").unwrap();
+        assert_eq!((spaced.size, spaced.payload), (hr.size, hr.payload));
+        assert_eq!(message_parse_build("smi w 1 2 3 4 5").err(), Some("Wrong number of arguments for operation\n\r"));
+        assert_eq!(message_parse_build("bad w 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15").err(), Some("Invalid Interface\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_pwm_forms() {
+        let hr = parse("gpio pwm 3 1000 50").unwrap();
+        assert_eq!((hr.operation, hr.size, &hr.payload[..3]), (ValidOps::Pwm, 3, &[3, 1000, 50][..]));
+        let hr = parse("gpio pwm 3 stop").unwrap();
+        assert_eq!((hr.operation, hr.size, hr.payload[0]), (ValidOps::PwmStop, 1, 3));
+        let hr = parse("gpio pwm 3 duty 75").unwrap();
+        assert_eq!((hr.operation, hr.size, hr.payload[1]), (ValidOps::PwmDuty, 2, 75));
+        assert!(parse("gpio pwm 3 duty 101").is_err());
+        assert!(parse("gpio pwm 3 1000 101").is_err());
+        assert!(parse("gpio pwm 3 5 50").is_err());
+        assert!(parse("gpio pwm 3 duty").is_err());
+    }
+
+    #[test]
+    fn stat_usb() {
+        let hr = parse("stat usb").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.size), (ValidInterfaces::Stat, ValidOps::Usb, 0));
+        assert!(parse("stat usb 1").is_err());
+        assert!(parse("stat r").is_err());
+    }
+
+    #[test]
+    fn sync_parses() {
+        let hr = parse("sync").unwrap();
+        assert_eq!((hr.interface, hr.operation), (ValidInterfaces::Config, ValidOps::Sync));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_open_drain() {
+        let hr = parse("gpio od 4 high").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::OpenDrain, &[4, 1][..]));
+        assert_eq!(parse("gpio od 4 low").unwrap().payload[..2], [4, 0]);
+        assert_eq!(parse("gpio od 30 low").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("gpio od 4 mid").is_err());
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn spi_write_then_read() {
+        use crate::protocol::spi_wr_byte;
+        let hr = parse("spi wr 0x03 0x00 : 4").unwrap();
+        assert_eq!((hr.operation, hr.size, hr.payload[0]), (ValidOps::WriteRead, 2, 4));
+        assert_eq!((spi_wr_byte(&hr.payload, 0), spi_wr_byte(&hr.payload, 1)), (3, 0));
+        let hr = parse("spi wr 1 2 3 4 5 6 7 8 9 10 11 12 : 16").unwrap();
+        assert_eq!(spi_wr_byte(&hr.payload, 11), 12);
+        assert!(parse("spi wr 1 2 3 4 5 6 7 8 9 10 11 12 13 : 16").is_err());
+        assert!(parse("spi wr 0x03 0x00 4").is_err());
+        assert!(parse("spi wr 0x103 : 4").is_err());
+        assert!(parse("spi wr 1 : 17").is_err());
+        assert!(parse("spi wr 1 : ").is_err());
+    }
+
+    #[test]
+    fn caps_fit_the_answer_buffer() {
+        assert_eq!(parse("cfg caps").unwrap().operation, ValidOps::Caps);
+        let mut buf = [0_u8; 768];
+        let mut out = Wrapper::new(&mut buf);
+        write_caps(&mut out).unwrap();
+        let caps = out.as_str();
+        assert!(caps.starts_with("\n\rsystem:") && caps.ends_with("\n\r->"));
+        assert!(caps.contains("\n\rcfg:") && caps.contains("\n\radc:"));
+        // Only the interfaces built in are listed
+        assert_eq!(caps.contains("\n\rsmi:"), cfg!(feature = "smi"));
+        assert_eq!(caps.contains("\n\rgpio: w drive slew od pwm"), cfg!(feature = "gpio"));
+    }
+
+    #[cfg(all(feature = "smi", feature = "gpio"))]
+    #[test]
+    fn op_checked_against_the_interface() {
+        assert_eq!(message_parse_build("gpio smiset 1").err(), Some("Invalid Operation: smiset is only valid for smi\n\r"));
+        assert!(parse("cfg smiset 10").is_err());
+        assert!(parse("adc r 1").is_err());
+        assert_eq!(parse("smi smiset 1000000").unwrap().operation, ValidOps::SmiSet);
+        assert!(parse("gpio w 1").is_ok());
+    }
+
+    #[cfg(all(feature = "uart", feature = "spi"))]
+    #[test]
+    fn raw_text_passthrough() {
+        let hr = parse("uart wstr hello").unwrap();
+        assert_eq!((hr.interface, hr.operation, &hr.text[..hr.size as usize]), (ValidInterfaces::UART, ValidOps::WriteStr, &b"hello"[..]));
+        // The text is taken as typed, inner spacing and all
+        let hr = parse("spi wstr hello  big world").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"hello  big world");
+        let hr = parse("uart wstr a b c d e f g h i j k l m n o p q r s t").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"a b c d e f g h i j k l m n o p q r s t");
+        assert!(parse("uart wstr").is_err());
+        assert!(parse("uart w 1").is_err());
+    }
+
+    #[test]
+    fn auto_newline_toggle() {
+        let hr = parse("cfg autonl off").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::AutoNewline, 0));
+        assert_eq!(parse("cfg autonl on").unwrap().payload[0], 1);
+        assert!(parse("cfg autonl maybe").is_err());
+        assert!(parse("cfg autonl").is_err());
+        // On by default, as before the toggle, and shown by cfg show
+        let mut config = Config::new();
+        assert!(config.auto_newline);
+        config.auto_newline = false;
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().starts_with("\n\rautonl=off\n\r"));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn stale_bytes_after_a_shorter_line() {
+        let mut editor: LineEditor = LineEditor::new();
+        assert_eq!(feed_line(&mut editor, "smi w 1 2 3\r"), Edit::Line);
+        editor.clear();
+        assert_eq!(feed_line(&mut editor, "smi r 1\r"), Edit::Line);
+        // The "2 3" of the longer line is still in the buffer but not in the line
+        assert_eq!(editor.as_str(), "smi r 1");
+        let hr = message_parse_build(editor.as_str()).unwrap();
+        assert_eq!(hr.size, 1);
+        assert_eq!(message_parse_build("smi w 1 2 3 4").err(), Some("Wrong number of arguments for operation\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_interrupt_status() {
+        let hr = parse("gpio istat 7").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::IntStatus, 7));
+        let hr = parse("gpio iclear 29").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::IntClear, 29));
+        assert_eq!(parse("gpio istat 30").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("gpio iclear").is_err());
+    }
+
+    #[cfg(all(feature = "smi", feature = "gpio"))]
+    #[test]
+    fn smi_preamble_length() {
+        let hr = parse("smi preamble 0").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Preamble, 0));
+        assert!(parse("smi preamble 32").is_ok());
+        assert!(parse("smi preamble 33").is_err());
+        assert!(parse("gpio preamble 3").is_err());
+        // The standard 32 bits until set
+        assert_eq!(Config::new().smi_preamble, 32);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn time_prefix() {
+        let hr = parse("time smi r 1 16").unwrap();
+        let untimed = parse("smi r 1 16").unwrap();
+        assert!(hr.timed && !untimed.timed);
+        assert_eq!((hr.interface, hr.operation, hr.payload), (untimed.interface, untimed.operation, untimed.payload));
+        assert!(parse("time").is_err());
+        assert!(parse("time bad r").is_err());
+    }
+
+    #[test]
+    fn held_prompt_is_dropped() {
+        assert_eq!(without_held_prompt(b"\n\rReset reason: power on\n\r->"), b"\n\rReset reason: power on\n\r->");
+        hold_prompt(true);
+        let answer = without_held_prompt(b"\n\rReset reason: power on\n\r->");
+        let plain = without_held_prompt(b"\n\r");
+        hold_prompt(false);
+        assert_eq!((answer, plain), (&b"\n\rReset reason: power on\n\r"[..], &b"\n\r"[..]));
+    }
+
+    #[test]
+    fn nothing_left_after_the_prefixes() {
+        for line in ["# only a comment", "@5", "*A3", "", "   ", "@5 *A3 # x"] {
+            assert_eq!(message_parse_build(line).err(), Some(EMPTY_COMMAND));
+        }
+        assert!(message_parse_build("@300 cfg retry 1").is_err());
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn prefixes_around_a_command() {
+        let hr = message_parse_build("@7 smi r 1 2 *1F # read id").unwrap();
+        assert_eq!((hr.size, &hr.payload[..2]), (2, &[1, 2][..]));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_schmitt() {
+        let hr = parse("gpio schmitt 3 on").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::Schmitt, &[3, 1][..]));
+        assert_eq!(parse("gpio schmitt 3 off").unwrap().payload[..2], [3, 0]);
+        assert!(parse("gpio schmitt 3 maybe").is_err());
+        assert_eq!(parse("gpio schmitt 31 on").err(), Some("Invalid GPIO pin\n\r"));
+    }
+
+    const WRONG_COUNT: Option<&str> = Some("Wrong number of arguments for operation\n\r");
+
+    #[test]
+    fn fixed_arity() {
+        assert!(parse("stat usb").is_ok());
+        assert_eq!(parse("stat usb 1").err(), WRONG_COUNT);
+        assert!(parse("adc scan 0 1 2 3").is_ok());
+        assert_eq!(parse("adc scan").err(), Some("Invalid Arguments for ADC: Scan\n\r"));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn fixed_arity_smi() {
+        assert!(parse("smi r 1 2").is_ok());
+        assert_eq!(parse("smi r 1 2 3").err(), WRONG_COUNT);
+        assert_eq!(parse("smi r").err(), WRONG_COUNT);
+        assert!(parse("smi smiset 10").is_ok());
+        assert_eq!(parse("smi smiset 10 2").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn fixed_arity_gpio() {
+        assert_eq!(parse("gpio drive 3").err(), WRONG_COUNT);
+        assert_eq!(parse("gpio drive 3 4 5").err(), WRONG_COUNT);
+        assert!(parse("gpio pwm 3 1000 50").is_ok());
+        assert_eq!(parse("gpio pwm 3 1000").err(), WRONG_COUNT);
+        assert!(parse("gpio pwm 3 stop").is_ok());
+        assert_eq!(parse("gpio pwm 3 stop 1").err(), WRONG_COUNT);
+        assert!(parse("gpio pwm 3 duty 20").is_ok());
+    }
+
+    #[cfg(feature = "led")]
+    #[test]
+    fn led_set() {
+        let hr = parse("led set 16 255 0 0").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.payload), (ValidInterfaces::LED, ValidOps::Set, [16, 255, 0, 0]));
+        assert_eq!(parse("led set 16 256 0 0").err(), Some("LED color must be 0 to 255\n\r"));
+        assert_eq!(parse("led set 30 1 0 0").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("led set 16 1 0").is_err());
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn cfg_clocks() {
+        let hr = parse("cfg clocks").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.size), (ValidInterfaces::Config, ValidOps::Clocks, 0));
+        assert_eq!(parse("cfg clocks 1").err(), WRONG_COUNT);
+        assert!(parse("gpio clocks").is_err());
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_register_defaults_to_the_last_one() {
+        let mut cache = SmiRegCache::new();
+        let mut hr = message_parse_build("smi r 1").unwrap();
+        assert_eq!(cache.fill(&mut hr).err(), Some("No register address cached for this PHY\n\r"));
+        let mut hr = message_parse_build("smi r 1 16").unwrap();
+        cache.fill(&mut hr).unwrap();
+        let mut hr = message_parse_build("smi r 1").unwrap();
+        cache.fill(&mut hr).unwrap();
+        assert_eq!((hr.size, &hr.payload[..2]), (2, &[1, 16][..]));
+        assert!(hr.init_clean().is_ok());
+        // Cached per PHY address
+        let mut hr = message_parse_build("smi r 2").unwrap();
+        assert!(cache.fill(&mut hr).is_err());
+    }
+
+    #[test]
+    fn cfg_build() {
+        assert_eq!(parse("cfg build").unwrap().operation, ValidOps::Build);
+        assert_eq!(parse("cfg build 1").err(), WRONG_COUNT);
+    }
+
+    #[cfg(all(feature = "smi", feature = "gpio"))]
+    #[test]
+    fn runtime_interface_gate() {
+        let hr = parse("cfg disable gpio").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Disable, ValidInterfaces::GPIO as u32));
+        assert_eq!(parse("cfg enable smi").unwrap().payload[0], ValidInterfaces::SMI as u32);
+        assert_eq!(parse("cfg disable bogus").err(), Some("Invalid Interface\n\r"));
+        // cfg can not lock itself out
+        assert!(parse("cfg disable cfg").is_err());
+        assert!(parse("cfg enable cfg").is_ok());
+        let mut config = Config::new();
+        assert!(config.check(ValidInterfaces::GPIO).is_ok());
+        config.set_enabled(ValidInterfaces::GPIO, false);
+        assert_eq!(config.check(ValidInterfaces::GPIO).err(), Some("Interface disabled\n\r"));
+        assert!(config.check(ValidInterfaces::SMI).is_ok());
+        config.set_enabled(ValidInterfaces::GPIO, true);
+        assert!(config.check(ValidInterfaces::GPIO).is_ok());
+    }
+
+    #[test]
+    fn bare_hex_prefix() {
+        assert_eq!(bytes_to_number("0x"), Err("Empty numeric literal\n\r"));
+        assert_eq!(bytes_to_number("0X"), Err("Empty numeric literal\n\r"));
+        assert_eq!(bytes_to_number("0xff"), Ok(255));
+        assert_eq!(bytes_to_number("0XFF"), Ok(255));
+        assert_eq!(bytes_to_number("0xg"), Err("Invalid hex character\n\r"));
+        assert_eq!(parse("cfg retry 0x").err(), Some("Empty numeric literal\n\r"));
+    }
+
+    #[test]
+    fn zero_and_leading_zeros() {
+        assert_eq!(bytes_to_number("0"), Ok(0));
+        assert_eq!(bytes_to_number("00"), Ok(0));
+        assert_eq!(bytes_to_number("010"), Ok(10));
+        assert_eq!(bytes_to_number("0 "), Err("Invalid decimal character\n\r"));
+        assert_eq!(bytes_to_number("4294967295"), Ok(u32::MAX));
+        assert_eq!(bytes_to_number("4294967296"), Err("Integer number too large!\n\r"));
+        assert_eq!(parse("cfg retry 0").unwrap().payload[0], 0);
+    }
+
+    #[test]
+    fn cfg_channel() {
+        use crate::config::Channel;
+        assert_eq!(parse("cfg channel data").unwrap().payload[0], 1);
+        assert_eq!(parse("cfg channel control").unwrap().payload[0], 0);
+        assert_eq!(parse("cfg channel both").err(), Some("Channel must be control or data\n\r"));
+        // Only interface results move to the data port
+        let mut config = Config::new();
+        assert_eq!(config.output_channel(ValidInterfaces::SMI), Channel::Control);
+        config.channel = Channel::Data;
+        assert_eq!(config.output_channel(ValidInterfaces::SMI), Channel::Data);
+        assert_eq!(config.output_channel(ValidInterfaces::Config), Channel::Control);
+        assert_eq!(config.output_channel(ValidInterfaces::Stat), Channel::Control);
+    }
+
+    #[cfg(feature = "jtag")]
+    #[test]
+    fn jtag_mem() {
+        let hr = parse("jtag mem 0x20000000").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.size, hr.payload[0]), (ValidInterfaces::JTAG, ValidOps::Mem, 1, 0x2000_0000));
+        assert_eq!(parse("jtag mem 0x20000002").err(), Some("JTAG memory address must be word aligned\n\r"));
+        assert_eq!(parse("jtag mem").err(), WRONG_COUNT);
+        assert!(parse("smi mem 0").is_err());
+    }
+
+    #[test]
+    fn token_length_limit() {
+        assert!(parse("cfg retry 0x00000000000001").is_ok());
+        assert_eq!(parse("cfg retry 0x000000000000001").err(), Some("Token too long\n\r"));
+        assert_eq!(parse("cfg retry 11111111111111111111111111111111").err(), Some("Token too long\n\r"));
+    }
+
+    #[cfg(feature = "uart")]
+    #[test]
+    fn raw_text_is_not_a_token() {
+        let hr = parse("uart wstr aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap();
+        assert_eq!(hr.size, 40);
+    }
+
+    #[test]
+    fn cfg_blockwrite() {
+        assert_eq!(parse("cfg blockwrite on").unwrap().payload[0], 1);
+        assert_eq!(parse("cfg blockwrite off").unwrap().payload[0], 0);
+        assert!(parse("cfg blockwrite maybe").is_err());
+        // Output is dropped rather than waited for until it is turned on
+        assert!(!Config::new().block_write);
+    }
+
+    #[test]
+    fn cfg_usbpoll() {
+        let hr = parse("cfg usbpoll 4").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::UsbPoll, 4));
+        assert!(parse("cfg usbpoll 0").is_ok());
+        assert!(parse("cfg usbpoll 16").is_ok());
+        assert_eq!(parse("cfg usbpoll 17").err(), Some("USB poll count must be 0 to 16\n\r"));
+        assert_eq!(parse("cfg usbpoll").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn cfg_loglevel() {
+        use crate::log::Level;
+        let hr = parse("cfg loglevel debug").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::LogLevel, Level::Debug as u32));
+        assert_eq!(parse("cfg loglevel WARN").unwrap().payload[0], Level::Warn as u32);
+        assert_eq!(parse("cfg loglevel loud").err(), Some("Log level must be error, warn, info or debug\n\r"));
+        assert!(parse("cfg loglevel").is_err());
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_set_and_clr() {
+        let hr = parse("smi set 1 0 0x8000").unwrap();
+        assert_eq!((hr.interface, hr.operation, &hr.payload[..3]), (ValidInterfaces::SMI, ValidOps::Set, &[1, 0, 0x8000][..]));
+        let hr = parse("smi clr 0x1f 4 0x0c00").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..3]), (ValidOps::Clr, &[31, 4, 0x0c00][..]));
+        assert!(parse("smi set 1 0 0xFFFF").is_ok());
+        assert_eq!(parse("smi set 1 0 0x10000").err(), Some("SMI mask must be 16 bits\n\r"));
+        assert_eq!(parse("smi clr 32 0 1").err(), Some("SMI PHY and register address must be 0 to 31\n\r"));
+        assert!(parse("smi clr 1 0").is_err());
+        assert!(parse("smi set 1 0 1 2").is_err());
+    }
+
+    #[test]
+    fn cfg_usbserial() {
+        let hr = parse("cfg usbserial get").unwrap();
+        assert_eq!((hr.operation, hr.size), (ValidOps::UsbSerial, 0));
+        let hr = parse("cfg usbserial set BRIDGE-07").unwrap();
+        assert_eq!((hr.operation, &hr.text[..hr.size as usize]), (ValidOps::UsbSerial, &b"BRIDGE-07"[..]));
+        assert!(parse("cfg usbserial set 0123456789ABCDEF").is_ok());
+        assert_eq!(parse("cfg usbserial set 0123456789ABCDEFG").err(), Some("Token too long\n\r"));
+        assert!(parse("cfg usbserial set").is_err());
+        assert!(parse("cfg usbserial set a b").is_err());
+        assert!(parse("cfg usbserial get x").is_err());
+        assert!(parse("cfg usbserial").is_err());
+    }
+
+    #[test]
+    fn cfg_mirror() {
+        let hr = parse("cfg mirror on 22").unwrap();
+        assert_eq!((hr.operation, hr.size, &hr.payload[..2]), (ValidOps::Mirror, 2, &[1, 22][..]));
+        let hr = parse("cfg mirror off").unwrap();
+        assert_eq!((hr.size, hr.payload[0]), (1, 0));
+        assert_eq!(parse("cfg mirror on 30").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("cfg mirror on").is_err());
+        assert!(parse("cfg mirror off 22").is_err());
+        assert!(parse("cfg mirror maybe 22").is_err());
+    }
+
+    #[test]
+    fn hex_leading_zeros() {
+        assert_eq!(bytes_to_number("0x00000000FF"), Ok(255));
+        assert_eq!(bytes_to_number("0x0000000000"), Ok(0));
+        assert_eq!(bytes_to_number("0xFFFFFFFF"), Ok(u32::MAX));
+        assert_eq!(bytes_to_number("0x1FFFFFFFF"), Err("Integer number too large!\n\r"));
+        assert_eq!(bytes_to_number("0x000100000000"), Err("Integer number too large!\n\r"));
+    }
+
+    #[test]
+    fn stat_resetreason() {
+        let hr = parse("stat resetreason").unwrap();
+        assert_eq!((hr.interface, hr.operation), (ValidInterfaces::Stat, ValidOps::ResetReason));
+        assert_eq!(parse("stat resetreason 1").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn stat_txqueue() {
+        let hr = parse("stat txqueue").unwrap();
+        assert_eq!((hr.interface, hr.operation), (ValidInterfaces::Stat, ValidOps::TxQueue));
+        assert_eq!(parse("stat txqueue 1").err(), WRONG_COUNT);
+        let before = tx_dropped();
+        count_dropped(10);
+        assert_eq!(tx_dropped().wrapping_sub(before), 10);
+        assert!(tx_overflow());
+    }
+
+    #[test]
+    fn cfg_maxline() {
+        assert_eq!(parse("cfg maxline 40").unwrap().payload[0], 40);
+        assert!(parse("cfg maxline 0").is_ok());
+        assert!(parse("cfg maxline 255").is_ok());
+        assert_eq!(parse("cfg maxline 15").err(), Some("Line width must be 0 (off) or 16 to 255\n\r"));
+        assert!(parse("cfg maxline 256").is_err());
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn spi_hex_blob() {
+        let hr = parse("spi xferx DEADBEEF").unwrap();
+        assert_eq!((hr.interface, hr.operation, &hr.text[..hr.size as usize]), (ValidInterfaces::SPI, ValidOps::XferHex, &[0xDE, 0xAD, 0xBE, 0xEF][..]));
+        assert_eq!(parse("spi xferx 00ff7a").unwrap().text[..3], [0x00, 0xFF, 0x7A]);
+        assert_eq!(parse("spi xferx DEADBEE").err(), Some("Hex string must have an even number of digits\n\r"));
+        assert_eq!(parse("spi xferx DEADBEEG").err(), Some("Invalid hex character\n\r"));
+        assert!(parse("spi xferx").is_err());
+        assert!(parse("spi xferx AA BB").is_err());
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn spi_hex_blob_length() {
+        let mut line = [b'A'; 10 + 2 * TEXT_MAX + 2];
+        line[..10].copy_from_slice(b"spi xferx ");
+        let full = str::from_utf8(&line[..10 + 2 * TEXT_MAX]).unwrap();
+        assert_eq!(parse(full).unwrap().size as usize, TEXT_MAX);
+        assert_eq!(parse(str::from_utf8(&line).unwrap()).err(), Some("Too many bytes\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_clock() {
+        let hr = parse("gpio clock 15 1000000").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::Clock, &[15, 1_000_000][..]));
+        let hr = parse("gpio clock 15 off").unwrap();
+        assert_eq!((hr.operation, hr.size, hr.payload[0]), (ValidOps::PwmStop, 1, 15));
+        assert_eq!(parse("gpio clock 15 5").err(), Some("Clock frequency out of range\n\r"));
+        assert_eq!(parse("gpio clock 30 1000").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("gpio clock 15").is_err());
+        assert!(parse("gpio clock 15 off 1").is_err());
+    }
+
+    #[test]
+    fn grammar_lists_every_command() {
+        assert_eq!(parse("cfg grammar").unwrap().operation, ValidOps::Grammar);
+        assert_eq!(parse("cfg grammar 1").err(), WRONG_COUNT);
+        // Fits the buffer cfg grammar formats into
+        let mut buf = [0_u8; 1536];
+        let mut out = Wrapper::new(&mut buf);
+        write_grammar(&mut out).unwrap();
+        let mut lines = out.as_str().split("\n\r");
+        assert_eq!((lines.next(), lines.next()), (Some(""), Some("grammar 1")));
+        for keyword in SYSTEM_KEYWORDS.iter() {
+            assert!(out.as_str().split("\n\r").any(|line| line.strip_prefix("system ") == Some(keyword.lower)));
+        }
+        for entry in INTERFACE_KEYWORDS.iter().filter(|entry| entry.keyword.value.is_enabled()) {
+            for op in entry.ops {
+                assert!(out.as_str().split("\n\r").any(|line| {
+                    let mut words = line.split(' ');
+                    (words.next(), words.next()) == (Some(entry.keyword.lower), Some(op_word(*op)))
+                }));
+            }
+        }
+        assert!(out.as_str().contains("\n\rcfg retry 1 1\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_strobe() {
+        let hr = parse("gpio strobe 5 100 100 10").unwrap();
+        assert_eq!((hr.operation, hr.payload), (ValidOps::Strobe, [5, 100, 100, 10]));
+        assert_eq!(parse("gpio strobe 5 100 100 10001").err(), Some("Strobe cycles must be 1 to 10000\n\r"));
+        assert_eq!(parse("gpio strobe 5 100 100 0").err(), Some("Strobe cycles must be 1 to 10000\n\r"));
+        assert_eq!(parse("gpio strobe 5 0 100 1").err(), Some("Strobe times must be 1 to 1000000 us\n\r"));
+        assert_eq!(parse("gpio strobe 30 1 1 1").err(), Some("Invalid GPIO pin\n\r"));
+        assert!(parse("gpio strobe 5 100 100").is_err());
+        // The whole pattern is bounded too
+        assert!(parse("gpio strobe 2 1000 1000 5000").is_ok());
+        assert_eq!(parse("gpio strobe 2 1000 1001 5000").err(), Some("Strobe pattern must be at most 10 s\n\r"));
+        assert_eq!(parse("gpio strobe 2 1000000 1000000 10000").err(), Some("Strobe pattern must be at most 10 s\n\r"));
+    }
+
+    #[test]
+    fn hex_overflow() {
+        assert_eq!(bytes_to_number("0x80000000"), Ok(0x8000_0000));
+        assert_eq!(bytes_to_number("0x0FFFFFFFF"), Ok(u32::MAX));
+        assert_eq!(bytes_to_number("0xFFFFFFFF1"), Err("Integer number too large!\n\r"));
+        assert_eq!(bytes_to_number("0x100000000"), Err("Integer number too large!\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_measure() {
+        let hr = parse("gpio measure 3 1000").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::Measure, &[3, 1000][..]));
+        assert_eq!(parse("gpio measure 30 1000").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("gpio measure 3 0").err(), Some("Measure timeout must be 1 to 10000 ms\n\r"));
+        assert!(parse("gpio measure 3").is_err());
+    }
+
+    #[test]
+    fn cfg_show() {
+        assert_eq!(parse("cfg show").unwrap().operation, ValidOps::Show);
+        assert_eq!(parse("cfg show all").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn quoted_arguments() {
+        let hr = message_parse_build("cfg usbserial set \"my board\"").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"my board");
+        // Parsed as one word, then refused for the space
+        assert_eq!(parse("cfg usbserial set \"my board\"").err(), Some("USB serial must be 1 to 16 printable ASCII characters\n\r"));
+        let hr = parse("cfg usbserial set \"board1\"").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"board1");
+        assert_eq!(parse("gpio \"drive\" 3 8").unwrap().payload[..2], [3, 8]);
+        assert_eq!(parse("cfg usbserial set \"my board").err(), Some("Unterminated quote\n\r"));
+        assert_eq!(parse("cfg usbserial set \"a\"b").err(), Some("Missing space after quote\n\r"));
+    }
+
+    #[cfg(feature = "uart")]
+    #[test]
+    fn quoted_text() {
+        let hr = parse("uart wstr \" AT \"").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b" AT ");
+        let hr = parse("uart wstr say \"hi\" there").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"say \"hi\" there");
+        assert_eq!(parse("uart wstr \"open").err(), Some("Unterminated quote\n\r"));
+    }
+
+    #[cfg(feature = "uart")]
+    #[test]
+    fn hash_inside_quotes_is_text() {
+        let hr = parse("uart wstr \"a#b\"").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"a#b");
+        let hr = parse("uart wstr \"a#b\" # sent as a#b").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"a#b");
+        let hr = parse("uart wstr a#b").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"a");
+    }
+
+    #[test]
+    fn limits_match_the_build() {
+        assert_eq!(parse("cfg limits").unwrap().operation, ValidOps::Limits);
+        // Fits the buffer cfg limits formats into
+        let mut buf = [0_u8; 96];
+        let mut out = Wrapper::new(&mut buf);
+        write_limits(&mut out).unwrap();
+        let value = |key: &str| out.as_str().split("\n\r")
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .and_then(|value| bytes_to_number(value).ok());
+        assert_eq!(value("payload"), Some(HostRequest::<host::Unclean>::new().payload.len() as u32));
+        assert_eq!(value("line"), Some(LINE_LEN as u32));
+        assert_eq!(value("token"), Some(16));
+        assert_eq!(value("text"), Some(TEXT_MAX as u32));
+        assert!(out.as_str().ends_with("\n\r->"));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_extended_pages() {
+        let hr = parse("smi rpage 1 2 16").unwrap();
+        assert_eq!((hr.operation, hr.size, &hr.payload[..3]), (ValidOps::ReadPage, 3, &[1, 2, 16][..]));
+        let hr = parse("smi wpage 1 0xFF 31 0xBEEF").unwrap();
+        assert_eq!((hr.operation, hr.payload), (ValidOps::WritePage, [1, 0xFF, 31, 0xBEEF]));
+        assert_eq!(parse("smi rpage 1 256 16").err(), Some("SMI page must be 0 to 255\n\r"));
+        assert_eq!(parse("smi rpage 32 2 16").err(), Some("SMI PHY and register address must be 0 to 31\n\r"));
+        assert_eq!(parse("smi wpage 1 2 32 0").err(), Some("SMI PHY and register address must be 0 to 31\n\r"));
+        assert_eq!(parse("smi wpage 1 2 3 0x10000").err(), Some("SMI data must be 16 bits\n\r"));
+        assert_eq!(parse("smi rpage 1 2").err(), WRONG_COUNT);
+        assert_eq!(parse("smi wpage 1 2 3").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn cfg_framing() {
+        let hr = parse("cfg framing length").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Framing, 1));
+        assert_eq!(parse("cfg framing line").unwrap().payload[0], 0);
+        assert_eq!(parse("cfg framing cobs").err(), Some("Framing must be line or length\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_conn() {
+        let hr = parse("gpio conn 2 3").unwrap();
+        assert_eq!((hr.operation, hr.size, &hr.payload[..2]), (ValidOps::Conn, 2, &[2, 3][..]));
+        assert_eq!(parse("gpio conn 4 4").err(), Some("Drive and sense pin must differ\n\r"));
+        assert_eq!(parse("gpio conn 2 30").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("gpio conn 2").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn several_lines_in_one_buffer() {
+        let mut editor: LineEditor = LineEditor::new();
+        let mut retries = [0_u32; 2];
+        let mut lines = 0;
+        for byte in b"cfg retry 1\ncfg retry 2\n" {
+            if editor.feed(*byte) == Edit::Line {
+                retries[lines] = parse(editor.as_str()).unwrap().payload[0];
+                lines += 1;
+                editor.clear();
+            }
+        }
+        assert_eq!((lines, retries), (2, [1, 2]));
+        // CRLF is one terminator, a lone CR or LF each end a line, the last LF an empty one
+        let mut lines = 0;
+        for byte in b"cfg show\r\ncfg limits\rcfg caps\n\n" {
+            if editor.feed(*byte) == Edit::Line {
+                lines += 1;
+                editor.clear();
+            }
+        }
+        assert_eq!(lines, 4);
+    }
+
+    #[test]
+    fn stat_tasks() {
+        assert_eq!(parse("stat tasks").unwrap().operation, ValidOps::Tasks);
+        assert_eq!(parse("stat tasks 1").err(), WRONG_COUNT);
+    }
+
+    #[cfg(all(feature = "smi", feature = "gpio"))]
+    #[test]
+    fn smi_reset() {
+        let hr = parse("smi reset 20 10").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..hr.size as usize]), (ValidOps::Reset, &[20, 10][..]));
+        assert_eq!(parse("smi reset 30 10").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("smi reset 8 10").err(), Some("Reset pin must not be an SMI pin\n\r"));
+        assert_eq!(parse("smi reset 20 0").err(), Some("SMI reset hold must be 1 to 1000 ms\n\r"));
+        assert_eq!(parse("smi reset 20 1001").err(), Some("SMI reset hold must be 1 to 1000 ms\n\r"));
+        assert_eq!(parse("smi reset 20").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn cfg_iface() {
+        let hr = parse("cfg iface smi").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::DefaultInterface, ValidInterfaces::SMI as u32));
+        assert_eq!(parse("cfg iface off").unwrap().payload[0], ValidInterfaces::None as u32);
+        assert_eq!(parse("cfg iface foo").err(), Some("Invalid Interface\n\r"));
+        let mut config = Config::new();
+        config.default_interface = Some(ValidInterfaces::SMI);
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().contains("\n\riface=smi\n\r"));
+    }
+
+    #[cfg(all(feature = "smi", feature = "gpio"))]
+    #[test]
+    fn default_interface() {
+        let with_default = |line| message_parse_build_default(line, Some(ValidInterfaces::SMI),
+            ValidHostInterfaces::Serial, SmiClause::Clause22);
+        let implied = with_default("r 1 16").unwrap().init_clean().unwrap();
+        assert_eq!((implied.interface, implied.payload[0]), (ValidInterfaces::SMI, parse("smi r 1 16").unwrap().payload[0]));
+        let explicit = with_default("gpio drive 3 8").unwrap().init_clean().unwrap();
+        assert_eq!((explicit.interface, explicit.operation), (ValidInterfaces::GPIO, ValidOps::Drive));
+        assert!(with_default("time r 1 16").unwrap().timed);
+        assert_eq!(with_default("drive 3 8").err(), Some("Invalid Operation for interface\n\r"));
+        // Without cfg iface the interface can not be left out
+        assert_eq!(parse("r 1 16").err(), Some("Invalid Interface\n\r"));
+    }
+
+    #[test]
+    fn cfg_ratelimit() {
+        let hr = parse("cfg ratelimit 50").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::RateLimit, 50));
+        assert_eq!(parse("cfg ratelimit 0").unwrap().payload[0], 0);
+        assert_eq!(parse("cfg ratelimit 1001").err(), Some("Rate limit must be 0 (off) to 1000 Hz\n\r"));
+    }
+
+    #[test]
+    fn cfg_flash() {
+        assert_eq!(parse("cfg flash").unwrap().operation, ValidOps::Flash);
+        assert_eq!(parse("cfg flash 1").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn three_escapes_resync() {
+        let mut resync = Resync::new();
+        // A single ESC (arrow key) and two in a row do not count
+        let fired = b"\x1b[A\x1b\x1bx\x1b\x1b\x1b".iter().filter(|byte| resync.feed(**byte)).count();
+        assert_eq!(fired, 1);
+        // The count starts over after the sequence
+        assert!(!resync.feed(0x1B));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_pins() {
+        let hr = parse("smi pins 14 15").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..hr.size as usize]), (ValidOps::Pins, &[14, 15][..]));
+        assert_eq!(parse("smi pins 14 14").err(), Some("MDC and MDIO pin must differ\n\r"));
+        assert_eq!(parse("smi pins 14 30").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("smi pins 14").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_scanmap() {
+        assert_eq!(parse("smi scanmap").unwrap().operation, ValidOps::ScanMap);
+        assert_eq!(parse("smi scanmap 1").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn stat_parser() {
+        assert_eq!(parse("stat parser").unwrap().operation, ValidOps::Parser);
+        assert_eq!(parse("stat parser 1").err(), WRONG_COUNT);
+        let names = [(ReaderState::Idle, "idle"), (ReaderState::Accumulating, "accumulating"),
+            (ReaderState::InQuote, "in-quote"), (ReaderState::Escape, "escape")];
+        for (state, name) in names {
+            assert_eq!(state.name(), name);
+        }
+    }
+
+    #[test]
+    fn reader_state_follows_the_line() {
+        let mut editor: LineEditor = LineEditor::new();
+        assert_eq!((editor.state(), editor.len()), (ReaderState::Idle, 0));
+        for byte in b"uart wstr \"a" {
+            editor.feed(*byte);
+        }
+        assert_eq!((editor.state(), editor.len()), (ReaderState::InQuote, 12));
+        editor.feed(b'"');
+        assert_eq!(editor.state(), ReaderState::Accumulating);
+        editor.feed(0x1B);
+        assert_eq!(editor.state(), ReaderState::Escape);
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn empty_line_repeats_the_last_command() {
+        let mut last = LastCommand::new();
+        assert_eq!(last.line("", message_parse_build).err(), Some(EMPTY_COMMAND));
+        assert_eq!(last.line("gpio drive 3 8", message_parse_build).unwrap().operation, ValidOps::Drive);
+        let again = last.line("", message_parse_build).unwrap().init_clean().unwrap();
+        assert_eq!((again.interface, again.operation, &again.payload[..2]), (ValidInterfaces::GPIO, ValidOps::Drive, &[3, 8][..]));
+        // A failed parse keeps the previous command
+        assert!(last.line("gpio bogus", message_parse_build).is_err());
+        assert_eq!(last.line("  ", message_parse_build).unwrap().operation, ValidOps::Drive);
+        // Streams and the batch controls are not repeated
+        for line in ["adc stream 0 1000 10", "begin", "end", "pause", "resume", "estop"] {
+            let _ = last.line(line, message_parse_build);
+            assert_eq!(last.line("", message_parse_build).unwrap().operation, ValidOps::Drive);
+        }
+    }
+
+    #[test]
+    fn cfg_endian() {
+        let hr = parse("cfg endian big").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Endian, 1));
+        assert_eq!(parse("cfg endian little").unwrap().payload[0], 0);
+        assert_eq!(parse("cfg endian middle").err(), Some("Endian must be little or big\n\r"));
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn spi_read_words() {
+        assert_eq!(parse("spi r 4").unwrap().payload[0], 4);
+        assert_eq!(parse("spi r 5").err(), Some("SPI read must be 1 to 4 words\n\r"));
+        assert_eq!(parse("spi r 0").err(), Some("SPI read must be 1 to 4 words\n\r"));
+        assert_eq!(parse("spi w 1 2 3").unwrap().size, 3);
+    }
+
+    #[cfg(feature = "i2c")]
+    #[test]
+    fn i2c_dump() {
+        let hr = parse("i2c dump 0x50 0 16").unwrap();
+        assert_eq!((hr.interface, hr.operation, &hr.payload[..3]), (ValidInterfaces::I2C, ValidOps::Dump, &[0x50, 0, 16][..]));
+        assert_eq!(parse("i2c dump 0x80 0 16").err(), Some("I2C address must be 0 to 0x7F\n\r"));
+        assert_eq!(parse("i2c dump 0x50 0x100 1").err(), Some("I2C register must be 0 to 0xFF\n\r"));
+        assert_eq!(parse("i2c dump 0x50 0 33").err(), Some("I2C dump count must be 1 to 32\n\r"));
+        assert_eq!(parse("i2c dump 0x50 0 0").err(), Some("I2C dump count must be 1 to 32\n\r"));
+        assert!(parse("i2c dump 0x50 0").is_err());
+    }
+
+    #[cfg(feature = "i2c")]
+    #[test]
+    fn i2c_speed() {
+        let hr = parse("i2c cfg 400000").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.payload[0]), (ValidInterfaces::I2C, ValidOps::Speed, 400_000));
+        assert!(parse("i2c cfg 100000").is_ok() && parse("i2c cfg 1000000").is_ok());
+        assert_eq!(parse("i2c cfg 250000").err(), Some("I2C speed must be 100000, 400000 or 1000000\n\r"));
+        // cfg still names the config interface at the start of a line
+        assert_eq!(parse("cfg show").unwrap().interface, ValidInterfaces::Config);
+    }
+
+    #[cfg(feature = "i2c")]
+    #[test]
+    fn i2c_scan() {
+        let hr = parse("i2c scan").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.size), (ValidInterfaces::I2C, ValidOps::Scan, 0));
+        assert_eq!(parse("i2c scan 1").err(), Some("Invalid Arguments for I2C: Scan\n\r"));
+        // adc scan still needs its channels
+        assert!(parse("adc scan").is_err());
+        assert!(parse("adc scan 0 4").is_ok());
+    }
+
+    #[test]
+    fn colliding_keywords_caught() {
+        // Upper case spellings count as well
+        let interfaces = [InterfaceKeyword { keyword: Keyword { lower: "cfg", upper: "CFG", value: ValidInterfaces::Config }, ops: &[] }];
+        let clash = [Keyword { lower: "cfgx", upper: "CFG", value: ValidOps::Read }];
+        assert!(interfaces_collide(&interfaces, &clash));
+        assert!(!interfaces_collide(&interfaces, &clash[..0]));
+        let clash = [Keyword { lower: "sync", upper: "SYNC", value: ValidOps::Read }];
+        assert!(tables_collide(&clash, &SYSTEM_KEYWORDS));
+        assert!(!tables_collide(&clash[..0], &SYSTEM_KEYWORDS));
+    }
+
+    #[cfg(feature = "i2c")]
+    #[test]
+    fn i2c_cfg_alias() {
+        // i2c cfg stays as another name for i2c speed, only on i2c
+        assert_eq!(parse("i2c speed 400000").unwrap().operation, ValidOps::Speed);
+        assert_eq!(parse("i2c cfg 400000").unwrap().operation, ValidOps::Speed);
+        assert!(parse("adc cfg 1").is_err());
+    }
+
+    #[test]
+    fn cfg_led() {
+        let hr = parse("cfg led on").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.payload[0]), (ValidInterfaces::Config, ValidOps::Indicator, 1));
+        assert_eq!(parse("cfg led off").unwrap().payload[0], 0);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_link() {
+        let hr = parse("smi link 1").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.payload[0]), (ValidInterfaces::SMI, ValidOps::Link, 1));
+        assert_eq!(parse("smi link 32").err(), Some("SMI PHY address must be 0 to 31\n\r"));
+        assert_eq!(parse("smi link").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn cfg_retry() {
+        let hr = parse("cfg retry 3").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Retry, 3));
+        assert_eq!(parse("cfg retry 6").err(), Some("Retry count must be 0 to 5\n\r"));
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_whois() {
+        let hr = parse("smi whois 3").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Whois, 3));
+        assert_eq!(parse("smi whois 32").err(), Some("SMI PHY address must be 0 to 31\n\r"));
+        assert_eq!(parse("smi whois").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn cfg_slowdown() {
+        let hr = parse("cfg slowdown 250").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Slowdown, 250));
+        assert_eq!(parse("cfg slowdown 1000").unwrap().payload[0], 1000);
+        assert_eq!(parse("cfg slowdown 1001").err(), Some("Slowdown must be 0 (off) to 1000 us\n\r"));
+        assert_eq!(parse("cfg slowdown").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn history_listed_oldest_first() {
+        let hr = parse("history").unwrap();
+        assert_eq!((hr.interface, hr.operation), (ValidInterfaces::Config, ValidOps::History));
+        let mut editor: LineEditor = LineEditor::new();
+        let mut buf = [0_u8; HISTORY_ANSWER_LEN];
+        let mut out = Wrapper::new(&mut buf);
+        editor.write_history(&mut out).unwrap();
+        assert_eq!(out.as_str(), "\n\rHistory empty\n\r->");
+        // Empty lines are not kept
+        for line in ["cfg retry 1\r", "\r", "cfg retry 2\r"] {
+            feed_line(&mut editor, line);
+            editor.clear();
+        }
+        let mut buf = [0_u8; HISTORY_ANSWER_LEN];
+        let mut out = Wrapper::new(&mut buf);
+        editor.write_history(&mut out).unwrap();
+        assert_eq!(out.as_str(), "\n\rcfg retry 1\n\rcfg retry 2\n\r->");
+        // The oldest lines make room
+        for line in ["cfg retry 3\r", "cfg retry 4\r", "cfg retry 5\r", "cfg retry 0\r", "cfg autonl on\r",
+            "cfg autonl off\r", "cfg led on\r", "cfg led off\r"] {
+            feed_line(&mut editor, line);
+            editor.clear();
+        }
+        let mut buf = [0_u8; HISTORY_ANSWER_LEN];
+        let mut out = Wrapper::new(&mut buf);
+        editor.write_history(&mut out).unwrap();
+        let mut lines = out.as_str().split("\n\r");
+        assert_eq!((lines.nth(1), lines.clone().count()), (Some("cfg retry 3"), HISTORY_LEN));
+        assert_eq!(lines.nth(HISTORY_LEN - 2), Some("cfg led off"));
+    }
+
+    #[test]
+    fn full_history_fits_its_answer() {
+        let mut editor: LineEditor = LineEditor::new();
+        for i in 0..HISTORY_LEN {
+            for _ in 0..LINE_LEN {
+                editor.feed(b'a' + i as u8);
+            }
+            editor.feed(b'\r');
+            editor.clear();
+        }
+        // Whatever cfg maxline wraps at, 0 is no wrapping
+        for max_line in [0, MAX_LINE_MIN as usize, MAX_LINE_MIN as usize + 1, 40] {
+            let mut buf = [0_u8; HISTORY_ANSWER_LEN];
+            let mut out = Wrapper::with_max_line(&mut buf, max_line);
+            assert!(editor.write_history(&mut out).is_ok());
+            assert!(out.as_str().ends_with("->"));
+        }
+    }
+
+    #[test]
+    fn adc_stream() {
+        let hr = parse("adc stream 0 1000 100").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..3]), (ValidOps::Stream, &[0, 1000, 100][..]));
+        assert_eq!(parse("adc stream 5 1000 100").err(), Some("Invalid ADC channel\n\r"));
+        assert_eq!(parse("adc stream 4 0 100").err(), Some("ADC stream rate must be 1 to 100000 Hz\n\r"));
+        assert_eq!(parse("adc stream 4 1000 257").err(), Some("ADC stream count must be 1 to 256\n\r"));
+        assert_eq!(parse("adc stream 4 10 101").err(), Some("ADC stream must not last over 10 s\n\r"));
+        assert!(parse("adc stream 4 10 100").is_ok());
+        assert!(parse("adc stream 0 1000").is_err());
+    }
+
+    #[test]
+    fn cfg_strict() {
+        let hr = parse("cfg strict off").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Strict, 0));
+        assert_eq!(parse("cfg strict on").unwrap().payload[0], 1);
+        assert!(parse("cfg strict maybe").is_err());
+    }
+
+    #[test]
+    fn lenient_drops_unknown_lines() {
+        assert_eq!(unknown_line(message_parse_build("xyzzy 1 2"), true).err(), Some("Invalid Interface\n\r"));
+        assert_eq!(unknown_line(message_parse_build("xyzzy 1 2"), false).err(), Some("Ok"));
+        assert!(unknown_line(message_parse_build("cfg retry 1"), false).is_ok());
+        // Parsed but out of range is still an error
+        let out_of_range = unknown_line(message_parse_build("cfg retry 9"), false).and_then(|hr| hr.init_clean());
+        assert_eq!(out_of_range.err(), Some("Retry count must be 0 to 5\n\r"));
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_threshold() {
+        // The threshold defaults to half the supply
+        let hr = parse("gpio threshold 26").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..hr.size as usize]), (ValidOps::Threshold, &[26, 1650][..]));
+        assert_eq!(parse("gpio threshold 29 500").unwrap().payload[1], 500);
+        assert_eq!(parse("gpio threshold 25").err(), Some("GPIO has no analog input, use 26 to 29\n\r"));
+        assert_eq!(parse("gpio threshold 30").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("gpio threshold 27 3301").err(), Some("Threshold must be 0 to 3300 mV\n\r"));
+    }
+
+    #[test]
+    fn cfg_timestamp() {
+        let hr = parse("cfg timestamp on").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Timestamp, 1));
+        assert_eq!(parse("cfg timestamp off").unwrap().payload[0], 0);
+        assert_eq!(parse("cfg timestamp").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "lcd")]
+    #[test]
+    fn lcd_init_and_clear() {
+        let hr = parse("lcd init 16 2").unwrap();
+        assert_eq!((hr.interface, hr.operation, &hr.payload[..2]), (ValidInterfaces::LCD, ValidOps::Init, &[16, 2][..]));
+        assert!(parse("lcd init 20 4").is_ok() && parse("lcd init 40 2").is_ok());
+        assert!(parse("lcd init 40 4").is_err());
+        assert!(parse("lcd init 41 1").is_err());
+        assert!(parse("lcd init 16 0").is_err());
+        let hr = parse("lcd clear").unwrap();
+        assert_eq!((hr.operation, hr.size), (ValidOps::Clear, 0));
+        assert!(parse("lcd clear 1").is_err());
+        assert!(parse("lcd r 0").is_err());
+    }
+
+    #[cfg(feature = "lcd")]
+    #[test]
+    fn lcd_write() {
+        let hr = parse("lcd write 1 3 Link up  100M").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..2]), (ValidOps::WriteAt, &[1, 3][..]));
+        assert_eq!(&hr.text[..hr.size as usize], b"Link up  100M");
+        let hr = parse("lcd write 0 0 \"ok  \"").unwrap();
+        assert_eq!(&hr.text[..hr.size as usize], b"ok  ");
+        assert_eq!(parse("lcd write 4 0 x").err(), Some("LCD position must be row 0 to 3, column 0 to 39\n\r"));
+        assert_eq!(parse("lcd write 0 40 x").err(), Some("LCD position must be row 0 to 3, column 0 to 39\n\r"));
+        assert!(parse("lcd write 0 0").is_err());
+        assert!(parse("lcd write 0").is_err());
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_read_fields() {
+        let hr = parse("smi r 1 1 fields").unwrap();
+        assert_eq!((hr.operation, hr.fields, &hr.payload[..hr.size as usize]), (ValidOps::Read, true, &[1, 1][..]));
+        let hr = parse("smi r 1 1").unwrap();
+        assert_eq!((hr.fields, hr.size), (false, 1));
+        assert!(parse("smi r 1 1 fields fields").is_err());
+        assert!(parse("smi r 1 32 fields").is_err());
+        assert!(parse("smi r fields").is_err());
+        assert!(parse("smi w 1 1 fields").is_err());
+    }
+
+    #[test]
+    fn ping_echoes_its_payload() {
+        assert_eq!(ping_payload("ping 12345"), Some("12345"));
+        assert_eq!(ping_payload("PING"), Some(""));
+        assert_eq!(ping_payload("  ping  a b \r"), Some("a b"));
+        assert_eq!(ping_payload("pingx"), None);
+        assert_eq!(ping_payload("cfg show"), None);
+        let mut buf = [0_u8; 80];
+        let mut out = Wrapper::new(&mut buf);
+        write_pong(&mut out, ping_payload("ping 12345").unwrap()).unwrap();
+        assert_eq!(out.as_str(), "\n\rpong 12345\n\r->");
+        let mut buf = [0_u8; 80];
+        let mut out = Wrapper::new(&mut buf);
+        write_pong(&mut out, "").unwrap();
+        assert_eq!(out.as_str(), "\n\rpong\n\r->");
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn gpio_freq() {
+        let hr = parse("gpio freq 7 100").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..hr.size as usize]), (ValidOps::Freq, &[7, 100][..]));
+        assert_eq!(parse("gpio freq 6 100").err(), Some("Frequency is counted on odd GPIO pins only\n\r"));
+        assert_eq!(parse("gpio freq 7 0").err(), Some("Gate must be 1 to 10000 ms\n\r"));
+        assert_eq!(parse("gpio freq 7 10001").err(), Some("Gate must be 1 to 10000 ms\n\r"));
+        assert_eq!(parse("gpio freq 31 10").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("gpio freq 7").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "smi")]
+    #[test]
+    fn smi_clause_45() {
+        use crate::protocol::{encode_smi, encode_smi45, Smi45Op};
+        let hr = parse("smi clause 45").unwrap();
+        assert_eq!((hr.operation, hr.payload[0]), (ValidOps::Clause, 45));
+        assert_eq!(parse("smi clause 22").unwrap().payload[0], 22);
+        assert_eq!(parse("smi clause 30").err(), Some("SMI clause must be 22 or 45\n\r"));
+        // Plain smi r keeps Clause 22 by default
+        let hr = parse("smi r 5 1").unwrap();
+        assert_eq!((hr.size, hr.payload[0]), (1, encode_smi(true, 5, 1, 0)));
+        let clause45 = |line| message_parse_build_default(line, None, ValidHostInterfaces::Serial, SmiClause::Clause45)
+            .and_then(|hr| hr.init_clean());
+        let hr = clause45("smi r 5 1 0x7").unwrap();
+        assert_eq!(&hr.payload[..hr.size as usize], &[encode_smi45(Smi45Op::Address, 5, 1, 7), encode_smi45(Smi45Op::Read, 5, 1, 0)][..]);
+        let hr = clause45("smi w 5 1 0x7 0x1234").unwrap();
+        assert_eq!(&hr.payload[..2], &[encode_smi45(Smi45Op::Address, 5, 1, 7), encode_smi45(Smi45Op::Write, 5, 1, 0x1234)][..]);
+        // The register can not be left out
+        assert_eq!(clause45("smi r 5 1").err(), WRONG_COUNT);
+        assert_eq!(clause45("smi r 5 32 1").err(), Some("SMI port and device address must be 0 to 31\n\r"));
+        assert_eq!(clause45("smi w 5 1 0x10000 1").err(), Some("SMI register and data must be 16 bit\n\r"));
+        let mut config = Config::new();
+        config.smi_clause = SmiClause::Clause45;
+        let mut buf = [0_u8; 512];
+        let mut out = Wrapper::new(&mut buf);
+        config.write_settings(&mut out).unwrap();
+        assert!(out.as_str().contains("\n\rclause=45\n\r"));
+    }
+
+    #[cfg(all(feature = "spi", feature = "smi"))]
+    #[test]
+    fn last_transfer() {
+        assert_eq!(parse("spi last").unwrap().operation, ValidOps::Last);
+        assert_eq!(parse("smi last").unwrap().operation, ValidOps::Last);
+        assert_eq!(parse("spi last 1").err(), WRONG_COUNT);
+    }
+
+    #[cfg(feature = "gpio")]
+    #[test]
+    fn cfg_safestate() {
+        use crate::safestate::LEVEL_OFF;
+        let hr = parse("cfg safestate 5 high").unwrap();
+        assert_eq!((hr.operation, &hr.payload[..hr.size as usize]), (ValidOps::SafeState, &[5, 1][..]));
+        assert_eq!(parse("cfg safestate 6 0").unwrap().payload[1], 0);
+        assert_eq!(parse("cfg safestate 6 low").unwrap().payload[1], 0);
+        assert_eq!(parse("cfg safestate 6 off").unwrap().payload[1], LEVEL_OFF);
+        assert_eq!(parse("cfg safestate 6 2").err(), Some("Level must be high, low or off\n\r"));
+        assert_eq!(parse("cfg safestate 30 1").err(), Some("Invalid GPIO pin\n\r"));
+        assert_eq!(parse("cfg safestate 6").err(), WRONG_COUNT);
+    }
+
+    #[cfg(all(feature = "spi", feature = "i2c"))]
+    #[test]
+    fn spi_flashid() {
+        let hr = parse("spi flashid").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.size), (ValidInterfaces::SPI, ValidOps::FlashId, 0));
+        assert_eq!(parse("spi flashid 1").err(), WRONG_COUNT);
+        assert!(parse("i2c flashid").is_err());
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn larger_editor_keeps_a_long_line() {
+        let text = "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcd";
+        let mut line = [0_u8; 128];
+        let mut out = Wrapper::new(&mut line);
+        write!(out, "spi wstr \"{}\" # long line with a comment after it", text).unwrap();
+        let line = out.as_str();
+        assert!(line.len() > LINE_LEN);
+        // The default editor stops short of the line, a larger one keeps it whole
+        let mut small: LineEditor = LineEditor::new();
+        assert_eq!(small.capacity(), LINE_LEN);
+        feed_line(&mut small, line);
+        assert_ne!(small.as_str(), line);
+        let mut big = LineEditor::<256>::new();
+        for byte in line.bytes() {
+            assert_eq!(big.feed(byte), Edit::Echo);
+        }
+        assert_eq!(big.feed(b'\r'), Edit::Line);
+        assert_eq!((big.as_str(), big.capacity()), (line, 256));
+        let hr = parse(big.as_str()).unwrap();
+        assert_eq!((hr.operation, &hr.text[..hr.size as usize]), (ValidOps::WriteStr, text.as_bytes()));
+    }
+
+    #[test]
+    fn pong_holds_a_default_line() {
+        let payload = [b'x'; LINE_LEN];
+        // The reply buffer of match_usb_serial_buf
+        let mut reply = [0_u8; LINE_LEN + 16];
+        let mut out = Wrapper::new(&mut reply);
+        assert!(write_pong(&mut out, str::from_utf8(&payload).unwrap()).is_ok());
+        // A longer editor line is refused
+        let longer = [b'x'; LINE_LEN + 16];
+        let mut reply = [0_u8; LINE_LEN + 16];
+        let mut out = Wrapper::new(&mut reply);
+        assert!(write_pong(&mut out, str::from_utf8(&longer).unwrap()).is_err());
+    }
+
+    #[test]
+    fn truncation_marker_framed_on_its_own() {
+        // Under cfg framing length the marker goes out as a frame of its own
+        assert_eq!(length_prefix(TRUNCATED_MARKER.as_bytes()), [0x14, 0]);
+        assert!(TRUNCATED_MARKER.ends_with("\n\r"));
+    }
+
+    #[test]
+    fn cfg_addr() {
+        let hr = parse("cfg addr 3").unwrap();
+        assert_eq!((hr.interface, hr.operation, hr.payload[0]), (ValidInterfaces::Config, ValidOps::Address, 3));
+        assert_eq!(parse("cfg addr off").unwrap().payload[0], ADDR_OFF);
+        assert_eq!(parse("cfg addr 0x10").unwrap().payload[0], 16);
+        assert_eq!(parse("cfg addr 300").err(), Some("Address must be 0 to 254 or off\n\r"));
+        assert_eq!(parse("cfg addr").err(), WRONG_COUNT);
+        assert_eq!(parse("cfg addr 1 2").err(), WRONG_COUNT);
+    }
+
+    #[test]
+    fn addressed_lines() {
+        // Matching address, the prefix is stripped before the line is parsed
+        assert_eq!(addressed_line(">3 cfg retry 1", Some(3)), Some("cfg retry 1"));
+        assert_eq!(addressed_line("  >0x3   cfg retry 1", Some(3)), Some("cfg retry 1"));
+        assert_eq!(parse(addressed_line(">3 cfg retry 1", Some(3)).unwrap()).unwrap().payload[0], 1);
+        assert_eq!(addressed_line(">3", Some(3)), Some(""));
+        // Not this bridge, or no prefix at all once addressed: dropped
+        assert_eq!(addressed_line(">4 cfg retry 1", Some(3)), None);
+        assert_eq!(addressed_line("cfg retry 1", Some(3)), None);
+        assert_eq!(addressed_line(">x cfg retry 1", Some(3)), None);
+        assert_eq!(addressed_line("", Some(3)), None);
+        // Broadcast reaches every bridge
+        assert_eq!(addressed_line(">* cfg led on", Some(3)), Some("cfg led on"));
+        assert_eq!(addressed_line(">* cfg led on", Some(200)), Some("cfg led on"));
+        assert_eq!(addressed_line(">* cfg led on", None), Some("cfg led on"));
+        // Addressing off takes every line, a prefix is stripped if it has one
+        assert_eq!(addressed_line("cfg retry 1", None), Some("cfg retry 1"));
+        assert_eq!(addressed_line(">7 cfg retry 1", None), Some("cfg retry 1"));
+        assert_eq!(addressed_line(">x cfg retry 1", None), Some(">x cfg retry 1"));
+    }
+}
@@ -0,0 +1,61 @@
+//! USB serial number string, for `cfg usbserial`.
+//! A new serial is kept in watchdog scratch registers 0-3, which survive a
+//! soft or watchdog reset but not a power cycle. init reads it back before the
+//! USB descriptors are built; scratch 4-7 are left to the boot ROM.
+
+// WATCHDOG SCRATCH0, the first of the four words used
+const WATCHDOG_SCRATCH0: u32 = 0x4005_800c;
+const SCRATCH_WORDS: usize = 4;
+
+// Longest serial, the bytes of the scratch words
+pub const SERIAL_NUMBER_MAX: usize = SCRATCH_WORDS * 4;
+// Reported until a serial is set, and after a power cycle
+pub const SERIAL_NUMBER_DEFAULT: &str = "TEST";
+
+// A serial is shown in udev rules and shells, so only printable ASCII without spaces
+pub fn validate(serial: &[u8]) -> Result<(), &'static str> {
+    if serial.is_empty() || serial.len() > SERIAL_NUMBER_MAX
+        || !serial.iter().all(|byte| (0x21..=0x7E).contains(byte)) {
+        return Err("USB serial must be 1 to 16 printable ASCII characters\n\r")
+    }
+    Ok(())
+}
+
+// Keep a validated serial for the next reset, zero padded
+pub fn store(serial: &[u8]) {
+    let mut bytes = [0_u8; SERIAL_NUMBER_MAX];
+    bytes[..serial.len()].copy_from_slice(serial);
+    for (i, word) in bytes.chunks(4).enumerate() {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        unsafe { core::ptr::write_volatile((WATCHDOG_SCRATCH0 + 4 * i as u32) as *mut u32, word) }
+    }
+}
+
+// Serial the next reset will report, the default if none was stored
+pub fn load(buf: &mut [u8; SERIAL_NUMBER_MAX]) -> &str {
+    for i in 0..SCRATCH_WORDS {
+        let word = unsafe { core::ptr::read_volatile((WATCHDOG_SCRATCH0 + 4 * i as u32) as *const u32) };
+        buf[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(SERIAL_NUMBER_MAX);
+    // Scratch registers also hold whatever other firmware left there
+    match validate(&buf[..len]) {
+        Ok(()) => core::str::from_utf8(&buf[..len]).unwrap_or(SERIAL_NUMBER_DEFAULT),
+        Err(_) => SERIAL_NUMBER_DEFAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printable_ascii_only() {
+        assert!(validate(b"BRIDGE-07").is_ok());
+        assert!(validate(&[b'A'; SERIAL_NUMBER_MAX]).is_ok());
+        assert_eq!(validate(b""), Err("USB serial must be 1 to 16 printable ASCII characters\n\r"));
+        assert!(validate(b"a b").is_err());
+        assert!(validate("bridg\u{e9}".as_bytes()).is_err());
+        assert!(validate(&[b'A'; SERIAL_NUMBER_MAX + 1]).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//! Blocking SMI transactions on the PIO state machine, for commands that need
+//! a register value back inside the dispatcher instead of through PIO0_IRQ_0
+
+use rp_pico::hal::pio::{Rx, Tx, ValidStateMachine};
+use crate::protocol::{encode_smi, SMI_MAX_ADDR};
+use core::fmt;
+
+// Polls of the RX FIFO before a read is given up, a frame is 64 MDC cycles
+const SMI_READ_TIMEOUT: u32 = 100_000;
+
+// PIO0 instruction memory, one 16 bit instruction per 32 bit register
+const PIO0_INSTR_MEM0: u32 = 0x5020_0048;
+// Program relative index of "set x, 31", the preamble bit counter, and of the
+// first instruction after the preamble loop
+const PREAMBLE_COUNT_INSTR: u8 = 3;
+const PREAMBLE_END_INSTR: u8 = 7;
+// "set x, <n> side 0" and "jmp <addr> side 0" with no delay
+const SET_X: u16 = 0xE020;
+const JMP: u16 = 0x0000;
+
+// Pins the SMI program drives from init until smi pins moves them
+pub const SMI_MDIO_PIN: u8 = 8;
+pub const SMI_MDC_PIN: u8 = 9;
+
+// PIO0 state machine 0 PINCTRL and INSTR (forced execution)
+const PIO0_SM0_PINCTRL: *mut u32 = 0x5020_00dc as *mut u32;
+const PIO0_SM0_INSTR: *mut u32 = 0x5020_00d8 as *mut u32;
+const PINCTRL_SET_BASE_SHIFT: u32 = 5;
+const PINCTRL_SIDESET_BASE_SHIFT: u32 = 10;
+const PINCTRL_IN_BASE_SHIFT: u32 = 15;
+// OUT_BASE, SET_BASE, SIDESET_BASE and IN_BASE, the counts are kept
+const PINCTRL_BASES_MASK: u32 = 0xF_FFFF;
+// "set pindirs, 1 side 0"
+const SET_PINDIRS_1: u32 = 0xE081;
+
+// IO_BANK0 GPIO0_CTRL, each pin has a status and a control register
+const IO_BANK0_GPIO0_CTRL: u32 = 0x4001_4004;
+const FUNCSEL_PIO0: u32 = 6;
+const FUNCSEL_NULL: u32 = 0x1f;
+
+// Longest reset pulse of smi reset, and how long the PHY gets after it
+// before the scan (strap latching and PLL lock take a few ms on most PHYs)
+pub const SMI_RESET_MAX_MS: u32 = 1000;
+pub const SMI_RESET_SETTLE_MS: u32 = 50;
+
+// PHY Identifier 1 and 2 (Clause 22)
+const PHY_ID1_REG: u8 = 2;
+const PHY_ID2_REG: u8 = 3;
+
+// Default MDIO preamble, 32 ones as IEEE 802.3 clause 22 asks for
+pub const SMI_PREAMBLE_DEFAULT: u8 = 32;
+pub const SMI_PREAMBLE_MAX: u8 = 32;
+
+// Change the number of preamble bits sent before each frame by patching the
+// counter of the installed SMI program. A count of 0 jumps over the preamble.
+// Only call between frames: the state machine is then stalled on its pull
+pub fn set_preamble(program_offset: u8, count: u8) {
+    let instr = if count == 0 {
+        JMP | (program_offset + PREAMBLE_END_INSTR) as u16
+    } else {
+        // The loop runs x + 1 times
+        SET_X | (count - 1) as u16
+    };
+    let reg = PIO0_INSTR_MEM0 + 4 * (program_offset + PREAMBLE_COUNT_INSTR) as u32;
+    unsafe { core::ptr::write_volatile(reg as *mut u32, instr as u32) }
+}
+
+fn set_function(pin: u8, funcsel: u32) {
+    unsafe { core::ptr::write_volatile((IO_BANK0_GPIO0_CTRL + 8 * pin as u32) as *mut u32, funcsel) }
+}
+
+fn pinctrl_bases(mdc: u8, set_base: u8, mdio: u8) -> u32 {
+    mdio as u32
+        | (set_base as u32) << PINCTRL_SET_BASE_SHIFT
+        | (mdc as u32) << PINCTRL_SIDESET_BASE_SHIFT
+        | (mdio as u32) << PINCTRL_IN_BASE_SHIFT
+}
+
+// Move MDC and MDIO from the old (mdc, mdio) pair to the new one. MDC is
+// made an output by pointing the set pins at it for one forced instruction,
+// the program drives the MDIO direction itself. Only call between frames,
+// like set_preamble. The old pins are left floating
+pub fn set_pins(old: (u8, u8), new: (u8, u8)) {
+    let (mdc, mdio) = new;
+    unsafe {
+        let counts = core::ptr::read_volatile(PIO0_SM0_PINCTRL) & !PINCTRL_BASES_MASK;
+        core::ptr::write_volatile(PIO0_SM0_PINCTRL, counts | pinctrl_bases(mdc, mdc, mdio));
+        core::ptr::write_volatile(PIO0_SM0_INSTR, SET_PINDIRS_1);
+        core::ptr::write_volatile(PIO0_SM0_PINCTRL, counts | pinctrl_bases(mdc, mdio, mdio));
+    }
+    set_function(old.0, FUNCSEL_NULL);
+    set_function(old.1, FUNCSEL_NULL);
+    set_function(mdc, FUNCSEL_PIO0);
+    set_function(mdio, FUNCSEL_PIO0);
+}
+
+// A read pushes the second turnaround bit above the 16 data bits
+const READ_TURNAROUND_BIT: u32 = 1 << 16;
+
+// The PHY drives the second turnaround bit of a read low. Left high by the
+// bus pull-up, no PHY answered and the data bits are not a register value
+pub fn check_turnaround(word: u32) -> Result<u16, &'static str> {
+    if word & READ_TURNAROUND_BIT != 0 {
+        return Err("SMI read error: no turnaround\n\r")
+    }
+    Ok(word as u16)
+}
+
+// OR in the set bits and take out the clear bits of a register value
+pub fn apply_mask(value: u16, set: u16, clear: u16) -> u16 {
+    (value | set) & !clear
+}
+
+// Read a PHY register, an error if the state machine never pushed a result
+// or the PHY did not drive the turnaround
+pub fn read_blocking<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8, reg: u8) -> Result<u16, &'static str> {
+    // Drop anything left over from an earlier transaction
+    while rx.read().is_some() {}
+    tx.write(encode_smi(true, phy, reg, 0));
+    for _ in 0..SMI_READ_TIMEOUT {
+        if let Some(word) = rx.read() {
+            return check_turnaround(word)
+        }
+    }
+    Err("SMI read timeout\n\r")
+}
+
+// 32 bit identifier (ID1 high) of the PHY at an address, None if nothing
+// answers there: the read fails the turnaround or reads all ones
+pub fn read_phy_id<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8) -> Option<u32> {
+    let id1 = match read_blocking(tx, rx, phy, PHY_ID1_REG) {
+        Ok(id1) if id1 != 0xFFFF => id1,
+        _ => return None,
+    };
+    let id2 = read_blocking(tx, rx, phy, PHY_ID2_REG).ok()?;
+    Some((id1 as u32) << 16 | id2 as u32)
+}
+
+// First PHY address that answers, with its identifier
+pub fn find_phy<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>) -> Option<(u8, u32)> {
+    (0..=SMI_MAX_ADDR as u8).find_map(|phy| read_phy_id(tx, rx, phy).map(|id| (phy, id)))
+}
+
+// Registers 2 and 3 carry OUI bits 3 to 24, then a 6 bit model and a 4 bit revision
+const PHY_OUI_SHIFT: u32 = 10;
+const PHY_MODEL_SHIFT: u32 = 4;
+const PHY_MODEL_MASK: u32 = 0x3F;
+const PHY_REVISION_MASK: u32 = 0xF;
+
+pub struct PhyVendor {
+    // Identifier with model and revision cleared, as datasheets list it
+    pub id: u32,
+    pub name: &'static str,
+}
+
+// Manufacturers of PHYs commonly found on evaluation boards. Some have more
+// than one OUI block, each gets its own line
+pub const PHY_VENDORS: [PhyVendor; 10] = [
+    PhyVendor { id: 0x0007_C000, name: "Microchip (SMSC)" },
+    PhyVendor { id: 0x0022_1400, name: "Microchip (Micrel)" },
+    PhyVendor { id: 0x001C_C800, name: "Realtek" },
+    PhyVendor { id: 0x2000_5C00, name: "Texas Instruments" },
+    PhyVendor { id: 0x2000_A000, name: "Texas Instruments" },
+    PhyVendor { id: 0x0141_0C00, name: "Marvell" },
+    PhyVendor { id: 0x0020_6000, name: "Broadcom" },
+    PhyVendor { id: 0x004D_D000, name: "Qualcomm Atheros" },
+    PhyVendor { id: 0x0243_0C00, name: "IC Plus" },
+    PhyVendor { id: 0x0181_B800, name: "Davicom" },
+];
+
+// Fields of a PHY identifier, as read_phy_id returns it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PhyIdent {
+    pub oui: u32,
+    pub model: u8,
+    pub revision: u8,
+    pub vendor: Option<&'static str>,
+}
+
+pub fn decode_phy_id(id: u32) -> PhyIdent {
+    let oui = id >> PHY_OUI_SHIFT;
+    PhyIdent {
+        oui,
+        model: ((id >> PHY_MODEL_SHIFT) & PHY_MODEL_MASK) as u8,
+        revision: (id & PHY_REVISION_MASK) as u8,
+        vendor: PHY_VENDORS.iter().find(|vendor| vendor.id >> PHY_OUI_SHIFT == oui).map(|vendor| vendor.name),
+    }
+}
+
+// Answer of smi scanmap, bit N set when PHY address N answered
+pub fn scan_bitmap(phys: impl Iterator<Item = u8>) -> u32 {
+    phys.fold(0, |map, phy| map | 1 << phy)
+}
+
+// Clause 22 registers smi link decodes
+const BMCR_REG: u8 = 0;
+const BMSR_REG: u8 = 1;
+const ANAR_REG: u8 = 4;
+const ANLPAR_REG: u8 = 5;
+const GBCR_REG: u8 = 9;
+const GBSR_REG: u8 = 10;
+
+// BMCR: forced speed (bit 6 high, bit 13 low half of the code), autoneg and duplex
+const BMCR_SPEED_MSB: u16 = 1 << 6;
+const BMCR_FULL_DUPLEX: u16 = 1 << 8;
+const BMCR_ANEG_ENABLE: u16 = 1 << 12;
+const BMCR_SPEED_LSB: u16 = 1 << 13;
+// BMSR: link status latches low until read, extended status means 1000BASE-T registers
+const BMSR_LINK: u16 = 1 << 2;
+const BMSR_EXT_STATUS: u16 = 1 << 8;
+// ANAR and ANLPAR abilities
+const AN_10_FULL: u16 = 1 << 6;
+const AN_100_HALF: u16 = 1 << 7;
+const AN_100_FULL: u16 = 1 << 8;
+// 1000BASE-T control (advertised) and status (link partner) bits
+const GBCR_1000_HALF: u16 = 1 << 8;
+const GBCR_1000_FULL: u16 = 1 << 9;
+const GBSR_1000_HALF: u16 = 1 << 10;
+const GBSR_1000_FULL: u16 = 1 << 11;
+
+// Registers a link report is decoded from. gbcr and gbsr stay 0 on PHYs
+// without extended status
+#[derive(Copy, Clone, Default)]
+pub struct LinkRegs {
+    pub bmcr: u16,
+    pub bmsr: u16,
+    pub anar: u16,
+    pub anlpar: u16,
+    pub gbcr: u16,
+    pub gbsr: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Link {
+    Down,
+    Up { mbps: u16, full_duplex: bool },
+}
+
+// The speed and duplex of an up link: with autonegotiation the best mode both
+// ends advertise, 1000 before 100 before 10 and full before half, as 802.3
+// Annex 28B orders them. Otherwise what BMCR forces
+pub fn decode_link(regs: &LinkRegs) -> Link {
+    if regs.bmsr & BMSR_LINK == 0 {
+        return Link::Down
+    }
+    let (mbps, full_duplex) = if regs.bmcr & BMCR_ANEG_ENABLE != 0 {
+        let common = regs.anar & regs.anlpar;
+        if regs.gbcr & GBCR_1000_FULL != 0 && regs.gbsr & GBSR_1000_FULL != 0 {
+            (1000, true)
+        } else if regs.gbcr & GBCR_1000_HALF != 0 && regs.gbsr & GBSR_1000_HALF != 0 {
+            (1000, false)
+        } else if common & AN_100_FULL != 0 {
+            (100, true)
+        } else if common & AN_100_HALF != 0 {
+            (100, false)
+        } else {
+            (10, common & AN_10_FULL != 0)
+        }
+    } else {
+        let mbps = match (regs.bmcr & BMCR_SPEED_MSB != 0, regs.bmcr & BMCR_SPEED_LSB != 0) {
+            (true, _) => 1000,
+            (false, true) => 100,
+            (false, false) => 10,
+        };
+        (mbps, regs.bmcr & BMCR_FULL_DUPLEX != 0)
+    };
+    Link::Up { mbps, full_duplex }
+}
+
+// Read the registers decode_link needs. BMSR is read twice, the first read
+// returns a link loss latched since the last one
+pub fn read_link<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8) -> Result<Link, &'static str> {
+    let mut regs = LinkRegs::default();
+    read_blocking(tx, rx, phy, BMSR_REG)?;
+    regs.bmsr = read_blocking(tx, rx, phy, BMSR_REG)?;
+    regs.bmcr = read_blocking(tx, rx, phy, BMCR_REG)?;
+    regs.anar = read_blocking(tx, rx, phy, ANAR_REG)?;
+    regs.anlpar = read_blocking(tx, rx, phy, ANLPAR_REG)?;
+    if regs.bmsr & BMSR_EXT_STATUS != 0 {
+        regs.gbcr = read_blocking(tx, rx, phy, GBCR_REG)?;
+        regs.gbsr = read_blocking(tx, rx, phy, GBSR_REG)?;
+    }
+    Ok(decode_link(&regs))
+}
+
+// One named bit field of a register, width bits from shift
+pub struct RegField {
+    pub name: &'static str,
+    pub shift: u8,
+    pub width: u8,
+}
+
+const fn bit(name: &'static str, shift: u8) -> RegField {
+    RegField { name, shift, width: 1 }
+}
+
+// Fields of the Clause 22 registers every PHY implements, most significant
+// first. Vendor registers (16 and up) differ between parts and are left out
+const BMCR_FIELDS: [RegField; 10] = [
+    bit("RESET", 15), bit("LOOPBACK", 14), bit("SPEED_LSB", 13), bit("ANEG_EN", 12), bit("POWER_DOWN", 11),
+    bit("ISOLATE", 10), bit("ANEG_RESTART", 9), bit("DUPLEX", 8), bit("COL_TEST", 7), bit("SPEED_MSB", 6),
+];
+const BMSR_FIELDS: [RegField; 13] = [
+    bit("100T4", 15), bit("100FD", 14), bit("100HD", 13), bit("10FD", 12), bit("10HD", 11),
+    bit("EXT_STATUS", 8), bit("PRE_SUPPRESS", 6), bit("ANEG_DONE", 5), bit("REMOTE_FAULT", 4),
+    bit("ANEG_ABLE", 3), bit("LINK", 2), bit("JABBER", 1), bit("EXT_CAP", 0),
+];
+// ANAR and ANLPAR share the layout
+const AN_FIELDS: [RegField; 10] = [
+    bit("NEXT_PAGE", 15), bit("REMOTE_FAULT", 13), bit("ASYM_PAUSE", 11), bit("PAUSE", 10), bit("100T4", 9),
+    bit("100FD", 8), bit("100HD", 7), bit("10FD", 6), bit("10HD", 5), RegField { name: "SELECTOR", shift: 0, width: 5 },
+];
+const GBCR_FIELDS: [RegField; 6] = [
+    RegField { name: "TEST_MODE", shift: 13, width: 3 }, bit("MS_MANUAL", 12), bit("MS_MASTER", 11),
+    bit("MULTI_PORT", 10), bit("1000FD", 9), bit("1000HD", 8),
+];
+const GBSR_FIELDS: [RegField; 7] = [
+    bit("MS_FAULT", 15), bit("MS_MASTER", 14), bit("LOCAL_RX_OK", 13), bit("REMOTE_RX_OK", 12),
+    bit("LP_1000FD", 11), bit("LP_1000HD", 10), RegField { name: "IDLE_ERRORS", shift: 0, width: 8 },
+];
+
+pub fn reg_fields(reg: u8) -> Option<&'static [RegField]> {
+    match reg {
+        BMCR_REG => Some(&BMCR_FIELDS),
+        BMSR_REG => Some(&BMSR_FIELDS),
+        ANAR_REG | ANLPAR_REG => Some(&AN_FIELDS),
+        GBCR_REG => Some(&GBCR_FIELDS),
+        GBSR_REG => Some(&GBSR_FIELDS),
+        _ => None,
+    }
+}
+
+// Answer of smi r ... fields, ie "reg1: 100T4=0 100FD=1 ... LINK=1"
+pub fn write_fields(out: &mut impl fmt::Write, reg: u8, value: u16) -> fmt::Result {
+    write!(out, "reg{}:", reg)?;
+    match reg_fields(reg) {
+        Some(fields) => {
+            for field in fields {
+                let mask = (1_u32 << field.width) - 1;
+                write!(out, " {}={}", field.name, (value as u32 >> field.shift) & mask)?;
+            }
+            Ok(())
+        }
+        None => write!(out, " no field table"),
+    }
+}
+
+// Register that selects the extended page on the PHYs this is used with
+// (22 on Marvell, Realtek uses 31)
+pub const SMI_PAGE_REG: u8 = 22;
+
+// Read a register of an extended page. The page register is read first and
+// put back afterwards, so the PHY is left on the page it was on
+pub fn read_page_blocking<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8, page: u16, reg: u8) -> Result<u16, &'static str> {
+    let saved = read_blocking(tx, rx, phy, SMI_PAGE_REG)?;
+    tx.write(encode_smi(false, phy, SMI_PAGE_REG, page));
+    let value = read_blocking(tx, rx, phy, reg);
+    tx.write(encode_smi(false, phy, SMI_PAGE_REG, saved));
+    value
+}
+
+// Write a register of an extended page, restoring the page like read_page_blocking.
+// Nothing is written if the page register could not be read
+pub fn write_page_blocking<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8, page: u16, reg: u8, data: u16) -> Result<(), &'static str> {
+    let saved = read_blocking(tx, rx, phy, SMI_PAGE_REG)?;
+    tx.write(encode_smi(false, phy, SMI_PAGE_REG, page));
+    tx.write(encode_smi(false, phy, reg, data));
+    tx.write(encode_smi(false, phy, SMI_PAGE_REG, saved));
+    Ok(())
+}
+
+// Read-modify-write of a PHY register, returns the value written back.
+// Nothing is written if the read failed
+pub fn modify_blocking<SM: ValidStateMachine>(tx: &mut Tx<SM>, rx: &mut Rx<SM>, phy: u8, reg: u8, set: u16, clear: u16) -> Result<u16, &'static str> {
+    let value = apply_mask(read_blocking(tx, rx, phy, reg)?, set, clear);
+    tx.write(encode_smi(false, phy, reg, value));
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn turnaround_driven_low() {
+        assert_eq!(check_turnaround(0x1234), Ok(0x1234));
+        assert_eq!(check_turnaround(0xFFFF), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn turnaround_left_high() {
+        // Nobody drove the bus, the pull-up leaves every bit high
+        assert_eq!(check_turnaround(0x1_FFFF), Err("SMI read error: no turnaround\n\r"));
+        assert!(check_turnaround(READ_TURNAROUND_BIT | 0x1234).is_err());
+    }
+
+    #[test]
+    fn mask_set_and_clear() {
+        assert_eq!(apply_mask(0x1140, 0x8000, 0), 0x9140);
+        assert_eq!(apply_mask(0x1140, 0, 0x1000), 0x0140);
+        // Clear wins over set for a bit in both
+        assert_eq!(apply_mask(0x0000, 0x0001, 0x0001), 0x0000);
+    }
+
+    #[test]
+    fn bit_per_answering_phy() {
+        assert_eq!(scan_bitmap([1_u8, 5, 31].iter().copied()), 0x8000_0022);
+        assert_eq!(scan_bitmap(core::iter::empty()), 0);
+        assert_eq!(scan_bitmap(0..32_u8), u32::MAX);
+    }
+
+    #[test]
+    fn link_decoded() {
+        // Link bit clear: down whatever the rest says
+        assert_eq!(decode_link(&LinkRegs { bmcr: 0x1140, bmsr: 0x7949, ..Default::default() }), Link::Down);
+        // Autoneg, both ends 10/100 full and half: 100 full
+        let regs = LinkRegs { bmcr: 0x1000, bmsr: 0x786D, anar: 0x01E1, anlpar: 0x45E1, ..Default::default() };
+        assert_eq!(decode_link(&regs), Link::Up { mbps: 100, full_duplex: true });
+        // Partner only 10 half
+        let regs = LinkRegs { anlpar: 0x0021, ..regs };
+        assert_eq!(decode_link(&regs), Link::Up { mbps: 10, full_duplex: false });
+        // Gigabit advertised and answered
+        let regs = LinkRegs { bmcr: 0x1140, bmsr: 0x796D, anar: 0x01E1, anlpar: 0xC1E1, gbcr: 0x0300, gbsr: 0x3C00 };
+        assert_eq!(decode_link(&regs), Link::Up { mbps: 1000, full_duplex: true });
+        // Forced 100 half, then forced 1000 full, autoneg off
+        let regs = LinkRegs { bmcr: 0x2000, bmsr: 0x784D, ..Default::default() };
+        assert_eq!(decode_link(&regs), Link::Up { mbps: 100, full_duplex: false });
+        let regs = LinkRegs { bmcr: 0x0140, ..regs };
+        assert_eq!(decode_link(&regs), Link::Up { mbps: 1000, full_duplex: true });
+    }
+
+    #[test]
+    fn phy_id_decoded() {
+        // RTL8211F
+        assert_eq!(decode_phy_id(0x001C_C916), PhyIdent { oui: 0x732, model: 0x11, revision: 6, vendor: Some("Realtek") });
+        // LAN8720A, KSZ9031, DP83867
+        assert_eq!(decode_phy_id(0x0007_C0F1).vendor, Some("Microchip (SMSC)"));
+        assert_eq!(decode_phy_id(0x0022_1622).vendor, Some("Microchip (Micrel)"));
+        assert_eq!(decode_phy_id(0x2000_A231).vendor, Some("Texas Instruments"));
+        assert_eq!(decode_phy_id(0x1234_5678).vendor, None);
+    }
+
+    #[test]
+    fn fields_named() {
+        let mut buf = [0_u8; 256];
+        let mut out = Wrapper::new(&mut buf);
+        write_fields(&mut out, 1, 0x796D).unwrap();
+        assert_eq!(out.as_str(), "reg1: 100T4=0 100FD=1 100HD=1 10FD=1 10HD=1 EXT_STATUS=1 PRE_SUPPRESS=1 ANEG_DONE=1 REMOTE_FAULT=0 ANEG_ABLE=1 LINK=1 JABBER=0 EXT_CAP=1");
+        let mut buf = [0_u8; 256];
+        let mut out = Wrapper::new(&mut buf);
+        write_fields(&mut out, 4, 0x01E1).unwrap();
+        assert!(out.as_str().ends_with("100FD=1 100HD=1 10FD=1 10HD=1 SELECTOR=1"));
+        let mut buf = [0_u8; 256];
+        let mut out = Wrapper::new(&mut buf);
+        write_fields(&mut out, 17, 0xFFFF).unwrap();
+        assert_eq!(out.as_str(), "reg17: no field table");
+    }
+}
@@ -0,0 +1,60 @@
+//! RTIC tasks and their priorities, for `stat tasks`.
+//! RTIC only takes a priority as a literal in the #[task] attribute and
+//! derives the resource ceilings from it at compile time, so a priority can
+//! not come from a constant or change at boot. Change the attribute in main.rs
+//! and the entry here together.
+
+use core::fmt;
+
+// Priority of usb_rx. Equal to send_out, so a request is never dispatched
+// while a USB packet is being split into lines. Above the UART0 and SPI0
+// handlers so a slow serial host transport does not stall the USB console
+pub const USB_PRIORITY: u8 = 3;
+
+pub struct TaskInfo {
+    pub name: &'static str,
+    // Interrupt the task is bound to, "spawn" for software tasks
+    pub binds: &'static str,
+    pub priority: u8,
+}
+
+pub const TASKS: [TaskInfo; 8] = [
+    TaskInfo { name: "usb_rx", binds: "USBCTRL_IRQ", priority: USB_PRIORITY },
+    TaskInfo { name: "send_out", binds: "spawn", priority: 3 },
+    TaskInfo { name: "step_due", binds: "TIMER_IRQ_1", priority: 3 },
+    TaskInfo { name: "pio_sm_rx", binds: "PIO0_IRQ_0", priority: 3 },
+    TaskInfo { name: "respond_to_host", binds: "spawn", priority: 3 },
+    TaskInfo { name: "uart0", binds: "UART0_IRQ", priority: 2 },
+    TaskInfo { name: "spi0", binds: "SPI0_IRQ", priority: 2 },
+    TaskInfo { name: "idle", binds: "idle", priority: 0 },
+];
+
+// Answer to `stat tasks`, one "<name> <binds> <priority>" line per task
+pub fn write_tasks(out: &mut impl fmt::Write) -> fmt::Result {
+    for task in TASKS.iter() {
+        write!(out, "\n\r{} {} {}", task.name, task.binds, task.priority)?;
+    }
+    write!(out, "\n\r->")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::Wrapper;
+
+    #[test]
+    fn one_line_per_task() {
+        // Fits the buffer stat tasks formats into
+        let mut buf = [0_u8; 192];
+        let mut out = Wrapper::new(&mut buf);
+        write_tasks(&mut out).unwrap();
+        let shown = out.as_str();
+        assert!(shown.contains("\n\rusb_rx USBCTRL_IRQ 3\n\r") && shown.ends_with("\n\r->"));
+        assert_eq!(shown.matches("\n\r").count(), TASKS.len() + 1);
+    }
+
+    #[test]
+    fn batch_pacing_alarm_listed() {
+        assert!(TASKS.iter().any(|task| (task.name, task.binds, task.priority) == ("step_due", "TIMER_IRQ_1", 3)));
+    }
+}
@@ -0,0 +1,70 @@
+//! WS2812 (NeoPixel) output on PIO1 state machine 0, for `led set`.
+//! The program is installed at init like the SMI one; the pin it drives is
+//! chosen per request, so the side-set pin of the state machine is moved here
+//! with raw register writes before each pixel is sent.
+
+// Installed at init either way, only led set needs the pin routing
+#![cfg_attr(not(feature = "led"), allow(dead_code))]
+
+#[cfg(feature = "led")]
+use crate::gpio;
+
+// One bit is 10 PIO cycles at 800 kHz
+pub const WS2812_BIT_HZ: u32 = 800_000 * 10;
+// Pin the program is built for, moved by the first led set
+pub const WS2812_DEFAULT_PIN: u8 = 16;
+
+// PIO1 state machine 0 INSTR (forced execution) and PINCTRL registers
+const PIO1_SM0_INSTR: *mut u32 = 0x5030_00d8 as *mut u32;
+const PIO1_SM0_PINCTRL: *mut u32 = 0x5030_00dc as *mut u32;
+const PINCTRL_SIDESET_COUNT_SHIFT: u32 = 29;
+const PINCTRL_SET_COUNT_SHIFT: u32 = 26;
+const PINCTRL_SIDESET_BASE_SHIFT: u32 = 10;
+const PINCTRL_SET_BASE_SHIFT: u32 = 5;
+// "set pindirs, 1 side 0"
+const SET_PINDIRS_1: u32 = 0xE081;
+
+const FUNCSEL_PIO1: u32 = 7;
+
+// Integer and 1/256 fraction of the state machine clock divisor
+pub fn clock_divisor(sys_clk_hz: u32) -> (u16, u8) {
+    let int = sys_clk_hz / WS2812_BIT_HZ;
+    let frac = (sys_clk_hz % WS2812_BIT_HZ) as u64 * 256 / WS2812_BIT_HZ as u64;
+    (int as u16, frac as u8)
+}
+
+// Point the state machine at pin and make it an output driven low.
+// Only call between pixels: the state machine is then stalled on its pull
+#[cfg(feature = "led")]
+pub fn select_pin(pin: u8) {
+    let pinctrl = (1 << PINCTRL_SIDESET_COUNT_SHIFT)
+        | (1 << PINCTRL_SET_COUNT_SHIFT)
+        | ((pin as u32) << PINCTRL_SIDESET_BASE_SHIFT)
+        | ((pin as u32) << PINCTRL_SET_BASE_SHIFT);
+    unsafe {
+        core::ptr::write_volatile(PIO1_SM0_PINCTRL, pinctrl);
+        core::ptr::write_volatile(PIO1_SM0_INSTR, SET_PINDIRS_1);
+    }
+    gpio::set_function(pin, FUNCSEL_PIO1);
+}
+
+// The pixel shifts out green, red then blue, MSB first, from the top 24 bits
+pub fn grb(red: u8, green: u8, blue: u8) -> u32 {
+    (green as u32) << 24 | (red as u32) << 16 | (blue as u32) << 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_word() {
+        assert_eq!(grb(1, 2, 3), 0x0201_0300);
+    }
+
+    #[test]
+    fn divisor_at_the_default_clock() {
+        // 125 MHz over 10 cycles of an 800 kHz bit
+        assert_eq!(clock_divisor(125_000_000), (15, 160));
+    }
+}